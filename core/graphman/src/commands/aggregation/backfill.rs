@@ -0,0 +1,82 @@
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use graph::components::store::DeploymentLocator;
+use graph_store_postgres::command_support::catalog;
+use graph_store_postgres::ConnectionPool;
+use graph_store_postgres::Store;
+use thiserror::Error;
+
+use crate::deployment::DeploymentSelector;
+use crate::deployment::DeploymentVersionSelector;
+use crate::GraphmanError;
+
+/// A deployment that is not being indexed, either because it is paused or
+/// because it is not assigned to any node.
+pub struct InactiveDeployment {
+    locator: DeploymentLocator,
+}
+
+#[derive(Debug, Error)]
+pub enum BackfillAggregationError {
+    #[error("deployment '{0}' is being indexed; pause or unassign it before backfilling")]
+    NotPaused(String),
+
+    #[error(transparent)]
+    Common(#[from] GraphmanError),
+}
+
+impl InactiveDeployment {
+    pub fn locator(&self) -> &DeploymentLocator {
+        &self.locator
+    }
+}
+
+pub fn load_inactive_deployment(
+    primary_pool: ConnectionPool,
+    deployment: &DeploymentSelector,
+) -> Result<InactiveDeployment, BackfillAggregationError> {
+    let mut primary_conn = primary_pool.get().map_err(GraphmanError::from)?;
+
+    let locator = crate::deployment::load_deployment_locator(
+        &mut primary_conn,
+        deployment,
+        &DeploymentVersionSelector::All,
+    )?;
+
+    let mut catalog_conn = catalog::Connection::new(primary_conn);
+
+    let site = catalog_conn
+        .locate_site(locator.clone())
+        .map_err(GraphmanError::from)?
+        .ok_or_else(|| {
+            GraphmanError::Store(anyhow!("deployment site not found for '{locator}'"))
+        })?;
+
+    // Rolling up while the deployment is being indexed would race with the
+    // rollups that indexing does
+    let status = catalog_conn
+        .assignment_status(&site)
+        .map_err(GraphmanError::from)?;
+    if let Some((_, false)) = status {
+        return Err(BackfillAggregationError::NotPaused(locator.to_string()));
+    }
+
+    Ok(InactiveDeployment { locator })
+}
+
+/// Recompute the aggregation `aggregation` of the deployment from the data
+/// in its source timeseries and return the number of rows that were
+/// written.
+pub async fn backfill_aggregation(
+    store: Arc<Store>,
+    deployment: &InactiveDeployment,
+    aggregation: &str,
+) -> Result<usize, GraphmanError> {
+    let count = store
+        .subgraph_store()
+        .backfill_aggregation(&deployment.locator, aggregation)
+        .await?;
+
+    Ok(count)
+}
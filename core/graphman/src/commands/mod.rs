@@ -1 +1,2 @@
+pub mod aggregation;
 pub mod deployment;
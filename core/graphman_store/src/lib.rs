@@ -77,6 +77,7 @@ pub struct ExecutionId(pub i64);
 #[strum(serialize_all = "snake_case")]
 pub enum CommandKind {
     RestartDeployment,
+    BackfillAggregation,
 }
 
 /// All possible states of a command execution.
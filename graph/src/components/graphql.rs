@@ -1,11 +1,61 @@
-use crate::data::query::QueryResults;
+use crate::components::store::ExportPages;
+use crate::data::query::{Federation, QueryResults};
 use crate::data::query::{Query, QueryTarget};
-use crate::prelude::DeploymentHash;
+use crate::data::value::{Object, Word};
+use crate::prelude::{r, s, DeploymentHash, QueryExecutionError};
 
 use async_trait::async_trait;
+use futures03::Stream;
+use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
 
+/// The results of a GraphQL subscription, one for every time the data the
+/// subscription reads changed
+pub type QueryResultStream = Pin<Box<dyn Stream<Item = QueryResults> + Send>>;
+
+/// The entities that an export of one collection reads, a page at a time.
+/// The entities only hold the fields that the export selects, under
+/// their response keys
+pub struct QueryExport {
+    /// The response key of each selected field and the attribute of the
+    /// entities that holds its value
+    fields: Vec<(Word, Word)>,
+    pages: ExportPages,
+}
+
+impl QueryExport {
+    pub fn new(fields: Vec<(Word, Word)>, pages: ExportPages) -> Self {
+        QueryExport { fields, pages }
+    }
+
+    /// The response keys of the selected fields, in the order in which
+    /// the query selects them
+    pub fn columns(&self) -> impl Iterator<Item = &str> {
+        self.fields.iter().map(|(key, _)| key.as_str())
+    }
+
+    /// The next page of entities, or `None` once all of them have been
+    /// read
+    pub async fn next_page(&mut self) -> Option<Result<Vec<Object>, QueryExecutionError>> {
+        let page = self.pages.recv().await?;
+        Some(page.map(|objects| {
+            objects
+                .into_iter()
+                .map(|object| {
+                    self.fields
+                        .iter()
+                        .map(|(key, attr)| {
+                            let value = object.entity.get(attr.as_str()).cloned();
+                            (key.clone(), value.unwrap_or(r::Value::Null))
+                        })
+                        .collect()
+                })
+                .collect()
+        }))
+    }
+}
+
 pub enum GraphQlTarget {
     SubgraphName(String),
     Deployment(DeploymentHash),
@@ -27,6 +77,56 @@ pub trait GraphQlRunner: Send + Sync + 'static {
         max_skip: Option<u32>,
     ) -> QueryResults;
 
+    /// Runs a batch of GraphQL queries and returns their results in the
+    /// same order. All queries see the subgraph at the same block unless
+    /// they ask for a specific block
+    async fn run_batch(
+        self: Arc<Self>,
+        queries: Vec<Query>,
+        target: QueryTarget,
+    ) -> Vec<QueryResults>;
+
+    /// Runs a GraphQL subscription. The stream yields the result of the
+    /// subscription for the current state of the subgraph, and then a new
+    /// result whenever the subgraph reverts blocks or advances to a block
+    /// that changes entities the subscription reads. If the subscription
+    /// can't be run at all, the errors are returned right away
+    async fn run_subscription(
+        self: Arc<Self>,
+        query: Query,
+        target: QueryTarget,
+    ) -> Result<QueryResultStream, QueryResults>;
+
+    /// Runs `query`, which must select exactly one collection of entities,
+    /// as an export that reads all entities that the collection matches at
+    /// the block the query resolves to, `page_size` entities at a time.
+    /// If the export can't be started, the errors are returned right away
+    async fn run_export(
+        self: Arc<Self>,
+        query: Query,
+        target: QueryTarget,
+        page_size: usize,
+    ) -> Result<QueryExport, QueryResults>;
+
+    /// Runs a GraphQL query against the federated schema of the subgraphs
+    /// in `federation`. Each root field of the query is sent to the
+    /// subgraph whose namespace prefixes it, and the results are combined
+    /// into one response
+    async fn run_federated(self: Arc<Self>, query: Query, federation: Federation) -> QueryResults;
+
+    /// Returns the federated schema of the subgraphs in `federation`
+    async fn federated_schema(
+        self: Arc<Self>,
+        federation: Federation,
+    ) -> Result<s::Document, QueryExecutionError>;
+
+    /// Returns the deployment that `target` refers to and the names of all
+    /// subgraphs whose current or pending version is that deployment
+    async fn resolve_target(
+        self: Arc<Self>,
+        target: QueryTarget,
+    ) -> Result<(DeploymentHash, Vec<String>), QueryExecutionError>;
+
     fn metrics(&self) -> Arc<dyn GraphQLMetrics>;
 }
 
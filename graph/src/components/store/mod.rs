@@ -32,13 +32,13 @@ use crate::blockchain::{Block, BlockHash, BlockPtr};
 use crate::cheap_clone::CheapClone;
 use crate::components::store::write::EntityModification;
 use crate::data::store::scalar::Bytes;
-use crate::data::store::{Id, IdList, Value};
-use crate::data::value::Word;
+use crate::data::store::{Id, IdList, IdType, Value, ID};
+use crate::data::value::{Object, Word};
 use crate::data_source::CausalityRegion;
 use crate::derive::CheapClone;
 use crate::env::ENV_VARS;
 use crate::internal_error;
-use crate::prelude::{s, Attribute, DeploymentHash, ValueType};
+use crate::prelude::{r, s, Attribute, DeploymentHash, ValueType};
 use crate::schema::{ast as sast, EntityKey, EntityType, InputSchema};
 use crate::util::stats::MovingStats;
 
@@ -134,6 +134,18 @@ pub enum EntityFilter {
     EndsWithNoCase(Attribute, Value),
     NotEndsWith(Attribute, Value),
     NotEndsWithNoCase(Attribute, Value),
+    EqualNoCase(Attribute, Value),
+    InNoCase(Attribute, Vec<Value>),
+    /// The attribute matches the POSIX regular expression in the value
+    Matches(Attribute, Value),
+    /// The list attribute has at least one element in common with the value
+    Overlaps(Attribute, Value),
+    /// Every element of the list attribute is also an element of the value
+    ContainedBy(Attribute, Value),
+    /// The list attribute has more elements than the value
+    LengthGreaterThan(Attribute, Value),
+    /// The list attribute has fewer elements than the value
+    LengthLessThan(Attribute, Value),
     ChangeBlockGte(BlockNumber),
     Child(Child),
     Fulltext(Attribute, Value),
@@ -175,6 +187,17 @@ impl fmt::Display for EntityFilter {
             EndsWithNoCase(a, v) => write!(f, "{a} ~ *{v}$i"),
             NotEndsWith(a, v) => write!(f, "{a} !~ *{v}$"),
             NotEndsWithNoCase(a, v) => write!(f, "{a} !~ *{v}$i"),
+            EqualNoCase(a, v) => write!(f, "{a} = {v}i"),
+            InNoCase(a, vs) => write!(
+                f,
+                "{a} in ({})i",
+                vs.iter().map(|v| v.to_string()).join(",")
+            ),
+            Matches(a, v) => write!(f, "{a} ~ /{v}/"),
+            Overlaps(a, v) => write!(f, "{a} && {v}"),
+            ContainedBy(a, v) => write!(f, "{a} <@ {v}"),
+            LengthGreaterThan(a, v) => write!(f, "len({a}) > {v}"),
+            LengthLessThan(a, v) => write!(f, "len({a}) < {v}"),
             ChangeBlockGte(b) => write!(f, "block >= {b}"),
             Child(child /* a, et, cf, _ */) => write!(
                 f,
@@ -225,6 +248,18 @@ impl EntityFilter {
             None => self,
         }
     }
+
+    /// Return `true` if this filter, or any filter nested in it, matches
+    /// an attribute against a regular expression
+    pub fn uses_regex(&self) -> bool {
+        use EntityFilter::*;
+        match self {
+            And(filters) | Or(filters) => filters.iter().any(|f| f.uses_regex()),
+            Child(child) => child.filter.uses_regex(),
+            Matches(_, _) => true,
+            _ => false,
+        }
+    }
 }
 
 /// Holds the information needed to query a store.
@@ -261,6 +296,82 @@ pub enum EntityOrder {
     /// Do not order at all. This speeds up queries where we know that
     /// order does not matter
     Unordered,
+    /// Order by several attributes, one after the other. Each entry is
+    /// one of `Ascending`, `Descending`, `ChildAscending` or
+    /// `ChildDescending`, and there are always at least two of them. Use
+    /// `id`, in the direction of the last entry, as a tie-breaker
+    Multi(Vec<EntityOrder>),
+}
+
+impl EntityOrder {
+    /// The attributes, and their types, whose values make up the cursor of
+    /// an entity in a collection sorted by this order. Sorting by `id`
+    /// ends the list since `id` is unique and part of every cursor. Only
+    /// orders by attributes of the entity itself can be used with cursors
+    pub fn cursor_attributes(&self) -> Result<Vec<(&str, ValueType)>, String> {
+        let orders = match self {
+            EntityOrder::Multi(orders) => orders.as_slice(),
+            order => std::slice::from_ref(order),
+        };
+        let mut attributes = Vec::new();
+        for order in orders {
+            match order {
+                EntityOrder::Ascending(attr, value_type)
+                | EntityOrder::Descending(attr, value_type) => {
+                    if attr.as_str() == ID.as_str() {
+                        break;
+                    }
+                    attributes.push((attr.as_str(), *value_type));
+                }
+                EntityOrder::Default => break,
+                EntityOrder::ChildAscending(_)
+                | EntityOrder::ChildDescending(_)
+                | EntityOrder::Unordered
+                | EntityOrder::Multi(_) => {
+                    return Err(
+                        "cursors require sorting by `id` or attributes of the entity".to_string(),
+                    )
+                }
+            }
+        }
+        Ok(attributes)
+    }
+
+    /// The order that sorts entities in exactly the opposite direction of
+    /// this one, including the `id` that breaks ties. Orders by child
+    /// attributes can not be reversed, and neither can not ordering at all
+    pub fn reversed(&self, id_type: IdType) -> Option<EntityOrder> {
+        match self {
+            EntityOrder::Ascending(attr, value_type) => {
+                Some(EntityOrder::Descending(attr.clone(), *value_type))
+            }
+            EntityOrder::Descending(attr, value_type) => {
+                Some(EntityOrder::Ascending(attr.clone(), *value_type))
+            }
+            EntityOrder::Default => Some(EntityOrder::Descending(
+                ID.to_string(),
+                id_type.value_type(),
+            )),
+            EntityOrder::Multi(orders) => orders
+                .iter()
+                .map(|order| order.reversed(id_type))
+                .collect::<Option<Vec<_>>>()
+                .map(EntityOrder::Multi),
+            EntityOrder::ChildAscending(_)
+            | EntityOrder::ChildDescending(_)
+            | EntityOrder::Unordered => None,
+        }
+    }
+
+    /// Combine `orders` into one order. A single order is returned as is,
+    /// and no orders at all result in `EntityOrder::Default`
+    pub fn multi(mut orders: Vec<EntityOrder>) -> Self {
+        match orders.len() {
+            0 => EntityOrder::Default,
+            1 => orders.pop().unwrap(),
+            _ => EntityOrder::Multi(orders),
+        }
+    }
 }
 
 /// How many entities to return, how many to skip etc.
@@ -271,6 +382,12 @@ pub struct EntityRange {
 
     /// How many entities to skip.
     pub skip: u32,
+
+    /// Only return entities that come after this cursor in the sort order
+    pub after: Option<EntityCursor>,
+
+    /// Only return entities that come before this cursor in the sort order
+    pub before: Option<EntityCursor>,
 }
 
 impl EntityRange {
@@ -283,6 +400,8 @@ impl EntityRange {
         Self {
             first: Some(n),
             skip: 0,
+            after: None,
+            before: None,
         }
     }
 }
@@ -292,10 +411,82 @@ impl std::default::Default for EntityRange {
         Self {
             first: Some(Self::FIRST),
             skip: 0,
+            after: None,
+            before: None,
         }
     }
 }
 
+/// The position of an entity in a collection that is sorted by some of
+/// its attributes and then by `id`. Queries use cursors for keyset
+/// pagination: rather than skipping over a number of entities, they only
+/// look at the entities that sort after or before the cursor, which the
+/// database can find without scanning everything that comes earlier.
+///
+/// Clients see cursors as opaque strings; they are the URL-safe base64
+/// encoding of the JSON array `[value1, .., valueN, id]` with the values of
+/// the attributes by which the collection is sorted, or just `[id]` when
+/// the collection is sorted by `id` only
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntityCursor {
+    /// The values of the attributes by which the collection is sorted, in
+    /// the order in which they are sorted; empty if the collection is
+    /// sorted by `id` only
+    pub values: Vec<Value>,
+    /// The `id` of the entity
+    pub id: Value,
+}
+
+impl EntityCursor {
+    /// Encode the cursor for an entity with the given `id` and the
+    /// given `values` for the attributes by which the collection is sorted
+    pub fn encode(values: &[&r::Value], id: &r::Value) -> String {
+        use base64::Engine;
+
+        let key: Vec<_> = values.iter().copied().chain(std::iter::once(id)).collect();
+        let json = serde_json::to_vec(&key).expect("query values can be serialized as JSON");
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(json)
+    }
+
+    /// Decode a cursor that was produced by `encode` for a collection
+    /// that is sorted by `order`. See `EntityOrder::cursor_attributes` for
+    /// the orders that can be used with cursors
+    pub fn decode(cursor: &str, order: &EntityOrder, id_type: IdType) -> Result<Self, String> {
+        use base64::Engine;
+
+        let attributes = order.cursor_attributes()?;
+
+        let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(cursor)
+            .map_err(|e| format!("the cursor is not valid base64: {}", e))?;
+        let key: Vec<serde_json::Value> = serde_json::from_slice(&bytes)
+            .map_err(|e| format!("the cursor is not a valid JSON array: {}", e))?;
+        let mut key = key.into_iter().map(r::Value::from);
+
+        let values = attributes
+            .into_iter()
+            .map(|(_, value_type)| {
+                let value = key
+                    .next()
+                    .ok_or_else(|| "the cursor is missing a sort value".to_string())?;
+                let ty = s::Type::NamedType(value_type.to_str().to_string());
+                Value::from_query_value(&value, &ty).map_err(|e| e.to_string())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let id = match key.next() {
+            Some(r::Value::String(id)) => id_type
+                .parse(Word::from(id))
+                .map(Value::from)
+                .map_err(|e| format!("the cursor has an invalid id: {}", e))?,
+            _ => return Err("the cursor is missing the id".to_string()),
+        };
+        if key.next().is_some() {
+            return Err("the cursor does not match the `orderBy` of the query".to_string());
+        }
+        Ok(EntityCursor { values, id })
+    }
+}
+
 /// The attribute we want to window by in an `EntityWindow`. We have to
 /// distinguish between scalar and list attributes since we need to use
 /// different queries for them, and the JSONB storage scheme can not
@@ -456,6 +647,10 @@ pub struct EntityQuery {
     /// A range to limit the size of the result.
     pub range: EntityRange,
 
+    /// Only for aggregations: combine all the buckets that match `filter`
+    /// into one row for each combination of dimensions
+    pub rollup: bool,
+
     /// Optional logger for anything related to this query
     pub logger: Option<Logger>,
 
@@ -479,6 +674,7 @@ impl EntityQuery {
             filter: None,
             order: EntityOrder::Default,
             range: EntityRange::default(),
+            rollup: false,
             logger: None,
             query_id: None,
             trace: false,
@@ -501,6 +697,11 @@ impl EntityQuery {
         self
     }
 
+    pub fn rollup(mut self, rollup: bool) -> Self {
+        self.rollup = rollup;
+        self
+    }
+
     pub fn first(mut self, first: u32) -> Self {
         self.range.first = Some(first);
         self
@@ -542,6 +743,103 @@ impl EntityQuery {
     }
 }
 
+/// An aggregate value computed over all entities that match an
+/// `EntityQuery` with `QueryStore::find_aggregates`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EntityAggregate {
+    /// The number of matching entities
+    Count,
+    /// The sum of the given attribute over all matching entities
+    Sum(Attribute),
+    /// The smallest value of the given attribute
+    Min(Attribute),
+    /// The largest value of the given attribute
+    Max(Attribute),
+}
+
+/// One version of an entity as returned by `QueryStore::entity_history`
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntityVersion {
+    /// The block at which this version was written
+    pub from_block: BlockNumber,
+    /// The hash of `from_block` if we know it
+    pub from_hash: Option<BlockHash>,
+    /// The block at which this version was replaced or the entity was
+    /// removed, `None` if the version is still current
+    pub to_block: Option<BlockNumber>,
+    /// The hash of `to_block` if we know it
+    pub to_hash: Option<BlockHash>,
+    /// The attributes whose values differ from the previous version; all
+    /// attributes if this version created the entity. `None` if pruning
+    /// might have removed the previous version
+    pub changed: Option<Vec<Word>>,
+    /// The attributes of the entity in this version
+    pub data: Object,
+}
+
+/// How a block changed an entity
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntityDiffOperation {
+    /// The block created the entity
+    Insert,
+    /// The block replaced the entity with a new version
+    Update,
+    /// The block removed the entity
+    Delete,
+}
+
+impl EntityDiffOperation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EntityDiffOperation::Insert => "INSERT",
+            EntityDiffOperation::Update => "UPDATE",
+            EntityDiffOperation::Delete => "DELETE",
+        }
+    }
+}
+
+/// The position of a change in the list of changes in a range of blocks;
+/// changes are ordered by block, and then by the `vid` of the version of
+/// the entity that the change wrote or removed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EntityDiffCursor {
+    pub block: BlockNumber,
+    pub vid: i64,
+}
+
+impl fmt::Display for EntityDiffCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.block, self.vid)
+    }
+}
+
+impl std::str::FromStr for EntityDiffCursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow::anyhow!("`{}` is not a valid cursor", s);
+        let (block, vid) = s.split_once(':').ok_or_else(invalid)?;
+        Ok(EntityDiffCursor {
+            block: block.parse().map_err(|_| invalid())?,
+            vid: vid.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// One change to an entity in a range of blocks as returned by
+/// `QueryStore::entity_diff`
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntityDiff {
+    pub id: Id,
+    pub operation: EntityDiffOperation,
+    /// Where this change is in the list of changes; `cursor.block` is the
+    /// block at which the change happened
+    pub cursor: EntityDiffCursor,
+    /// The attributes of the entity after the change, or before it for
+    /// deletions. Only present when they were asked for
+    pub data: Option<Object>,
+}
+
 /// Operation types that lead to changes in assignments
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(rename_all = "lowercase")]
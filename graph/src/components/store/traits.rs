@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::ops::Range;
+use std::ops::{Range, RangeInclusive};
 
 use anyhow::Error;
 use async_trait::async_trait;
@@ -18,7 +18,7 @@ use crate::data::store::ethereum::call;
 use crate::data::store::QueryObject;
 use crate::data::subgraph::{status, DeploymentFeatures};
 use crate::data::{query::QueryTarget, subgraph::schema::*};
-use crate::prelude::{DeploymentState, NodeId, QueryExecutionError, SubgraphName};
+use crate::prelude::{r, DeploymentState, NodeId, QueryExecutionError, SubgraphName};
 use crate::schema::{ApiSchema, InputSchema};
 
 pub trait SubscriptionManager: Send + Sync + 'static {
@@ -144,6 +144,18 @@ pub trait SubgraphStore: Send + Sync + 'static {
         block_number: BlockNumber,
     ) -> Result<Vec<EntityOperation>, StoreError>;
 
+    /// Return the changes to entities of the type named `entity_type` in
+    /// `blocks` like [`QueryStore::entity_diff`] does
+    fn entity_diff(
+        &self,
+        subgraph_id: &DeploymentHash,
+        entity_type: &str,
+        blocks: RangeInclusive<BlockNumber>,
+        after: Option<EntityDiffCursor>,
+        first: usize,
+        with_data: bool,
+    ) -> Result<Vec<EntityDiff>, StoreError>;
+
     /// Return the GraphQL schema supplied by the user
     fn input_schema(&self, subgraph_id: &DeploymentHash) -> Result<InputSchema, StoreError>;
 
@@ -443,6 +455,13 @@ pub trait QueryStoreManager: Send + Sync + 'static {
         &self,
         target: QueryTarget,
     ) -> Result<Arc<dyn QueryStore + Send + Sync>, QueryExecutionError>;
+
+    /// The deployment that `target` refers to, and the names of all
+    /// subgraphs whose current or pending version is that deployment
+    async fn resolve_target(
+        &self,
+        target: QueryTarget,
+    ) -> Result<(DeploymentHash, Vec<String>), QueryExecutionError>;
 }
 
 pub trait BlockStore: Send + Sync + 'static {
@@ -629,6 +648,10 @@ pub struct QueryPermit {
     pub wait: Duration,
 }
 
+/// The pages of entities that [`QueryStore::export`] produces; an error
+/// ends the export
+pub type ExportPages = tokio::sync::mpsc::Receiver<Result<Vec<QueryObject>, QueryExecutionError>>;
+
 /// Store operations used when serving queries for a specific deployment
 #[async_trait]
 pub trait QueryStore: Send + Sync {
@@ -637,6 +660,62 @@ pub trait QueryStore: Send + Sync {
         query: EntityQuery,
     ) -> Result<(Vec<QueryObject>, Trace), QueryExecutionError>;
 
+    /// Read all entities that `query` matches in pages of `page_size`
+    /// entities through a server-side cursor. The entities are read on
+    /// a connection of their own that is held until all of them have been
+    /// read or the receiver is dropped, but a query permit is only held
+    /// while a page is fetched. At most one page is read ahead
+    fn export(
+        &self,
+        query: EntityQuery,
+        page_size: usize,
+    ) -> Result<ExportPages, QueryExecutionError>;
+
+    /// Compute the `aggregates` over all entities that `query` matches,
+    /// returning one value for each aggregate in the same order. Only
+    /// `query.collection`, `query.filter` and `query.block` are used. If
+    /// more than `max_entities` entities match, return an error instead
+    fn find_aggregates(
+        &self,
+        query: EntityQuery,
+        aggregates: &[EntityAggregate],
+        max_entities: usize,
+    ) -> Result<(Vec<r::Value>, Trace), QueryExecutionError>;
+
+    /// Return the versions of the entity of type `entity_type` with the
+    /// given `id` that were current at some block between `from` and
+    /// `to`, oldest first, and no more than `first` of them. When `from`
+    /// is not given, start at the earliest block of the deployment. Since
+    /// pruning removes old versions, it is an error to ask for versions
+    /// before the earliest block
+    fn entity_history(
+        &self,
+        entity_type: &EntityType,
+        id: &Id,
+        from: Option<BlockNumber>,
+        to: BlockNumber,
+        first: usize,
+        trace: bool,
+    ) -> Result<(Vec<EntityVersion>, Trace), QueryExecutionError>;
+
+    /// Return the changes to entities of type `entity_type` between the
+    /// blocks `from` and `to`, ordered by their cursor and starting after
+    /// `after`, and no more than `first` of them. Only include the
+    /// attributes of the entities when `with_data` is `true`. Like for
+    /// [`QueryStore::entity_history`], `from` defaults to the earliest
+    /// block of the deployment, and it is an error to ask for changes
+    /// before it
+    fn entity_diff(
+        &self,
+        entity_type: &EntityType,
+        from: Option<BlockNumber>,
+        to: BlockNumber,
+        after: Option<EntityDiffCursor>,
+        first: usize,
+        with_data: bool,
+        trace: bool,
+    ) -> Result<(Vec<EntityDiff>, Trace), QueryExecutionError>;
+
     async fn is_deployment_synced(&self) -> Result<bool, Error>;
 
     async fn block_ptr(&self) -> Result<Option<BlockPtr>, StoreError>;
@@ -657,6 +736,21 @@ pub trait QueryStore: Send + Sync {
         block_hash: &BlockHash,
     ) -> Result<Option<(BlockNumber, Option<u64>, Option<BlockHash>)>, StoreError>;
 
+    /// Find the latest block that the deployment has indexed whose
+    /// timestamp is at or before `timestamp`, given in seconds since the
+    /// Unix epoch. Return `None` if the deployment has not indexed any
+    /// block that old.
+    ///
+    /// The timestamps come from the chain store since deployments do not
+    /// record the timestamps of the blocks they index. Return an error if
+    /// the chain does not have timestamps, or if the chain store was
+    /// pruned so far that it has none of the blocks around `timestamp`
+    /// that the deployment has data for
+    async fn block_number_for_timestamp(
+        &self,
+        timestamp: u64,
+    ) -> Result<Option<BlockNumber>, QueryExecutionError>;
+
     fn wait_stats(&self) -> PoolWaitStats;
 
     /// Find the current state for the subgraph deployment `id` and
@@ -667,6 +761,20 @@ pub trait QueryStore: Send + Sync {
 
     fn input_schema(&self) -> Result<InputSchema, QueryExecutionError>;
 
+    /// Estimates of how many entities of each type the deployment holds,
+    /// keyed by the name of the entity type. The estimates are based on
+    /// database statistics and can be quite a bit off; types for which no
+    /// statistics are available yet are missing
+    fn entity_estimates(&self) -> Result<HashMap<String, u64>, QueryExecutionError>;
+
+    /// Return all entity changes that happened in `block`, like
+    /// [`SubgraphStore::entity_changes_in_block`], but read them through
+    /// the connection that this store uses for queries
+    async fn entity_changes_in_block(
+        &self,
+        block: BlockNumber,
+    ) -> Result<Vec<EntityOperation>, QueryExecutionError>;
+
     fn network_name(&self) -> &str;
 
     /// A permit should be acquired before starting query execution.
@@ -678,6 +786,14 @@ pub trait QueryStore: Send + Sync {
 
     /// Return the deployment id that is queried by this `QueryStore`
     fn deployment_id(&self) -> DeploymentId;
+
+    /// Return a store for the same deployment that can answer queries at
+    /// `block`. A store that reads from a read replica that has not caught
+    /// up to `block` yet returns a store that reads from the primary
+    async fn for_block(
+        self: Arc<Self>,
+        block: BlockNumber,
+    ) -> Result<Arc<dyn QueryStore + Send + Sync>, QueryExecutionError>;
 }
 
 /// A view of the store that can provide information about the indexing status
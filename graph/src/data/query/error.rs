@@ -71,6 +71,11 @@ pub enum QueryExecutionError {
     SubgraphManifestResolveError(Arc<SubgraphManifestResolveError>),
     InvalidSubgraphManifest,
     ResultTooBig(usize, usize),
+    AggregateTooLarge(String, usize), // (entity type, max_entities)
+    CostTooHigh(u64, u64),            // (cost, max_cost)
+    PersistedQueryNotFound,
+    PersistedQueryHashMismatch(String),
+    QueryNotAllowed(String),
     DeploymentNotFound(String),
     IdMissing,
     IdNotString,
@@ -129,6 +134,11 @@ impl QueryExecutionError {
             | InvalidSubgraphManifest
             | ValidationError(_, _)
             | ResultTooBig(_, _)
+            | AggregateTooLarge(_, _)
+            | CostTooHigh(_, _)
+            | PersistedQueryNotFound
+            | PersistedQueryHashMismatch(_)
+            | QueryNotAllowed(_)
             | DeploymentNotFound(_)
             | IdMissing
             | IdNotString
@@ -271,6 +281,13 @@ impl fmt::Display for QueryExecutionError {
             SubgraphManifestResolveError(e) => write!(f, "failed to resolve subgraph manifest: {}", e),
             InvalidSubgraphManifest => write!(f, "invalid subgraph manifest file"),
             ResultTooBig(actual, limit) => write!(f, "the result size of {} is larger than the allowed limit of {}", actual, limit),
+            CostTooHigh(cost, max_cost) => write!(f, "the estimated cost `{}` of the query exceeds the limit of `{}` for this subgraph. Use `first` to fetch fewer entities, or query fewer nested collections", cost, max_cost),
+            // Clients that use automatic persisted queries look for exactly
+            // this message to know that they need to send the full query
+            PersistedQueryNotFound => write!(f, "PersistedQueryNotFound"),
+            PersistedQueryHashMismatch(hash) => write!(f, "the sha256 hash `{}` does not match the query", hash),
+            QueryNotAllowed(hash) => write!(f, "the query with sha256 hash `{}` is not on the allow-list for this subgraph", hash),
+            AggregateTooLarge(entity_type, limit) => write!(f, "aggregating `{}` would require looking at more than the allowed limit of {} entities; use a more restrictive filter", entity_type, limit),
             DeploymentNotFound(id_or_name) => write!(f, "deployment `{}` does not exist", id_or_name),
             IdMissing => write!(f, "entity is missing an `id` attribute"),
             IdNotString => write!(f, "entity `id` attribute is not a string"),
@@ -451,6 +468,12 @@ impl Serialize for QueryError {
                 map.serialize_entry("locations", &vec![location])?;
                 format!("{}", self)
             }
+            QueryError::ExecutionError(PersistedQueryNotFound) => {
+                let mut extensions = HashMap::new();
+                extensions.insert("code", "PERSISTED_QUERY_NOT_FOUND");
+                map.serialize_entry("extensions", &extensions)?;
+                format!("{}", self)
+            }
             _ => format!("{}", self),
         };
 
@@ -6,6 +6,6 @@ mod trace;
 
 pub use self::cache_status::CacheStatus;
 pub use self::error::{QueryError, QueryExecutionError};
-pub use self::query::{Query, QueryTarget, QueryVariables};
-pub use self::result::{LatestBlockInfo, QueryResult, QueryResults};
+pub use self::query::{Federation, Query, QueryTarget, QueryVariables};
+pub use self::result::{HttpCache, LatestBlockInfo, QueryCost, QueryResult, QueryResults};
 pub use self::trace::Trace;
@@ -124,6 +124,28 @@ impl QueryTarget {
     }
 }
 
+/// The subgraphs behind a federated query. Each subgraph has a namespace
+/// that prefixes its root fields and types in the federated schema
+#[derive(Clone, Debug)]
+pub struct Federation {
+    pub subgraphs: Vec<(String, QueryTarget)>,
+    /// Query every subgraph at its latest block at or before this Unix
+    /// timestamp (in seconds) instead of at its latest block
+    pub timestamp: Option<u64>,
+}
+
+impl Federation {
+    /// Find the subgraph whose namespace prefixes `name`, and return its
+    /// position in `subgraphs` together with the rest of `name`
+    pub fn split_name<'a>(&self, name: &'a str) -> Option<(usize, &'a str)> {
+        let (namespace, rest) = name.split_once('_')?;
+        self.subgraphs
+            .iter()
+            .position(|(ns, _)| ns == namespace)
+            .map(|idx| (idx, rest))
+    }
+}
+
 /// A GraphQL query as submitted by a client, either directly or through a subscription.
 #[derive(Clone, Debug)]
 pub struct Query {
@@ -133,6 +155,16 @@ pub struct Query {
     pub query_text: Arc<String>,
     pub variables_text: Arc<String>,
     pub trace: bool,
+    /// Only estimate the cost of the query, but do not execute it
+    pub dry_run: bool,
+    /// The sha256 hash of the query text for persisted queries. Persisted
+    /// queries only need to be validated once
+    pub persisted_hash: Option<String>,
+    /// Identifies the request for HTTP caching; requests with the same
+    /// key are the same query with the same variables
+    pub cache_key: Option<String>,
+    /// The value of the `If-None-Match` header of the request
+    pub if_none_match: Option<String>,
     _force_use_of_new: (),
 }
 
@@ -161,7 +193,61 @@ impl Query {
             query_text: Arc::new(query_text),
             variables_text: Arc::new(variables_text),
             trace,
+            dry_run: false,
+            persisted_hash: None,
+            cache_key: None,
+            if_none_match: None,
             _force_use_of_new: (),
         }
     }
+
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn with_persisted_hash(mut self, hash: Option<String>) -> Self {
+        self.persisted_hash = hash;
+        self
+    }
+
+    pub fn with_http_cache(mut self, cache_key: String, if_none_match: Option<String>) -> Self {
+        self.cache_key = Some(cache_key);
+        self.if_none_match = if_none_match;
+        self
+    }
+
+    /// Return `true` if the document contains a `subscription` operation
+    pub fn is_subscription(&self) -> bool {
+        self.document.definitions.iter().any(|defn| {
+            matches!(
+                defn,
+                q::Definition::Operation(q::OperationDefinition::Subscription(_))
+            )
+        })
+    }
+
+    /// Subscriptions are answered by rerunning them as queries whenever
+    /// the data they read changes. Turn every `subscription` operation in
+    /// the document into a `query` with the same selection set
+    pub fn subscription_as_query(mut self) -> Self {
+        self.document.definitions = self
+            .document
+            .definitions
+            .into_iter()
+            .map(|defn| match defn {
+                q::Definition::Operation(q::OperationDefinition::Subscription(sub)) => {
+                    q::Definition::Operation(q::OperationDefinition::Query(q::Query {
+                        position: sub.position,
+                        name: sub.name,
+                        variable_definitions: sub.variable_definitions,
+                        directives: sub.directives,
+                        selection_set: sub.selection_set,
+                    }))
+                }
+                defn => defn,
+            })
+            .collect();
+        self
+    }
 }
@@ -4,15 +4,16 @@ use crate::cheap_clone::CheapClone;
 use crate::components::server::query::ServerResponse;
 use crate::data::value::Object;
 use crate::derive::CacheWeight;
-use crate::prelude::{r, BlockHash, BlockNumber, CacheWeight, DeploymentHash};
+use crate::prelude::{r, BlockHash, BlockNumber, BlockPtr, CacheWeight, DeploymentHash, ENV_VARS};
 use http_body_util::Full;
 use hyper::header::{
     ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
-    CONTENT_TYPE,
+    ACCESS_CONTROL_EXPOSE_HEADERS, CACHE_CONTROL, CONTENT_TYPE, ETAG, VARY,
 };
-use hyper::Response;
+use hyper::{Response, StatusCode};
 use serde::ser::*;
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::convert::TryFrom;
 use std::sync::Arc;
 use std::time::Instant;
@@ -63,6 +64,94 @@ pub struct QueryResults {
     results: Vec<Arc<QueryResult>>,
     pub trace: Trace,
     pub indexed_block: Option<LatestBlockInfo>,
+    /// The estimated cost of the query, if it was computed
+    pub cost: Option<QueryCost>,
+    /// How HTTP caches may cache the response, `None` if they should not
+    pub http_cache: Option<HttpCache>,
+}
+
+/// What HTTP caches need to know about the response to a query
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpCache {
+    /// The `ETag` of the response, including the surrounding quotes
+    pub etag: String,
+    /// Whether the response can never change
+    pub immutable: bool,
+    /// Whether shared caches may store the response. Only responses to
+    /// `GET` requests are public since caches key POST responses by their
+    /// URL and not by their body
+    pub public: bool,
+    /// Whether the client already has the response; we then send a
+    /// `304 Not Modified` without a body
+    pub not_modified: bool,
+}
+
+impl HttpCache {
+    /// The cache information for the request identified by `cache_key`
+    /// when it was run against `deployment` at `blocks`. The `reorg_count`
+    /// of the deployment should be passed in if any of the blocks might
+    /// still be reverted, since we might only know the block number and
+    /// not the hash of such blocks
+    pub fn new<'a>(
+        cache_key: &str,
+        deployment: &DeploymentHash,
+        blocks: impl IntoIterator<Item = &'a BlockPtr>,
+        reorg_count: Option<u32>,
+        immutable: bool,
+    ) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(cache_key.as_bytes());
+        hasher.update(deployment.as_str().as_bytes());
+        for ptr in blocks {
+            hasher.update(ptr.number.to_be_bytes());
+            hasher.update(ptr.hash.as_slice());
+        }
+        if let Some(reorg_count) = reorg_count {
+            hasher.update(reorg_count.to_be_bytes());
+        }
+        let hash = hasher.finalize();
+        HttpCache {
+            etag: format!("\"{}\"", hex::encode(&hash[..16])),
+            immutable,
+            public: false,
+            not_modified: false,
+        }
+    }
+
+    /// Check whether the value of an `If-None-Match` header matches our
+    /// `etag`, using weak comparison as `If-None-Match` requires
+    pub fn matches(&self, if_none_match: &str) -> bool {
+        if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == self.etag)
+    }
+
+    fn cache_control(&self) -> String {
+        let scope = if self.public { "public" } else { "private" };
+        if self.immutable {
+            format!("{}, max-age=31536000, immutable", scope)
+        } else {
+            format!(
+                "{}, max-age={}, must-revalidate",
+                scope,
+                ENV_VARS.graphql.http_cache_max_age.as_secs()
+            )
+        }
+    }
+}
+
+/// The estimated cost of a query and the limit it was checked against.
+/// It is reported to clients in the `extensions` of the response
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryCost {
+    pub estimated: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+    /// Whether the query was only costed but not executed
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub dry_run: bool,
 }
 
 #[derive(Debug, Serialize)]
@@ -79,6 +168,8 @@ impl QueryResults {
             results: Vec::new(),
             trace,
             indexed_block,
+            cost: None,
+            http_cache: None,
         }
     }
 
@@ -86,6 +177,11 @@ impl QueryResults {
         self.results.first()
     }
 
+    /// The data of all results that have data
+    pub fn data(&self) -> impl Iterator<Item = &Data> {
+        self.results.iter().filter_map(|result| result.data())
+    }
+
     pub fn has_errors(&self) -> bool {
         self.results.iter().any(|result| result.has_errors())
     }
@@ -158,6 +254,15 @@ impl Serialize for QueryResults {
             state.serialize_field("errors", &SerError(self))?;
         }
 
+        if let Some(cost) = &self.cost {
+            #[derive(Serialize)]
+            struct Extensions<'a> {
+                cost: &'a QueryCost,
+            }
+
+            state.serialize_field("extensions", &Extensions { cost })?;
+        }
+
         if !self.trace.is_none() {
             let http = HttpTrace::new(start.elapsed(), self.results.weight());
             state.serialize_field("trace", &self.trace)?;
@@ -173,6 +278,8 @@ impl From<Data> for QueryResults {
             results: vec![Arc::new(x.into())],
             trace: Trace::None,
             indexed_block: None,
+            cost: None,
+            http_cache: None,
         }
     }
 }
@@ -183,6 +290,8 @@ impl From<QueryResult> for QueryResults {
             results: vec![Arc::new(x)],
             trace: Trace::None,
             indexed_block: None,
+            cost: None,
+            http_cache: None,
         }
     }
 }
@@ -193,6 +302,8 @@ impl From<Arc<QueryResult>> for QueryResults {
             results: vec![x],
             trace: Trace::None,
             indexed_block: None,
+            cost: None,
+            http_cache: None,
         }
     }
 }
@@ -203,6 +314,8 @@ impl From<QueryExecutionError> for QueryResults {
             results: vec![Arc::new(x.into())],
             trace: Trace::None,
             indexed_block: None,
+            cost: None,
+            http_cache: None,
         }
     }
 }
@@ -213,6 +326,8 @@ impl From<Vec<QueryExecutionError>> for QueryResults {
             results: vec![Arc::new(x.into())],
             trace: Trace::None,
             indexed_block: None,
+            cost: None,
+            http_cache: None,
         }
     }
 }
@@ -224,21 +339,51 @@ impl QueryResults {
         self.results.push(other);
     }
 
+    /// Add all results from `other`, which will be serialized together
+    /// with the results we already have. The trace of `other` is dropped
+    pub fn extend(&mut self, other: QueryResults) {
+        self.results.extend(other.results);
+    }
+
     pub fn as_http_response(&self) -> ServerResponse {
         let json = serde_json::to_string(&self).unwrap();
         let attestable = self.results.iter().all(|r| r.is_attestable());
         let indexed_block = serde_json::to_string(&self.indexed_block).unwrap();
-        Response::builder()
+        let mut builder = Response::builder()
             .status(200)
             .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
             .header(CONTENT_TYPE, "application/json")
-            .header(ACCESS_CONTROL_ALLOW_HEADERS, "Content-Type, User-Agent")
+            .header(
+                ACCESS_CONTROL_ALLOW_HEADERS,
+                "Content-Type, User-Agent, If-None-Match",
+            )
             .header(ACCESS_CONTROL_ALLOW_METHODS, "GET, OPTIONS, POST")
+            .header(ACCESS_CONTROL_EXPOSE_HEADERS, "ETag")
             .header(CONTENT_TYPE, "application/json")
             .header("graph-attestable", attestable.to_string())
-            .header("graph-indexed", indexed_block)
-            .body(Full::from(json))
-            .unwrap()
+            .header("graph-indexed", indexed_block);
+        match &self.http_cache {
+            // Errors might go away when the query is retried
+            Some(_) if self.has_errors() => {
+                builder = builder.header(CACHE_CONTROL, "no-store");
+            }
+            Some(cache) => {
+                // Traced and dry-run requests get different responses
+                // for the same query
+                builder = builder
+                    .header(ETAG, &cache.etag)
+                    .header(CACHE_CONTROL, cache.cache_control())
+                    .header(VARY, "X-GraphTraceQuery, X-GraphDryRun");
+                if cache.not_modified {
+                    return builder
+                        .status(StatusCode::NOT_MODIFIED)
+                        .body(Full::default())
+                        .unwrap();
+                }
+            }
+            None => {}
+        }
+        builder.body(Full::from(json)).unwrap()
     }
 }
 
@@ -419,3 +564,76 @@ fn multiple_data_items() {
     let actual = serde_json::to_string(&res).unwrap();
     assert_eq!(expected, actual)
 }
+
+#[test]
+fn cost_in_extensions() {
+    use serde_json::json;
+
+    let mut res = QueryResults::empty(Trace::None, None);
+    res.cost = Some(QueryCost {
+        estimated: 1200,
+        limit: Some(5000),
+        dry_run: true,
+    });
+
+    let expected = json!({
+        "extensions": { "cost": { "estimated": 1200, "limit": 5000, "dryRun": true } }
+    });
+    let actual = serde_json::to_value(&res).unwrap();
+    assert_eq!(expected, actual)
+}
+
+#[test]
+fn http_cache_headers() {
+    let deployment = DeploymentHash::new("QmHttpCache").unwrap();
+    let ptr = BlockPtr::new(BlockHash::zero(), 17);
+    let cache = HttpCache::new("key", &deployment, [&ptr], None, true);
+
+    // The etag depends on the request, the blocks and the reorg count
+    assert_eq!(
+        cache,
+        HttpCache::new("key", &deployment, [&ptr], None, true)
+    );
+    assert_ne!(
+        cache,
+        HttpCache::new("other", &deployment, [&ptr], None, true)
+    );
+    let next = BlockPtr::new(BlockHash::zero(), 18);
+    assert_ne!(
+        cache,
+        HttpCache::new("key", &deployment, [&next], None, true)
+    );
+    assert_ne!(
+        cache,
+        HttpCache::new("key", &deployment, [&ptr], Some(1), true)
+    );
+
+    assert!(cache.matches(&cache.etag));
+    assert!(cache.matches(&format!("\"abc\", W/{}", cache.etag)));
+    assert!(cache.matches("*"));
+    assert!(!cache.matches("\"abc\""));
+
+    // Responses are private unless the request was a `GET`
+    let mut res = QueryResults::empty(Trace::None, None);
+    res.http_cache = Some(cache.clone());
+    let resp = res.as_http_response();
+    assert_eq!(StatusCode::OK, resp.status());
+    assert_eq!(cache.etag, resp.headers()[ETAG]);
+    assert_eq!(
+        "private, max-age=31536000, immutable",
+        resp.headers()[CACHE_CONTROL]
+    );
+    assert_eq!("X-GraphTraceQuery, X-GraphDryRun", resp.headers()[VARY]);
+
+    res.http_cache = Some(HttpCache {
+        public: true,
+        not_modified: true,
+        ..cache
+    });
+    let resp = res.as_http_response();
+    assert_eq!(StatusCode::NOT_MODIFIED, resp.status());
+    assert_eq!(
+        "public, max-age=31536000, immutable",
+        resp.headers()[CACHE_CONTROL]
+    );
+}
@@ -63,6 +63,15 @@ impl IdType {
         }
     }
 
+    /// The type of the values of an `id` attribute of this type
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            IdType::String => ValueType::String,
+            IdType::Bytes => ValueType::Bytes,
+            IdType::Int8 => ValueType::Int8,
+        }
+    }
+
     /// Generate an entity id from the block number and a sequence number.
     ///
     /// * Bytes: `[block:4, seq:4]`
@@ -64,10 +64,6 @@ impl Timestamp {
     pub fn as_secs_since_epoch(&self) -> i64 {
         self.0.timestamp()
     }
-
-    pub(crate) fn timestamp_millis(&self) -> i64 {
-        self.0.timestamp_millis()
-    }
 }
 
 impl StableHash for Timestamp {
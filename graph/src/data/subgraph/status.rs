@@ -113,7 +113,12 @@ pub struct Info {
     /// ID of the Graph Node that the subgraph is indexed by.
     pub node: Option<String>,
 
+    /// How many blocks of history the deployment keeps
     pub history_blocks: i32,
+
+    /// How many seconds of history the deployment keeps if its retention
+    /// was set as a duration; `history_blocks` is derived from this
+    pub history_seconds: Option<i32>,
 }
 
 impl IntoValue for Info {
@@ -130,6 +135,7 @@ impl IntoValue for Info {
             non_fatal_errors,
             synced,
             history_blocks,
+            history_seconds,
         } = self;
 
         fn subgraph_error_to_value(subgraph_error: SubgraphError) -> r::Value {
@@ -173,6 +179,7 @@ impl IntoValue for Info {
             entityCount: format!("{}", entity_count),
             node: node,
             historyBlocks: history_blocks,
+            historySeconds: history_seconds,
         }
     }
 }
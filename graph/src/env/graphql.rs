@@ -1,4 +1,6 @@
+use std::collections::HashMap;
 use std::fmt;
+use std::path::PathBuf;
 
 use super::*;
 
@@ -92,6 +94,124 @@ pub struct EnvVarsGraphQl {
     /// Set by the flag `GRAPH_GRAPHQL_DISABLE_CHILD_SORTING`. Off by default.
     /// Disables child-based sorting
     pub disable_child_sorting: bool,
+    /// Set by the flag `GRAPH_GRAPHQL_ENABLE_AGGREGATE_FIELDS`. Off by
+    /// default. Adds `<entities>_aggregate` fields to the `Query` type
+    pub enable_aggregate_fields: bool,
+    /// Set by the environment variable
+    /// `GRAPH_GRAPHQL_AGGREGATE_MAX_ENTITIES`. The maximum number of
+    /// entities that an `<entities>_aggregate` field may aggregate over.
+    /// The default value is 100,000
+    pub aggregate_max_entities: usize,
+    /// Set by the flag `GRAPH_GRAPHQL_ENABLE_HISTORY_FIELDS`. Off by
+    /// default. Adds `<entity>_history` fields to the `Query` type
+    pub enable_history_fields: bool,
+    /// Set by the flag `GRAPH_GRAPHQL_ENABLE_CHANGES_FIELDS`. Off by
+    /// default. Adds `<entity>_changes` fields to the `Query` type
+    pub enable_changes_fields: bool,
+    /// Set by the environment variable `GRAPH_GRAPHQL_MAX_REGEX_LENGTH`. The
+    /// maximum length of the regular expression in a `_matches` filter.
+    /// The default value is 256
+    pub max_regex_length: usize,
+    /// Set by the environment variable `GRAPH_GRAPHQL_REGEX_TIMEOUT`
+    /// (expressed in seconds). The statement timeout for SQL queries that
+    /// use a `_matches` filter; if `GRAPH_SQL_STATEMENT_TIMEOUT` is lower,
+    /// that is used instead. The default value is 10 seconds
+    pub regex_timeout: Duration,
+    /// Set by the environment variables `GRAPH_GRAPHQL_MAX_COST` and
+    /// `GRAPH_GRAPHQL_MAX_COST_OVERRIDES`. See `MaxCost` for details
+    pub max_cost: MaxCost,
+    /// Set by the flag `GRAPH_GRAPHQL_REPORT_COST`. Off by default. Include
+    /// the estimated cost of every query in the `extensions` of the
+    /// response, and not just of queries that are subject to a cost limit
+    pub report_cost: bool,
+    /// Set by the environment variable
+    /// `GRAPH_GRAPHQL_SUBSCRIPTION_POLL_INTERVAL` (expressed in
+    /// milliseconds). How often subscriptions check whether the deployment
+    /// they are subscribed to has advanced. The default value is 1000ms
+    pub subscription_poll_interval: Duration,
+    /// Set by the environment variable
+    /// `GRAPH_GRAPHQL_SUBSCRIPTION_MAX_BLOCK_SCAN`. When a deployment
+    /// advances by more than this many blocks between two checks,
+    /// subscriptions are rerun without looking at the entity changes in
+    /// each block. The default value is 10
+    pub subscription_max_block_scan: u32,
+    /// Set by the environment variable
+    /// `GRAPH_GRAPHQL_MAX_SUBSCRIPTIONS_PER_CONNECTION`. The maximum number
+    /// of active subscriptions on one WebSocket connection. The default
+    /// value is 100
+    pub max_subscriptions_per_connection: usize,
+    /// Set by the environment variable
+    /// `GRAPH_GRAPHQL_SUBSCRIPTION_BUFFER_SIZE`. How many results for a
+    /// WebSocket connection are buffered before subscriptions stop
+    /// producing new ones until the client catches up. The default value
+    /// is 16
+    pub subscription_buffer_size: usize,
+    /// Set by the environment variable
+    /// `GRAPH_GRAPHQL_PERSISTED_QUERY_CACHE_SIZE`. How many persisted
+    /// queries are kept in memory, parsed and validated. Set to 0 to turn
+    /// automatic persisted queries off. The default value is 1000
+    pub persisted_query_cache_size: usize,
+    /// Set by the environment variable `GRAPH_GRAPHQL_QUERY_ALLOW_LIST_DIR`.
+    /// A directory with allow-lists of queries, one file per subgraph.
+    /// Subgraphs that have an allow-list only accept the queries on it. No
+    /// default value is provided
+    pub query_allow_list_dir: Option<PathBuf>,
+    /// Set by the environment variable `GRAPH_GRAPHQL_HTTP_CACHE_MAX_AGE`
+    /// (expressed in seconds). How long HTTP caches may serve the response
+    /// to a query that can still change without checking back with us.
+    /// Responses for final blocks are always cacheable forever. The
+    /// default value is 0
+    pub http_cache_max_age: Duration,
+    /// Set by the environment variable `GRAPH_GRAPHQL_MAX_BATCH_SIZE`. The
+    /// maximum number of operations in a batched request. Set to 0 to
+    /// turn batching off. The default value is 50
+    pub max_batch_size: usize,
+    /// Set by the environment variable
+    /// `GRAPH_GRAPHQL_MAX_FEDERATED_SUBGRAPHS`. The maximum number of
+    /// subgraphs that one federated query can combine. Set to 0 to turn
+    /// federated queries off. The default value is 10
+    pub max_federated_subgraphs: usize,
+    /// Set by the environment variable `GRAPH_GRAPHQL_EXPORT_PAGE_SIZE`.
+    /// The number of entities that an export reads from its cursor at a
+    /// time. Exports are off unless this is set to a value bigger than 0.
+    /// The default value is 0
+    pub export_page_size: usize,
+    /// Set by the environment variable
+    /// `GRAPH_GRAPHQL_MAX_CONCURRENT_EXPORTS`. How many exports can run at
+    /// the same time in each shard. The default value is 4
+    pub max_concurrent_exports: usize,
+    /// Set by the environment variable `GRAPH_GRAPHQL_EXPORT_TIMEOUT`
+    /// (expressed in seconds). How long an export can take before it is
+    /// stopped, including the time spent waiting for the client to read
+    /// what was sent. The default value is 1800s
+    pub export_timeout: Duration,
+    /// Set by the environment variable `GRAPH_GRAPHQL_RATE_LIMIT_KEY`. What
+    /// identifies a client for rate limiting: `ip` for the address of the
+    /// peer, or `header:<name>` for the value of a header. The default
+    /// value is `ip`
+    pub rate_limit_key: String,
+    /// Set by the environment variable `GRAPH_GRAPHQL_RATE_LIMIT_REQUESTS`.
+    /// The number of requests that one client can make per minute. No
+    /// default value is provided
+    pub rate_limit_requests: Option<u64>,
+    /// Set by the environment variable `GRAPH_GRAPHQL_RATE_LIMIT_COST`. The
+    /// total estimated cost of the queries that one client can run per
+    /// minute. No default value is provided
+    pub rate_limit_cost: Option<u64>,
+    /// Set by the environment variable `GRAPH_GRAPHQL_RATE_LIMIT_KEYS`. A
+    /// comma-separated list of the header values that identify clients
+    /// when `rate_limit_key` is a header. Requests with other values are
+    /// limited by their address. Empty by default
+    pub rate_limit_keys: Vec<String>,
+    /// Set by the environment variable
+    /// `GRAPH_GRAPHQL_RATE_LIMIT_MAX_CLIENTS`. The number of clients whose
+    /// usage is tracked; the least recently seen clients are forgotten
+    /// first. The default value is 100000
+    pub rate_limit_max_clients: usize,
+    /// Set by the environment variable
+    /// `GRAPH_GRAPHQL_RATE_LIMIT_KEY_METRICS`. Whether to report usage per
+    /// key for the keys in `rate_limit_keys`. Off by default
+    pub rate_limit_key_metrics: bool,
     /// Set by `GRAPH_GRAPHQL_TRACE_TOKEN`, the token to use to enable query
     /// tracing for a GraphQL request. If this is set, requests that have a
     /// header `X-GraphTraceQuery` set to this value will include a trace of
@@ -102,6 +222,32 @@ pub struct EnvVarsGraphQl {
     pub parallel_block_constraints: bool,
 }
 
+/// The maximum estimated cost of queries; queries that are estimated to
+/// be more expensive are rejected before they are executed
+#[derive(Clone, Debug, Default)]
+pub struct MaxCost {
+    /// Set by the environment variable `GRAPH_GRAPHQL_MAX_COST`. The
+    /// maximum cost for all subgraphs. No default value is provided
+    default: Option<u64>,
+    /// Set by the environment variable `GRAPH_GRAPHQL_MAX_COST_OVERRIDES`,
+    /// a comma-separated list of `<deployment>=<max cost>` that sets the
+    /// maximum cost for individual subgraphs, overriding
+    /// `GRAPH_GRAPHQL_MAX_COST`. Empty by default
+    overrides: HashMap<String, u64>,
+}
+
+impl MaxCost {
+    pub fn new(default: Option<u64>, overrides: HashMap<String, u64>) -> Self {
+        Self { default, overrides }
+    }
+
+    /// The maximum estimated cost for queries against `deployment`, if
+    /// there is one
+    pub fn for_deployment(&self, deployment: &str) -> Option<u64> {
+        self.overrides.get(deployment).copied().or(self.default)
+    }
+}
+
 // This does not print any values avoid accidentally leaking any sensitive env vars
 impl fmt::Debug for EnvVarsGraphQl {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -143,6 +289,38 @@ impl From<InnerGraphQl> for EnvVarsGraphQl {
             error_result_size: x.error_result_size.0 .0,
             disable_bool_filters: x.disable_bool_filters.0,
             disable_child_sorting: x.disable_child_sorting.0,
+            enable_aggregate_fields: x.enable_aggregate_fields.0,
+            aggregate_max_entities: x.aggregate_max_entities.0,
+            enable_history_fields: x.enable_history_fields.0,
+            enable_changes_fields: x.enable_changes_fields.0,
+            max_regex_length: x.max_regex_length.0,
+            regex_timeout: Duration::from_secs(x.regex_timeout_in_secs.0),
+            max_cost: MaxCost::new(x.max_cost.map(|x| x.0), x.max_cost_overrides.0),
+            report_cost: x.report_cost.0,
+            subscription_poll_interval: Duration::from_millis(x.subscription_poll_interval_in_ms),
+            subscription_max_block_scan: x.subscription_max_block_scan,
+            max_subscriptions_per_connection: x.max_subscriptions_per_connection,
+            subscription_buffer_size: x.subscription_buffer_size,
+            persisted_query_cache_size: x.persisted_query_cache_size,
+            query_allow_list_dir: x.query_allow_list_dir.map(PathBuf::from),
+            http_cache_max_age: Duration::from_secs(x.http_cache_max_age_in_secs),
+            max_batch_size: x.max_batch_size,
+            max_federated_subgraphs: x.max_federated_subgraphs,
+            export_page_size: x.export_page_size.0,
+            max_concurrent_exports: x.max_concurrent_exports,
+            export_timeout: Duration::from_secs(x.export_timeout_in_secs),
+            rate_limit_key: x.rate_limit_key,
+            rate_limit_requests: x.rate_limit_requests.map(|x| x.0),
+            rate_limit_cost: x.rate_limit_cost.map(|x| x.0),
+            rate_limit_keys: x
+                .rate_limit_keys
+                .split(',')
+                .map(str::trim)
+                .filter(|key| !key.is_empty())
+                .map(str::to_string)
+                .collect(),
+            rate_limit_max_clients: x.rate_limit_max_clients,
+            rate_limit_key_metrics: x.rate_limit_key_metrics.0,
             query_trace_token: x.query_trace_token,
             parallel_block_constraints: x.parallel_block_constraints.0,
         }
@@ -192,8 +370,89 @@ pub struct InnerGraphQl {
     pub disable_bool_filters: EnvVarBoolean,
     #[envconfig(from = "GRAPH_GRAPHQL_DISABLE_CHILD_SORTING", default = "false")]
     pub disable_child_sorting: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_GRAPHQL_ENABLE_AGGREGATE_FIELDS", default = "false")]
+    pub enable_aggregate_fields: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_GRAPHQL_AGGREGATE_MAX_ENTITIES", default = "100000")]
+    aggregate_max_entities: NoUnderscores<usize>,
+    #[envconfig(from = "GRAPH_GRAPHQL_ENABLE_HISTORY_FIELDS", default = "false")]
+    pub enable_history_fields: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_GRAPHQL_ENABLE_CHANGES_FIELDS", default = "false")]
+    pub enable_changes_fields: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_GRAPHQL_MAX_REGEX_LENGTH", default = "256")]
+    max_regex_length: NoUnderscores<usize>,
+    #[envconfig(from = "GRAPH_GRAPHQL_REGEX_TIMEOUT", default = "10")]
+    regex_timeout_in_secs: NoUnderscores<u64>,
+    #[envconfig(from = "GRAPH_GRAPHQL_MAX_COST")]
+    max_cost: Option<NoUnderscores<u64>>,
+    #[envconfig(from = "GRAPH_GRAPHQL_MAX_COST_OVERRIDES", default = "")]
+    max_cost_overrides: CostLimits,
+    #[envconfig(from = "GRAPH_GRAPHQL_REPORT_COST", default = "false")]
+    report_cost: EnvVarBoolean,
+    #[envconfig(from = "GRAPH_GRAPHQL_SUBSCRIPTION_POLL_INTERVAL", default = "1000")]
+    subscription_poll_interval_in_ms: u64,
+    #[envconfig(from = "GRAPH_GRAPHQL_SUBSCRIPTION_MAX_BLOCK_SCAN", default = "10")]
+    subscription_max_block_scan: u32,
+    #[envconfig(
+        from = "GRAPH_GRAPHQL_MAX_SUBSCRIPTIONS_PER_CONNECTION",
+        default = "100"
+    )]
+    max_subscriptions_per_connection: usize,
+    #[envconfig(from = "GRAPH_GRAPHQL_SUBSCRIPTION_BUFFER_SIZE", default = "16")]
+    subscription_buffer_size: usize,
+    #[envconfig(from = "GRAPH_GRAPHQL_PERSISTED_QUERY_CACHE_SIZE", default = "1000")]
+    persisted_query_cache_size: usize,
+    #[envconfig(from = "GRAPH_GRAPHQL_QUERY_ALLOW_LIST_DIR")]
+    query_allow_list_dir: Option<String>,
+    #[envconfig(from = "GRAPH_GRAPHQL_HTTP_CACHE_MAX_AGE", default = "0")]
+    http_cache_max_age_in_secs: u64,
+    #[envconfig(from = "GRAPH_GRAPHQL_MAX_BATCH_SIZE", default = "50")]
+    max_batch_size: usize,
+    #[envconfig(from = "GRAPH_GRAPHQL_MAX_FEDERATED_SUBGRAPHS", default = "10")]
+    max_federated_subgraphs: usize,
+    #[envconfig(from = "GRAPH_GRAPHQL_EXPORT_PAGE_SIZE", default = "0")]
+    export_page_size: NoUnderscores<usize>,
+    #[envconfig(from = "GRAPH_GRAPHQL_MAX_CONCURRENT_EXPORTS", default = "4")]
+    max_concurrent_exports: usize,
+    #[envconfig(from = "GRAPH_GRAPHQL_EXPORT_TIMEOUT", default = "1800")]
+    export_timeout_in_secs: u64,
+    #[envconfig(from = "GRAPH_GRAPHQL_RATE_LIMIT_KEY", default = "ip")]
+    rate_limit_key: String,
+    #[envconfig(from = "GRAPH_GRAPHQL_RATE_LIMIT_REQUESTS")]
+    rate_limit_requests: Option<NoUnderscores<u64>>,
+    #[envconfig(from = "GRAPH_GRAPHQL_RATE_LIMIT_COST")]
+    rate_limit_cost: Option<NoUnderscores<u64>>,
+    #[envconfig(from = "GRAPH_GRAPHQL_RATE_LIMIT_KEYS", default = "")]
+    rate_limit_keys: String,
+    #[envconfig(from = "GRAPH_GRAPHQL_RATE_LIMIT_MAX_CLIENTS", default = "100000")]
+    rate_limit_max_clients: usize,
+    #[envconfig(from = "GRAPH_GRAPHQL_RATE_LIMIT_KEY_METRICS", default = "false")]
+    rate_limit_key_metrics: EnvVarBoolean,
     #[envconfig(from = "GRAPH_GRAPHQL_TRACE_TOKEN", default = "")]
     query_trace_token: String,
     #[envconfig(from = "GRAPH_PARALLEL_BLOCK_CONSTRAINTS", default = "false")]
     pub parallel_block_constraints: EnvVarBoolean,
 }
+
+/// A comma-separated list of `<deployment>=<max cost>` entries
+#[derive(Clone, Debug, Default)]
+struct CostLimits(HashMap<String, u64>);
+
+impl FromStr for CostLimits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (deployment, limit) = entry.split_once('=').ok_or_else(|| {
+                    format!("expected `<deployment>=<max cost>` but got `{entry}`")
+                })?;
+                let limit = NoUnderscores::<u64>::from_str(limit.trim())
+                    .map_err(|e| format!("invalid cost limit for `{deployment}`: {e}"))?;
+                Ok((deployment.trim().to_string(), limit.0))
+            })
+            .collect::<Result<_, _>>()
+            .map(CostLimits)
+    }
+}
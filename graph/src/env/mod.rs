@@ -7,6 +7,7 @@ use lazy_static::lazy_static;
 use semver::Version;
 use std::{collections::HashSet, env::VarError, fmt, str::FromStr, time::Duration};
 
+pub use self::graphql::MaxCost;
 use self::graphql::*;
 use self::mappings::*;
 use self::store::*;
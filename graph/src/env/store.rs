@@ -140,6 +140,26 @@ pub struct EnvVarsStore {
     /// The number of rows to fetch from the foreign data wrapper in one go,
     /// this will be set as the option 'fetch_size' on all foreign servers
     pub fdw_fetch_size: usize,
+    /// The fraction of entity queries whose shape is recorded for the
+    /// index advisor. Set by `GRAPH_STORE_INDEX_ADVISOR_SAMPLE_RATE`. The
+    /// default is 0, which turns sampling off
+    pub index_advisor_sample_rate: f64,
+    /// Whether to periodically create the indexes the index advisor
+    /// recommends. Set by `GRAPH_STORE_INDEX_ADVISOR_AUTO_CREATE`. The
+    /// default is `false`
+    pub index_advisor_auto_create: bool,
+    /// The index advisor job only considers query shapes that were sampled
+    /// within this long. Set by `GRAPH_STORE_INDEX_ADVISOR_MAX_AGE` in
+    /// seconds. The default is 7 days
+    pub index_advisor_max_age: Duration,
+    /// The number of indexes the index advisor job creates at most each
+    /// time it runs. Set by `GRAPH_STORE_INDEX_ADVISOR_MAX_CREATE`. The
+    /// default is 3
+    pub index_advisor_max_create: usize,
+    /// Read replicas that lag further behind the primary than this are
+    /// not used for queries. Set by `GRAPH_STORE_REPLICA_MAX_LAG` in
+    /// seconds. By default, replicas are used regardless of their lag
+    pub replica_max_lag: Option<Duration>,
 }
 
 // This does not print any values avoid accidentally leaking any sensitive env vars
@@ -195,6 +215,11 @@ impl TryFrom<InnerStore> for EnvVarsStore {
             disable_block_cache_for_lookup: x.disable_block_cache_for_lookup,
             insert_extra_cols: x.insert_extra_cols,
             fdw_fetch_size: x.fdw_fetch_size,
+            index_advisor_sample_rate: x.index_advisor_sample_rate.0,
+            index_advisor_auto_create: x.index_advisor_auto_create,
+            index_advisor_max_age: Duration::from_secs(x.index_advisor_max_age_in_secs),
+            index_advisor_max_create: x.index_advisor_max_create,
+            replica_max_lag: x.replica_max_lag_in_secs.map(Duration::from_secs),
         };
         if let Some(timeout) = vars.batch_timeout {
             if timeout < 2 * vars.batch_target_duration {
@@ -277,6 +302,16 @@ pub struct InnerStore {
     insert_extra_cols: usize,
     #[envconfig(from = "GRAPH_STORE_FDW_FETCH_SIZE", default = "1000")]
     fdw_fetch_size: usize,
+    #[envconfig(from = "GRAPH_STORE_INDEX_ADVISOR_SAMPLE_RATE", default = "0")]
+    index_advisor_sample_rate: ZeroToOneF64,
+    #[envconfig(from = "GRAPH_STORE_INDEX_ADVISOR_AUTO_CREATE", default = "false")]
+    index_advisor_auto_create: bool,
+    #[envconfig(from = "GRAPH_STORE_INDEX_ADVISOR_MAX_AGE", default = "604800")]
+    index_advisor_max_age_in_secs: u64,
+    #[envconfig(from = "GRAPH_STORE_INDEX_ADVISOR_MAX_CREATE", default = "3")]
+    index_advisor_max_create: usize,
+    #[envconfig(from = "GRAPH_STORE_REPLICA_MAX_LAG")]
+    replica_max_lag_in_secs: Option<u64>,
 }
 
 #[derive(Clone, Copy, Debug)]
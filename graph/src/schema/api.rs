@@ -1,4 +1,4 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::str::FromStr;
 use std::sync::Arc;
 
@@ -9,17 +9,20 @@ use thiserror::Error;
 
 use crate::cheap_clone::CheapClone;
 use crate::data::graphql::{ObjectOrInterface, ObjectTypeExt, TypeExt};
-use crate::data::store::IdType;
+use crate::data::store::{IdType, ValueType};
 use crate::env::ENV_VARS;
-use crate::schema::{ast, META_FIELD_NAME, META_FIELD_TYPE, SCHEMA_TYPE_NAME};
+use crate::schema::{
+    ast, AGGREGATE_SUFFIX, BLOCK_FIELD_TYPE, CHANGES_SUFFIX, CHANGE_SUFFIX, CURSOR_FIELD,
+    HISTORY_SUFFIX, META_FIELD_NAME, META_FIELD_TYPE, SCHEMA_TYPE_NAME, VERSION_SUFFIX,
+};
 
 use crate::data::graphql::ext::{
-    camel_cased_names, DefinitionExt, DirectiveExt, DocumentExt, ValueExt,
+    camel_cased_names, DefinitionExt, DirectiveExt, DocumentExt, TypeDefinitionExt, ValueExt,
 };
 use crate::derive::CheapClone;
 use crate::prelude::{q, r, s, DeploymentHash};
 
-use super::{Aggregation, Field, InputSchema, Schema, TypeKind};
+use super::{Aggregation, AggregationInterval, Field, InputSchema, Schema, TypeKind};
 
 #[derive(Error, Debug)]
 pub enum APISchemaError {
@@ -39,6 +42,8 @@ pub enum APISchemaError {
 const BLOCK_HEIGHT: &str = "Block_height";
 const CHANGE_BLOCK_FILTER_NAME: &str = "BlockChangedFilter";
 const ERROR_POLICY_TYPE: &str = "_SubgraphErrorPolicy_";
+const CHANGE_OPERATION_TYPE: &str = "_ChangeOperation_";
+const AGGREGATION_INTERVAL: &str = "Aggregation_interval";
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone, CheapClone)]
 pub enum ErrorPolicy {
@@ -345,14 +350,48 @@ fn add_introspection_schema(schema: &mut s::Document) {
 /// all its fields and their input arguments, based on the existing types.
 pub(in crate::schema) fn api_schema(
     input_schema: &InputSchema,
+) -> Result<s::Document, APISchemaError> {
+    derive_api_schema(
+        input_schema,
+        ENV_VARS.graphql.enable_aggregate_fields,
+        ENV_VARS.graphql.enable_history_fields,
+        ENV_VARS.graphql.enable_changes_fields,
+    )
+}
+
+/// Derive the API schema; if `aggregate_fields` is `true`, add an
+/// `<entities>_aggregate` field to the `Query` type for each plain entity
+/// type, if `history_fields` is `true`, an `<entity>_history` field, and
+/// if `changes_fields` is `true`, an `<entity>_changes` field
+fn derive_api_schema(
+    input_schema: &InputSchema,
+    aggregate_fields: bool,
+    history_fields: bool,
+    changes_fields: bool,
 ) -> Result<s::Document, APISchemaError> {
     // Refactor: Don't clone the schema.
     let mut api = init_api_schema(input_schema)?;
     add_meta_field_type(&mut api.document);
+    add_custom_aggregation_intervals(&mut api.document, input_schema);
     add_types_for_object_types(&mut api, input_schema)?;
     add_types_for_interface_types(&mut api, input_schema)?;
     add_types_for_aggregation_types(&mut api, input_schema)?;
-    add_query_type(&mut api.document, input_schema)?;
+    if aggregate_fields {
+        add_types_for_entity_aggregates(&mut api.document, input_schema)?;
+    }
+    if history_fields {
+        add_types_for_entity_versions(&mut api.document, input_schema)?;
+    }
+    if changes_fields {
+        add_types_for_entity_changes(&mut api.document, input_schema)?;
+    }
+    add_query_type(
+        &mut api.document,
+        input_schema,
+        aggregate_fields,
+        history_fields,
+        changes_fields,
+    )?;
     Ok(api.document)
 }
 
@@ -377,6 +416,27 @@ fn init_api_schema(input_schema: &InputSchema) -> Result<Schema, APISchemaError>
         }
     }
 
+    /// Add the `_cursor` field to the fields of an object or interface
+    /// type unless the type already has a field with that name
+    fn add_cursor_field(fields: &mut Vec<s::Field>) {
+        if fields.iter().any(|field| field.name == CURSOR_FIELD) {
+            return;
+        }
+        fields.push(s::Field {
+            position: Pos::default(),
+            description: Some(
+                "Opaque cursor for the position of this entity in the collection it was \
+                 queried from. Pass it as `after` or `before` to that collection to get the \
+                 next or previous page."
+                    .to_owned(),
+            ),
+            name: CURSOR_FIELD.to_string(),
+            arguments: vec![],
+            field_type: s::Type::NamedType("String".to_string()),
+            directives: vec![],
+        });
+    }
+
     fn add_type_def(
         api: &mut s::Document,
         type_def: &s::TypeDefinition,
@@ -387,6 +447,7 @@ fn init_api_schema(input_schema: &InputSchema) -> Result<Schema, APISchemaError>
                 if ot.name != SCHEMA_TYPE_NAME {
                     let mut ot = ot.clone();
                     add_collection_arguments(&mut ot.fields, input_schema);
+                    add_cursor_field(&mut ot.fields);
                     let typedef = s::TypeDefinition::Object(ot);
                     let def = s::Definition::TypeDefinition(typedef);
                     api.definitions.push(def);
@@ -395,6 +456,7 @@ fn init_api_schema(input_schema: &InputSchema) -> Result<Schema, APISchemaError>
             s::TypeDefinition::Interface(it) => {
                 let mut it = it.clone();
                 add_collection_arguments(&mut it.fields, input_schema);
+                add_cursor_field(&mut it.fields);
                 let typedef = s::TypeDefinition::Interface(it);
                 let def = s::Definition::TypeDefinition(typedef);
                 api.definitions.push(def);
@@ -457,6 +519,39 @@ fn add_meta_field_type(api: &mut s::Document) {
         .extend(META_FIELD_SCHEMA.definitions.iter().cloned());
 }
 
+/// Add the custom intervals like `5m` that aggregations in the schema use
+/// to the `Aggregation_interval` enum from `meta.graphql`
+fn add_custom_aggregation_intervals(api: &mut s::Document, input_schema: &InputSchema) {
+    let custom: BTreeSet<_> = input_schema
+        .aggregation_types()
+        .flat_map(|(_, agg_type)| agg_type.intervals.iter())
+        .filter(|interval| matches!(interval, AggregationInterval::Minutes(_)))
+        .map(|interval| interval.as_enum_value())
+        .collect();
+    if custom.is_empty() {
+        return;
+    }
+
+    let interval_type = api.definitions.iter_mut().find_map(|def| match def {
+        s::Definition::TypeDefinition(s::TypeDefinition::Enum(enum_type))
+            if enum_type.name == AGGREGATION_INTERVAL =>
+        {
+            Some(enum_type)
+        }
+        _ => None,
+    });
+    if let Some(interval_type) = interval_type {
+        interval_type
+            .values
+            .extend(custom.into_iter().map(|name| s::EnumValue {
+                position: Pos::default(),
+                description: None,
+                name,
+                directives: vec![],
+            }));
+    }
+}
+
 fn add_types_for_object_types(
     api: &mut Schema,
     schema: &InputSchema,
@@ -496,28 +591,372 @@ fn add_types_for_aggregation_types(
     Ok(())
 }
 
-/// Adds a `<type_name>_orderBy` enum type for the given fields to the schema.
-fn add_order_by_type(
+/// The object types that get an `<entities>_aggregate` query field. These
+/// are all object types that are neither timeseries nor part of an
+/// aggregation
+fn entity_aggregate_types(
+    input_schema: &InputSchema,
+) -> impl Iterator<Item = (&str, &super::ObjectType)> {
+    input_schema
+        .object_types()
+        .filter(|(_, obj_type)| !obj_type.timeseries && !obj_type.is_aggregation())
+}
+
+/// The type of the value of `sum` for a field of the given type, or `None`
+/// if the field can not be summed up
+fn aggregate_sum_type(field: &Field) -> Option<&'static str> {
+    if field.is_list() || field.is_derived() || field.name.as_str() == "id" {
+        return None;
+    }
+    match field.value_type {
+        ValueType::Int | ValueType::Int8 | ValueType::BigInt => Some("BigInt"),
+        ValueType::BigDecimal => Some("BigDecimal"),
+        _ => None,
+    }
+}
+
+/// The type of the value of `min` and `max` for a field of the given
+/// type, or `None` if the field does not have a meaningful ordering
+fn aggregate_bound_type(field: &Field) -> Option<String> {
+    if field.is_list() || field.is_derived() {
+        return None;
+    }
+    match field.value_type {
+        ValueType::Int
+        | ValueType::Int8
+        | ValueType::BigInt
+        | ValueType::BigDecimal
+        | ValueType::Timestamp => Some(field.field_type.get_base_type().to_string()),
+        _ => None,
+    }
+}
+
+/// Adds the `<type>_aggregate` result type for `<entities>_aggregate`
+/// fields, and the types `<type>_aggregate_sum` and `<type>_aggregate_bound`
+/// for its `sum` and `min`/`max` fields if the type has fields that can be
+/// aggregated in that way
+fn add_types_for_entity_aggregates(
     api: &mut s::Document,
-    type_name: &str,
-    fields: &[Field],
+    input_schema: &InputSchema,
 ) -> Result<(), APISchemaError> {
-    let type_name = format!("{}_orderBy", type_name);
+    fn object_type(name: String, description: String, fields: Vec<s::Field>) -> s::Definition {
+        s::Definition::TypeDefinition(s::TypeDefinition::Object(s::ObjectType {
+            position: Pos::default(),
+            description: Some(description),
+            name,
+            implements_interfaces: vec![],
+            directives: vec![],
+            fields,
+        }))
+    }
 
-    match api.get_named_type(&type_name) {
-        None => {
-            let typedef = s::TypeDefinition::Enum(s::EnumType {
+    fn field(name: &str, field_type: s::Type) -> s::Field {
+        s::Field {
+            position: Pos::default(),
+            description: None,
+            name: name.to_string(),
+            arguments: vec![],
+            field_type,
+            directives: vec![],
+        }
+    }
+
+    let mut defs = Vec::new();
+    for (name, object_type) in entity_aggregate_types(input_schema) {
+        let type_name = format!("{}{}", name, AGGREGATE_SUFFIX);
+        let sum_name = format!("{}_sum", type_name);
+        let bound_name = format!("{}_bound", type_name);
+        for name in [&type_name, &sum_name, &bound_name] {
+            if api.get_named_type(name).is_some() {
+                return Err(APISchemaError::TypeExists(name.clone()));
+            }
+        }
+
+        let sum_fields: Vec<_> = object_type
+            .fields
+            .iter()
+            .filter_map(|f| {
+                aggregate_sum_type(f).map(|ty| field(&f.name, s::Type::NamedType(ty.to_string())))
+            })
+            .collect();
+        let bound_fields: Vec<_> = object_type
+            .fields
+            .iter()
+            .filter_map(|f| {
+                aggregate_bound_type(f).map(|ty| field(&f.name, s::Type::NamedType(ty)))
+            })
+            .collect();
+
+        let mut fields = vec![field(
+            "count",
+            s::Type::NonNullType(Box::new(s::Type::NamedType("Int".to_string()))),
+        )];
+        if !sum_fields.is_empty() {
+            fields.push(field("sum", s::Type::NamedType(sum_name.clone())));
+            defs.push(object_type(
+                sum_name,
+                format!("Sums of the numeric fields of matching `{}` entities", name),
+                sum_fields,
+            ));
+        }
+        if !bound_fields.is_empty() {
+            fields.push(field("min", s::Type::NamedType(bound_name.clone())));
+            fields.push(field("max", s::Type::NamedType(bound_name.clone())));
+            defs.push(object_type(
+                bound_name,
+                format!("Smallest or largest values of matching `{}` entities", name),
+                bound_fields,
+            ));
+        }
+        defs.push(object_type(
+            type_name,
+            format!("Aggregate values over a collection of `{}` entities", name),
+            fields,
+        ));
+    }
+    api.definitions.extend(defs);
+    Ok(())
+}
+
+/// Adds the `<type>_version` types that `<entity>_history` fields return.
+/// Besides the block range and the changed fields of the version, they
+/// have the non-derived fields of the entity type; references to other
+/// entities are replaced by their ids since we only keep the history of
+/// one entity
+fn add_types_for_entity_versions(
+    api: &mut s::Document,
+    input_schema: &InputSchema,
+) -> Result<(), APISchemaError> {
+    let mut defs = Vec::new();
+    for (name, object_type) in entity_aggregate_types(input_schema) {
+        let type_name = format!("{}{}", name, VERSION_SUFFIX);
+        if api.get_named_type(&type_name).is_some() {
+            return Err(APISchemaError::TypeExists(type_name));
+        }
+
+        let mut fields = vec![
+            output_field(
+                "_fromBlock",
+                Some("The block at which this version was written"),
+                s::Type::NonNullType(Box::new(s::Type::NamedType(BLOCK_FIELD_TYPE.to_string()))),
+            ),
+            output_field(
+                "_toBlock",
+                Some(
+                    "The block at which this version was replaced or the entity was removed; \
+                     null if this version is current",
+                ),
+                s::Type::NamedType(BLOCK_FIELD_TYPE.to_string()),
+            ),
+            output_field(
+                "_changedFields",
+                Some(
+                    "The fields whose values differ from the previous version, or all fields \
+                     if this version created the entity; null if the previous version was \
+                     pruned",
+                ),
+                s::Type::ListType(Box::new(s::Type::NonNullType(Box::new(
+                    s::Type::NamedType("String".to_string()),
+                )))),
+            ),
+        ];
+        fields.extend(stored_fields(input_schema, object_type));
+        defs.push(s::Definition::TypeDefinition(s::TypeDefinition::Object(
+            s::ObjectType {
                 position: Pos::default(),
-                description: None,
+                description: Some(format!("One version of a `{}` entity", name)),
+                name: type_name,
+                implements_interfaces: vec![],
+                directives: vec![],
+                fields,
+            },
+        )));
+    }
+    api.definitions.extend(defs);
+    Ok(())
+}
+
+/// Adds the `_ChangeOperation_` enum and the `<type>_change` types that
+/// `<entity>_changes` fields return. Besides how and when the entity
+/// changed, they have the non-derived fields of the entity type like the
+/// `<type>_version` types, but all fields except `id` are nullable since
+/// they are only set when the query selects them
+fn add_types_for_entity_changes(
+    api: &mut s::Document,
+    input_schema: &InputSchema,
+) -> Result<(), APISchemaError> {
+    if api.get_named_type(CHANGE_OPERATION_TYPE).is_some() {
+        return Err(APISchemaError::TypeExists(
+            CHANGE_OPERATION_TYPE.to_string(),
+        ));
+    }
+    let value = |name: &str, description: &str| s::EnumValue {
+        position: Pos::default(),
+        description: Some(description.to_string()),
+        name: name.to_string(),
+        directives: vec![],
+    };
+    let mut defs = vec![s::Definition::TypeDefinition(s::TypeDefinition::Enum(
+        s::EnumType {
+            position: Pos::default(),
+            description: Some("How a block changed an entity".to_string()),
+            name: CHANGE_OPERATION_TYPE.to_string(),
+            directives: vec![],
+            values: vec![
+                value("INSERT", "The block created the entity"),
+                value("UPDATE", "The block changed the entity"),
+                value("DELETE", "The block removed the entity"),
+            ],
+        },
+    ))];
+
+    for (name, object_type) in entity_aggregate_types(input_schema) {
+        let type_name = format!("{}{}", name, CHANGE_SUFFIX);
+        if api.get_named_type(&type_name).is_some() {
+            return Err(APISchemaError::TypeExists(type_name));
+        }
+
+        let mut fields = vec![
+            output_field(
+                "_operation",
+                None,
+                s::Type::NonNullType(Box::new(s::Type::NamedType(
+                    CHANGE_OPERATION_TYPE.to_string(),
+                ))),
+            ),
+            output_field(
+                "_block",
+                Some("The number of the block that made the change"),
+                s::Type::NonNullType(Box::new(s::Type::NamedType("Int".to_string()))),
+            ),
+            output_field(
+                CURSOR_FIELD,
+                Some("Pass this as `after` to get the changes after this one"),
+                s::Type::NonNullType(Box::new(s::Type::NamedType("String".to_string()))),
+            ),
+        ];
+        fields.extend(stored_fields(input_schema, object_type).map(|mut field| {
+            if field.name != "id" {
+                if let s::Type::NonNullType(inner) = field.field_type {
+                    field.field_type = *inner;
+                }
+            }
+            field
+        }));
+        defs.push(s::Definition::TypeDefinition(s::TypeDefinition::Object(
+            s::ObjectType {
+                position: Pos::default(),
+                description: Some(format!(
+                    "A change to a `{}` entity; its fields hold the values after the \
+                     change, or before it for deletions",
+                    name
+                )),
                 name: type_name,
+                implements_interfaces: vec![],
                 directives: vec![],
-                values: field_enum_values(api, fields)?,
-            });
-            let def = s::Definition::TypeDefinition(typedef);
-            api.definitions.push(def);
+                fields,
+            },
+        )));
+    }
+    api.definitions.extend(defs);
+    Ok(())
+}
+
+/// The non-derived fields of `object_type` for the types that describe a
+/// stored version of an entity. References to other entities are replaced
+/// by their ids
+fn stored_fields<'a>(
+    input_schema: &'a InputSchema,
+    object_type: &'a super::ObjectType,
+) -> impl Iterator<Item = s::Field> + 'a {
+    fn with_base_type(field_type: &s::Type, base_type: &str) -> s::Type {
+        match field_type {
+            s::Type::NamedType(_) => s::Type::NamedType(base_type.to_string()),
+            s::Type::ListType(inner) => {
+                s::Type::ListType(Box::new(with_base_type(inner, base_type)))
+            }
+            s::Type::NonNullType(inner) => {
+                s::Type::NonNullType(Box::new(with_base_type(inner, base_type)))
+            }
+        }
+    }
+
+    object_type
+        .fields
+        .iter()
+        .filter(|f| !f.is_derived())
+        .map(|f| {
+            let base_type = f.field_type.get_base_type();
+            let field_type = if input_schema.object_or_interface(base_type, None).is_some() {
+                with_base_type(&f.field_type, f.value_type.to_str())
+            } else {
+                f.field_type.clone()
+            };
+            output_field(&f.name, None, field_type)
+        })
+}
+
+fn output_field(name: &str, description: Option<&str>, field_type: s::Type) -> s::Field {
+    s::Field {
+        position: Pos::default(),
+        description: description.map(str::to_string),
+        name: name.to_string(),
+        arguments: vec![],
+        field_type,
+        directives: vec![],
+    }
+}
+
+/// Adds a `<type_name>_orderBy` enum type for the given fields to the
+/// schema, together with the `<type_name>_orderByField` input type that
+/// pairs one of them with the direction in which to sort by it
+fn add_order_by_type(
+    api: &mut s::Document,
+    type_name: &str,
+    fields: &[Field],
+) -> Result<(), APISchemaError> {
+    let order_by_name = format!("{}_orderBy", type_name);
+    let order_by_field_name = format!("{}_orderByField", type_name);
+    for name in [&order_by_name, &order_by_field_name] {
+        if api.get_named_type(name).is_some() {
+            return Err(APISchemaError::TypeExists(name.clone()));
         }
-        Some(_) => return Err(APISchemaError::TypeExists(type_name)),
     }
+
+    let typedef = s::TypeDefinition::Enum(s::EnumType {
+        position: Pos::default(),
+        description: None,
+        name: order_by_name.clone(),
+        directives: vec![],
+        values: field_enum_values(api, fields)?,
+    });
+    api.definitions.push(s::Definition::TypeDefinition(typedef));
+
+    let mut direction = input_value(
+        "direction",
+        "",
+        s::Type::NamedType("OrderDirection".to_string()),
+    );
+    direction.description =
+        Some("Defaults to `orderDirection`, or to `asc` if that is not given".to_string());
+    let typedef = s::TypeDefinition::InputObject(s::InputObjectType {
+        position: Pos::default(),
+        description: Some(format!(
+            "A field by which `{}` entities are sorted and the direction in which to sort by it",
+            type_name
+        )),
+        name: order_by_field_name,
+        directives: vec![],
+        fields: vec![
+            input_value(
+                "field",
+                "",
+                s::Type::NonNullType(Box::new(s::Type::NamedType(order_by_name))),
+            ),
+            direction,
+        ],
+    });
+    api.definitions.push(s::Definition::TypeDefinition(typedef));
     Ok(())
 }
 
@@ -671,13 +1110,33 @@ impl FilterOps {
         let filter_type = s::Type::NamedType(format!("{}_filter", type_name));
         let filter = input_value("where", "", filter_type);
 
-        let order_by = match self {
+        // `orderByFields: [<type_name>_orderByField!]` sorts by several
+        // fields, each in its own direction, and is used instead of
+        // `orderBy`. Queries can pass just the name of a field as in
+        // `orderByFields: name`; that is expanded into a list with one
+        // `{ field: name }` entry before the argument is coerced
+        let mut order_by_fields = input_value(
+            "orderByFields",
+            "",
+            s::Type::ListType(Box::new(s::Type::NonNullType(Box::new(
+                s::Type::NamedType(format!("{}_orderByField", type_name)),
+            )))),
+        );
+        order_by_fields.description = Some(
+            "Sort by these fields, one after the other, and then by `id`. \
+             Can not be combined with `orderBy`."
+                .to_owned(),
+        );
+
+        let order_by = input_value(
+            "orderBy",
+            "",
+            s::Type::NamedType(format!("{}_orderBy", type_name)),
+        );
+
+        let order_by_args = match self {
             FilterOps::Object => vec![
-                input_value(
-                    "orderBy",
-                    "",
-                    s::Type::NamedType(format!("{}_orderBy", type_name)),
-                ),
+                order_by,
                 input_value(
                     "orderDirection",
                     "",
@@ -689,14 +1148,10 @@ impl FilterOps {
                     "interval",
                     "",
                     s::Type::NonNullType(Box::new(s::Type::NamedType(
-                        "Aggregation_interval".to_string(),
+                        AGGREGATION_INTERVAL.to_string(),
                     ))),
                 ),
-                input_value(
-                    "orderBy",
-                    "",
-                    s::Type::NamedType(format!("{}_orderBy", type_name)),
-                ),
+                order_by,
                 input_value(
                     "orderDirection",
                     "",
@@ -706,7 +1161,8 @@ impl FilterOps {
         };
 
         let mut args = vec![skip, first];
-        args.extend(order_by);
+        args.extend(order_by_args);
+        args.push(order_by_fields);
         args.push(filter);
 
         args
@@ -898,6 +1354,9 @@ fn field_filter_ops(set: FilterOpsSet<'_>) -> &'static [&'static str] {
             "ends_with_nocase",
             "not_ends_with",
             "not_ends_with_nocase",
+            "eq_nocase",
+            "in_nocase",
+            "matches",
         ],
         Aggregation("BigInt")
         | Aggregation("BigDecimal")
@@ -920,7 +1379,7 @@ fn field_scalar_filter_input_values(
         .map(|filter_type| {
             let field_type = s::Type::NamedType(set.type_name().to_string());
             let value_type = match *filter_type {
-                "in" | "not_in" => {
+                "in" | "not_in" | "in_nocase" => {
                     s::Type::ListType(Box::new(s::Type::NonNullType(Box::new(field_type))))
                 }
                 _ => field_type,
@@ -1006,6 +1465,8 @@ fn field_list_filter_input_values(
             "contains_nocase",
             "not_contains",
             "not_contains_nocase",
+            "overlaps",
+            "contained_by",
         ]
         .into_iter()
         .map(|filter_type| {
@@ -1017,6 +1478,13 @@ fn field_list_filter_input_values(
                 )))),
             )
         })
+        .chain(["length_gt", "length_lt"].into_iter().map(|filter_type| {
+            input_value(
+                &field.name,
+                filter_type,
+                s::Type::NamedType("Int".to_string()),
+            )
+        }))
         .collect(),
     };
 
@@ -1044,7 +1512,13 @@ fn input_value(name: &str, suffix: &'static str, value_type: s::Type) -> s::Inpu
 }
 
 /// Adds a root `Query` object type to the schema.
-fn add_query_type(api: &mut s::Document, input_schema: &InputSchema) -> Result<(), APISchemaError> {
+fn add_query_type(
+    api: &mut s::Document,
+    input_schema: &InputSchema,
+    aggregate_fields: bool,
+    history_fields: bool,
+    changes_fields: bool,
+) -> Result<(), APISchemaError> {
     let type_name = String::from("Query");
 
     if api.get_named_type(&type_name).is_some() {
@@ -1069,6 +1543,21 @@ fn add_query_type(api: &mut s::Document, input_schema: &InputSchema) -> Result<(
         .filter_map(|fulltext| query_field_for_fulltext(fulltext))
         .collect();
     fields.append(&mut agg_fields);
+    if aggregate_fields {
+        fields.extend(
+            entity_aggregate_types(input_schema).map(|(name, _)| query_field_for_aggregate(name)),
+        );
+    }
+    if history_fields {
+        fields.extend(
+            entity_aggregate_types(input_schema).map(|(name, _)| query_field_for_history(name)),
+        );
+    }
+    if changes_fields {
+        fields.extend(
+            entity_aggregate_types(input_schema).map(|(name, _)| query_field_for_changes(name)),
+        );
+    }
     fields.append(&mut fulltext_fields);
     fields.push(meta_field());
 
@@ -1152,9 +1641,14 @@ fn block_argument() -> s::InputValue {
             "The block at which the query should be executed. \
              Can either be a `{ hash: Bytes }` value containing a block hash, \
              a `{ number: Int }` containing the block number, \
-             or a `{ number_gte: Int }` containing the minimum block number. \
+             a `{ number_gte: Int }` containing the minimum block number, \
+             or a `{ timestamp_lte: Int }` containing a Unix timestamp in seconds. \
              In the case of `number_gte`, the query will be executed on the latest block only if \
              the subgraph has progressed to or past the minimum block number. \
+             In the case of `timestamp_lte`, the query will be executed on the latest block \
+             whose timestamp is at or before the given timestamp; that requires the \
+             timestamps of the network's blocks and fails if the network does not record them \
+             or if this node no longer stores the blocks. \
              Defaults to the latest block when omitted."
                 .to_owned(),
         ),
@@ -1190,9 +1684,36 @@ fn subgraph_error_argument() -> s::InputValue {
     }
 }
 
+/// The `after` and `before` arguments for keyset pagination of the
+/// collections on the `Query` type
+fn cursor_arguments() -> Vec<s::InputValue> {
+    let cursor = |name: &str, description: &str| s::InputValue {
+        position: Pos::default(),
+        description: Some(description.to_owned()),
+        name: name.to_string(),
+        value_type: s::Type::NamedType("String".to_string()),
+        default_value: None,
+        directives: vec![],
+    };
+    vec![
+        cursor(
+            "after",
+            "Only return entities that come after the entity with this `_cursor` \
+             in the order given by `orderBy` and `orderDirection`.",
+        ),
+        cursor(
+            "before",
+            "Only return entities that come before the entity with this `_cursor` \
+             in the order given by `orderBy` and `orderDirection`. Without `after`, \
+             return the `first` entities just before the cursor, i.e., the previous page.",
+        ),
+    ]
+}
+
 /// Generates `Query` fields for the given type name (e.g. `users` and `user`).
 fn query_fields_for_type(type_name: &str, ops: FilterOps) -> Vec<s::Field> {
     let mut collection_arguments = ops.collection_arguments(type_name);
+    collection_arguments.extend(cursor_arguments());
     collection_arguments.push(block_argument());
 
     let mut by_id_arguments = vec![
@@ -1234,8 +1755,26 @@ fn query_fields_for_type(type_name: &str, ops: FilterOps) -> Vec<s::Field> {
     ]
 }
 
+fn rollup_argument() -> s::InputValue {
+    s::InputValue {
+        position: Pos::default(),
+        description: Some(
+            "Set to `true` to combine all buckets that match the `where` filter into one \
+             value for each combination of dimensions. The filter must restrict the \
+             `timestamp` from below and above, e.g. with `timestamp_gte` and `timestamp_lt`."
+                .to_owned(),
+        ),
+        name: "rollup".to_string(),
+        value_type: s::Type::NamedType("Boolean".to_string()),
+        default_value: Some(s::Value::Boolean(false)),
+        directives: vec![],
+    }
+}
+
 fn query_fields_for_agg_type(type_name: &str) -> Vec<s::Field> {
     let mut collection_arguments = FilterOps::Aggregation.collection_arguments(type_name);
+    collection_arguments.extend(cursor_arguments());
+    collection_arguments.push(rollup_argument());
     collection_arguments.push(block_argument());
     collection_arguments.push(subgraph_error_argument());
 
@@ -1252,42 +1791,324 @@ fn query_fields_for_agg_type(type_name: &str) -> Vec<s::Field> {
     }]
 }
 
-fn meta_field() -> s::Field {
-    lazy_static! {
-        static ref META_FIELD: s::Field = s::Field {
-            position: Pos::default(),
-            description: Some("Access to subgraph metadata".to_string()),
-            name: META_FIELD_NAME.to_string(),
-            arguments: vec![
-                // block: BlockHeight
-                s::InputValue {
-                    position: Pos::default(),
-                    description: None,
-                    name: String::from("block"),
-                    value_type: s::Type::NamedType(BLOCK_HEIGHT.to_string()),
-                    default_value: None,
-                    directives: vec![],
-                },
-            ],
-            field_type: s::Type::NamedType(META_FIELD_TYPE.to_string()),
-            directives: vec![],
-        };
+/// Generates the `<entities>_aggregate` field for the given type name
+fn query_field_for_aggregate(type_name: &str) -> s::Field {
+    let arguments = vec![
+        input_value(
+            "where",
+            "",
+            s::Type::NamedType(format!("{}_filter", type_name)),
+        ),
+        block_argument(),
+        subgraph_error_argument(),
+    ];
+
+    let (_, plural) = camel_cased_names(type_name);
+    s::Field {
+        position: Pos::default(),
+        description: Some(format!(
+            "Count and aggregate values of the `{}` entities that match `where`",
+            type_name
+        )),
+        name: format!("{}{}", plural, AGGREGATE_SUFFIX),
+        arguments,
+        field_type: s::Type::NonNullType(Box::new(s::Type::NamedType(format!(
+            "{}{}",
+            type_name, AGGREGATE_SUFFIX
+        )))),
+        directives: vec![],
     }
-    META_FIELD.clone()
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::{
-        data::{
-            graphql::{ext::FieldExt, ObjectTypeExt, TypeExt as _},
-            subgraph::LATEST_VERSION,
-        },
-        prelude::{s, DeploymentHash},
-        schema::{InputSchema, SCHEMA_TYPE_NAME},
+/// Generates the `<entity>_history` field for the given type name
+fn query_field_for_history(type_name: &str) -> s::Field {
+    let block = |name: &str, description: &str| s::InputValue {
+        position: Pos::default(),
+        description: Some(description.to_owned()),
+        name: name.to_string(),
+        value_type: s::Type::NamedType("Int".to_string()),
+        default_value: None,
+        directives: vec![],
     };
-    use graphql_parser::schema::*;
-    use lazy_static::lazy_static;
+    let arguments = vec![
+        s::InputValue {
+            position: Pos::default(),
+            description: None,
+            name: "id".to_string(),
+            value_type: s::Type::NonNullType(Box::new(s::Type::NamedType("ID".to_string()))),
+            default_value: None,
+            directives: vec![],
+        },
+        s::InputValue {
+            position: Pos::default(),
+            description: None,
+            name: "first".to_string(),
+            value_type: s::Type::NamedType("Int".to_string()),
+            default_value: Some(s::Value::Int(100.into())),
+            directives: vec![],
+        },
+        block(
+            "fromBlock",
+            "Only return versions that were current at or after this block. \
+             Defaults to the earliest block of the subgraph.",
+        ),
+        block(
+            "toBlock",
+            "Only return versions that were current at or before this block. \
+             Defaults to the latest block.",
+        ),
+        subgraph_error_argument(),
+    ];
+
+    let (singular, _) = camel_cased_names(type_name);
+    s::Field {
+        position: Pos::default(),
+        description: Some(format!(
+            "The versions of the `{}` entity with the given `id`, oldest first",
+            type_name
+        )),
+        name: format!("{}{}", singular, HISTORY_SUFFIX),
+        arguments,
+        field_type: s::Type::NonNullType(Box::new(s::Type::ListType(Box::new(
+            s::Type::NonNullType(Box::new(s::Type::NamedType(format!(
+                "{}{}",
+                type_name, VERSION_SUFFIX
+            )))),
+        )))),
+        directives: vec![],
+    }
+}
+
+/// Generates the `<entity>_changes` field for the given type name
+fn query_field_for_changes(type_name: &str) -> s::Field {
+    let input = |name: &str, description: Option<&str>, value_type: s::Type| s::InputValue {
+        position: Pos::default(),
+        description: description.map(str::to_owned),
+        name: name.to_string(),
+        value_type,
+        default_value: None,
+        directives: vec![],
+    };
+    let int = || s::Type::NamedType("Int".to_string());
+    let mut first = input("first", None, int());
+    first.default_value = Some(s::Value::Int(100.into()));
+    let arguments = vec![
+        input(
+            "fromBlock",
+            Some("Only return changes made at or after this block"),
+            s::Type::NonNullType(Box::new(int())),
+        ),
+        input(
+            "toBlock",
+            Some(
+                "Only return changes made at or before this block. \
+                 Defaults to the latest block.",
+            ),
+            int(),
+        ),
+        first,
+        input(
+            "after",
+            Some("Only return changes after the change with this `_cursor`"),
+            s::Type::NamedType("String".to_string()),
+        ),
+        subgraph_error_argument(),
+    ];
+
+    let (singular, _) = camel_cased_names(type_name);
+    s::Field {
+        position: Pos::default(),
+        description: Some(format!(
+            "The inserts, updates and deletions of `{}` entities in a range of blocks, \
+             ordered by block",
+            type_name
+        )),
+        name: format!("{}{}", singular, CHANGES_SUFFIX),
+        arguments,
+        field_type: s::Type::NonNullType(Box::new(s::Type::ListType(Box::new(
+            s::Type::NonNullType(Box::new(s::Type::NamedType(format!(
+                "{}{}",
+                type_name, CHANGE_SUFFIX
+            )))),
+        )))),
+        directives: vec![],
+    }
+}
+
+fn meta_field() -> s::Field {
+    lazy_static! {
+        static ref META_FIELD: s::Field = s::Field {
+            position: Pos::default(),
+            description: Some("Access to subgraph metadata".to_string()),
+            name: META_FIELD_NAME.to_string(),
+            arguments: vec![
+                // block: BlockHeight
+                s::InputValue {
+                    position: Pos::default(),
+                    description: None,
+                    name: String::from("block"),
+                    value_type: s::Type::NamedType(BLOCK_HEIGHT.to_string()),
+                    default_value: None,
+                    directives: vec![],
+                },
+            ],
+            field_type: s::Type::NamedType(META_FIELD_TYPE.to_string()),
+            directives: vec![],
+        };
+    }
+    META_FIELD.clone()
+}
+
+/// The name of a type or root query field of the subgraph with the given
+/// `namespace` in a federated schema
+pub fn federated_name(namespace: &str, name: &str) -> String {
+    format!("{}_{}", namespace, name)
+}
+
+/// Combine the API schemas of several subgraphs into the schema for
+/// federated queries across all of them. The root query fields and the
+/// types of each subgraph are prefixed with the subgraph's namespace (see
+/// `federated_name`) so that they can not clash. Scalars and directives are
+/// shared between all subgraphs, and introspection fields are left out
+pub fn federated_schema<'a>(
+    subgraphs: impl IntoIterator<Item = (&'a str, &'a ApiSchema)>,
+) -> s::Document {
+    fn rename_type(ty: &mut s::Type, rename: &impl Fn(&str) -> String) {
+        match ty {
+            s::Type::NamedType(name) => *name = rename(name),
+            s::Type::ListType(inner) | s::Type::NonNullType(inner) => rename_type(inner, rename),
+        }
+    }
+
+    fn rename_fields(fields: &mut [s::Field], rename: &impl Fn(&str) -> String) {
+        for field in fields {
+            rename_type(&mut field.field_type, rename);
+            rename_input_values(&mut field.arguments, rename);
+        }
+    }
+
+    fn rename_input_values(values: &mut [s::InputValue], rename: &impl Fn(&str) -> String) {
+        for value in values {
+            rename_type(&mut value.value_type, rename);
+        }
+    }
+
+    let mut definitions = Vec::new();
+    let mut shared = BTreeSet::new();
+    let mut query_fields = Vec::new();
+    for (namespace, schema) in subgraphs {
+        let document = schema.document();
+        let query_type = schema.query_type.name.as_str();
+
+        // Only types that the subgraph defines get renamed; built-in
+        // scalars like `String` are not defined in the document
+        let types: BTreeSet<_> = document
+            .definitions
+            .iter()
+            .filter_map(|defn| match defn {
+                s::Definition::TypeDefinition(s::TypeDefinition::Scalar(_)) => None,
+                s::Definition::TypeDefinition(typedef) => Some(typedef.name()),
+                _ => None,
+            })
+            .collect();
+        let rename = |name: &str| {
+            if types.contains(name) {
+                federated_name(namespace, name)
+            } else {
+                name.to_string()
+            }
+        };
+
+        for defn in &document.definitions {
+            let typedef = match defn {
+                s::Definition::DirectiveDefinition(directive) => {
+                    if shared.insert(format!("@{}", directive.name)) {
+                        definitions.push(defn.clone());
+                    }
+                    continue;
+                }
+                s::Definition::TypeDefinition(typedef) => typedef,
+                s::Definition::SchemaDefinition(_) | s::Definition::TypeExtension(_) => continue,
+            };
+            if typedef.name().starts_with("__") {
+                continue;
+            }
+
+            let mut typedef = typedef.clone();
+            match &mut typedef {
+                s::TypeDefinition::Scalar(scalar) => {
+                    if shared.insert(scalar.name.clone()) {
+                        definitions.push(s::Definition::TypeDefinition(typedef));
+                    }
+                    continue;
+                }
+                s::TypeDefinition::Object(obj) if obj.name == query_type => {
+                    let mut fields: Vec<_> = obj
+                        .fields
+                        .drain(..)
+                        .filter(|field| !field.name.starts_with("__"))
+                        .collect();
+                    rename_fields(&mut fields, &rename);
+                    for field in &mut fields {
+                        field.name = federated_name(namespace, &field.name);
+                    }
+                    query_fields.extend(fields);
+                    continue;
+                }
+                s::TypeDefinition::Object(obj) => {
+                    obj.name = rename(&obj.name);
+                    for name in &mut obj.implements_interfaces {
+                        *name = rename(name);
+                    }
+                    rename_fields(&mut obj.fields, &rename);
+                }
+                s::TypeDefinition::Interface(intf) => {
+                    intf.name = rename(&intf.name);
+                    rename_fields(&mut intf.fields, &rename);
+                }
+                s::TypeDefinition::Union(union) => {
+                    union.name = rename(&union.name);
+                    for name in &mut union.types {
+                        *name = rename(name);
+                    }
+                }
+                s::TypeDefinition::Enum(enum_type) => {
+                    enum_type.name = rename(&enum_type.name);
+                }
+                s::TypeDefinition::InputObject(input) => {
+                    input.name = rename(&input.name);
+                    rename_input_values(&mut input.fields, &rename);
+                }
+            }
+            definitions.push(s::Definition::TypeDefinition(typedef));
+        }
+    }
+
+    definitions.push(s::Definition::TypeDefinition(s::TypeDefinition::Object(
+        s::ObjectType {
+            position: Pos::default(),
+            description: None,
+            name: "Query".to_string(),
+            implements_interfaces: vec![],
+            directives: vec![],
+            fields: query_fields,
+        },
+    )));
+    s::Document { definitions }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        data::{
+            graphql::{ext::FieldExt, ObjectTypeExt, TypeExt as _},
+            subgraph::LATEST_VERSION,
+        },
+        prelude::{s, DeploymentHash},
+        schema::{InputSchema, CURSOR_FIELD, SCHEMA_TYPE_NAME},
+    };
+    use graphql_parser::schema::*;
+    use lazy_static::lazy_static;
 
     use super::ApiSchema;
     use crate::schema::ast;
@@ -1305,6 +2126,39 @@ mod tests {
             .expect("Failed to derive API schema")
     }
 
+    /// Like `parse`, but with `<entities>_aggregate` fields turned on
+    #[track_caller]
+    fn parse_with_aggregates(raw: &str) -> ApiSchema {
+        let input_schema = InputSchema::parse(LATEST_VERSION, raw, ID.clone())
+            .expect("Failed to parse input schema");
+        let mut schema = input_schema.schema().clone();
+        schema.document = super::derive_api_schema(&input_schema, true, false, false)
+            .expect("Failed to derive API schema");
+        ApiSchema::from_api_schema(schema).expect("Failed to derive API schema")
+    }
+
+    /// Like `parse`, but with `<entity>_history` fields turned on
+    #[track_caller]
+    fn parse_with_history(raw: &str) -> ApiSchema {
+        let input_schema = InputSchema::parse(LATEST_VERSION, raw, ID.clone())
+            .expect("Failed to parse input schema");
+        let mut schema = input_schema.schema().clone();
+        schema.document = super::derive_api_schema(&input_schema, false, true, false)
+            .expect("Failed to derive API schema");
+        ApiSchema::from_api_schema(schema).expect("Failed to derive API schema")
+    }
+
+    /// Like `parse`, but with `<entity>_changes` fields turned on
+    #[track_caller]
+    fn parse_with_changes(raw: &str) -> ApiSchema {
+        let input_schema = InputSchema::parse(LATEST_VERSION, raw, ID.clone())
+            .expect("Failed to parse input schema");
+        let mut schema = input_schema.schema().clone();
+        schema.document = super::derive_api_schema(&input_schema, false, false, true)
+            .expect("Failed to derive API schema");
+        ApiSchema::from_api_schema(schema).expect("Failed to derive API schema")
+    }
+
     /// Return a field from the `Query` type. If the field does not exist,
     /// fail the test
     #[track_caller]
@@ -1645,18 +2499,29 @@ mod tests {
                 "name_ends_with_nocase",
                 "name_not_ends_with",
                 "name_not_ends_with_nocase",
+                "name_eq_nocase",
+                "name_in_nocase",
+                "name_matches",
                 "favoritePetNames",
                 "favoritePetNames_not",
                 "favoritePetNames_contains",
                 "favoritePetNames_contains_nocase",
                 "favoritePetNames_not_contains",
                 "favoritePetNames_not_contains_nocase",
+                "favoritePetNames_overlaps",
+                "favoritePetNames_contained_by",
+                "favoritePetNames_length_gt",
+                "favoritePetNames_length_lt",
                 "pets",
                 "pets_not",
                 "pets_contains",
                 "pets_contains_nocase",
                 "pets_not_contains",
                 "pets_not_contains_nocase",
+                "pets_overlaps",
+                "pets_contained_by",
+                "pets_length_gt",
+                "pets_length_lt",
                 "pets_",
                 "favoriteFurType",
                 "favoriteFurType_not",
@@ -1682,6 +2547,9 @@ mod tests {
                 "favoritePet_ends_with_nocase",
                 "favoritePet_not_ends_with",
                 "favoritePet_not_ends_with_nocase",
+                "favoritePet_eq_nocase",
+                "favoritePet_in_nocase",
+                "favoritePet_matches",
                 "favoritePet_",
                 "leastFavoritePet_",
                 "mostFavoritePets_",
@@ -1750,12 +2618,19 @@ mod tests {
                 "name_ends_with_nocase",
                 "name_not_ends_with",
                 "name_not_ends_with_nocase",
+                "name_eq_nocase",
+                "name_in_nocase",
+                "name_matches",
                 "mostHatedBy",
                 "mostHatedBy_not",
                 "mostHatedBy_contains",
                 "mostHatedBy_contains_nocase",
                 "mostHatedBy_not_contains",
                 "mostHatedBy_not_contains_nocase",
+                "mostHatedBy_overlaps",
+                "mostHatedBy_contained_by",
+                "mostHatedBy_length_gt",
+                "mostHatedBy_length_lt",
                 "mostHatedBy_",
                 "mostLovedBy",
                 "mostLovedBy_not",
@@ -1763,6 +2638,10 @@ mod tests {
                 "mostLovedBy_contains_nocase",
                 "mostLovedBy_not_contains",
                 "mostLovedBy_not_contains_nocase",
+                "mostLovedBy_overlaps",
+                "mostLovedBy_contained_by",
+                "mostLovedBy_length_gt",
+                "mostLovedBy_length_lt",
                 "mostLovedBy_",
                 "_change_block",
                 "and",
@@ -1868,6 +2747,9 @@ mod tests {
                 "name_ends_with_nocase",
                 "name_not_ends_with",
                 "name_not_ends_with_nocase",
+                "name_eq_nocase",
+                "name_in_nocase",
+                "name_matches",
                 "pets_",
                 "favoritePet",
                 "favoritePet_not",
@@ -1889,6 +2771,9 @@ mod tests {
                 "favoritePet_ends_with_nocase",
                 "favoritePet_not_ends_with",
                 "favoritePet_not_ends_with_nocase",
+                "favoritePet_eq_nocase",
+                "favoritePet_in_nocase",
+                "favoritePet_matches",
                 "favoritePet_",
                 "_change_block",
                 "and",
@@ -1976,7 +2861,10 @@ mod tests {
                 "first",
                 "orderBy",
                 "orderDirection",
+                "orderByFields",
                 "where",
+                "after",
+                "before",
                 "block",
                 "subgraphError",
             ]
@@ -2071,7 +2959,10 @@ mod tests {
                 "first",
                 "orderBy",
                 "orderDirection",
+                "orderByFields",
                 "where",
+                "after",
+                "before",
                 "block",
                 "subgraphError"
             ]
@@ -2264,6 +3155,8 @@ type Gravatar @entity {
         let schema = parse(SCHEMA);
         let stats = query_field(&schema, "stats_collection");
         assert_aggregation_field(&schema, stats, "Stats");
+        let rollup = stats.argument("rollup").unwrap();
+        assert_eq!("Boolean", rollup.value_type.get_base_type());
 
         // Make sure that Stuff.stats has collection arguments, in
         // particular a `where` filter
@@ -2275,6 +3168,48 @@ type Gravatar @entity {
         };
         let stats = stuff.field("stats").unwrap();
         assert_aggregation_field(&schema, stats, "Stats");
+        // Rollups are only possible at the root of a query
+        assert!(stats.argument("rollup").is_none());
+    }
+
+    #[test]
+    fn aggregation_intervals() {
+        const SCHEMA: &str = r#"
+        type Data @entity(timeseries: true) {
+            id: Int8!
+            timestamp: Timestamp!
+            value: BigDecimal!
+        }
+
+        type Stats @aggregation(source: "Data", intervals: ["15m", "hour", "2h", "week", "month"]) {
+            id: Int8!
+            timestamp: Timestamp!
+            sum: BigDecimal! @aggregate(fn: "sum", arg: "value")
+        }
+
+        type OtherStats @aggregation(source: "Data", intervals: ["5m", "15m", "day"]) {
+            id: Int8!
+            timestamp: Timestamp!
+            sum: BigDecimal! @aggregate(fn: "sum", arg: "value", cumulative: true)
+        }
+        "#;
+
+        let schema = parse(SCHEMA);
+        let s::TypeDefinition::Enum(intervals) = schema
+            .get_named_type(AGGREGATION_INTERVAL)
+            .expect("Aggregation_interval type is missing")
+        else {
+            panic!("Aggregation_interval is not an enum")
+        };
+        let values = intervals
+            .values
+            .iter()
+            .map(|value| value.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec!["hour", "day", "week", "month", "_15m", "_2h", "_5m"],
+            values
+        );
     }
 
     #[test]
@@ -2351,15 +3286,472 @@ type Gravatar @entity {
                 [
                     "pools",
                     "pools_",
+                    "pools_contained_by",
                     "pools_contains",
                     "pools_contains_nocase",
+                    "pools_length_gt",
+                    "pools_length_lt",
                     "pools_not",
                     "pools_not_contains",
                     "pools_not_contains_nocase",
+                    "pools_overlaps",
                 ],
                 pools_fields.as_slice(),
                 "Field {protos} has the wrong pools filters"
             );
         }
     }
+
+    #[test]
+    fn entity_aggregate_fields() {
+        const SCHEMA: &str = r#"
+        type Token @entity {
+            id: Bytes!
+            name: String!
+            decimals: Int!
+            supply: BigInt!
+            price: BigDecimal
+            created: Timestamp!
+            holders: [Bytes!]!
+        }
+
+        type Tag @entity {
+            id: ID!
+            label: String!
+        }
+
+        type Data @entity(timeseries: true) {
+            id: Int8!
+            timestamp: Timestamp!
+            value: BigDecimal!
+        }
+
+        type Stats @aggregation(source: "Data", intervals: ["hour"]) {
+            id: Int8!
+            timestamp: Timestamp!
+            sum: BigDecimal! @aggregate(fn: "sum", arg: "value")
+        }
+        "#;
+
+        #[track_caller]
+        fn object_fields(schema: &ApiSchema, name: &str) -> Vec<(String, String)> {
+            let Some(s::TypeDefinition::Object(obj)) = schema.get_named_type(name) else {
+                panic!("Schema should contain an object type named `{name}`")
+            };
+            obj.fields
+                .iter()
+                .map(|f| (f.name.clone(), f.field_type.to_string()))
+                .collect()
+        }
+
+        #[track_caller]
+        fn assert_fields(schema: &ApiSchema, name: &str, exp: &[(&str, &str)]) {
+            let exp: Vec<_> = exp
+                .iter()
+                .map(|(name, ty)| (name.to_string(), ty.to_string()))
+                .collect();
+            assert_eq!(exp, object_fields(schema, name), "fields of `{name}`");
+        }
+
+        // Without the flag, there are no aggregate fields
+        let schema = parse(SCHEMA);
+        let query_type = schema.get_named_type("Query").unwrap();
+        let TypeDefinition::Object(query_type) = query_type else {
+            panic!("Query type is not an object type")
+        };
+        assert!(query_type.field("tokens_aggregate").is_none());
+        assert!(schema.get_named_type("Token_aggregate").is_none());
+
+        let schema = parse_with_aggregates(SCHEMA);
+
+        let field = query_field(&schema, "tokens_aggregate");
+        assert_eq!("Token_aggregate!", field.field_type.to_string());
+        let args: Vec<_> = field
+            .arguments
+            .iter()
+            .map(|arg| arg.name.as_str())
+            .collect();
+        assert_eq!(["where", "block", "subgraphError"], args.as_slice());
+        assert_eq!(
+            "Token_filter",
+            field.argument("where").unwrap().value_type.get_base_type()
+        );
+
+        assert_fields(
+            &schema,
+            "Token_aggregate",
+            &[
+                ("count", "Int!"),
+                ("sum", "Token_aggregate_sum"),
+                ("min", "Token_aggregate_bound"),
+                ("max", "Token_aggregate_bound"),
+            ],
+        );
+        assert_fields(
+            &schema,
+            "Token_aggregate_sum",
+            &[
+                ("decimals", "BigInt"),
+                ("supply", "BigInt"),
+                ("price", "BigDecimal"),
+            ],
+        );
+        assert_fields(
+            &schema,
+            "Token_aggregate_bound",
+            &[
+                ("decimals", "Int"),
+                ("supply", "BigInt"),
+                ("price", "BigDecimal"),
+                ("created", "Timestamp"),
+            ],
+        );
+
+        // Types without numeric fields can only be counted
+        query_field(&schema, "tags_aggregate");
+        assert_fields(&schema, "Tag_aggregate", &[("count", "Int!")]);
+        assert!(schema.get_named_type("Tag_aggregate_sum").is_none());
+
+        // Timeseries and aggregations do not get aggregate fields
+        let TypeDefinition::Object(query_type) = schema.get_named_type("Query").unwrap() else {
+            panic!("Query type is not an object type")
+        };
+        assert!(query_type.field("data_aggregate").is_none());
+        assert!(query_type.field("stats_aggregate").is_none());
+        assert!(schema.get_named_type("Stats_aggregate").is_none());
+    }
+
+    #[test]
+    fn cursor_fields_and_arguments() {
+        const SCHEMA: &str = r#"
+        interface Pet {
+            id: ID!
+            name: String!
+        }
+
+        type Dog implements Pet @entity {
+            id: ID!
+            name: String!
+            owner: User!
+        }
+
+        type User @entity {
+            id: ID!
+            pets: [Pet!]! @derivedFrom(field: "owner")
+        }
+        "#;
+
+        let schema = parse(SCHEMA);
+
+        // Objects and interfaces have a `_cursor`
+        for name in ["Pet", "Dog", "User"] {
+            let cursor = match schema.get_named_type(name) {
+                Some(TypeDefinition::Object(obj)) => obj.field(CURSOR_FIELD),
+                Some(TypeDefinition::Interface(intf)) => intf.field(CURSOR_FIELD),
+                _ => panic!("Schema should contain a type named `{name}`"),
+            };
+            let cursor = cursor.unwrap_or_else(|| panic!("`{name}` should have a `_cursor`"));
+            assert_eq!("String", cursor.field_type.to_string());
+        }
+
+        // Collections on `Query` can be paginated with cursors, nested
+        // collections can not
+        for name in ["pets", "dogs", "users"] {
+            let field = query_field(&schema, name);
+            assert!(field.argument("after").is_some());
+            assert!(field.argument("before").is_some());
+        }
+        let Some(TypeDefinition::Object(user)) = schema.get_named_type("User") else {
+            panic!("Schema should contain the `User` type")
+        };
+        let pets = user.field("pets").unwrap();
+        assert!(pets.argument("where").is_some());
+        assert!(pets.argument("after").is_none());
+    }
+
+    #[test]
+    fn order_by_fields_argument() {
+        const SCHEMA: &str = r#"
+        type User @entity {
+            id: ID!
+            name: String!
+            age: Int!
+            pets: [Pet!]! @derivedFrom(field: "owner")
+        }
+
+        type Pet @entity {
+            id: ID!
+            name: String!
+            owner: User!
+        }
+        "#;
+
+        let schema = parse(SCHEMA);
+
+        let Some(TypeDefinition::InputObject(order_by_field)) =
+            schema.get_named_type("User_orderByField")
+        else {
+            panic!("Schema should contain the `User_orderByField` input type")
+        };
+        let fields: Vec<_> = order_by_field
+            .fields
+            .iter()
+            .map(|field| (field.name.as_str(), field.value_type.to_string()))
+            .collect();
+        assert_eq!(
+            vec![("field", "User_orderBy!"), ("direction", "OrderDirection")],
+            fields
+        );
+        // The direction defaults to `orderDirection`
+        assert_eq!(None, order_by_field.fields[1].default_value.as_ref());
+
+        // Both collections on `Query` and nested collections can be
+        // sorted by several fields; `orderBy` still takes a single field
+        let users = query_field(&schema, "users");
+        let order_by_fields = users.argument("orderByFields").unwrap();
+        assert_eq!(
+            "[User_orderByField!]",
+            order_by_fields.value_type.to_string()
+        );
+        let order_by = users.argument("orderBy").unwrap();
+        assert_eq!("User_orderBy", order_by.value_type.to_string());
+
+        let Some(TypeDefinition::Object(user)) = schema.get_named_type("User") else {
+            panic!("Schema should contain the `User` type")
+        };
+        let pets = user.field("pets").unwrap();
+        let order_by_fields = pets.argument("orderByFields").unwrap();
+        assert_eq!(
+            "[Pet_orderByField!]",
+            order_by_fields.value_type.to_string()
+        );
+        let order_by = pets.argument("orderBy").unwrap();
+        assert_eq!("Pet_orderBy", order_by.value_type.to_string());
+    }
+
+    #[test]
+    fn string_and_list_filter_value_types() {
+        const SCHEMA: &str = r#"
+        type User @entity {
+            id: ID!
+            name: String!
+            tags: [String!]!
+        }
+        "#;
+
+        let schema = parse(SCHEMA);
+
+        let Some(TypeDefinition::InputObject(filter)) = schema.get_named_type("User_filter") else {
+            panic!("Schema should contain the `User_filter` input type")
+        };
+        let value_type = |name: &str| {
+            filter
+                .fields
+                .iter()
+                .find(|field| field.name == name)
+                .map(|field| field.value_type.to_string())
+                .expect(&format!("User_filter should have a `{name}` field"))
+        };
+
+        assert_eq!("String", value_type("name_eq_nocase"));
+        assert_eq!("[String!]", value_type("name_in_nocase"));
+        assert_eq!("String", value_type("name_matches"));
+        assert_eq!("[String!]", value_type("tags_overlaps"));
+        assert_eq!("[String!]", value_type("tags_contained_by"));
+        assert_eq!("Int", value_type("tags_length_gt"));
+        assert_eq!("Int", value_type("tags_length_lt"));
+    }
+
+    #[test]
+    fn federated_schema_namespaces_subgraphs() {
+        use crate::data::graphql::ext::DocumentExt;
+
+        let uni = parse("type Pool @entity { id: ID!, volume: BigInt! }");
+        let sushi = parse("type Pool @entity { id: ID!, name: String! }");
+        let schema = super::federated_schema([("uni", &uni), ("sushi", &sushi)]);
+
+        let query_type = schema
+            .get_root_query_type()
+            .expect("federated schema has a Query type");
+        let pools = ast::get_field(query_type, "uni_pools").expect("uni_pools is a root field");
+        assert_eq!("[uni_Pool!]!", pools.field_type.to_string());
+        assert!(ast::get_field(query_type, "sushi_pool").is_some());
+        assert!(ast::get_field(query_type, "uni__meta").is_some());
+        assert!(ast::get_field(query_type, "pools").is_none());
+        assert!(ast::get_field(query_type, "uni___schema").is_none());
+
+        assert!(schema.get_named_type("uni_Pool").is_some());
+        assert!(schema.get_named_type("sushi_Pool_filter").is_some());
+        assert!(schema.get_named_type("Pool").is_none());
+        let scalars = schema
+            .definitions
+            .iter()
+            .filter(|defn| {
+                matches!(defn, s::Definition::TypeDefinition(TypeDefinition::Scalar(scalar)) if scalar.name == "BigInt")
+            })
+            .count();
+        assert_eq!(1, scalars);
+    }
+
+    #[test]
+    fn entity_history_fields() {
+        const SCHEMA: &str = r#"
+        type Token @entity {
+            id: Bytes!
+            name: String!
+            owner: Account!
+            holders: [Account!]!
+            transfers: [Transfer!]! @derivedFrom(field: "token")
+        }
+
+        type Account @entity {
+            id: ID!
+        }
+
+        type Transfer @entity(immutable: true) {
+            id: Bytes!
+            token: Token!
+        }
+
+        type Data @entity(timeseries: true) {
+            id: Int8!
+            timestamp: Timestamp!
+            value: BigDecimal!
+        }
+        "#;
+
+        // Without the flag, there are no history fields
+        let schema = parse(SCHEMA);
+        let TypeDefinition::Object(query_type) = schema.get_named_type("Query").unwrap() else {
+            panic!("Query type is not an object type")
+        };
+        assert!(query_type.field("token_history").is_none());
+        assert!(schema.get_named_type("Token_version").is_none());
+
+        let schema = parse_with_history(SCHEMA);
+
+        let field = query_field(&schema, "token_history");
+        assert_eq!("[Token_version!]!", field.field_type.to_string());
+        let args: Vec<_> = field
+            .arguments
+            .iter()
+            .map(|arg| (arg.name.as_str(), arg.value_type.to_string()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("id", "ID!".to_string()),
+                ("first", "Int".to_string()),
+                ("fromBlock", "Int".to_string()),
+                ("toBlock", "Int".to_string()),
+                ("subgraphError", "_SubgraphErrorPolicy_!".to_string()),
+            ],
+            args
+        );
+        query_field(&schema, "transfer_history");
+
+        let Some(s::TypeDefinition::Object(version)) = schema.get_named_type("Token_version")
+        else {
+            panic!("Schema should contain an object type named `Token_version`")
+        };
+        let fields: Vec<_> = version
+            .fields
+            .iter()
+            .map(|f| (f.name.as_str(), f.field_type.to_string()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("_fromBlock", "_Block_!".to_string()),
+                ("_toBlock", "_Block_".to_string()),
+                ("_changedFields", "[String!]".to_string()),
+                ("id", "Bytes!".to_string()),
+                ("name", "String!".to_string()),
+                ("owner", "String!".to_string()),
+                ("holders", "[String!]!".to_string()),
+            ],
+            fields
+        );
+
+        // Timeseries do not get history fields
+        let TypeDefinition::Object(query_type) = schema.get_named_type("Query").unwrap() else {
+            panic!("Query type is not an object type")
+        };
+        assert!(query_type.field("data_history").is_none());
+    }
+
+    #[test]
+    fn entity_changes_fields() {
+        const SCHEMA: &str = r#"
+        type Token @entity {
+            id: Bytes!
+            name: String!
+            owner: Account!
+            transfers: [Transfer!]! @derivedFrom(field: "token")
+        }
+
+        type Account @entity {
+            id: ID!
+        }
+
+        type Transfer @entity(immutable: true) {
+            id: Bytes!
+            token: Token!
+        }
+        "#;
+
+        let schema = parse(SCHEMA);
+        let TypeDefinition::Object(query_type) = schema.get_named_type("Query").unwrap() else {
+            panic!("Query type is not an object type")
+        };
+        assert!(query_type.field("token_changes").is_none());
+        assert!(schema.get_named_type("Token_change").is_none());
+        assert!(schema.get_named_type("_ChangeOperation_").is_none());
+
+        let schema = parse_with_changes(SCHEMA);
+
+        let field = query_field(&schema, "token_changes");
+        assert_eq!("[Token_change!]!", field.field_type.to_string());
+        let args: Vec<_> = field
+            .arguments
+            .iter()
+            .map(|arg| (arg.name.as_str(), arg.value_type.to_string()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("fromBlock", "Int!".to_string()),
+                ("toBlock", "Int".to_string()),
+                ("first", "Int".to_string()),
+                ("after", "String".to_string()),
+                ("subgraphError", "_SubgraphErrorPolicy_!".to_string()),
+            ],
+            args
+        );
+        query_field(&schema, "transfer_changes");
+
+        let Some(s::TypeDefinition::Object(change)) = schema.get_named_type("Token_change") else {
+            panic!("Schema should contain an object type named `Token_change`")
+        };
+        let fields: Vec<_> = change
+            .fields
+            .iter()
+            .map(|f| (f.name.as_str(), f.field_type.to_string()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("_operation", "_ChangeOperation_!".to_string()),
+                ("_block", "Int!".to_string()),
+                ("_cursor", "String!".to_string()),
+                ("id", "Bytes!".to_string()),
+                ("name", "String".to_string()),
+                ("owner", "String".to_string()),
+            ],
+            fields
+        );
+
+        let Some(s::TypeDefinition::Enum(operation)) = schema.get_named_type("_ChangeOperation_")
+        else {
+            panic!("Schema should contain an enum named `_ChangeOperation_`")
+        };
+        let values: Vec<_> = operation.values.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(vec!["INSERT", "UPDATE", "DELETE"], values);
+    }
 }
@@ -32,6 +32,13 @@ pub enum FilterOp {
     EndsWithNoCase,
     NotEndsWith,
     NotEndsWithNoCase,
+    EqualNoCase,
+    InNoCase,
+    Matches,
+    Overlaps,
+    ContainedBy,
+    LengthGreaterThan,
+    LengthLessThan,
     Equal,
     Child,
     And,
@@ -42,6 +49,8 @@ pub enum FilterOp {
 pub fn parse_field_as_filter(key: &str) -> (String, FilterOp) {
     let (suffix, op) = match key {
         k if k.ends_with("_not") => ("_not", FilterOp::Not),
+        k if k.ends_with("_length_gt") => ("_length_gt", FilterOp::LengthGreaterThan),
+        k if k.ends_with("_length_lt") => ("_length_lt", FilterOp::LengthLessThan),
         k if k.ends_with("_gt") => ("_gt", FilterOp::GreaterThan),
         k if k.ends_with("_lt") => ("_lt", FilterOp::LessThan),
         k if k.ends_with("_gte") => ("_gte", FilterOp::GreaterOrEqual),
@@ -68,6 +77,11 @@ pub fn parse_field_as_filter(key: &str) -> (String, FilterOp) {
         }
         k if k.ends_with("_ends_with") => ("_ends_with", FilterOp::EndsWith),
         k if k.ends_with("_ends_with_nocase") => ("_ends_with_nocase", FilterOp::EndsWithNoCase),
+        k if k.ends_with("_eq_nocase") => ("_eq_nocase", FilterOp::EqualNoCase),
+        k if k.ends_with("_in_nocase") => ("_in_nocase", FilterOp::InNoCase),
+        k if k.ends_with("_matches") => ("_matches", FilterOp::Matches),
+        k if k.ends_with("_overlaps") => ("_overlaps", FilterOp::Overlaps),
+        k if k.ends_with("_contained_by") => ("_contained_by", FilterOp::ContainedBy),
         k if k.ends_with('_') => ("_", FilterOp::Child),
         k if k.eq("and") => ("and", FilterOp::And),
         k if k.eq("or") => ("or", FilterOp::Or),
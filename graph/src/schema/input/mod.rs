@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use std::collections::{BTreeMap, BTreeSet};
 use std::ops::Range;
 use std::str::FromStr;
@@ -5,6 +6,7 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{anyhow, Error};
+use chrono::{DateTime, Datelike, NaiveDate};
 use semver::Version;
 use store::Entity;
 
@@ -51,6 +53,10 @@ pub mod kw {
     pub const INTERVALS: &str = "intervals";
     pub const INTERVAL: &str = "interval";
     pub const CUMULATIVE: &str = "cumulative";
+    pub const INDEX: &str = "index";
+    pub const FIELDS: &str = "fields";
+    pub const WHERE: &str = "where";
+    pub const USING: &str = "using";
 }
 
 /// The internal representation of a subgraph schema, i.e., the
@@ -415,6 +421,8 @@ pub struct ObjectType {
     /// is part of an aggregation
     aggregation: Option<Atom>,
     pub timeseries: bool,
+    /// The indexes declared with `@index` directives on this type
+    pub indexes: Box<[IndexDefinition]>,
     interfaces: Box<[Word]>,
     shared_interfaces: Box<[Atom]>,
 }
@@ -454,6 +462,12 @@ impl ObjectType {
             None => timeseries,
             _ => unreachable!("validations ensure we don't get here"),
         };
+        let indexes = object_type
+            .directives
+            .iter()
+            .filter(|dir| dir.name == kw::INDEX)
+            .map(IndexDefinition::new)
+            .collect();
         Self {
             name,
             fields,
@@ -461,6 +475,7 @@ impl ObjectType {
             immutable,
             aggregation: None,
             timeseries,
+            indexes,
             interfaces,
             shared_interfaces,
         }
@@ -492,6 +507,7 @@ impl ObjectType {
             immutable: false,
             aggregation: None,
             timeseries: false,
+            indexes: Box::new([]),
             fields,
             shared_interfaces: Box::new([]),
         }
@@ -507,6 +523,80 @@ impl ObjectType {
     }
 }
 
+/// The index methods that can be used in an `@index` directive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndexMethod {
+    BTree,
+    Brin,
+    Gin,
+    Gist,
+}
+
+impl IndexMethod {
+    fn parse(method: &str) -> Option<Self> {
+        match method {
+            "btree" => Some(IndexMethod::BTree),
+            "brin" => Some(IndexMethod::Brin),
+            "gin" => Some(IndexMethod::Gin),
+            "gist" => Some(IndexMethod::Gist),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IndexMethod::BTree => "btree",
+            IndexMethod::Brin => "brin",
+            IndexMethod::Gin => "gin",
+            IndexMethod::Gist => "gist",
+        }
+    }
+}
+
+/// An index declared on an entity type with a directive like
+/// `@index(fields: ["pool", "timestamp"], where: "amount > 0", using: brin)`
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexDefinition {
+    /// The fields that are indexed, in the order in which they are indexed
+    pub fields: Box<[Word]>,
+    /// A SQL expression over the fields of the entity type that restricts
+    /// the index to the entities for which it is true
+    pub filter: Option<String>,
+    pub method: IndexMethod,
+}
+
+impl IndexDefinition {
+    fn new(dir: &s::Directive) -> Self {
+        let fields = match dir.argument(kw::FIELDS) {
+            Some(Value::List(fields)) => fields
+                .iter()
+                .map(|field| match field {
+                    Value::String(field) => Word::from(field.as_str()),
+                    _ => unreachable!("validations ensure we don't get here"),
+                })
+                .collect(),
+            _ => unreachable!("validations ensure we don't get here"),
+        };
+        let filter = match dir.argument(kw::WHERE) {
+            Some(Value::String(filter)) => Some(filter.clone()),
+            None => None,
+            _ => unreachable!("validations ensure we don't get here"),
+        };
+        let method = match dir.argument(kw::USING) {
+            Some(Value::Enum(method)) | Some(Value::String(method)) => {
+                IndexMethod::parse(method).expect("validations ensure we don't get here")
+            }
+            None => IndexMethod::BTree,
+            _ => unreachable!("validations ensure we don't get here"),
+        };
+        IndexDefinition {
+            fields,
+            filter,
+            method,
+        }
+    }
+}
+
 #[derive(PartialEq, Debug)]
 pub struct InterfaceType {
     pub name: Atom,
@@ -612,6 +702,14 @@ pub enum AggregateFn {
     Count,
     First,
     Last,
+    Avg,
+    /// The sample variance
+    Variance,
+    /// The sample standard deviation
+    Stddev,
+    /// The given percentile, between 1 and 99, of the values, e.g., `p95`
+    Percentile(u8),
+    CountDistinct,
 }
 
 impl FromStr for AggregateFn {
@@ -625,7 +723,17 @@ impl FromStr for AggregateFn {
             "count" => Ok(AggregateFn::Count),
             "first" => Ok(AggregateFn::First),
             "last" => Ok(AggregateFn::Last),
-            _ => Err(anyhow!("invalid aggregate function `{}`", s)),
+            "avg" => Ok(AggregateFn::Avg),
+            "variance" => Ok(AggregateFn::Variance),
+            "stddev" => Ok(AggregateFn::Stddev),
+            "countDistinct" => Ok(AggregateFn::CountDistinct),
+            _ => s
+                .strip_prefix('p')
+                .filter(|p| p.len() == 2 && p.chars().all(|c| c.is_ascii_digit()))
+                .and_then(|p| p.parse::<u8>().ok())
+                .filter(|p| *p > 0)
+                .map(AggregateFn::Percentile)
+                .ok_or_else(|| anyhow!("invalid aggregate function `{}`", s)),
         }
     }
 }
@@ -634,46 +742,254 @@ impl AggregateFn {
     pub fn has_arg(&self) -> bool {
         use AggregateFn::*;
         match self {
-            Sum | Max | Min | First | Last => true,
+            Sum | Max | Min | First | Last | Avg | Variance | Stddev | Percentile(_)
+            | CountDistinct => true,
             Count => false,
         }
     }
 
-    fn as_str(&self) -> &'static str {
+    /// Whether this function can be used for cumulative aggregates.
+    /// Percentiles and distinct counts can not be computed from a fixed
+    /// amount of state for the previous bucket
+    pub fn can_be_cumulative(&self) -> bool {
         use AggregateFn::*;
         match self {
-            Sum => "sum",
-            Max => "max",
-            Min => "min",
-            Count => "count",
-            First => "first",
-            Last => "last",
+            Sum | Max | Min | Count | First | Last | Avg | Variance | Stddev => true,
+            Percentile(_) | CountDistinct => false,
+        }
+    }
+
+    /// Whether the aggregate always produces fractional values, even if
+    /// its argument is an integer
+    pub fn is_fractional(&self) -> bool {
+        use AggregateFn::*;
+        match self {
+            Avg | Variance | Stddev => true,
+            Sum | Max | Min | Count | First | Last | Percentile(_) | CountDistinct => false,
+        }
+    }
+
+    /// The state needed to combine cumulative aggregates with this
+    /// function; see `Aggregate::state_fields`
+    pub fn state(&self) -> &'static [AggregateState] {
+        use AggregateFn::*;
+        use AggregateState as S;
+        match self {
+            Avg => &[S::Count, S::Sum],
+            Variance | Stddev => &[S::Count, S::Sum, S::SumOfSquares],
+            Sum | Max | Min | Count | First | Last | Percentile(_) | CountDistinct => &[],
+        }
+    }
+
+    fn as_str(&self) -> Cow<'static, str> {
+        use AggregateFn::*;
+        match self {
+            Sum => "sum".into(),
+            Max => "max".into(),
+            Min => "min".into(),
+            Count => "count".into(),
+            First => "first".into(),
+            Last => "last".into(),
+            Avg => "avg".into(),
+            Variance => "variance".into(),
+            Stddev => "stddev".into(),
+            Percentile(p) => format!("p{:02}", p).into(),
+            CountDistinct => "countDistinct".into(),
         }
     }
 }
 
-/// The supported intervals for timeseries in order of decreasing
-/// granularity. The intervals must all be divisible by the smallest
-/// interval
-#[derive(Clone, Copy, PartialEq, Eq, Debug, PartialOrd, Ord, Hash)]
+#[test]
+fn parse_aggregate_fn() {
+    use AggregateFn::*;
+
+    assert_eq!(Avg, "avg".parse().unwrap());
+    assert_eq!(CountDistinct, "countDistinct".parse().unwrap());
+    assert_eq!(Percentile(5), "p05".parse().unwrap());
+    assert_eq!(Percentile(95), "p95".parse().unwrap());
+    assert_eq!("p05", Percentile(5).as_str());
+    for invalid in ["p5", "p00", "p100", "p9x", "median", "stdev"] {
+        assert!(
+            invalid.parse::<AggregateFn>().is_err(),
+            "{invalid} should not be a valid aggregate function"
+        );
+    }
+}
+
+/// The intermediate state that cumulative aggregates like `avg` keep for
+/// each bucket so that the aggregate for the next bucket can be combined
+/// exactly from the state of the previous bucket and the new values
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AggregateState {
+    /// The number of non-null values
+    Count,
+    /// The sum of the values
+    Sum,
+    /// The sum of the squares of the values
+    SumOfSquares,
+}
+
+impl AggregateState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AggregateState::Count => "count",
+            AggregateState::Sum => "sum",
+            AggregateState::SumOfSquares => "sumsq",
+        }
+    }
+
+    fn value_type(&self) -> ValueType {
+        match self {
+            AggregateState::Count => ValueType::Int8,
+            AggregateState::Sum | AggregateState::SumOfSquares => ValueType::BigDecimal,
+        }
+    }
+}
+
+/// The supported intervals for timeseries, ordered by their length.
+///
+/// Buckets for all intervals are aligned to UTC: intervals shorter than a
+/// day start at multiples of their length since the epoch, days start at
+/// midnight, weeks start on Mondays, and months start on the first day of
+/// the month. Custom intervals are given as a number of minutes and must
+/// evenly divide a day so that they line up with daily buckets.
+///
+/// Intervals should be constructed by parsing them from a string which
+/// normalizes them, e.g., `60m` becomes `hour`. Intervals are compared by
+/// their length, so that `Minutes(60)` is equal to `Hour` even if it was
+/// constructed directly
+#[derive(Clone, Copy, Debug)]
 pub enum AggregationInterval {
+    /// A custom interval of the given number of minutes like `5m` or `2h`
+    Minutes(u32),
     Hour,
     Day,
+    Week,
+    Month,
 }
 
 impl AggregationInterval {
-    pub fn as_str(&self) -> &'static str {
+    const MINUTES_PER_DAY: u32 = 24 * 60;
+    const SECS_PER_DAY: i64 = 24 * 3600;
+    /// Weeks start on Mondays; 1970-01-05 was the first Monday after the
+    /// epoch
+    const WEEK_ORIGIN: i64 = 4 * Self::SECS_PER_DAY;
+
+    fn minutes(minutes: u32) -> Result<Self, Error> {
+        match minutes {
+            60 => Ok(AggregationInterval::Hour),
+            Self::MINUTES_PER_DAY => Ok(AggregationInterval::Day),
+            0 => Err(anyhow!("aggregation intervals must not be empty")),
+            m if m > Self::MINUTES_PER_DAY || Self::MINUTES_PER_DAY % m != 0 => Err(anyhow!(
+                "the aggregation interval of {m} minutes does not evenly divide a day"
+            )),
+            m => Ok(AggregationInterval::Minutes(m)),
+        }
+    }
+
+    /// The length of each bucket and the time since the epoch at which
+    /// buckets are anchored for intervals where all buckets have the same
+    /// length. Returns `None` for calendar months
+    fn fixed_length(&self) -> Option<(Duration, i64)> {
+        use AggregationInterval::*;
         match self {
-            AggregationInterval::Hour => "hour",
-            AggregationInterval::Day => "day",
+            Minutes(m) => Some((Duration::from_secs(*m as u64 * 60), 0)),
+            Hour => Some((Duration::from_secs(3600), 0)),
+            Day => Some((Duration::from_secs(3600 * 24), 0)),
+            Week => Some((Duration::from_secs(3600 * 24 * 7), Self::WEEK_ORIGIN)),
+            Month => None,
         }
     }
 
-    pub fn as_duration(&self) -> Duration {
-        use AggregationInterval::*;
+    /// The length of buckets for this interval if all buckets have the same
+    /// length, `None` for calendar-based intervals like `month`
+    pub fn as_duration(&self) -> Option<Duration> {
+        self.fixed_length().map(|(length, _)| length)
+    }
+
+    /// The point in time, in seconds since the epoch, with respect to
+    /// which buckets of fixed length are aligned
+    pub fn origin(&self) -> i64 {
+        self.fixed_length().map(|(_, origin)| origin).unwrap_or(0)
+    }
+
+    /// An upper bound for the length of buckets, used for ordering
+    /// intervals
+    fn max_secs(&self) -> u64 {
+        match self.as_duration() {
+            Some(length) => length.as_secs(),
+            None => 31 * 24 * 3600,
+        }
+    }
+
+    /// The name of this interval as a value of the `Aggregation_interval`
+    /// enum in the API schema. Custom intervals like `5m` are not valid
+    /// GraphQL names and are prefixed with an underscore
+    pub fn as_enum_value(&self) -> String {
         match self {
-            Hour => Duration::from_secs(3600),
-            Day => Duration::from_secs(3600 * 24),
+            AggregationInterval::Minutes(_) => format!("_{}", self),
+            _ => self.to_string(),
+        }
+    }
+
+    /// Parse the value of the `interval` argument in a GraphQL query
+    pub fn from_enum_value(value: &str) -> Result<Self, Error> {
+        match value.strip_prefix('_') {
+            Some(custom) => match custom.parse::<AggregationInterval>()? {
+                interval @ AggregationInterval::Minutes(_) => Ok(interval),
+                _ => Err(anyhow!("invalid aggregation interval `{}`", value)),
+            },
+            None => value.parse(),
+        }
+    }
+
+    /// Return the start of the bucket that contains `time`
+    pub fn bucket_start(&self, time: BlockTime) -> BlockTime {
+        // Treat any time before the epoch as the epoch; in practice, we
+        // will only deal with block times that are pretty far after the
+        // epoch
+        let secs = time.as_secs_since_epoch().max(0);
+        match self.fixed_length() {
+            Some((length, origin)) => {
+                let length = length.as_secs() as i64;
+                let start = origin + (secs - origin).div_euclid(length) * length;
+                BlockTime::since_epoch(start, 0)
+            }
+            None => {
+                let date = DateTime::from_timestamp(secs, 0)
+                    .expect("block times are valid timestamps")
+                    .date_naive();
+                month_start(date.year(), date.month())
+            }
+        }
+    }
+
+    /// Return the start of the bucket that follows the bucket starting at
+    /// `start`. `start` must be the start of a bucket
+    fn next_bucket_start(&self, start: BlockTime) -> BlockTime {
+        let secs = start.as_secs_since_epoch();
+        match self.fixed_length() {
+            Some((length, _)) => BlockTime::since_epoch(secs + length.as_secs() as i64, 0),
+            None => {
+                let date = DateTime::from_timestamp(secs, 0)
+                    .expect("block times are valid timestamps")
+                    .date_naive();
+                match date.month() {
+                    12 => month_start(date.year() + 1, 1),
+                    month => month_start(date.year(), month + 1),
+                }
+            }
+        }
+    }
+
+    /// Return the time range of the first bucket that intersects
+    /// `from..to` and ends before `to`, if there is one
+    pub fn first_bucket(&self, from: BlockTime, to: BlockTime) -> Option<Range<BlockTime>> {
+        let start = self.bucket_start(from);
+        if start < self.bucket_start(to) {
+            Some(start..self.next_bucket_start(start))
+        } else {
+            None
         }
     }
 
@@ -682,22 +998,64 @@ impl AggregationInterval {
     /// that overlap `from..to` and end before `to`. The ranges are in
     /// increasing order of the start time
     pub fn buckets(&self, from: BlockTime, to: BlockTime) -> Vec<Range<BlockTime>> {
-        let first = from.bucket(self.as_duration());
-        let last = to.bucket(self.as_duration());
-        (first..last)
-            .map(|nr| self.as_duration() * nr as u32)
-            .map(|start| {
-                let lower = BlockTime::from(start);
-                let upper = BlockTime::from(start + self.as_duration());
-                lower..upper
-            })
-            .collect()
+        let last = self.bucket_start(to);
+        let mut start = self.bucket_start(from);
+        let mut buckets = Vec::new();
+        while start < last {
+            let end = self.next_bucket_start(start);
+            buckets.push(start..end);
+            start = end;
+        }
+        buckets
+    }
+}
+
+/// The block time at midnight UTC on the first day of the given month
+fn month_start(year: i32, month: u32) -> BlockTime {
+    let start = NaiveDate::from_ymd_opt(year, month, 1)
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .expect("the first of the month is a valid date")
+        .and_utc();
+    BlockTime::since_epoch(start.timestamp(), 0)
+}
+
+impl PartialEq for AggregationInterval {
+    fn eq(&self, other: &Self) -> bool {
+        self.max_secs() == other.max_secs()
+    }
+}
+
+impl Eq for AggregationInterval {}
+
+impl std::hash::Hash for AggregationInterval {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.max_secs().hash(state)
+    }
+}
+
+impl Ord for AggregationInterval {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.max_secs().cmp(&other.max_secs())
+    }
+}
+
+impl PartialOrd for AggregationInterval {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
     }
 }
 
 impl std::fmt::Display for AggregationInterval {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(self.as_str())
+        use AggregationInterval::*;
+        match self {
+            Minutes(m) if m % 60 == 0 => write!(f, "{}h", m / 60),
+            Minutes(m) => write!(f, "{}m", m),
+            Hour => f.write_str("hour"),
+            Day => f.write_str("day"),
+            Week => f.write_str("week"),
+            Month => f.write_str("month"),
+        }
     }
 }
 
@@ -726,16 +1084,125 @@ fn buckets() {
     );
     assert_eq!(vec![eight_am..nine_am], Hour.buckets(one_hour, two_hour));
     assert_eq!(Vec::<Range<BlockTime>>::new(), Day.buckets(start, two_hour));
+    assert_eq!(Some(seven_am..eight_am), Hour.first_bucket(start, two_hour));
+    assert_eq!(None, Day.first_bucket(start, two_hour));
+
+    // 15 minute buckets: 07:30Z - 07:45Z and 07:45Z - 08:00Z
+    let seven_thirty = BlockTime::since_epoch(START - 10 * 60, 0);
+    let seven_45 = BlockTime::since_epoch(START + 5 * 60, 0);
+    assert_eq!(
+        vec![seven_thirty..seven_45, seven_45..eight_am],
+        Minutes(15).buckets(start, eight_am)
+    );
+
+    // 2006-07-16 was a Sunday; its week started on Monday 2006-07-10 and
+    // the next one on 2006-07-17
+    let monday = BlockTime::since_epoch(1152489600, 0);
+    let next_monday = BlockTime::since_epoch(1153094400, 0);
+    assert_eq!(monday, Week.bucket_start(start));
+    assert_eq!(vec![monday..next_monday], Week.buckets(start, next_monday));
+    assert_eq!(None, Week.first_bucket(monday, start));
+
+    // Months follow the calendar: 2006-07-01Z, 2006-08-01Z, 2006-09-01Z
+    let july = BlockTime::since_epoch(1151712000, 0);
+    let august = BlockTime::since_epoch(1154390400, 0);
+    let september = BlockTime::since_epoch(1157068800, 0);
+    assert_eq!(july, Month.bucket_start(start));
+    assert_eq!(
+        vec![july..august, august..september],
+        Month.buckets(start, september)
+    );
+    // December rolls over into the next year: 2006-12-01Z, 2007-01-01Z
+    let december = BlockTime::since_epoch(1164931200, 0);
+    let january = BlockTime::since_epoch(1167609600, 0);
+    assert_eq!(
+        Some(december..january),
+        Month.first_bucket(december, january)
+    );
+}
+
+#[test]
+fn parse_intervals() {
+    use AggregationInterval::*;
+
+    assert_eq!(Hour, "hour".parse().unwrap());
+    assert_eq!(Week, "week".parse().unwrap());
+    assert_eq!(Month, "month".parse().unwrap());
+    assert_eq!(Minutes(5), "5m".parse().unwrap());
+    assert_eq!(Minutes(120), "2h".parse().unwrap());
+    assert_eq!(Hour, "60m".parse().unwrap());
+    assert_eq!(Day, "24h".parse().unwrap());
+    assert_eq!("2h", Minutes(120).to_string());
+    assert_eq!("_15m", Minutes(15).as_enum_value());
+    assert_eq!(
+        Minutes(15),
+        AggregationInterval::from_enum_value("_15m").unwrap()
+    );
+    assert_eq!(Day, AggregationInterval::from_enum_value("day").unwrap());
+
+    for invalid in [
+        "fortnight",
+        "0m",
+        "7m",
+        "5h",
+        "48h",
+        "m",
+        "-5m",
+        "5s",
+        "_day",
+    ] {
+        assert!(
+            invalid.parse::<AggregationInterval>().is_err(),
+            "{invalid} should not be a valid interval"
+        );
+    }
+    assert!(AggregationInterval::from_enum_value("_day").is_err());
+
+    let mut intervals = vec![Month, Day, Minutes(5), Week, Hour, Minutes(120)];
+    intervals.sort();
+    assert_eq!(
+        vec![Minutes(5), Hour, Minutes(120), Day, Week, Month],
+        intervals
+    );
+
+    // Equality agrees with the order even for intervals that were not
+    // normalized when they were constructed
+    assert_eq!(Hour, Minutes(60));
+    assert_eq!(std::cmp::Ordering::Equal, Minutes(1440).cmp(&Day));
+    let mut intervals = vec![Hour, Minutes(60), Minutes(5)];
+    intervals.sort();
+    intervals.dedup();
+    assert_eq!(vec![Minutes(5), Hour], intervals);
+    let set: std::collections::HashSet<_> = [Hour, Minutes(60)].into_iter().collect();
+    assert_eq!(1, set.len());
 }
 
 impl FromStr for AggregationInterval {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow!("invalid aggregation interval `{}`", s);
+
         match s {
             "hour" => Ok(AggregationInterval::Hour),
             "day" => Ok(AggregationInterval::Day),
-            _ => Err(anyhow!("invalid aggregation interval `{}`", s)),
+            "week" => Ok(AggregationInterval::Week),
+            "month" => Ok(AggregationInterval::Month),
+            _ => {
+                let (count, minutes) = if let Some(count) = s.strip_suffix('m') {
+                    (count, 1)
+                } else if let Some(count) = s.strip_suffix('h') {
+                    (count, 60)
+                } else {
+                    return Err(invalid());
+                };
+                if count.is_empty() || !count.chars().all(|c| c.is_ascii_digit()) {
+                    return Err(invalid());
+                }
+                let count: u32 = count.parse().map_err(|_| invalid())?;
+                let minutes = count.checked_mul(minutes).ok_or_else(invalid)?;
+                Self::minutes(minutes)
+            }
         }
     }
 }
@@ -832,6 +1299,53 @@ impl Aggregate {
             derived_from: None,
         }
     }
+
+    /// The name of the field that stores `state` for the aggregate
+    /// `name`. Since the name contains a `$`, it can not clash with any
+    /// field that users define
+    pub fn state_field_name(name: &str, state: AggregateState) -> String {
+        format!("{name}${}", state.as_str())
+    }
+
+    /// The state that this aggregate needs to keep; only cumulative
+    /// aggregates like `avg` that can not be combined from the values of
+    /// the aggregate alone need state
+    pub fn state(&self) -> &'static [AggregateState] {
+        if self.cumulative {
+            self.func.state()
+        } else {
+            &[]
+        }
+    }
+
+    /// Whether the values of this aggregate for several buckets can be
+    /// combined into one value for all of them at query time. Cumulative
+    /// aggregates can always be combined since the value of the latest
+    /// bucket already covers all earlier buckets
+    pub fn can_roll_up(&self) -> bool {
+        use AggregateFn::*;
+
+        self.cumulative || matches!(self.func, Sum | Max | Min | Count | First | Last)
+    }
+
+    /// The fields that hold the state of this aggregate in the tables for
+    /// the aggregation. These fields are not visible in the API schema
+    pub fn state_fields(&self) -> Vec<Field> {
+        self.state()
+            .iter()
+            .map(|state| {
+                let value_type = state.value_type();
+                Field {
+                    name: Word::from(Self::state_field_name(&self.name, *state)),
+                    field_type: s::Type::NonNullType(Box::new(s::Type::NamedType(
+                        value_type.to_str().to_string(),
+                    ))),
+                    value_type,
+                    derived_from: None,
+                }
+            })
+            .collect()
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -877,7 +1391,7 @@ impl Aggregation {
         let obj_types = intervals
             .iter()
             .map(|interval| {
-                let name = format!("{}_{}", &agg_type.name, interval.as_str());
+                let name = format!("{}_{}", &agg_type.name, interval);
                 let name = pool.lookup(&name).unwrap();
                 ObjectType {
                     name,
@@ -886,10 +1400,12 @@ impl Aggregation {
                         .iter()
                         .cloned()
                         .chain(aggregates.iter().map(Aggregate::as_agg_field))
+                        .chain(aggregates.iter().flat_map(Aggregate::state_fields))
                         .collect(),
                     immutable: true,
                     aggregation: Some(name),
                     timeseries: false,
+                    indexes: Box::new([]),
                     interfaces: Box::new([]),
                     shared_interfaces: Box::new([]),
                 }
@@ -1626,13 +2142,29 @@ fn atom_pool(document: &s::Document) -> AtomPool {
                         .and_then(|dir| dir.argument(kw::INTERVALS))
                         .and_then(Value::as_list)
                         .unwrap_or(&NO_VALUE);
+                    // Intern the normalized name of the interval since
+                    // that is what we use when we construct the types
                     for interval in intervals {
-                        if let Some(interval) = interval.as_str() {
+                        if let Some(interval) = interval
+                            .as_str()
+                            .and_then(|interval| interval.parse::<AggregationInterval>().ok())
+                        {
                             pool.intern(&format!("{}_{}", t.name, interval));
                         }
                     }
                     for field in &t.fields {
                         pool.intern(&field.name);
+
+                        // Intern the names of the fields that hold the
+                        // state of cumulative aggregates
+                        let func = field
+                            .find_directive(kw::AGGREGATE)
+                            .and_then(|dir| dir.argument(kw::FUNC))
+                            .and_then(Value::as_str)
+                            .and_then(|func| func.parse::<AggregateFn>().ok());
+                        for state in func.iter().flat_map(AggregateFn::state) {
+                            pool.intern(&Aggregate::state_field_name(&field.name, *state));
+                        }
                     }
                 }
                 s::TypeDefinition::Enum(t) => {
@@ -1694,7 +2226,7 @@ mod validations {
         },
         prelude::s,
         schema::{
-            input::{kw, sqlexpr, AggregateFn, AggregationInterval},
+            input::{kw, sqlexpr, AggregateFn, AggregationInterval, IndexMethod},
             FulltextAlgorithm, FulltextLanguage, Schema as BaseSchema, SchemaValidationError,
             SchemaValidationError as Err, Strings, SCHEMA_TYPE_NAME,
         },
@@ -1732,6 +2264,7 @@ mod validations {
         .collect();
 
         errors.append(&mut schema.validate_entity_directives());
+        errors.append(&mut schema.validate_index_directives());
         errors.append(&mut schema.validate_entity_type_ids());
         errors.append(&mut schema.validate_fields());
         errors.append(&mut schema.validate_fulltext_directives());
@@ -2053,6 +2586,116 @@ mod validations {
                 .collect()
         }
 
+        /// The `@index` directive on entity types needs a non-empty list
+        /// of `fields` that are stored in the database, and can have an
+        /// index method in `using` and an expression over the type's
+        /// fields in `where`. It can be used several times on a type
+        fn validate_index_directives(&self) -> Vec<SchemaValidationError> {
+            fn validate_index(
+                object_type: &s::ObjectType,
+                dir: &s::Directive,
+            ) -> Vec<SchemaValidationError> {
+                let name = &object_type.name;
+                let stored_field = |field: &str| {
+                    object_type
+                        .field(field)
+                        .filter(|field| field.derived_from().is_none())
+                };
+                let mut errors = Vec::new();
+
+                for (arg, _) in &dir.arguments {
+                    if ![kw::FIELDS, kw::WHERE, kw::USING].contains(&arg.as_str()) {
+                        errors.push(Err::IndexUnknownArg(name.to_owned(), arg.to_owned()));
+                    }
+                }
+
+                let method = match dir.argument(kw::USING) {
+                    None => Some(IndexMethod::BTree),
+                    Some(s::Value::Enum(method)) | Some(s::Value::String(method)) => {
+                        let parsed = IndexMethod::parse(method);
+                        if parsed.is_none() {
+                            errors
+                                .push(Err::IndexInvalidMethod(name.to_owned(), method.to_owned()));
+                        }
+                        parsed
+                    }
+                    Some(method) => {
+                        errors.push(Err::IndexInvalidMethod(name.to_owned(), method.to_string()));
+                        None
+                    }
+                };
+
+                match dir.argument(kw::FIELDS) {
+                    Some(s::Value::List(fields)) if !fields.is_empty() => {
+                        for field in fields {
+                            let s::Value::String(field) = field else {
+                                errors.push(Err::IndexInvalidFields(name.to_owned()));
+                                continue;
+                            };
+                            match stored_field(field) {
+                                Some(field) => {
+                                    if method == Some(IndexMethod::Gin)
+                                        && !field.field_type.is_list()
+                                    {
+                                        errors.push(Err::IndexGinNonList(
+                                            name.to_owned(),
+                                            field.name.to_owned(),
+                                        ));
+                                    }
+                                }
+                                None => errors.push(Err::IndexUnknownField(
+                                    name.to_owned(),
+                                    field.to_owned(),
+                                )),
+                            }
+                        }
+                    }
+                    _ => errors.push(Err::IndexInvalidFields(name.to_owned())),
+                }
+
+                match dir.argument(kw::WHERE) {
+                    None => { /* not a partial index */ }
+                    Some(s::Value::String(filter)) => {
+                        let check_ident = |ident: &str| match stored_field(ident) {
+                            Some(_) => Ok(()),
+                            None => Err(Err::IndexUnknownField(name.to_owned(), ident.to_owned())),
+                        };
+                        let check_timestamp = |ident: &str| match stored_field(ident) {
+                            Some(field)
+                                if matches!(
+                                    field.field_type.value_type(),
+                                    Ok(ValueType::Timestamp)
+                                ) =>
+                            {
+                                Ok(())
+                            }
+                            Some(_) => {
+                                Err(Err::IndexNonTimestampArg(name.to_owned(), ident.to_owned()))
+                            }
+                            None => Err(Err::IndexUnknownField(name.to_owned(), ident.to_owned())),
+                        };
+                        if let Err(mut errs) = sqlexpr::parse(filter, check_ident, check_timestamp)
+                        {
+                            errors.append(&mut errs);
+                        }
+                    }
+                    Some(_) => errors.push(Err::IndexInvalidWhere(name.to_owned())),
+                }
+                errors
+            }
+
+            self.entity_types
+                .iter()
+                .flat_map(|object_type| {
+                    object_type
+                        .directives
+                        .iter()
+                        .filter(|dir| dir.name == kw::INDEX)
+                        .flat_map(|dir| validate_index(object_type, dir))
+                })
+                .collect()
+        }
+
         /// 1. All object types besides `_Schema_` must have an id field
         /// 2. The id field must be recognized by IdType
         fn validate_entity_type_ids(&self) -> Vec<SchemaValidationError> {
@@ -2104,11 +2747,17 @@ mod validations {
                 .map(|type_name| format!("{}_filter", type_name))
                 .collect();
 
-            // TYPE_NAME_orderBy types for all object and interface types
+            // TYPE_NAME_orderBy and TYPE_NAME_orderByField types for all
+            // object and interface types
             let mut order_by_types: Vec<_> = object_types
                 .iter()
                 .chain(interface_types.iter())
-                .map(|type_name| format!("{}_orderBy", type_name))
+                .flat_map(|type_name| {
+                    [
+                        format!("{}_orderBy", type_name),
+                        format!("{}_orderByField", type_name),
+                    ]
+                })
                 .collect();
 
             let mut reserved_types: Vec<String> = vec![
@@ -2492,7 +3141,7 @@ mod validations {
                                         errors.push(Err::AggregationMissingArg(
                                             agg_type.name.to_owned(),
                                             field.name.to_owned(),
-                                            func.as_str().to_owned(),
+                                            func.as_str().to_string(),
                                         ));
                                         continue;
                                     } else {
@@ -2503,6 +3152,14 @@ mod validations {
                                 }
                             };
                             match agg.argument(kw::CUMULATIVE) {
+                                Some(s::Value::Boolean(true)) if !func.can_be_cumulative() => {
+                                    errors.push(Err::AggregationCumulativeNotSupported(
+                                        agg_type.name.to_owned(),
+                                        field.name.to_owned(),
+                                        func.as_str().to_string(),
+                                    ));
+                                    continue;
+                                }
                                 Some(s::Value::Boolean(_)) | None => { /* ok */ }
                                 Some(_) => {
                                     errors.push(Err::AggregationInvalidCumulative(
@@ -2522,6 +3179,14 @@ mod validations {
                                     continue;
                                 }
                             };
+                            if func.is_fractional() && field_type != ValueType::BigDecimal {
+                                errors.push(Err::AggregationNonDecimalType(
+                                    agg_type.name.to_owned(),
+                                    field.name.to_owned(),
+                                    func.as_str().to_string(),
+                                ));
+                                continue;
+                            }
                             // It would be nicer to use a proper struct here
                             // and have that implement
                             // `sqlexpr::ExprVisitor` but we need access to
@@ -2529,6 +3194,19 @@ mod validations {
                             // setting up that struct a bit awkward, so we
                             // use a closure instead
                             let check_ident = |ident: &str| -> Result<(), SchemaValidationError> {
+                                if func == AggregateFn::CountDistinct {
+                                    // Distinct counts can be taken over
+                                    // fields of any type, and the count
+                                    // fits into any numeric type
+                                    return match source.field(ident) {
+                                        Some(_) => Ok(()),
+                                        None => Err(Err::AggregationUnknownArg(
+                                            agg_type.name.to_owned(),
+                                            field.name.to_owned(),
+                                            arg.to_owned(),
+                                        )),
+                                    };
+                                }
                                 let arg_type = match source.field(ident) {
                                     Some(arg_field) => match arg_field.field_type.value_type() {
                                         Ok(arg_type) if arg_type.is_numeric() => arg_type,
@@ -2560,7 +3238,32 @@ mod validations {
                                 }
                                 Ok(())
                             };
-                            if let Err(mut errs) = sqlexpr::parse(arg, check_ident) {
+                            let check_timestamp =
+                                |ident: &str| -> Result<(), SchemaValidationError> {
+                                    match source.field(ident) {
+                                        Some(arg_field)
+                                            if matches!(
+                                                arg_field.field_type.value_type(),
+                                                Ok(ValueType::Timestamp)
+                                            ) =>
+                                        {
+                                            Ok(())
+                                        }
+                                        Some(_) => Err(Err::AggregationNonTimestampArg(
+                                            agg_type.name.to_owned(),
+                                            field.name.to_owned(),
+                                            source.name.to_owned(),
+                                            arg.to_owned(),
+                                        )),
+                                        None => Err(Err::AggregationUnknownArg(
+                                            agg_type.name.to_owned(),
+                                            field.name.to_owned(),
+                                            arg.to_owned(),
+                                        )),
+                                    }
+                                };
+                            if let Err(mut errs) = sqlexpr::parse(arg, check_ident, check_timestamp)
+                            {
                                 errors.append(&mut errs);
                             }
                         }
@@ -12,13 +12,17 @@ impl<T> CheckIdentFn for T where T: Fn(&str) -> Result<(), SchemaValidationError
 
 /// Parse a SQL expression and check that it only uses whitelisted
 /// operations and functions. The `check_ident` function is called for each
-/// identifier in the expression
-pub(crate) fn parse<F: CheckIdentFn>(
+/// identifier in the expression, except for identifiers that are used as
+/// the source of `extract(<field> from <ident>)`; those are checked with
+/// `check_timestamp`
+pub(crate) fn parse<F: CheckIdentFn, G: CheckIdentFn>(
     sql: &str,
     check_ident: F,
+    check_timestamp: G,
 ) -> Result<(), Vec<SchemaValidationError>> {
     let mut validator = Validator {
         check_ident,
+        check_timestamp,
         errors: Vec::new(),
     };
     VisitExpr::visit(sql, &mut validator)
@@ -35,6 +39,10 @@ pub trait ExprVisitor {
     /// Visit an identifier (column name). Must return `Err` if the
     /// identifier is not allowed
     fn visit_ident(&mut self, ident: &mut p::Ident) -> Result<(), ()>;
+    /// Visit an identifier that is used in `extract(<field> from <ident>)`
+    /// and must therefore refer to a timestamp. Must return `Err` if the
+    /// identifier is not allowed
+    fn visit_timestamp_ident(&mut self, ident: &mut p::Ident) -> Result<(), ()>;
     /// Visit a function name. Must return `Err` if the function is not
     /// allowed
     fn visit_func_name(&mut self, func: &mut p::Ident) -> Result<(), ()>;
@@ -150,15 +158,63 @@ impl<'a> VisitExpr<'a> {
                 self.visit_expr(expr2)?;
                 Ok(())
             }
+            InList {
+                expr,
+                list,
+                negated: _,
+            } => {
+                self.visit_expr(expr)?;
+                for item in list {
+                    self.visit_expr(item)?;
+                }
+                Ok(())
+            }
+            Between {
+                expr,
+                negated: _,
+                low,
+                high,
+            } => {
+                self.visit_expr(expr)?;
+                self.visit_expr(low)?;
+                self.visit_expr(high)?;
+                Ok(())
+            }
+            // `floor(<expr>)` and `ceil(<expr>)` are parsed into these
+            // rather than into function calls; rounding to a date/time
+            // field is not Postgres syntax
+            Ceil { expr, field } | Floor { expr, field } => match field {
+                p::DateTimeField::NoDateTime => self.visit_expr(expr),
+                _ => self.nope(&format!("rounding to {field}")),
+            },
+            Extract { field, expr } => {
+                let name = field.to_string().to_lowercase();
+                if !EXTRACT_FIELDS.contains(&name.as_str()) {
+                    return self.nope(&format!("extracting {field}"));
+                }
+                match expr.as_mut() {
+                    Identifier(ident) => self.visitor.visit_timestamp_ident(ident)?,
+                    _ => return self.nope("extract from anything but an attribute"),
+                }
+                // The result of `extract` from a `timestamptz` depends on
+                // the session time zone; force it to UTC so the expression
+                // is deterministic
+                let ts = std::mem::replace(expr.as_mut(), p::Expr::Value(p::Value::Null));
+                **expr = p::Expr::AtTimeZone {
+                    timestamp: Box::new(ts),
+                    time_zone: Box::new(p::Expr::Value(p::Value::SingleQuotedString(
+                        "UTC".to_string(),
+                    ))),
+                };
+                Ok(())
+            }
             CompoundIdentifier(_) => self.nope("CompoundIdentifier"),
             JsonAccess { .. } => self.nope("JsonAccess"),
             CompositeAccess { .. } => self.nope("CompositeAccess"),
             IsUnknown(_) => self.nope("IsUnknown"),
             IsNotUnknown(_) => self.nope("IsNotUnknown"),
-            InList { .. } => self.nope("InList"),
             InSubquery { .. } => self.nope("InSubquery"),
             InUnnest { .. } => self.nope("InUnnest"),
-            Between { .. } => self.nope("Between"),
             Like { .. } => self.nope("Like"),
             ILike { .. } => self.nope("ILike"),
             SimilarTo { .. } => self.nope("SimilarTo"),
@@ -167,9 +223,6 @@ impl<'a> VisitExpr<'a> {
             AllOp { .. } => self.nope("AllOp"),
             Convert { .. } => self.nope("Convert"),
             AtTimeZone { .. } => self.nope("AtTimeZone"),
-            Extract { .. } => self.nope("Extract"),
-            Ceil { .. } => self.nope("Ceil"),
-            Floor { .. } => self.nope("Floor"),
             Position { .. } => self.nope("Position"),
             Substring { .. } => self.nope("Substring"),
             Trim { .. } => self.nope("Trim"),
@@ -319,23 +372,54 @@ impl<'a> VisitExpr<'a> {
 }
 
 /// An `ExprVisitor` that validates an expression
-struct Validator<F> {
+struct Validator<F, G> {
     check_ident: F,
+    check_timestamp: G,
     errors: Vec<SchemaValidationError>,
 }
 
-const FN_WHITELIST: [&'static str; 14] = [
+const FN_WHITELIST: [&'static str; 19] = [
     // Clearly deterministic functions from
     // https://www.postgresql.org/docs/current/functions-math.html, Table
     // 9.5. We could also add trig functions (Table 9.7 and 9.8), but under
-    // no circumstances random functions from Table 9.6
-    "abs", "ceil", "ceiling", "div", "floor", "gcd", "lcm", "mod", "power", "sign",
+    // no circumstances random functions from Table 9.6. Functions like
+    // `sqrt`, `exp`, and the logarithms raise an error for arguments
+    // outside their domain, which would make the subgraph fail when the
+    // rollup runs, and are therefore not allowed
+    "abs",
+    "ceil",
+    "ceiling",
+    "div",
+    "floor",
+    "gcd",
+    "lcm",
+    "mod",
+    "power",
+    "round",
+    "sign",
+    "trunc",
+    "width_bucket",
     // Conditional functions from
     // https://www.postgresql.org/docs/current/functions-conditional.html.
-    "coalesce", "nullif", "greatest", "least",
+    "coalesce",
+    "nullif",
+    "greatest",
+    "least",
+    // Comparison functions from
+    // https://www.postgresql.org/docs/current/functions-comparison.html,
+    // Table 9.3
+    "num_nonnulls",
+    "num_nulls",
 ];
 
-impl<F: CheckIdentFn> ExprVisitor for Validator<F> {
+/// The fields that can be used in `extract(<field> from <expr>)`. All of
+/// them are computed in UTC
+const EXTRACT_FIELDS: [&'static str; 12] = [
+    "year", "quarter", "month", "week", "day", "dow", "isodow", "doy", "hour", "minute", "epoch",
+    "isoyear",
+];
+
+impl<F: CheckIdentFn, G: CheckIdentFn> ExprVisitor for Validator<F, G> {
     fn visit_ident(&mut self, ident: &mut p::Ident) -> Result<(), ()> {
         match (self.check_ident)(&ident.value) {
             Ok(()) => Ok(()),
@@ -346,6 +430,16 @@ impl<F: CheckIdentFn> ExprVisitor for Validator<F> {
         }
     }
 
+    fn visit_timestamp_ident(&mut self, ident: &mut p::Ident) -> Result<(), ()> {
+        match (self.check_timestamp)(&ident.value) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                self.errors.push(e);
+                Err(())
+            }
+        }
+    }
+
     fn visit_func_name(&mut self, func: &mut p::Ident) -> Result<(), ()> {
         let p::Ident { value, quote_style } = &func;
         let whitelisted = match quote_style {
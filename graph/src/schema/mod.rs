@@ -25,15 +25,16 @@ pub(crate) mod input;
 
 pub use api::{is_introspection_field, APISchemaError, INTROSPECTION_QUERY_TYPE};
 
-pub use api::{ApiSchema, ErrorPolicy};
+pub use api::{federated_name, federated_schema, ApiSchema, ErrorPolicy};
 pub use entity_key::EntityKey;
 pub use entity_type::{AsEntityTypeName, EntityType};
 pub use fulltext::{FulltextAlgorithm, FulltextConfig, FulltextDefinition, FulltextLanguage};
 pub use input::sqlexpr::{ExprVisitor, VisitExpr};
 pub(crate) use input::POI_OBJECT;
 pub use input::{
-    kw, Aggregate, AggregateFn, Aggregation, AggregationInterval, AggregationMapping, Field,
-    InputSchema, InterfaceType, ObjectOrInterface, ObjectType, TypeKind,
+    kw, Aggregate, AggregateFn, AggregateState, Aggregation, AggregationInterval,
+    AggregationMapping, Field, IndexDefinition, IndexMethod, InputSchema, InterfaceType,
+    ObjectOrInterface, ObjectType, TypeKind,
 };
 
 pub const SCHEMA_TYPE_NAME: &str = "_Schema_";
@@ -46,6 +47,28 @@ pub const INTROSPECTION_TYPE_FIELD_NAME: &str = "__type";
 
 pub const BLOCK_FIELD_TYPE: &str = "_Block_";
 
+/// The suffix for the `<entities>_aggregate` query fields and their
+/// `<Entity>_aggregate` result types
+pub const AGGREGATE_SUFFIX: &str = "_aggregate";
+
+/// The suffix for the `<entity>_history` query fields
+pub const HISTORY_SUFFIX: &str = "_history";
+
+/// The suffix for the `<Entity>_version` types that `<entity>_history`
+/// fields return
+pub const VERSION_SUFFIX: &str = "_version";
+
+/// The suffix for the `<entity>_changes` query fields
+pub const CHANGES_SUFFIX: &str = "_changes";
+
+/// The suffix for the `<Entity>_change` types that `<entity>_changes`
+/// fields return
+pub const CHANGE_SUFFIX: &str = "_change";
+
+/// The field on entity types that holds the cursor for an entity's
+/// position in the collection it was queried from
+pub const CURSOR_FIELD: &str = "_cursor";
+
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Strings(Vec<String>);
 
@@ -135,7 +158,7 @@ pub enum SchemaValidationError {
         "Aggregation {0} has an invalid argument for `intervals`: it must be a non-empty list of strings"
     )]
     AggregationWrongIntervals(String),
-    #[error("Aggregation {0}: the interval {1} is not supported")]
+    #[error("Aggregation {0}: the interval {1} is not supported; intervals must be `hour`, `day`, `week`, `month`, or a number of minutes or hours like `5m` or `2h` that evenly divides a day")]
     AggregationInvalidInterval(String, String),
     #[error("Aggregation {0} has no @aggregate fields")]
     PointlessAggregation(String),
@@ -175,12 +198,32 @@ pub enum SchemaValidationError {
     AggregationNonMatchingArg(String, String, String, String, String),
     #[error("Field {1} in aggregation {0} has arg `{3}` but that is not a numeric field in {2}")]
     AggregationNonNumericArg(String, String, String, String),
+    #[error("Field {1} in aggregation {0} has arg `{3}` which extracts a date field from something that is not a timestamp field in {2}")]
+    AggregationNonTimestampArg(String, String, String, String),
     #[error("Field {1} in aggregation {0} has an invalid value for `cumulative`. It needs to be a boolean")]
     AggregationInvalidCumulative(String, String),
+    #[error("Field {1} in aggregation {0} uses the function {2} which can not be cumulative")]
+    AggregationCumulativeNotSupported(String, String, String),
+    #[error("Field {1} in aggregation {0} uses the function {2} which produces fractional values; the field must have type BigDecimal")]
+    AggregationNonDecimalType(String, String, String),
     #[error("Aggregations are not supported with spec version {0}; please migrate the subgraph to the latest version")]
     AggregationsNotSupported(Version),
     #[error("Using Int8 as the type for the `id` field is not supported with spec version {0}; please migrate the subgraph to the latest version")]
     IdTypeInt8NotSupported(Version),
+    #[error("Type {0} has an @index directive with the unknown argument `{1}`")]
+    IndexUnknownArg(String, String),
+    #[error("Type {0} has an @index directive with an invalid argument for `fields`: it must be a non-empty list of strings")]
+    IndexInvalidFields(String),
+    #[error("Type {0} has an @index directive that uses {1}, but the type has no such field or the field is derived")]
+    IndexUnknownField(String, String),
+    #[error("Type {0} has an @index directive with the index method `{1}`, but it must be one of `btree`, `brin`, `gin`, or `gist`")]
+    IndexInvalidMethod(String, String),
+    #[error("Type {0} has an @index directive using `gin` on the field {1}, but `gin` can only be used for list fields")]
+    IndexGinNonList(String, String),
+    #[error("Type {0} has an @index directive with an invalid argument for `where`: it must be a string")]
+    IndexInvalidWhere(String),
+    #[error("Type {0} has an @index directive whose `where` extracts a date field from {1}, which is not a timestamp field")]
+    IndexNonTimestampArg(String, String),
     #[error("{0}")]
     ExprNotSupported(String),
     #[error("Expressions can't us the function {0}")]
@@ -326,17 +326,21 @@ impl Field {
             })
             .collect();
 
-        // We need to also select the `orderBy` field if there is one
-        use EntityOrder::*;
-        let order_field = match order {
-            Ascending(name, _) | Descending(name, _) => Some(name.as_str()),
-            Default => Some(ID.as_str()),
-            ChildAscending(_) | ChildDescending(_) | Unordered => {
-                // No need to select anything for these
-                None
+        // We need to also select the `orderBy` fields if there are any
+        fn order_fields<'a>(order: &'a EntityOrder, fields: &mut Vec<&'a str>) {
+            use EntityOrder::*;
+            match order {
+                Ascending(name, _) | Descending(name, _) => fields.push(name.as_str()),
+                Default => fields.push(ID.as_str()),
+                Multi(orders) => orders.iter().for_each(|order| order_fields(order, fields)),
+                ChildAscending(_) | ChildDescending(_) | Unordered => {
+                    // No need to select anything for these
+                }
             }
-        };
-        if let Some(order_field) = order_field {
+        }
+        let mut order_field_names = Vec::new();
+        order_fields(order, &mut order_field_names);
+        for order_field in order_field_names {
             // We assume that `order` only contains valid field names
             column_names.insert(order_field.to_string());
         }
@@ -349,13 +353,15 @@ impl Field {
     pub fn aggregation_interval(&self) -> Result<Option<AggregationInterval>, QueryExecutionError> {
         self.argument_value(kw::INTERVAL)
             .map(|value| match value {
-                r::Value::Enum(interval) => interval.parse::<AggregationInterval>().map_err(|_| {
-                    QueryExecutionError::InvalidArgumentError(
-                        self.position.clone(),
-                        kw::INTERVAL.to_string(),
-                        q::Value::from(value.clone()),
-                    )
-                }),
+                r::Value::Enum(interval) => {
+                    AggregationInterval::from_enum_value(interval).map_err(|_| {
+                        QueryExecutionError::InvalidArgumentError(
+                            self.position.clone(),
+                            kw::INTERVAL.to_string(),
+                            q::Value::from(value.clone()),
+                        )
+                    })
+                }
                 _ => Err(QueryExecutionError::InvalidArgumentError(
                     self.position.clone(),
                     kw::INTERVAL.to_string(),
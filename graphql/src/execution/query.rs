@@ -5,10 +5,11 @@ use graph::schema::ApiSchema;
 use graphql_tools::validation::rules::*;
 use graphql_tools::validation::validate::{validate, ValidationPlan};
 use lazy_static::lazy_static;
+use lru_time_cache::LruCache;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::iter::FromIterator;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use std::{collections::hash_map::DefaultHasher, convert::TryFrom};
 
@@ -20,7 +21,7 @@ use graph::prelude::{
     Logger, TryFromValue, ENV_VARS,
 };
 use graph::schema::ast::{self as sast};
-use graph::schema::ErrorPolicy;
+use graph::schema::{kw, ErrorPolicy, META_FIELD_NAME};
 
 use crate::execution::ast as a;
 use crate::execution::get_field;
@@ -60,6 +61,14 @@ lazy_static! {
         });
 }
 
+lazy_static! {
+    /// Persisted queries that passed validation, keyed by deployment and
+    /// the hash of the query
+    static ref VALIDATED_QUERIES: Mutex<LruCache<(String, String), ()>> = Mutex::new(
+        LruCache::with_capacity(ENV_VARS.graphql.persisted_query_cache_size.max(1))
+    );
+}
+
 #[derive(Clone, Debug)]
 pub enum ComplexityError {
     TooDeep,
@@ -197,9 +206,22 @@ impl Query {
             "query_id" => query_id.clone()
         ));
 
-        let validation_phase_start = Instant::now();
-        validate_query(&logger, &query, schema.document(), &metrics, schema.id())?;
-        metrics.observe_query_validation(validation_phase_start.elapsed(), schema.id());
+        // Persisted queries that we validated before against this schema
+        // don't need to be validated again
+        let validated_key = query
+            .persisted_hash
+            .as_ref()
+            .map(|hash| (schema.id().to_string(), hash.clone()));
+        let validated = validated_key
+            .as_ref()
+            .map(|key| VALIDATED_QUERIES.lock().unwrap().get(key).is_some())
+            .unwrap_or(false);
+
+        if !validated {
+            let validation_phase_start = Instant::now();
+            validate_query(&logger, &query, schema.document(), &metrics, schema.id())?;
+            metrics.observe_query_validation(validation_phase_start.elapsed(), schema.id());
+        }
 
         let mut operation = None;
         let mut fragments = HashMap::new();
@@ -223,7 +245,9 @@ impl Query {
             q::OperationDefinition::SelectionSet(selection_set) => selection_set,
             q::OperationDefinition::Subscription(_) => {
                 return Err(vec![QueryExecutionError::NotSupported(
-                    "Subscriptions are not supported".to_owned(),
+                    "Subscriptions are only supported over WebSocket connections \
+                     using the `graphql-transport-ws` protocol"
+                        .to_owned(),
                 )])
             }
             q::OperationDefinition::Mutation(_) => {
@@ -251,9 +275,15 @@ impl Query {
         // really care about the resulting complexity, only that all the
         // checks that `check_complexity` performs pass successfully
         let _ = raw_query.check_complexity(max_complexity, max_depth)?;
-        raw_query.validate_fields()?;
+        if !validated {
+            raw_query.validate_fields()?;
+        }
         let selection_set = raw_query.convert()?;
 
+        if let Some(key) = validated_key.filter(|_| !validated) {
+            VALIDATED_QUERIES.lock().unwrap().insert(key, ());
+        }
+
         let query = Self {
             schema,
             selection_set: Arc::new(selection_set),
@@ -333,6 +363,110 @@ impl Query {
         Ok(bcs)
     }
 
+    /// Estimate how expensive it is to execute this query. Every entity
+    /// that the query might load costs 1. A collection field loads as many
+    /// entities as its `first` argument asks for, but never more than the
+    /// number of entities of that type that `estimates` says there are.
+    /// Introspection fields are free since they don't touch the database
+    pub fn cost(&self, estimates: &HashMap<String, u64>) -> u64 {
+        fn selection_set_cost(
+            selection_set: &a::SelectionSet,
+            estimates: &HashMap<String, u64>,
+        ) -> u64 {
+            // Each entity has exactly one of the types in the selection
+            // set, so the most expensive type determines the cost
+            selection_set
+                .interior_fields()
+                .map(|(_, fields)| {
+                    fields
+                        .filter(|field| !field.name.starts_with("__"))
+                        .map(|field| field_cost(field, estimates))
+                        .fold(0, u64::saturating_add)
+                })
+                .max()
+                .unwrap_or(0)
+        }
+
+        fn field_cost(field: &a::Field, estimates: &HashMap<String, u64>) -> u64 {
+            let per_entity = selection_set_cost(&field.selection_set, estimates).saturating_add(1);
+            match field.multiplicity {
+                ChildMultiplicity::Single => per_entity,
+                ChildMultiplicity::Many => {
+                    let first = match field.argument_value("first") {
+                        Some(r::Value::Int(first)) => u64::try_from(*first).unwrap_or(0),
+                        _ => EntityRange::FIRST as u64,
+                    };
+                    // The types in the field's selection set are all the
+                    // types the entities in the collection can have
+                    let available: Option<u64> = field
+                        .selection_set
+                        .fields()
+                        .map(|(obj_type, _)| estimates.get(obj_type.name.as_str()).copied())
+                        .sum();
+                    let entities = available.map_or(first, |available| first.min(available));
+                    entities.saturating_mul(per_entity)
+                }
+            }
+        }
+
+        selection_set_cost(&self.selection_set, estimates)
+    }
+
+    /// The names of the entity types whose entities this query returns.
+    /// Returns `None` if the query filters by nested entities, since it
+    /// then also depends on entity types that are not part of the result.
+    /// It also returns `None` if the query selects `_meta` or aggregations
+    /// since their results change without any entity changes that we could
+    /// check for: `_meta` with every block, and aggregations whenever a
+    /// rollup happens
+    pub fn entity_types(&self) -> Option<BTreeSet<String>> {
+        fn filters_by_children(value: &r::Value) -> bool {
+            match value {
+                r::Value::Object(obj) => obj.iter().any(|(key, value)| match value {
+                    r::Value::Object(_) => key.ends_with('_'),
+                    r::Value::List(values) if key == "and" || key == "or" => {
+                        values.iter().any(filters_by_children)
+                    }
+                    _ => false,
+                }),
+                _ => false,
+            }
+        }
+
+        fn collect(
+            fields: &mut dyn Iterator<Item = &a::Field>,
+            types: &mut BTreeSet<String>,
+        ) -> bool {
+            for field in fields.filter(|field| !field.name.starts_with("__")) {
+                // Only aggregations have an `interval` argument
+                if field.name == META_FIELD_NAME || field.argument_value(kw::INTERVAL).is_some() {
+                    return false;
+                }
+                if field
+                    .argument_value("where")
+                    .map_or(false, filters_by_children)
+                {
+                    return false;
+                }
+                for (obj_type, mut fields) in field.selection_set.fields() {
+                    types.insert(obj_type.name.to_string());
+                    if !collect(&mut fields, types) {
+                        return false;
+                    }
+                }
+            }
+            true
+        }
+
+        let mut types = BTreeSet::new();
+        for (_, mut fields) in self.selection_set.fields() {
+            if !collect(&mut fields, &mut types) {
+                return None;
+            }
+        }
+        Some(types)
+    }
+
     /// Log details about the overall execution of the query
     pub fn log_execution(&self, block: BlockNumber) {
         if ENV_VARS.log_gql_timing() {
@@ -823,11 +957,11 @@ impl Transform {
             if arg_value.is_some() {
                 defined_args += 1;
             }
-            match coercion::coerce_input_value(
-                arg_value.as_deref().cloned(),
-                argument_def,
-                &resolver,
-            ) {
+            let mut value = arg_value.as_deref().cloned();
+            if argument_def.name == *"orderByFields" {
+                value = value.map(coercion::expand_order_by_fields);
+            }
+            match coercion::coerce_input_value(value, argument_def, &resolver) {
                 Ok(Some(value)) => {
                     let value = if argument_def.name == *"text" {
                         r::Value::Object(Object::from_iter(vec![(Word::from(field_name), value)]))
@@ -1039,3 +1173,167 @@ impl Transform {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use graph::data::query::Query as GraphDataQuery;
+    use graph::log::discard;
+    use graph::prelude::{q, DeploymentHash, MetricsRegistry};
+    use graph::schema::InputSchema;
+
+    use crate::metrics::GraphQLMetrics;
+
+    use super::Query;
+
+    const SCHEMA: &str = r#"
+    type Musician @entity {
+        id: ID!
+        name: String!
+        bands: [Band!]!
+    }
+
+    type Band @entity {
+        id: ID!
+        name: String!
+        members: [Musician!]! @derivedFrom(field: "bands")
+    }
+
+    type Data @entity(timeseries: true) {
+        id: Int8!
+        timestamp: Timestamp!
+        value: BigDecimal!
+    }
+
+    type Stats @aggregation(source: "Data", intervals: ["hour"]) {
+        id: Int8!
+        timestamp: Timestamp!
+        sum: BigDecimal! @aggregate(fn: "sum", arg: "value")
+    }
+    "#;
+
+    fn query(text: &str) -> Arc<Query> {
+        let id = DeploymentHash::new("query").unwrap();
+        let schema = InputSchema::parse_latest(SCHEMA, id).unwrap();
+        let schema = Arc::new(schema.api_schema().unwrap());
+        let document = q::parse_query(text).unwrap().into_static();
+        let metrics = Arc::new(GraphQLMetrics::make(Arc::new(MetricsRegistry::mock())));
+        Query::new(
+            &discard(),
+            schema,
+            None,
+            GraphDataQuery::new(document, None, false),
+            None,
+            u8::MAX,
+            metrics,
+        )
+        .unwrap()
+    }
+
+    /// The cost of the query `text` when the store has the given
+    /// number of entities for each entity type
+    fn cost(text: &str, estimates: &[(&str, u64)]) -> u64 {
+        let estimates: HashMap<_, _> = estimates
+            .iter()
+            .map(|(entity_type, count)| (entity_type.to_string(), *count))
+            .collect();
+        query(text).cost(&estimates)
+    }
+
+    #[test]
+    fn cost_of_collections() {
+        // Without estimates, a collection costs as many entities as it
+        // asks for, and `first` defaults to 100
+        assert_eq!(10, cost("{ musicians(first: 10) { id name } }", &[]));
+        assert_eq!(100, cost("{ musicians { id } }", &[]));
+
+        // There can't be more entities than there are in the store
+        assert_eq!(
+            3,
+            cost("{ musicians(first: 10) { id } }", &[("Musician", 3)])
+        );
+
+        // Lookups of a single entity cost 1
+        assert_eq!(
+            2,
+            cost(
+                r#"{ musician(id: "m1") { id } band(id: "b1") { id } }"#,
+                &[]
+            )
+        );
+
+        // Introspection does not touch the database
+        assert_eq!(
+            1,
+            cost(
+                r#"{ __schema { types { name } } musician(id: "m1") { id } }"#,
+                &[]
+            )
+        );
+    }
+
+    #[test]
+    fn cost_of_nested_collections() {
+        // Each band loads a window of up to 20 of its members
+        const BANDS: &str = "{ bands(first: 5) { id members(first: 20) { id } } }";
+        assert_eq!(5 * (1 + 20), cost(BANDS, &[]));
+        assert_eq!(2 * (1 + 4), cost(BANDS, &[("Band", 2), ("Musician", 4)]));
+
+        // Windows nest, and every level multiplies the cost
+        assert_eq!(
+            2 * (1 + 3 * (1 + 4)),
+            cost(
+                "{ bands(first: 2) { members(first: 3) { bands(first: 4) { id } } } }",
+                &[]
+            )
+        );
+
+        // A single entity costs 1 plus what its children cost
+        assert_eq!(
+            1 + 7,
+            cost(r#"{ band(id: "b1") { members(first: 7) { name } } }"#, &[])
+        );
+    }
+
+    #[test]
+    fn entity_types() {
+        fn entity_types(text: &str) -> Option<Vec<String>> {
+            query(text)
+                .entity_types()
+                .map(|types| types.into_iter().collect())
+        }
+
+        assert_eq!(
+            Some(vec!["Band".to_string(), "Musician".to_string()]),
+            entity_types("{ musicians { id bands { name } } }")
+        );
+        assert_eq!(
+            Some(vec!["Musician".to_string()]),
+            entity_types(r#"{ musicians(where: { name: "John" }) { id } __typename }"#)
+        );
+
+        // Filtering by children depends on types that are not selected
+        assert_eq!(
+            None,
+            entity_types(r#"{ musicians(where: { bands_: { name: "x" } }) { id } }"#)
+        );
+        assert_eq!(
+            None,
+            entity_types(
+                r#"{ musicians(where: { or: [{ name: "John" }, { bands_: { name: "x" } }] }) { id } }"#
+            )
+        );
+
+        // `_meta` and aggregations change without entity changes
+        assert_eq!(
+            None,
+            entity_types("{ _meta { block { number } } musicians { id } }")
+        );
+        assert_eq!(
+            None,
+            entity_types("{ musicians { id } stats_collection(interval: hour) { id sum } }")
+        );
+    }
+}
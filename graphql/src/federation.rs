@@ -0,0 +1,340 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use graph::data::query::Federation;
+use graph::prelude::{q, BlockNumber, QueryExecutionError};
+
+/// A federated query split up by subgraph
+pub(crate) struct SplitQuery {
+    /// The query for each subgraph that the federated query reads from,
+    /// keyed by the position of the subgraph in `Federation.subgraphs`
+    pub queries: BTreeMap<usize, q::Document>,
+    /// The response keys of `__typename` fields on the root type
+    pub typenames: Vec<String>,
+}
+
+/// Split `document`, a query against the federated schema for
+/// `federation`, into queries against the subgraphs. Root fields are
+/// renamed to the field in their subgraph, and aliased to the name under
+/// which the client expects them so that the results of the subgraph
+/// queries can be combined as they are. Type conditions, and the types of
+/// variables, lose the namespace prefix that the federated schema adds
+pub(crate) fn split_query(
+    federation: &Federation,
+    document: &q::Document,
+) -> Result<SplitQuery, QueryExecutionError> {
+    let mut operation = None;
+    let mut fragments = HashMap::new();
+    for defn in &document.definitions {
+        match defn {
+            q::Definition::Operation(op) => {
+                if operation.replace(op).is_some() {
+                    return Err(QueryExecutionError::OperationNameRequired);
+                }
+            }
+            q::Definition::Fragment(fragment) => {
+                fragments.insert(fragment.name.as_str(), fragment);
+            }
+        }
+    }
+    let query = match operation {
+        Some(q::OperationDefinition::Query(query)) => query.clone(),
+        Some(q::OperationDefinition::SelectionSet(selection_set)) => q::Query {
+            position: selection_set.span.0,
+            name: None,
+            variable_definitions: vec![],
+            directives: vec![],
+            selection_set: selection_set.clone(),
+        },
+        Some(q::OperationDefinition::Mutation(_) | q::OperationDefinition::Subscription(_)) => {
+            return Err(QueryExecutionError::NotSupported(
+                "Federated queries only support query operations".to_string(),
+            ))
+        }
+        None => return Err(QueryExecutionError::EmptyQuery),
+    };
+
+    let mut typenames = Vec::new();
+    let mut selections: BTreeMap<usize, Vec<q::Selection>> = BTreeMap::new();
+    for selection in &query.selection_set.items {
+        let field = match selection {
+            q::Selection::Field(field) => field,
+            q::Selection::FragmentSpread(_) | q::Selection::InlineFragment(_) => {
+                return Err(QueryExecutionError::NotSupported(
+                    "Federated queries do not support fragments on the root type".to_string(),
+                ))
+            }
+        };
+        if field.name == "__typename" {
+            typenames.push(field.alias.clone().unwrap_or_else(|| field.name.clone()));
+            continue;
+        }
+        let (idx, name) = federation.split_name(&field.name).ok_or_else(|| {
+            QueryExecutionError::UnknownField(
+                field.position,
+                "Query".to_string(),
+                field.name.clone(),
+            )
+        })?;
+        let namespace = &federation.subgraphs[idx].0;
+
+        let mut field = field.clone();
+        field.alias = Some(field.alias.take().unwrap_or_else(|| field.name.clone()));
+        field.name = name.to_string();
+        strip_namespace_from_selection_set(&mut field.selection_set, namespace);
+        selections
+            .entry(idx)
+            .or_default()
+            .push(q::Selection::Field(field));
+    }
+
+    let queries = selections
+        .into_iter()
+        .map(|(idx, items)| {
+            let namespace = &federation.subgraphs[idx].0;
+            let selection_set = q::SelectionSet {
+                span: query.selection_set.span,
+                items,
+            };
+
+            // Validation rejects unused fragments and variables, and we
+            // therefore only pass on the ones this subgraph's fields use
+            let mut used = Used::default();
+            used.directives(&query.directives);
+            used.selection_set(&selection_set, &fragments);
+
+            let variable_definitions = query
+                .variable_definitions
+                .iter()
+                .filter(|var| used.variables.contains(var.name.as_str()))
+                .cloned()
+                .map(|mut var| {
+                    strip_namespace_from_type(&mut var.var_type, namespace);
+                    var
+                })
+                .collect();
+            let mut definitions = vec![q::Definition::Operation(q::OperationDefinition::Query(
+                q::Query {
+                    position: query.position,
+                    name: query.name.clone(),
+                    variable_definitions,
+                    directives: query.directives.clone(),
+                    selection_set,
+                },
+            ))];
+            for name in &used.fragments {
+                let mut fragment = fragments[name.as_str()].clone();
+                let q::TypeCondition::On(type_name) = &mut fragment.type_condition;
+                strip_namespace(type_name, namespace);
+                strip_namespace_from_selection_set(&mut fragment.selection_set, namespace);
+                definitions.push(q::Definition::Fragment(fragment));
+            }
+            (idx, q::Document { definitions })
+        })
+        .collect();
+
+    Ok(SplitQuery { queries, typenames })
+}
+
+/// Query all root fields in `document` that do not ask for a specific
+/// block at block `number`
+pub(crate) fn pin_to_block(document: &mut q::Document, number: BlockNumber) {
+    for defn in &mut document.definitions {
+        let selection_set = match defn {
+            q::Definition::Operation(q::OperationDefinition::Query(query)) => {
+                &mut query.selection_set
+            }
+            q::Definition::Operation(q::OperationDefinition::SelectionSet(selection_set)) => {
+                selection_set
+            }
+            _ => continue,
+        };
+        for selection in &mut selection_set.items {
+            if let q::Selection::Field(field) = selection {
+                if !field.arguments.iter().any(|(name, _)| name == "block") {
+                    let block = BTreeMap::from([(
+                        "number".to_string(),
+                        q::Value::Int(q::Number::from(number)),
+                    )]);
+                    field
+                        .arguments
+                        .push(("block".to_string(), q::Value::Object(block)));
+                }
+            }
+        }
+    }
+}
+
+fn strip_namespace(name: &mut String, namespace: &str) {
+    if let Some(local) = name
+        .strip_prefix(namespace)
+        .and_then(|rest| rest.strip_prefix('_'))
+    {
+        *name = local.to_string();
+    }
+}
+
+fn strip_namespace_from_type(ty: &mut q::Type, namespace: &str) {
+    match ty {
+        q::Type::NamedType(name) => strip_namespace(name, namespace),
+        q::Type::ListType(inner) | q::Type::NonNullType(inner) => {
+            strip_namespace_from_type(inner, namespace)
+        }
+    }
+}
+
+fn strip_namespace_from_selection_set(selection_set: &mut q::SelectionSet, namespace: &str) {
+    for selection in &mut selection_set.items {
+        match selection {
+            q::Selection::Field(field) => {
+                strip_namespace_from_selection_set(&mut field.selection_set, namespace)
+            }
+            q::Selection::FragmentSpread(_) => {}
+            q::Selection::InlineFragment(fragment) => {
+                if let Some(q::TypeCondition::On(type_name)) = &mut fragment.type_condition {
+                    strip_namespace(type_name, namespace);
+                }
+                strip_namespace_from_selection_set(&mut fragment.selection_set, namespace);
+            }
+        }
+    }
+}
+
+/// The fragments and variables that part of a query uses
+#[derive(Default)]
+struct Used {
+    fragments: BTreeSet<String>,
+    variables: BTreeSet<String>,
+}
+
+impl Used {
+    fn selection_set(
+        &mut self,
+        selection_set: &q::SelectionSet,
+        fragments: &HashMap<&str, &q::FragmentDefinition>,
+    ) {
+        for selection in &selection_set.items {
+            match selection {
+                q::Selection::Field(field) => {
+                    for (_, value) in &field.arguments {
+                        self.value(value);
+                    }
+                    self.directives(&field.directives);
+                    self.selection_set(&field.selection_set, fragments);
+                }
+                q::Selection::FragmentSpread(spread) => {
+                    self.directives(&spread.directives);
+                    // Unknown fragments are reported by validation
+                    if self.fragments.insert(spread.fragment_name.clone()) {
+                        if let Some(fragment) = fragments.get(spread.fragment_name.as_str()) {
+                            self.directives(&fragment.directives);
+                            self.selection_set(&fragment.selection_set, fragments);
+                        }
+                    }
+                }
+                q::Selection::InlineFragment(fragment) => {
+                    self.directives(&fragment.directives);
+                    self.selection_set(&fragment.selection_set, fragments);
+                }
+            }
+        }
+    }
+
+    fn directives(&mut self, directives: &[q::Directive]) {
+        for directive in directives {
+            for (_, value) in &directive.arguments {
+                self.value(value);
+            }
+        }
+    }
+
+    fn value(&mut self, value: &q::Value) {
+        match value {
+            q::Value::Variable(name) => {
+                self.variables.insert(name.clone());
+            }
+            q::Value::List(values) => values.iter().for_each(|value| self.value(value)),
+            q::Value::Object(map) => map.values().for_each(|value| self.value(value)),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use graph::data::query::{Federation, QueryTarget};
+    use graph::prelude::{q, DeploymentHash};
+
+    use super::{pin_to_block, split_query};
+
+    fn federation() -> Federation {
+        let target = |id: &str| {
+            QueryTarget::Deployment(DeploymentHash::new(id).unwrap(), Default::default())
+        };
+        Federation {
+            subgraphs: vec![
+                ("uni".to_string(), target("QmUni")),
+                ("ens".to_string(), target("QmEns")),
+            ],
+            timestamp: None,
+        }
+    }
+
+    fn parse(query: &str) -> q::Document {
+        q::parse_query(query).unwrap().into_static()
+    }
+
+    #[test]
+    fn splits_root_fields_by_subgraph() {
+        let query = parse(
+            "query($first: Int, $where: uni_Pool_filter, $name: String) {
+               __typename
+               pools: uni_pools(first: $first, where: $where) { ...pool }
+               uni__meta { block { number } }
+               ens_domains(where: { name: $name }) { ... on ens_Domain { id } }
+             }
+             fragment pool on uni_Pool { id }",
+        );
+        let split = split_query(&federation(), &query).unwrap();
+
+        assert_eq!(vec!["__typename".to_string()], split.typenames);
+        assert_eq!(2, split.queries.len());
+        assert_eq!(
+            parse(
+                "query($first: Int, $where: Pool_filter) {
+                   pools: pools(first: $first, where: $where) { ...pool }
+                   uni__meta: _meta { block { number } }
+                 }
+                 fragment pool on Pool { id }"
+            ),
+            split.queries[&0]
+        );
+        assert_eq!(
+            parse(
+                "query($name: String) {
+                   ens_domains: domains(where: { name: $name }) { ... on Domain { id } }
+                 }"
+            ),
+            split.queries[&1]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_namespaces() {
+        let query = parse("{ sushi_pools { id } }");
+        assert!(split_query(&federation(), &query).is_err());
+        let query = parse("{ pools { id } }");
+        assert!(split_query(&federation(), &query).is_err());
+    }
+
+    #[test]
+    fn pins_fields_to_block() {
+        let mut query = parse("{ pools { id } pool(id: \"1\", block: { hash: \"0x01\" }) { id } }");
+        pin_to_block(&mut query, 17);
+        assert_eq!(
+            parse(
+                "{ pools(block: { number: 17 }) { id } pool(id: \"1\", block: { hash: \"0x01\" }) { id } }"
+            ),
+            query
+        );
+    }
+}
@@ -16,6 +16,12 @@ mod store;
 /// The external interface for actually running queries
 mod runner;
 
+/// Splitting queries across the subgraphs of a federated schema
+mod federation;
+
+/// Rerunning subscriptions when the data they read changes
+mod subscription;
+
 /// Utilities for working with Prometheus.
 mod metrics;
 
@@ -61,6 +61,9 @@ pub enum BlockConstraint {
     /// Execute the query on the latest block only if the the subgraph has progressed to or past the
     /// given block number.
     Min(BlockNumber),
+    /// Execute the query on the latest block whose timestamp, in seconds
+    /// since the Unix epoch, is at or before the given one
+    Timestamp(u64),
     Latest,
 }
 
@@ -77,7 +80,7 @@ impl BlockConstraint {
         use BlockConstraint::*;
         match self {
             Hash(hash) => Some(hash),
-            Number(_) | Min(_) | Latest => None,
+            Number(_) | Min(_) | Timestamp(_) | Latest => None,
         }
     }
 }
@@ -101,6 +104,8 @@ impl TryFromValue for BlockConstraint {
             Ok(BlockConstraint::Min(BlockNumber::try_from_value(
                 number_value,
             )?))
+        } else if let Some(timestamp) = map.get("timestamp_lte") {
+            Ok(BlockConstraint::Timestamp(u64::try_from_value(timestamp)?))
         } else {
             Err(anyhow!("invalid `BlockConstraint`"))
         }
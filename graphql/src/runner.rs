@@ -1,19 +1,28 @@
 use std::sync::Arc;
 use std::time::Instant;
 
+use crate::federation::{pin_to_block, split_query};
 use crate::metrics::GraphQLMetrics;
 use crate::prelude::{QueryExecutionOptions, StoreResolver};
 use crate::query::execute_query;
+use graph::components::graphql::{QueryExport, QueryResultStream};
+use graph::data::query::{CacheStatus, Federation, Trace};
+use graph::data::value::{Object, Word};
+use graph::env::MaxCost;
 use graph::futures03::future;
 use graph::prelude::MetricsRegistry;
 use graph::prelude::{
-    async_trait, o, CheapClone, DeploymentState, GraphQLMetrics as GraphQLMetricsTrait,
-    GraphQlRunner as GraphQlRunnerTrait, Logger, Query, QueryExecutionError, ENV_VARS,
+    async_trait, o, CheapClone, DeploymentHash, DeploymentState,
+    GraphQLMetrics as GraphQLMetricsTrait, GraphQlRunner as GraphQlRunnerTrait, Logger, Query,
+    QueryExecutionError, ENV_VARS,
 };
+use graph::prelude::{r, s};
+use graph::tokio::sync::mpsc;
+use graph::tokio_stream::wrappers::ReceiverStream;
 use graph::{data::graphql::load_manager::LoadManager, prelude::QueryStoreManager};
 use graph::{
-    data::query::{LatestBlockInfo, QueryResults, QueryTarget},
-    prelude::QueryStore,
+    data::query::{HttpCache, LatestBlockInfo, QueryCost, QueryResults, QueryTarget},
+    prelude::{BlockPtr, QueryStore},
 };
 
 /// GraphQL runner implementation for The Graph.
@@ -22,6 +31,7 @@ pub struct GraphQlRunner<S> {
     store: Arc<S>,
     load_manager: Arc<LoadManager>,
     graphql_metrics: Arc<GraphQLMetrics>,
+    max_cost: MaxCost,
 }
 
 #[cfg(debug_assertions)]
@@ -30,6 +40,27 @@ lazy_static::lazy_static! {
     pub static ref INITIAL_DEPLOYMENT_STATE_FOR_TESTS: std::sync::Mutex<Option<DeploymentState>> = std::sync::Mutex::new(None);
 }
 
+/// How HTTP caches may cache the response to the request identified by
+/// `cache_key` when it runs at `blocks`. The response never changes if
+/// all blocks are pinned to blocks that can't be reverted any more, but
+/// only for queries by deployment id since subgraph names can move to
+/// other deployments
+fn http_cache(
+    cache_key: &str,
+    target: &QueryTarget,
+    state: &DeploymentState,
+    blocks: &[BlockPtr],
+) -> HttpCache {
+    let final_block = state.latest_block.number - ENV_VARS.reorg_threshold();
+    // Queries without a block constraint resolve to the latest block
+    let is_final = blocks
+        .iter()
+        .all(|ptr| ptr.number <= final_block && ptr != &state.latest_block);
+    let immutable = is_final && matches!(target, QueryTarget::Deployment(..));
+    let reorg_count = (!is_final).then_some(state.reorg_count);
+    HttpCache::new(cache_key, &state.id, blocks, reorg_count, immutable)
+}
+
 impl<S> GraphQlRunner<S>
 where
     S: QueryStoreManager,
@@ -48,9 +79,16 @@ where
             store,
             load_manager,
             graphql_metrics,
+            max_cost: ENV_VARS.graphql.max_cost.clone(),
         }
     }
 
+    /// Use `max_cost` instead of the cost limits from the environment
+    pub fn with_max_cost(mut self, max_cost: MaxCost) -> Self {
+        self.max_cost = max_cost;
+        self
+    }
+
     /// Check if the subgraph state differs from `state` now in a way that
     /// would affect a query that looked at data as fresh as `latest_block`.
     /// If the subgraph did change, return the `Err` that should be sent back
@@ -81,10 +119,44 @@ where
         Ok(())
     }
 
+    /// Get the store for queries against `target` together with the
+    /// current state of the deployment.
+    ///
+    /// We need to use the same `QueryStore` for the entire query to ensure
+    /// we have a consistent view if the world, even when replicas, which
+    /// are eventually consistent, are in use. If we run different parts
+    /// of the query against different replicas, it would be possible for
+    /// them to be at wildly different states, and we might unwittingly
+    /// mix data from different block heights even if no reverts happen
+    /// while the query is running. `self.store` must therefore not be used
+    /// while running a query, and everything needs to go through the
+    /// `store` we are setting up here
+    async fn query_store(
+        &self,
+        target: &QueryTarget,
+    ) -> Result<(Arc<dyn QueryStore + Send + Sync>, DeploymentState), QueryExecutionError> {
+        let store = self.store.query_store(target.clone()).await?;
+        let state = store.deployment_state().await?;
+
+        // Test only, see c435c25decbc4ad7bbbadf8e0ced0ff2
+        #[cfg(debug_assertions)]
+        let state = INITIAL_DEPLOYMENT_STATE_FOR_TESTS
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or(state);
+
+        Ok((store, state))
+    }
+
+    /// Run `query` against `store`, which must be the store for `target`,
+    /// with the deployment in `state`
     async fn execute(
         &self,
         query: Query,
-        target: QueryTarget,
+        target: &QueryTarget,
+        store: Arc<dyn QueryStore + Send + Sync>,
+        state: DeploymentState,
         max_complexity: Option<u64>,
         max_depth: Option<u8>,
         max_first: Option<u32>,
@@ -93,18 +165,6 @@ where
     ) -> Result<QueryResults, QueryResults> {
         let execute_start = Instant::now();
 
-        // We need to use the same `QueryStore` for the entire query to ensure
-        // we have a consistent view if the world, even when replicas, which
-        // are eventually consistent, are in use. If we run different parts
-        // of the query against different replicas, it would be possible for
-        // them to be at wildly different states, and we might unwittingly
-        // mix data from different block heights even if no reverts happen
-        // while the query is running. `self.store` can not be used after this
-        // point, and everything needs to go through the `store` we are
-        // setting up here
-
-        let store = self.store.query_store(target.clone()).await?;
-        let state = store.deployment_state().await?;
         let network = Some(store.network_name().to_string());
         let schema = store.api_schema()?;
 
@@ -122,16 +182,11 @@ where
             None => None,
         };
 
-        // Test only, see c435c25decbc4ad7bbbadf8e0ced0ff2
-        #[cfg(debug_assertions)]
-        let state = INITIAL_DEPLOYMENT_STATE_FOR_TESTS
-            .lock()
-            .unwrap()
-            .clone()
-            .unwrap_or(state);
-
         let max_depth = max_depth.unwrap_or(ENV_VARS.graphql.max_depth);
         let do_trace = query.trace;
+        let dry_run = query.dry_run;
+        let cache_key = query.cache_key.clone();
+        let if_none_match = query.if_none_match.clone();
         let query = crate::execution::Query::new(
             &self.logger,
             schema,
@@ -141,6 +196,38 @@ where
             max_depth,
             metrics.cheap_clone(),
         )?;
+
+        // Only estimate the cost if we need it since that needs the
+        // statistics from the store. The HTTP server charges the cost
+        // against the rate limit of the client
+        let max_cost = self.max_cost.for_deployment(query.schema.id().as_str());
+        let cost = if dry_run
+            || max_cost.is_some()
+            || ENV_VARS.graphql.report_cost
+            || ENV_VARS.graphql.rate_limit_cost.is_some()
+        {
+            Some(QueryCost {
+                estimated: query.cost(&store.entity_estimates()?),
+                limit: max_cost,
+                dry_run,
+            })
+        } else {
+            None
+        };
+        if let Some(cost) = cost {
+            if let Some(max_cost) = cost.limit.filter(|max_cost| cost.estimated > *max_cost) {
+                let mut result =
+                    QueryResults::from(QueryExecutionError::CostTooHigh(cost.estimated, max_cost));
+                result.cost = Some(cost);
+                return Err(result);
+            }
+            if dry_run {
+                let mut result = QueryResults::empty(query.root_trace(do_trace), latest_block);
+                result.cost = Some(cost);
+                return Ok(result);
+            }
+        }
+
         self.load_manager
             .decide(
                 &store.wait_stats(),
@@ -152,6 +239,30 @@ where
             .to_result()?;
         let by_block_constraint =
             StoreResolver::locate_blocks(store.as_ref(), &state, &query).await?;
+        // `state` comes from the primary; if the store reads from a replica
+        // that has not caught up to the blocks we need, read from the
+        // primary instead
+        let needed_block = by_block_constraint
+            .iter()
+            .map(|(ptr, _)| ptr.number)
+            .max()
+            .unwrap_or(state.latest_block.number);
+        let store = store.for_block(needed_block).await?;
+        let mut http_cache = cache_key.map(|cache_key| {
+            let blocks: Vec<_> = by_block_constraint
+                .iter()
+                .map(|(ptr, _)| ptr.clone())
+                .collect();
+            http_cache(&cache_key, target, &state, &blocks)
+        });
+        if let Some(cache) = &mut http_cache {
+            if if_none_match.is_some_and(|tags| cache.matches(&tags)) {
+                cache.not_modified = true;
+                let mut result = QueryResults::empty(query.root_trace(do_trace), latest_block);
+                result.http_cache = http_cache;
+                return Ok(result);
+            }
+        }
         let mut max_block = 0;
         let mut result: QueryResults =
             QueryResults::empty(query.root_trace(do_trace), latest_block);
@@ -201,6 +312,8 @@ where
         }
 
         query.log_execution(max_block);
+        result.cost = cost;
+        result.http_cache = http_cache;
         result.trace.finish(setup_elapsed, execute_start.elapsed());
         self.deployment_changed(store.as_ref(), state, max_block as u64)
             .await
@@ -235,9 +348,15 @@ where
         max_first: Option<u32>,
         max_skip: Option<u32>,
     ) -> QueryResults {
+        let (store, state) = match self.query_store(&target).await {
+            Ok(view) => view,
+            Err(e) => return e.into(),
+        };
         self.execute(
             query,
-            target,
+            &target,
+            store,
+            state,
             max_complexity,
             max_depth,
             max_first,
@@ -248,6 +367,205 @@ where
         .unwrap_or_else(|e| e)
     }
 
+    async fn run_batch(
+        self: Arc<Self>,
+        queries: Vec<Query>,
+        target: QueryTarget,
+    ) -> Vec<QueryResults> {
+        // Queries that don't ask for a specific block run at the latest
+        // block in `state`, which is therefore the same for all of them
+        let (store, state) = match self.query_store(&target).await {
+            Ok(view) => view,
+            Err(e) => return queries.iter().map(|_| e.clone().into()).collect(),
+        };
+
+        // Running the queries one after the other means that a batch never
+        // holds more than one of the store's query permits at a time, just
+        // like a single query
+        let mut results = Vec::with_capacity(queries.len());
+        for query in queries {
+            let result = self
+                .execute(
+                    query,
+                    &target,
+                    store.cheap_clone(),
+                    state.clone(),
+                    ENV_VARS.graphql.max_complexity,
+                    Some(ENV_VARS.graphql.max_depth),
+                    Some(ENV_VARS.graphql.max_first),
+                    Some(ENV_VARS.graphql.max_skip),
+                    self.graphql_metrics.clone(),
+                )
+                .await
+                .unwrap_or_else(|e| e);
+            results.push(result);
+        }
+        results
+    }
+
+    async fn run_subscription(
+        self: Arc<Self>,
+        query: Query,
+        target: QueryTarget,
+    ) -> Result<QueryResultStream, QueryResults> {
+        let query = query.subscription_as_query();
+
+        let store = self.store.query_store(target.clone()).await?;
+        let state = store.deployment_state().await?;
+        let network = Some(store.network_name().to_string());
+        let schema = store.api_schema()?;
+
+        // Validate the subscription once up front so that clients get
+        // errors right away, and find out which entity types it reads
+        let types = crate::execution::Query::new(
+            &self.logger,
+            schema,
+            network,
+            query.clone(),
+            ENV_VARS.graphql.max_complexity,
+            ENV_VARS.graphql.max_depth,
+            self.graphql_metrics.cheap_clone(),
+        )?
+        .entity_types();
+
+        let logger = self.logger.new(o!("subgraph_id" => state.id.to_string()));
+        let (sender, receiver) = mpsc::channel(1);
+        graph::spawn(crate::subscription::watch(
+            logger, self, store, query, target, types, state, sender,
+        ));
+        Ok(Box::pin(ReceiverStream::new(receiver)))
+    }
+
+    async fn run_export(
+        self: Arc<Self>,
+        query: Query,
+        target: QueryTarget,
+        page_size: usize,
+    ) -> Result<QueryExport, QueryResults> {
+        let (store, state) = self.query_store(&target).await?;
+        let network = Some(store.network_name().to_string());
+        let schema = store.api_schema()?;
+        let query = crate::execution::Query::new(
+            &self.logger,
+            schema,
+            network,
+            query,
+            ENV_VARS.graphql.max_complexity,
+            ENV_VARS.graphql.max_depth,
+            self.graphql_metrics.cheap_clone(),
+        )?;
+
+        self.load_manager
+            .decide(
+                &store.wait_stats(),
+                store.shard(),
+                store.deployment_id(),
+                query.shape_hash,
+                query.query_text.as_ref(),
+            )
+            .to_result()?;
+        // The export selects exactly one field and therefore has one
+        // block constraint
+        let (ptr, (selection_set, error_policy)) =
+            StoreResolver::locate_blocks(store.as_ref(), &state, &query)
+                .await?
+                .into_iter()
+                .next()
+                .ok_or_else(|| {
+                    QueryExecutionError::NotSupported("an export must select a collection".into())
+                })?;
+        let store = store.for_block(ptr.number).await?;
+        let resolver = StoreResolver::at_block(
+            &self.logger,
+            store,
+            &state,
+            ptr,
+            error_policy,
+            query.schema.id().clone(),
+            self.graphql_metrics.cheap_clone(),
+            self.load_manager.cheap_clone(),
+        )
+        .await?;
+        let export = resolver.export(&selection_set, query.query_id.clone(), page_size)?;
+        query.log_execution(resolver.block_number());
+        Ok(export)
+    }
+
+    async fn run_federated(self: Arc<Self>, query: Query, federation: Federation) -> QueryResults {
+        let split = match split_query(&federation, &query.document) {
+            Ok(split) => split,
+            Err(e) => return e.into(),
+        };
+
+        let timestamp = federation.timestamp;
+        let runs = split.queries.into_iter().map(|(idx, mut document)| {
+            let runner = self.cheap_clone();
+            let (namespace, target) = federation.subgraphs[idx].clone();
+            let query = &query;
+            async move {
+                // Every subgraph is queried at its own latest block at or
+                // before `timestamp`, so that the results line up in time
+                if let Some(timestamp) = timestamp {
+                    let (store, _) = runner.query_store(&target).await?;
+                    let number = store
+                        .block_number_for_timestamp(timestamp)
+                        .await?
+                        .ok_or_else(|| {
+                            QueryExecutionError::ValueParseError(
+                                "timestamp".to_string(),
+                                format!(
+                                    "subgraph `{}` has no block at or before timestamp {}",
+                                    namespace, timestamp
+                                ),
+                            )
+                        })?;
+                    pin_to_block(&mut document, number);
+                }
+                let query = Query::new(document, query.variables.clone(), query.trace)
+                    .with_dry_run(query.dry_run);
+                Ok::<_, QueryExecutionError>(runner.run_query(query, target).await)
+            }
+        });
+
+        let mut results = QueryResults::empty(Trace::None, None);
+        for result in future::join_all(runs).await {
+            results.extend(result.unwrap_or_else(|e| e.into()));
+        }
+        if !split.typenames.is_empty() {
+            let typenames = Object::from_iter(
+                split
+                    .typenames
+                    .into_iter()
+                    .map(|key| (Word::from(key), r::Value::String("Query".to_string()))),
+            );
+            results.append(Arc::new(typenames.into()), CacheStatus::default());
+        }
+        results
+    }
+
+    async fn federated_schema(
+        self: Arc<Self>,
+        federation: Federation,
+    ) -> Result<s::Document, QueryExecutionError> {
+        let mut schemas = Vec::with_capacity(federation.subgraphs.len());
+        for (namespace, target) in &federation.subgraphs {
+            let store = self.store.query_store(target.clone()).await?;
+            schemas.push((namespace.as_str(), store.api_schema()?));
+        }
+        Ok(graph::schema::federated_schema(
+            schemas
+                .iter()
+                .map(|(namespace, schema)| (*namespace, schema.as_ref())),
+        ))
+    }
+
+    async fn resolve_target(
+        self: Arc<Self>,
+        target: QueryTarget,
+    ) -> Result<(DeploymentHash, Vec<String>), QueryExecutionError> {
+        self.store.resolve_target(target).await
+    }
+
     fn metrics(&self) -> Arc<dyn GraphQLMetricsTrait> {
         self.graphql_metrics.clone()
     }
@@ -8,7 +8,7 @@ use graph::data::store::IdList;
 use graph::data::store::IdType;
 use graph::data::store::QueryObject;
 use graph::data::value::{Object, Word};
-use graph::prelude::{r, CacheWeight, CheapClone};
+use graph::prelude::{r, s, CacheWeight, CheapClone};
 use graph::schema::kw;
 use graph::schema::AggregationInterval;
 use graph::schema::Field;
@@ -18,16 +18,24 @@ use std::collections::{BTreeMap, HashMap};
 use std::rc::Rc;
 use std::time::Instant;
 
+use graph::blockchain::BlockHash;
+use graph::components::store::{BlockNumber, EntityAggregate, EntityCursor, EntityVersion};
 use graph::data::graphql::TypeExt;
 use graph::prelude::{
     AttributeNames, ChildMultiplicity, EntityCollection, EntityFilter, EntityLink, EntityOrder,
     EntityWindow, ParentLink, QueryExecutionError, Value as StoreValue, WindowAttribute, ENV_VARS,
 };
-use graph::schema::{EntityType, InputSchema, ObjectOrInterface};
+use graph::schema::{
+    EntityType, InputSchema, ObjectOrInterface, AGGREGATE_SUFFIX, BLOCK_FIELD_TYPE, CHANGE_SUFFIX,
+    CURSOR_FIELD, VERSION_SUFFIX,
+};
 
 use crate::execution::ast as a;
 use crate::metrics::GraphQLMetrics;
-use crate::store::query::build_query;
+use crate::store::query::{
+    build_aggregate_query, build_changes_query, build_cursors, build_history_query, build_query,
+    entity_aggregate,
+};
 use crate::store::StoreResolver;
 
 pub const ARG_ID: &str = "id";
@@ -228,6 +236,71 @@ impl Node {
     }
 }
 
+/// Add the `_cursor` for the position of `object` in a collection that is
+/// sorted by `order`. Entities in collections that are not sorted by `id`
+/// or by attributes of the entity do not get a cursor
+fn add_cursor(object: &mut QueryObject, order: &EntityOrder) {
+    let Ok(attributes) = order.cursor_attributes() else {
+        return;
+    };
+    let Some(id) = object.entity.get(ARG_ID) else {
+        return;
+    };
+    let values: Vec<_> = attributes
+        .iter()
+        .map(|(attr, _)| object.entity.get(attr).unwrap_or(&r::Value::Null))
+        .collect();
+    let cursor = EntityCursor::encode(&values, id);
+    object
+        .entity
+        .extend([(Word::from(CURSOR_FIELD), r::Value::String(cursor))]);
+}
+
+/// If `field` is an `<entities>_aggregate` field on the `Query` type,
+/// return the type of the entities it aggregates over
+fn aggregate_field_entity<'a>(
+    schema: &'a InputSchema,
+    field: &s::Field,
+) -> Option<ObjectOrInterface<'a>> {
+    let base_type = field.field_type.get_base_type();
+    if schema.object_or_interface(base_type, None).is_some() {
+        return None;
+    }
+    base_type
+        .strip_suffix(AGGREGATE_SUFFIX)
+        .and_then(|name| schema.object_or_interface(name, None))
+}
+
+/// If `field` is an `<entity>_history` field on the `Query` type, return
+/// the type of the entity whose history it lists
+fn history_field_entity<'a>(
+    schema: &'a InputSchema,
+    field: &s::Field,
+) -> Option<ObjectOrInterface<'a>> {
+    let base_type = field.field_type.get_base_type();
+    if schema.object_or_interface(base_type, None).is_some() {
+        return None;
+    }
+    base_type
+        .strip_suffix(VERSION_SUFFIX)
+        .and_then(|name| schema.object_or_interface(name, None))
+}
+
+/// If `field` is an `<entity>_changes` field on the `Query` type, return
+/// the type of the entity whose changes it lists
+fn changes_field_entity<'a>(
+    schema: &'a InputSchema,
+    field: &s::Field,
+) -> Option<ObjectOrInterface<'a>> {
+    let base_type = field.field_type.get_base_type();
+    if schema.object_or_interface(base_type, None).is_some() {
+        return None;
+    }
+    base_type
+        .strip_suffix(CHANGE_SUFFIX)
+        .and_then(|name| schema.object_or_interface(name, None))
+}
+
 /// Describe a field that we join on. The distinction between scalar and
 /// list is important for generating the right filter, and handling results
 /// correctly
@@ -622,6 +695,58 @@ impl<'a> Loader<'a> {
                 let field_type = object_type
                     .field(&field.name)
                     .expect("field names are valid");
+
+                if at_root {
+                    if let Some(entity) = aggregate_field_entity(&input_schema, field_type) {
+                        match self.fetch_aggregates(entity, field) {
+                            Ok((node, trace)) => {
+                                let node = Rc::new(node);
+                                for parent in parents.iter_mut() {
+                                    parent.set_children(
+                                        field.response_key().to_string(),
+                                        vec![node.cheap_clone()],
+                                    );
+                                }
+                                parent_trace.push(field.response_key(), trace);
+                            }
+                            Err(e) => errors.push(e),
+                        }
+                        continue;
+                    }
+                    if let Some(entity) = changes_field_entity(&input_schema, field_type) {
+                        match self.fetch_changes(entity, field) {
+                            Ok((nodes, trace)) => {
+                                let nodes: Vec<_> = nodes.into_iter().map(Rc::new).collect();
+                                for parent in parents.iter_mut() {
+                                    parent.set_children(
+                                        field.response_key().to_string(),
+                                        nodes.iter().map(CheapClone::cheap_clone).collect(),
+                                    );
+                                }
+                                parent_trace.push(field.response_key(), trace);
+                            }
+                            Err(e) => errors.push(e),
+                        }
+                        continue;
+                    }
+                    if let Some(entity) = history_field_entity(&input_schema, field_type) {
+                        match self.fetch_history(entity, field) {
+                            Ok((nodes, trace)) => {
+                                let nodes: Vec<_> = nodes.into_iter().map(Rc::new).collect();
+                                for parent in parents.iter_mut() {
+                                    parent.set_children(
+                                        field.response_key().to_string(),
+                                        nodes.iter().map(CheapClone::cheap_clone).collect(),
+                                    );
+                                }
+                                parent_trace.push(field.response_key(), trace);
+                            }
+                            Err(e) => errors.push(e),
+                        }
+                        continue;
+                    }
+                }
+
                 let child_type = input_schema
                     .object_or_interface(field_type.field_type.get_base_type(), child_interval)
                     .expect("we only collect fields that are objects or interfaces");
@@ -686,6 +811,220 @@ impl<'a> Loader<'a> {
         }
     }
 
+    /// Compute the values for an `<entities>_aggregate` field with one
+    /// aggregate query. The resulting node has the `count` and entries
+    /// `prefetch:{response_key}` for the `sum`, `min` and `max` objects
+    /// that the field selects
+    fn fetch_aggregates(
+        &self,
+        entity: ObjectOrInterface<'_>,
+        field: &a::Field,
+    ) -> Result<(Node, Trace), QueryExecutionError> {
+        let input_schema = self.resolver.store.input_schema()?;
+        let (mut query, aggregates) =
+            build_aggregate_query(&entity, self.resolver.block_number(), field, &input_schema)?;
+        query.trace = self.ctx.trace;
+        query.query_id = Some(self.ctx.query.query_id.clone());
+
+        let (values, trace) = self.resolver.store.find_aggregates(
+            query,
+            &aggregates,
+            ENV_VARS.graphql.aggregate_max_entities,
+        )?;
+        let value_of = |agg: Option<EntityAggregate>| {
+            agg.and_then(|agg| aggregates.iter().position(|a| a == &agg))
+                .map(|pos| values[pos].clone())
+                .unwrap_or(r::Value::Null)
+        };
+
+        let mut entries: BTreeMap<Word, r::Value> = BTreeMap::new();
+        for (_, fields) in field.selection_set.fields() {
+            for selected in fields {
+                if selected.name == "count" {
+                    entries.insert(Word::from("count"), value_of(Some(EntityAggregate::Count)));
+                    continue;
+                }
+                let mut object: BTreeMap<Word, r::Value> = BTreeMap::new();
+                for (_, attrs) in selected.selection_set.fields() {
+                    for attr in attrs {
+                        let agg = entity_aggregate(&selected.name, &attr.name);
+                        if agg.is_some() {
+                            object.insert(Word::from(attr.name.as_str()), value_of(agg));
+                        }
+                    }
+                }
+                if !selected.selection_set.is_empty() {
+                    entries.insert(
+                        Word::from(format!("prefetch:{}", selected.response_key())),
+                        r::Value::List(vec![r::Value::Object(Object::from_iter(object))]),
+                    );
+                }
+            }
+        }
+        let entity = Object::from_iter(entries);
+        let node = Node {
+            children_weight: entity.weight(),
+            parent: None,
+            entity,
+            children: BTreeMap::default(),
+        };
+        Ok((node, trace))
+    }
+
+    /// List the versions of an entity for an `<entity>_history` field.
+    /// Each version becomes one node that has the attributes of the
+    /// entity, `_changedFields`, and entries `prefetch:{response_key}`
+    /// for the `_fromBlock` and `_toBlock` objects
+    fn fetch_history(
+        &self,
+        entity: ObjectOrInterface<'_>,
+        field: &a::Field,
+    ) -> Result<(Vec<Node>, Trace), QueryExecutionError> {
+        let query = build_history_query(
+            &entity,
+            self.resolver.block_number(),
+            field,
+            self.ctx.max_first,
+        )?;
+        let (versions, trace) = self.resolver.store.entity_history(
+            &query.entity_type,
+            &query.id,
+            query.from,
+            query.to,
+            query.first,
+            self.ctx.trace,
+        )?;
+
+        let typename = format!("{}{}", query.entity_type.typename(), VERSION_SUFFIX);
+        let block = |number: BlockNumber, hash: Option<BlockHash>| {
+            let hash = hash
+                .map(|hash| r::Value::String(hash.to_string()))
+                .unwrap_or(r::Value::Null);
+            r::Value::object(BTreeMap::from([
+                (Word::from("hash"), hash),
+                (Word::from("number"), r::Value::Int(number.into())),
+                (Word::from("timestamp"), r::Value::Null),
+                (Word::from("parentHash"), r::Value::Null),
+                (
+                    Word::from("__typename"),
+                    r::Value::String(BLOCK_FIELD_TYPE.to_string()),
+                ),
+            ]))
+        };
+
+        let nodes = versions
+            .into_iter()
+            .map(|version| {
+                let EntityVersion {
+                    from_block,
+                    from_hash,
+                    to_block,
+                    to_hash,
+                    changed,
+                    data,
+                } = version;
+                let changed = changed
+                    .map(|changed| {
+                        r::Value::List(
+                            changed
+                                .into_iter()
+                                .map(|name| r::Value::String(name.to_string()))
+                                .collect(),
+                        )
+                    })
+                    .unwrap_or(r::Value::Null);
+                let mut entries: BTreeMap<Word, r::Value> = BTreeMap::from([
+                    (Word::from("__typename"), r::Value::String(typename.clone())),
+                    (Word::from("_changedFields"), changed),
+                ]);
+                for (_, fields) in field.selection_set.fields() {
+                    for selected in fields {
+                        let blocks = match selected.name.as_str() {
+                            "_fromBlock" => vec![block(from_block, from_hash.clone())],
+                            "_toBlock" => to_block
+                                .map(|to_block| block(to_block, to_hash.clone()))
+                                .into_iter()
+                                .collect(),
+                            _ => continue,
+                        };
+                        entries.insert(
+                            Word::from(format!("prefetch:{}", selected.response_key())),
+                            r::Value::List(blocks),
+                        );
+                    }
+                }
+                let mut entity = Object::from_iter(entries);
+                entity.append(data);
+                Node {
+                    children_weight: entity.weight(),
+                    parent: None,
+                    entity,
+                    children: BTreeMap::default(),
+                }
+            })
+            .collect();
+        Ok((nodes, trace))
+    }
+
+    /// List the changes to entities for an `<entity>_changes` field. Each
+    /// change becomes one node that has `_operation`, `_block`, `_cursor`,
+    /// the id of the entity, and its attributes if the field selects any
+    fn fetch_changes(
+        &self,
+        entity: ObjectOrInterface<'_>,
+        field: &a::Field,
+    ) -> Result<(Vec<Node>, Trace), QueryExecutionError> {
+        let query = build_changes_query(
+            &entity,
+            self.resolver.block_number(),
+            field,
+            self.ctx.max_first,
+        )?;
+        let (changes, trace) = self.resolver.store.entity_diff(
+            &query.entity_type,
+            query.from,
+            query.to,
+            query.after,
+            query.first,
+            query.with_data,
+            self.ctx.trace,
+        )?;
+
+        let typename = format!("{}{}", query.entity_type.typename(), CHANGE_SUFFIX);
+        let nodes = changes
+            .into_iter()
+            .map(|change| {
+                let mut entity = Object::from_iter([
+                    (Word::from("__typename"), r::Value::String(typename.clone())),
+                    (
+                        Word::from("_operation"),
+                        r::Value::Enum(change.operation.as_str().to_string()),
+                    ),
+                    (
+                        Word::from("_block"),
+                        r::Value::Int(change.cursor.block.into()),
+                    ),
+                    (
+                        Word::from(CURSOR_FIELD),
+                        r::Value::String(change.cursor.to_string()),
+                    ),
+                    (Word::from("id"), StoreValue::from(change.id).into()),
+                ]);
+                // `data` also has the id, which `extend` leaves alone
+                if let Some(data) = change.data {
+                    entity.extend(data);
+                }
+                Node {
+                    children_weight: entity.weight(),
+                    parent: None,
+                    entity,
+                    children: BTreeMap::default(),
+                }
+            })
+            .collect();
+        Ok((nodes, trace))
+    }
+
     /// Query child entities for `parents` from the store. The `join` indicates
     /// in which child field to look for the parent's id/join field. When
     /// `is_single` is `true`, there is at most one child per parent.
@@ -718,6 +1057,7 @@ impl<'a> Loader<'a> {
             let ts = child_type.field(kw::TIMESTAMP).unwrap();
             query.order = EntityOrder::Descending(ts.name.to_string(), ts.value_type);
         }
+        let reversed = build_cursors(&mut query, child_type, field)?;
         query.logger = Some(self.ctx.logger.cheap_clone());
         if let Some(r::Value::String(id)) = field.argument_value(ARG_ID) {
             query.filter = Some(
@@ -740,10 +1080,35 @@ impl<'a> Loader<'a> {
             }
             query.collection = EntityCollection::Window(windows);
         }
+
+        // Cursors can only be passed to collections at the root of the
+        // query, and we therefore only compute them there
+        let cursor_order = (matches!(join, MaybeJoin::Root { .. })
+            && field
+                .selection_set
+                .fields()
+                .any(|(_, mut fields)| fields.any(|selected| selected.name == CURSOR_FIELD)))
+        .then(|| query.order.clone());
         self.resolver
             .store
             .find_query_values(query)
-            .map(|(values, trace)| (values.into_iter().map(Node::from).collect(), trace))
+            .map(|(values, trace)| {
+                let mut nodes: Vec<_> = values
+                    .into_iter()
+                    .map(|mut object| {
+                        if let Some(order) = &cursor_order {
+                            add_cursor(&mut object, order);
+                        }
+                        Node::from(object)
+                    })
+                    .collect();
+                if reversed {
+                    // Put the page that we got by paging backwards from
+                    // a `before` cursor back in the order of the query
+                    nodes.reverse();
+                }
+                (nodes, trace)
+            })
     }
 
     fn check_result_size(&self, parents: &[&mut Node]) -> Result<(), QueryExecutionError> {
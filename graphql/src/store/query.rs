@@ -2,21 +2,22 @@ use std::mem::discriminant;
 
 use graph::cheap_clone::CheapClone;
 use graph::components::store::{
-    BlockNumber, Child, EntityCollection, EntityFilter, EntityOrder, EntityOrderByChild,
+    AttributeNames, BlockNumber, Child, ChildMultiplicity, EntityAggregate, EntityCollection,
+    EntityCursor, EntityDiffCursor, EntityFilter, EntityOrder, EntityOrderByChild,
     EntityOrderByChildInfo, EntityQuery, EntityRange,
 };
-use graph::data::graphql::TypeExt as _;
+use graph::data::graphql::{ObjectTypeExt as _, TypeExt as _};
 use graph::data::query::QueryExecutionError;
-use graph::data::store::{Attribute, Value, ValueType};
-use graph::data::value::Object;
+use graph::data::store::{Attribute, Id, Value, ValueType, ID};
 use graph::data::value::Value as DataValue;
+use graph::data::value::{Object, Word};
 use graph::prelude::{r, TryFromValue, ENV_VARS};
 use graph::schema::ast::{self as sast, FilterOp};
-use graph::schema::{EntityType, InputSchema, ObjectOrInterface};
+use graph::schema::{kw, EntityType, InputSchema, ObjectOrInterface};
 
 use crate::execution::ast as a;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 enum OrderDirection {
     Ascending,
     Descending,
@@ -49,9 +50,365 @@ pub(crate) fn build_query<'a>(
         query = query.filter(filter);
     }
     query = query.order(order);
+    if build_rollup(entity, field, schema, &query.order)? {
+        query = query.rollup(true);
+    }
     Ok(query)
 }
 
+/// Builds the `EntityQuery` and the list of aggregates to compute for an
+/// `<entities>_aggregate` field. The query matches all entities of
+/// `entity` that pass the `where` filter at `block`
+pub(crate) fn build_aggregate_query(
+    entity: &ObjectOrInterface<'_>,
+    block: BlockNumber,
+    field: &a::Field,
+    schema: &InputSchema,
+) -> Result<(EntityQuery, Vec<EntityAggregate>), QueryExecutionError> {
+    let entity_type = entity.object_types().into_iter().next().ok_or_else(|| {
+        QueryExecutionError::NotSupported(format!(
+            "`{}` can not be aggregated since it has no entities",
+            field.name
+        ))
+    })?;
+    let collection = EntityCollection::All(vec![(entity_type, AttributeNames::All)]);
+    let mut query = EntityQuery::new(schema.id().cheap_clone(), block, collection);
+    if let Some(filter) = build_filter(entity, field, schema)? {
+        query = query.filter(filter);
+    }
+
+    // The same aggregate can be selected several times through aliases;
+    // we only need to compute it once
+    let mut aggregates: Vec<EntityAggregate> = Vec::new();
+    let mut add = |agg: EntityAggregate| {
+        if !aggregates.contains(&agg) {
+            aggregates.push(agg);
+        }
+    };
+    for (_, fields) in field.selection_set.fields() {
+        for selected in fields {
+            if selected.name == "count" {
+                add(EntityAggregate::Count);
+            }
+            for (_, attrs) in selected.selection_set.fields() {
+                attrs
+                    .filter_map(|attr| entity_aggregate(&selected.name, &attr.name))
+                    .for_each(&mut add);
+            }
+        }
+    }
+    Ok((query, aggregates))
+}
+
+/// The aggregate for the attribute `attr` that is selected in the `sum`,
+/// `min` or `max` field (`kind`) of an `<entities>_aggregate` field
+pub(crate) fn entity_aggregate(kind: &str, attr: &str) -> Option<EntityAggregate> {
+    if attr == "__typename" {
+        return None;
+    }
+    let attr = Attribute::from(attr);
+    match kind {
+        "sum" => Some(EntityAggregate::Sum(attr)),
+        "min" => Some(EntityAggregate::Min(attr)),
+        "max" => Some(EntityAggregate::Max(attr)),
+        _ => None,
+    }
+}
+
+/// The entity and the range of blocks that an `<entity>_history` field
+/// asks for
+pub(crate) struct HistoryQuery {
+    pub entity_type: EntityType,
+    pub id: Id,
+    pub from: Option<BlockNumber>,
+    pub to: BlockNumber,
+    pub first: usize,
+}
+
+/// Parses the `fromBlock`, `toBlock` and `first` arguments that the
+/// `<entity>_history` and `<entity>_changes` fields share. `toBlock` is
+/// capped at `block`, the block at which the query runs
+fn block_range_args(
+    field: &a::Field,
+    block: BlockNumber,
+    max_first: u32,
+) -> Result<(Option<BlockNumber>, BlockNumber, usize), QueryExecutionError> {
+    let first = match field.argument_value("first") {
+        Some(r::Value::Int(n)) if *n > 0 && *n <= max_first as i64 => *n as usize,
+        Some(r::Value::Int(n)) => {
+            return Err(QueryExecutionError::RangeArgumentsError(
+                "first", max_first, *n,
+            ))
+        }
+        Some(r::Value::Null) | None => 100,
+        _ => unreachable!("first is an Int with a default value"),
+    };
+
+    let block_arg = |name: &str| match field.argument_value(name) {
+        Some(r::Value::Int(n)) => BlockNumber::try_from(*n).map(Some).map_err(|_| {
+            QueryExecutionError::ValueParseError(
+                name.to_owned(),
+                format!("{} is not a valid block number", n),
+            )
+        }),
+        _ => Ok(None),
+    };
+    let from = block_arg("fromBlock")?;
+    let to = block_arg("toBlock")?.map_or(block, |to| to.min(block));
+
+    Ok((from, to, first))
+}
+
+/// Builds the `HistoryQuery` for an `<entity>_history` field. Versions
+/// after `block`, the block at which the query runs, are never visible,
+/// even if `toBlock` asks for them
+pub(crate) fn build_history_query(
+    entity: &ObjectOrInterface<'_>,
+    block: BlockNumber,
+    field: &a::Field,
+    max_first: u32,
+) -> Result<HistoryQuery, QueryExecutionError> {
+    let entity_type = entity.object_types().into_iter().next().ok_or_else(|| {
+        QueryExecutionError::NotSupported(format!(
+            "`{}` has no history since it has no entities",
+            field.name
+        ))
+    })?;
+
+    let id = match field.argument_value("id") {
+        Some(r::Value::String(id)) => entity_type
+            .id_type()?
+            .parse(id.as_str().into())
+            .map_err(|e| QueryExecutionError::ValueParseError("id".to_owned(), e.to_string()))?,
+        _ => unreachable!("id is a required argument of type ID"),
+    };
+
+    let (from, to, first) = block_range_args(field, block, max_first)?;
+
+    Ok(HistoryQuery {
+        entity_type,
+        id,
+        from,
+        to,
+        first,
+    })
+}
+
+/// The entity type and the range of blocks that an `<entity>_changes`
+/// field asks for
+pub(crate) struct ChangesQuery {
+    pub entity_type: EntityType,
+    pub from: Option<BlockNumber>,
+    pub to: BlockNumber,
+    pub after: Option<EntityDiffCursor>,
+    pub first: usize,
+    /// Whether the query selects any fields of the entity besides its id
+    pub with_data: bool,
+}
+
+/// Builds the `ChangesQuery` for an `<entity>_changes` field. Like for
+/// `<entity>_history`, changes after `block` are never visible
+pub(crate) fn build_changes_query(
+    entity: &ObjectOrInterface<'_>,
+    block: BlockNumber,
+    field: &a::Field,
+    max_first: u32,
+) -> Result<ChangesQuery, QueryExecutionError> {
+    let entity_type = entity.object_types().into_iter().next().ok_or_else(|| {
+        QueryExecutionError::NotSupported(format!(
+            "`{}` has no changes since it has no entities",
+            field.name
+        ))
+    })?;
+
+    let (from, to, first) = block_range_args(field, block, max_first)?;
+
+    let after = match field.argument_value("after") {
+        Some(r::Value::String(after)) => Some(after.parse::<EntityDiffCursor>().map_err(|e| {
+            QueryExecutionError::ValueParseError("after".to_owned(), e.to_string())
+        })?),
+        _ => None,
+    };
+
+    let with_data = field.selection_set.fields().any(|(_, mut fields)| {
+        fields.any(|selected| !selected.name.starts_with('_') && selected.name.as_str() != "id")
+    });
+
+    Ok(ChangesQuery {
+        entity_type,
+        from,
+        to,
+        after,
+        first,
+        with_data,
+    })
+}
+
+/// Builds the `EntityQuery` for exporting all entities that the only
+/// field in `selection_set`, a collection of one entity type, matches at
+/// `block`. Also returns the response key of each field that the export
+/// selects together with the attribute that holds its value. Only fields
+/// that are stored with the entity can be exported; for a reference, that
+/// is the id of the referenced entity, and it may therefore only select
+/// the `id` of it
+pub(crate) fn build_export_query(
+    selection_set: &a::SelectionSet,
+    block: BlockNumber,
+    schema: &InputSchema,
+) -> Result<(EntityQuery, Vec<(Word, Word)>), QueryExecutionError> {
+    let not_exportable = |msg: String| QueryExecutionError::NotSupported(msg);
+
+    let (object_type, field) = selection_set
+        .fields()
+        .find_map(|(object_type, mut fields)| fields.next().map(|field| (object_type, field)))
+        .ok_or_else(|| not_exportable("an export must select a collection".to_string()))?;
+    let field_type = object_type
+        .field(&field.name)
+        .expect("field names are valid");
+    let entity = schema
+        .object_or_interface(
+            field_type.field_type.get_base_type(),
+            field.aggregation_interval()?,
+        )
+        .filter(|_| field.multiplicity == ChildMultiplicity::Many)
+        .ok_or_else(|| {
+            not_exportable(format!(
+                "only collections of entities can be exported, but `{}` is not one",
+                field.name
+            ))
+        })?;
+    if entity.is_interface() {
+        return Err(not_exportable(format!(
+            "only collections of one entity type can be exported, but `{}` is an interface",
+            field.name
+        )));
+    }
+    let entity_type = entity.entity_type();
+
+    let mut fields = Vec::new();
+    for (_, selected) in field.selection_set.fields() {
+        for selected in selected {
+            let stored = selected.name == "__typename"
+                || entity_type
+                    .field(&selected.name)
+                    .map_or(false, |field| !field.is_derived());
+            if !stored {
+                return Err(not_exportable(format!(
+                    "exports can only select fields that are stored with the entity, \
+                     but `{}` is not",
+                    selected.name
+                )));
+            }
+            let only_id = selected
+                .selection_set
+                .fields()
+                .all(|(_, mut fields)| fields.all(|field| field.name == ID.as_str()));
+            if !only_id {
+                return Err(not_exportable(format!(
+                    "exports can only select the `id` of the referenced entity `{}`",
+                    selected.name
+                )));
+            }
+            fields.push((
+                Word::from(selected.response_key()),
+                Word::from(selected.name.as_str()),
+            ));
+        }
+    }
+
+    let mut query = build_query(&entity, block, field, u32::MAX, u32::MAX, schema)?;
+    // Exports read all entities, no matter what `first` is
+    query.range = EntityRange {
+        first: None,
+        skip: 0,
+        after: None,
+        before: None,
+    };
+    if entity.is_aggregation() && matches!(query.order, EntityOrder::Default) {
+        let ts = entity.field(kw::TIMESTAMP).unwrap();
+        query.order = EntityOrder::Descending(ts.name.to_string(), ts.value_type);
+    }
+    Ok((query, fields))
+}
+
+/// Checks whether the query for an aggregation asks to roll up all
+/// matching buckets into one, and whether that is possible. Rolling up
+/// requires that the timestamp is restricted from below and above, that
+/// the query only selects aggregates that can be combined across buckets,
+/// and that it does not sort by child attributes
+fn build_rollup(
+    entity: &ObjectOrInterface,
+    field: &a::Field,
+    schema: &InputSchema,
+    order: &EntityOrder,
+) -> Result<bool, QueryExecutionError> {
+    match field.argument_value("rollup") {
+        Some(r::Value::Boolean(true)) => { /* checked below */ }
+        Some(r::Value::Boolean(false)) | Some(r::Value::Null) | None => return Ok(false),
+        _ => unreachable!("rollup is a Boolean with a default value"),
+    }
+
+    let has_bound = |ops: &[&str]| match field.argument_value("where") {
+        Some(r::Value::Object(filter)) => ops
+            .iter()
+            .any(|op| filter.get(&format!("{}_{}", kw::TIMESTAMP, op)).is_some()),
+        _ => false,
+    };
+    if !has_bound(&["gt", "gte"]) || !has_bound(&["lt", "lte"]) {
+        return Err(QueryExecutionError::NotSupported(format!(
+            "rolling up `{}` requires a filter on `timestamp_gte` and `timestamp_lt`",
+            field.name
+        )));
+    }
+
+    fn sorts_by_child(order: &EntityOrder) -> bool {
+        match order {
+            EntityOrder::ChildAscending(_) | EntityOrder::ChildDescending(_) => true,
+            EntityOrder::Multi(orders) => orders.iter().any(sorts_by_child),
+            _ => false,
+        }
+    }
+    if sorts_by_child(order) {
+        return Err(QueryExecutionError::NotSupported(format!(
+            "rolling up `{}` does not support sorting by child attributes",
+            field.name
+        )));
+    }
+
+    let entity_type = entity
+        .object_types()
+        .into_iter()
+        .next()
+        .expect("an aggregation is an object type");
+    let aggregation = schema
+        .agg_mappings()
+        .find(|mapping| mapping.agg_type(schema) == entity_type)
+        .map(|mapping| mapping.aggregation(schema))
+        .ok_or_else(|| {
+            QueryExecutionError::NotSupported(format!(
+                "`{}` is not an aggregation and can not be rolled up",
+                field.name
+            ))
+        })?;
+    for (_, fields) in field.selection_set.fields() {
+        for selected in fields {
+            let aggregate = aggregation
+                .aggregates
+                .iter()
+                .find(|aggregate| aggregate.name.as_str() == selected.name);
+            if let Some(aggregate) = aggregate {
+                if !aggregate.can_roll_up() {
+                    return Err(QueryExecutionError::NotSupported(format!(
+                        "the aggregate `{}` in `{}` can not be rolled up",
+                        aggregate.name, field.name
+                    )));
+                }
+            }
+        }
+    }
+    Ok(true)
+}
+
 /// Parses GraphQL arguments into a EntityRange, if present.
 fn build_range(
     field: &a::Field,
@@ -91,9 +448,64 @@ fn build_range(
     Ok(EntityRange {
         first: Some(first),
         skip,
+        after: None,
+        before: None,
     })
 }
 
+/// Parses the `after` and `before` arguments into cursors for the range
+/// of `query`. Cursors encode the value of the attribute that the query
+/// sorts by, and this must therefore only be called once `query.order`
+/// will not change anymore.
+///
+/// When only `before` is given, the page we want are the entities just
+/// before the cursor, not the first ones in the collection. We get them
+/// by reversing `query.order` and looking after the cursor instead; in
+/// that case, this returns `true` and the caller needs to reverse the
+/// entities that the query returns
+pub(crate) fn build_cursors(
+    query: &mut EntityQuery,
+    entity: &ObjectOrInterface,
+    field: &a::Field,
+) -> Result<bool, QueryExecutionError> {
+    let id_type = match entity.object_types().into_iter().next() {
+        Some(entity_type) => entity_type.id_type()?,
+        // There are no entities that we could page through
+        None => return Ok(false),
+    };
+    let cursor = |name: &str| -> Result<Option<EntityCursor>, QueryExecutionError> {
+        match field.argument_value(name) {
+            Some(r::Value::String(cursor)) => EntityCursor::decode(cursor, &query.order, id_type)
+                .map(Some)
+                .map_err(|e| QueryExecutionError::ValueParseError(name.to_string(), e)),
+            Some(r::Value::Null) | None => Ok(None),
+            _ => unreachable!("cursors are Strings"),
+        }
+    };
+    let after = cursor("after")?;
+    let before = cursor("before")?;
+    match (after, before) {
+        (None, Some(before)) => {
+            // Reversing the order does not change the cursors since
+            // they only depend on the attributes we sort by
+            query.order = query.order.reversed(id_type).ok_or_else(|| {
+                QueryExecutionError::ValueParseError(
+                    "before".to_string(),
+                    "cursors require sorting by `id` or attributes of the entity".to_string(),
+                )
+            })?;
+            query.range.after = Some(before);
+            query.range.before = None;
+            Ok(true)
+        }
+        (after, before) => {
+            query.range.after = after;
+            query.range.before = before;
+            Ok(false)
+        }
+    }
+}
+
 /// Parses GraphQL arguments into an EntityFilter, if present.
 fn build_filter(
     entity: &ObjectOrInterface,
@@ -186,6 +598,16 @@ fn build_entity_filter(
         FilterOp::EndsWithNoCase => Ok(EntityFilter::EndsWithNoCase(field_name, store_value)),
         FilterOp::NotEndsWith => Ok(EntityFilter::NotEndsWith(field_name, store_value)),
         FilterOp::NotEndsWithNoCase => Ok(EntityFilter::NotEndsWithNoCase(field_name, store_value)),
+        FilterOp::EqualNoCase => Ok(EntityFilter::EqualNoCase(field_name, store_value)),
+        FilterOp::InNoCase => Ok(EntityFilter::InNoCase(
+            field_name,
+            list_values(store_value, "_in_nocase")?,
+        )),
+        FilterOp::Matches => Ok(EntityFilter::Matches(field_name, store_value)),
+        FilterOp::Overlaps => Ok(EntityFilter::Overlaps(field_name, store_value)),
+        FilterOp::ContainedBy => Ok(EntityFilter::ContainedBy(field_name, store_value)),
+        FilterOp::LengthGreaterThan => Ok(EntityFilter::LengthGreaterThan(field_name, store_value)),
+        FilterOp::LengthLessThan => Ok(EntityFilter::LengthLessThan(field_name, store_value)),
         FilterOp::Equal => Ok(EntityFilter::Equal(field_name, store_value)),
         _ => unreachable!(),
     }
@@ -253,6 +675,7 @@ fn build_filter_from_object<'a>(
             }
             use self::sast::FilterOp::*;
             let (field_name, op) = sast::parse_field_as_filter(key);
+            let (field_name, op) = disambiguate_filter(entity, key, field_name, op, schema);
 
             Ok(match op {
                 And => {
@@ -311,6 +734,55 @@ fn build_filter_from_object<'a>(
         .collect::<Result<Vec<EntityFilter>, QueryExecutionError>>()
 }
 
+/// The suffixes `_eq_nocase`, `_in_nocase`, `_matches`, `_overlaps`,
+/// `_contained_by` and `_length_gt`/`_length_lt` only apply to string and
+/// list attributes respectively, but they can also be the tail of an
+/// ordinary filter on an attribute whose name happens to end in them, e.g.,
+/// `score_length_gt` for an `Int` attribute `score_length`. If the
+/// attribute the suffix would apply to doesn't exist or has the wrong
+/// type, parse the key without these suffixes.
+fn disambiguate_filter(
+    entity: &ObjectOrInterface,
+    key: &str,
+    field_name: String,
+    op: FilterOp,
+    schema: &InputSchema,
+) -> (String, FilterOp) {
+    // References to other entities are filtered by their id, which is
+    // treated like a string unless the id is `Bytes` or `Int8`
+    let applies = |field_name: &str, list: bool| {
+        entity.field(field_name).map_or(false, |field| {
+            let ty = &field.field_type;
+            if list {
+                ty.is_list()
+            } else {
+                let base = ty.get_base_type();
+                !ty.is_list()
+                    && (base == "String" || schema.object_or_interface(base, None).is_some())
+            }
+        })
+    };
+
+    match op {
+        FilterOp::EqualNoCase | FilterOp::InNoCase | FilterOp::Matches
+            if !applies(&field_name, false) =>
+        {
+            (key.to_owned(), FilterOp::Equal)
+        }
+        FilterOp::Overlaps | FilterOp::ContainedBy if !applies(&field_name, true) => {
+            (key.to_owned(), FilterOp::Equal)
+        }
+        FilterOp::LengthGreaterThan if !applies(&field_name, true) => (
+            key.trim_end_matches("_gt").to_owned(),
+            FilterOp::GreaterThan,
+        ),
+        FilterOp::LengthLessThan if !applies(&field_name, true) => {
+            (key.trim_end_matches("_lt").to_owned(), FilterOp::LessThan)
+        }
+        _ => (field_name, op),
+    }
+}
+
 fn build_child_filter_from_object(
     entity: &ObjectOrInterface,
     field_name: String,
@@ -459,10 +931,59 @@ fn build_order(
     field: &a::Field,
     schema: &InputSchema,
 ) -> Result<EntityOrder, QueryExecutionError> {
-    let order = match (
-        build_order_by(entity, field, schema)?,
-        build_order_direction(field)?,
-    ) {
+    let direction = build_order_direction(field.argument_value("orderDirection"))?;
+    if let Some(r::Value::List(order_by_fields)) = field.argument_value("orderByFields") {
+        if matches!(field.argument_value("orderBy"), Some(r::Value::Enum(_))) {
+            return Err(QueryExecutionError::NotSupported(
+                "`orderByFields` can not be combined with `orderBy`".to_string(),
+            ));
+        }
+        if !order_by_fields.is_empty() {
+            return build_multi_order(entity, order_by_fields, direction, schema);
+        }
+    }
+    entity_order(build_order_by(entity, field, schema)?, direction)
+}
+
+/// Parses the `orderByFields` argument, `[{ field, direction }]`, into an
+/// order that sorts by each of the given fields in turn. Entries without a
+/// direction are sorted in `direction`, the value of `orderDirection`
+fn build_multi_order(
+    entity: &ObjectOrInterface<'_>,
+    order_by_fields: &[r::Value],
+    direction: OrderDirection,
+    schema: &InputSchema,
+) -> Result<EntityOrder, QueryExecutionError> {
+    let orders = order_by_fields
+        .iter()
+        .map(|entry| {
+            let invalid = || {
+                QueryExecutionError::ValueParseError("orderByFields".to_string(), entry.to_string())
+            };
+            let (name, entry_direction) = match entry {
+                r::Value::Object(entry) => match entry.get("field") {
+                    Some(r::Value::Enum(name)) => (name, entry.get("direction")),
+                    _ => return Err(invalid()),
+                },
+                _ => return Err(invalid()),
+            };
+            let direction = match entry_direction {
+                None | Some(r::Value::Null) => direction,
+                Some(value) => build_order_direction(Some(value))?,
+            };
+            entity_order(Some(build_order_by_name(entity, name, schema)?), direction)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(EntityOrder::multi(orders))
+}
+
+/// Turns the attribute to order by, if there is one, and the direction
+/// into an `EntityOrder`
+fn entity_order(
+    order_by: Option<(String, ValueType, Option<OrderByChild>)>,
+    direction: OrderDirection,
+) -> Result<EntityOrder, QueryExecutionError> {
+    let order = match (order_by, direction) {
         (Some((attr, value_type, None)), OrderDirection::Ascending) => {
             EntityOrder::Ascending(attr, value_type)
         }
@@ -539,88 +1060,95 @@ fn build_order_by(
     schema: &InputSchema,
 ) -> Result<Option<(String, ValueType, Option<OrderByChild>)>, QueryExecutionError> {
     match field.argument_value("orderBy") {
-        Some(r::Value::Enum(name)) => match parse_order_by(name)? {
-            OrderByValue::Direct(name) => {
-                let field = entity.field(&name).ok_or_else(|| {
-                    QueryExecutionError::EntityFieldError(
+        Some(r::Value::Enum(name)) => build_order_by_name(entity, name, schema).map(Some),
+        _ => match field.argument_value("text") {
+            Some(r::Value::Object(filter)) => build_fulltext_order_by_from_object(filter)
+                .map(|order_by| order_by.map(|(attr, value)| (attr, value, None))),
+            None => Ok(None),
+            _ => Err(QueryExecutionError::InvalidFilterError),
+        },
+    }
+}
+
+/// Resolves the value `name` of a `<type>_orderBy` enum into the attribute
+/// to order by, its type, and the child entity it belongs to if it is an
+/// attribute of a child entity
+fn build_order_by_name(
+    entity: &ObjectOrInterface,
+    name: &String,
+    schema: &InputSchema,
+) -> Result<(String, ValueType, Option<OrderByChild>), QueryExecutionError> {
+    match parse_order_by(name)? {
+        OrderByValue::Direct(name) => {
+            let field = entity.field(&name).ok_or_else(|| {
+                QueryExecutionError::EntityFieldError(entity.typename().to_owned(), name.clone())
+            })?;
+            sast::get_field_value_type(&field.field_type)
+                .map(|value_type| (name.clone(), value_type, None))
+                .map_err(|_| {
+                    QueryExecutionError::OrderByNotSupportedError(
                         entity.typename().to_owned(),
                         name.clone(),
                     )
+                })
+        }
+        OrderByValue::Child(parent_field_name, child_field_name) => {
+            // Finds the field that connects the parent entity with the
+            // child entity. Note that `@derivedFrom` is only allowed on
+            // object types.
+            let field = entity
+                .implemented_field(&parent_field_name)
+                .ok_or_else(|| {
+                    QueryExecutionError::EntityFieldError(
+                        entity.typename().to_owned(),
+                        parent_field_name.clone(),
+                    )
+                })?;
+            let derived_from = field.derived_from(schema);
+            let base_type = field.field_type.get_base_type();
+
+            let child_entity = schema
+                .object_or_interface(base_type, None)
+                .ok_or_else(|| QueryExecutionError::NamedTypeError(base_type.into()))?;
+            let child_field = child_entity
+                .field(child_field_name.as_str())
+                .ok_or_else(|| {
+                    QueryExecutionError::EntityFieldError(
+                        child_entity.typename().to_owned(),
+                        child_field_name.clone(),
+                    )
                 })?;
-                sast::get_field_value_type(&field.field_type)
-                    .map(|value_type| Some((name.clone(), value_type, None)))
-                    .map_err(|_| {
-                        QueryExecutionError::OrderByNotSupportedError(
-                            entity.typename().to_owned(),
-                            name.clone(),
-                        )
-                    })
-            }
-            OrderByValue::Child(parent_field_name, child_field_name) => {
-                // Finds the field that connects the parent entity with the
-                // child entity. Note that `@derivedFrom` is only allowed on
-                // object types.
-                let field = entity
-                    .implemented_field(&parent_field_name)
-                    .ok_or_else(|| {
-                        QueryExecutionError::EntityFieldError(
-                            entity.typename().to_owned(),
-                            parent_field_name.clone(),
-                        )
-                    })?;
-                let derived_from = field.derived_from(schema);
-                let base_type = field.field_type.get_base_type();
-
-                let child_entity = schema
-                    .object_or_interface(base_type, None)
-                    .ok_or_else(|| QueryExecutionError::NamedTypeError(base_type.into()))?;
-                let child_field =
-                    child_entity
-                        .field(child_field_name.as_str())
-                        .ok_or_else(|| {
-                            QueryExecutionError::EntityFieldError(
-                                child_entity.typename().to_owned(),
-                                child_field_name.clone(),
-                            )
-                        })?;
-
-                let (join_attribute, derived) = match derived_from {
-                    Some(child_field) => (child_field.name.to_string(), true),
-                    None => (parent_field_name, false),
-                };
 
-                let child = match child_entity {
-                    ObjectOrInterface::Object(_, _) => OrderByChild::Object(ObjectOrderDetails {
-                        entity_type: schema.entity_type(base_type)?,
+            let (join_attribute, derived) = match derived_from {
+                Some(child_field) => (child_field.name.to_string(), true),
+                None => (parent_field_name, false),
+            };
+
+            let child = match child_entity {
+                ObjectOrInterface::Object(_, _) => OrderByChild::Object(ObjectOrderDetails {
+                    entity_type: schema.entity_type(base_type)?,
+                    join_attribute,
+                    derived,
+                }),
+                ObjectOrInterface::Interface(_, _) => {
+                    let entity_types = child_entity.object_types();
+                    OrderByChild::Interface(InterfaceOrderDetails {
+                        entity_types,
                         join_attribute,
                         derived,
-                    }),
-                    ObjectOrInterface::Interface(_, _) => {
-                        let entity_types = child_entity.object_types();
-                        OrderByChild::Interface(InterfaceOrderDetails {
-                            entity_types,
-                            join_attribute,
-                            derived,
-                        })
-                    }
-                };
-
-                sast::get_field_value_type(&child_field.field_type)
-                    .map(|value_type| Some((child_field_name.clone(), value_type, Some(child))))
-                    .map_err(|_| {
-                        QueryExecutionError::OrderByNotSupportedError(
-                            child_entity.typename().to_owned(),
-                            child_field_name.clone(),
-                        )
                     })
-            }
-        },
-        _ => match field.argument_value("text") {
-            Some(r::Value::Object(filter)) => build_fulltext_order_by_from_object(filter)
-                .map(|order_by| order_by.map(|(attr, value)| (attr, value, None))),
-            None => Ok(None),
-            _ => Err(QueryExecutionError::InvalidFilterError),
-        },
+                }
+            };
+
+            sast::get_field_value_type(&child_field.field_type)
+                .map(|value_type| (child_field_name.clone(), value_type, Some(child)))
+                .map_err(|_| {
+                    QueryExecutionError::OrderByNotSupportedError(
+                        child_entity.typename().to_owned(),
+                        child_field_name.clone(),
+                    )
+                })
+        }
     }
 }
 
@@ -639,10 +1167,10 @@ fn build_fulltext_order_by_from_object(
     )
 }
 
-/// Parses GraphQL arguments into a EntityOrder, if present.
-fn build_order_direction(field: &a::Field) -> Result<OrderDirection, QueryExecutionError> {
-    Ok(field
-        .argument_value("orderDirection")
+/// Parses the value of an `orderDirection` argument, defaulting to
+/// ascending order
+fn build_order_direction(value: Option<&r::Value>) -> Result<OrderDirection, QueryExecutionError> {
+    Ok(value
         .map(|value| match value {
             r::Value::Enum(name) if name == "asc" => OrderDirection::Ascending,
             r::Value::Enum(name) if name == "desc" => OrderDirection::Descending,
@@ -653,7 +1181,7 @@ fn build_order_direction(field: &a::Field) -> Result<OrderDirection, QueryExecut
 
 #[cfg(test)]
 mod tests {
-    use graph::components::store::EntityQuery;
+    use graph::components::store::{EntityCursor, EntityQuery};
     use graph::data::store::ID;
     use graph::env::ENV_VARS;
     use graph::{
@@ -666,12 +1194,12 @@ mod tests {
             AttributeNames, DeploymentHash, EntityCollection, EntityFilter, EntityOrder,
             EntityRange, Value, ValueType, BLOCK_NUMBER_MAX,
         },
-        schema::{EntityType, InputSchema},
+        schema::{AggregationInterval, EntityType, InputSchema},
     };
     use std::collections::BTreeSet;
     use std::{iter::FromIterator, sync::Arc};
 
-    use super::{a, build_query};
+    use super::{a, build_cursors, build_query};
 
     const DEFAULT_OBJECT: &str = "DefaultObject";
     const ENTITY1: &str = "Entity1";
@@ -687,6 +1215,24 @@ mod tests {
                 name: String
                 email: String
             }
+            type Post @entity {
+                id: ID!
+                title: String!
+                tags: [String!]!
+                score: Int!
+                score_length: Int!
+            }
+            type Data @entity(timeseries: true) {
+                id: Int8!
+                timestamp: Timestamp!
+                price: BigDecimal!
+            }
+            type Stats @aggregation(intervals: ["hour"], source: "Data") {
+                id: Int8!
+                timestamp: Timestamp!
+                sum: BigDecimal! @aggregate(fn: "sum", arg: "price")
+                avg: BigDecimal! @aggregate(fn: "avg", arg: "price")
+            }
         "#;
 
             let id = DeploymentHash::new("id").unwrap();
@@ -909,6 +1455,82 @@ mod tests {
         assert_eq!(query(&field).order, EntityOrder::Default);
     }
 
+    #[test]
+    fn build_query_parses_order_by_fields() {
+        fn order_by_field(name: &str, direction: Option<&str>) -> r::Value {
+            let mut entries = vec![("field".into(), r::Value::Enum(name.to_string()))];
+            if let Some(direction) = direction {
+                entries.push(("direction".into(), r::Value::Enum(direction.to_string())));
+            }
+            r::Value::Object(Object::from_iter(entries))
+        }
+
+        let field = default_field_with(
+            "orderByFields",
+            r::Value::List(vec![
+                order_by_field("name", Some("desc")),
+                order_by_field("email", None),
+            ]),
+        );
+        assert_eq!(
+            query(&field).order,
+            EntityOrder::Multi(vec![
+                EntityOrder::Descending("name".to_string(), ValueType::String),
+                EntityOrder::Ascending("email".to_string(), ValueType::String),
+            ])
+        );
+
+        // Fields without a direction use `orderDirection`
+        let field = default_field_with_vec(vec![
+            (
+                "orderByFields",
+                r::Value::List(vec![
+                    order_by_field("name", Some("asc")),
+                    order_by_field("email", None),
+                ]),
+            ),
+            ("orderDirection", r::Value::Enum("desc".to_string())),
+        ]);
+        assert_eq!(
+            query(&field).order,
+            EntityOrder::Multi(vec![
+                EntityOrder::Ascending("name".to_string(), ValueType::String),
+                EntityOrder::Descending("email".to_string(), ValueType::String),
+            ])
+        );
+
+        // A single field is the same as `orderBy: email`
+        let field = default_field_with(
+            "orderByFields",
+            r::Value::List(vec![order_by_field("email", Some("desc"))]),
+        );
+        assert_eq!(
+            query(&field).order,
+            EntityOrder::Descending("email".to_string(), ValueType::String)
+        );
+
+        // `orderBy` and `orderByFields` can not be used together
+        let field = default_field_with_vec(vec![
+            ("orderBy", r::Value::Enum("name".to_string())),
+            (
+                "orderByFields",
+                r::Value::List(vec![order_by_field("email", None)]),
+            ),
+        ]);
+        let object = INPUT_SCHEMA
+            .object_or_interface(DEFAULT_OBJECT, None)
+            .unwrap();
+        assert!(build_query(
+            &object,
+            BLOCK_NUMBER_MAX,
+            &field,
+            std::u32::MAX,
+            std::u32::MAX,
+            &INPUT_SCHEMA,
+        )
+        .is_err());
+    }
+
     #[test]
     fn build_query_yields_default_range_if_none_is_present() {
         assert_eq!(query(&default_field()).range, EntityRange::first(100));
@@ -924,10 +1546,104 @@ mod tests {
             EntityRange {
                 first: Some(100),
                 skip: 50,
+                after: None,
+                before: None,
             },
         );
     }
 
+    #[test]
+    fn build_cursors_decodes_after_and_before() {
+        let object = INPUT_SCHEMA
+            .object_or_interface(DEFAULT_OBJECT, None)
+            .unwrap();
+        let cursor_name = EntityCursor::encode(
+            &[&r::Value::String("Bob".to_string())],
+            &r::Value::String("1".to_string()),
+        );
+
+        let field = default_field_with_vec(vec![
+            ("orderBy", r::Value::Enum("name".to_string())),
+            ("after", r::Value::String(cursor_name.clone())),
+        ]);
+        let mut q = query(&field);
+        build_cursors(&mut q, &object, &field).unwrap();
+        assert_eq!(
+            q.range.after,
+            Some(EntityCursor {
+                values: vec![Value::String("Bob".to_string())],
+                id: Value::String("1".to_string()),
+            })
+        );
+        assert_eq!(q.range.before, None);
+
+        // A `before` cursor on its own pages backwards: the query looks
+        // after the cursor in the reversed order
+        let field = default_field_with_vec(vec![
+            ("orderBy", r::Value::Enum("name".to_string())),
+            ("before", r::Value::String(cursor_name.clone())),
+        ]);
+        let mut q = query(&field);
+        assert!(build_cursors(&mut q, &object, &field).unwrap());
+        assert_eq!(
+            q.order,
+            EntityOrder::Descending("name".to_string(), ValueType::String)
+        );
+        assert_eq!(
+            q.range.after,
+            Some(EntityCursor {
+                values: vec![Value::String("Bob".to_string())],
+                id: Value::String("1".to_string()),
+            })
+        );
+        assert_eq!(q.range.before, None);
+
+        // Cursors for a collection sorted by several attributes have a
+        // value for each of them
+        let cursor = EntityCursor::encode(
+            &[
+                &r::Value::String("Bob".to_string()),
+                &r::Value::String("bob@example.com".to_string()),
+            ],
+            &r::Value::String("1".to_string()),
+        );
+        let order_by = |name: &str| {
+            r::Value::Object(Object::from_iter(vec![(
+                "field".into(),
+                r::Value::Enum(name.to_string()),
+            )]))
+        };
+        let field = default_field_with_vec(vec![
+            (
+                "orderByFields",
+                r::Value::List(vec![order_by("name"), order_by("email")]),
+            ),
+            ("after", r::Value::String(cursor)),
+        ]);
+        let mut q = query(&field);
+        build_cursors(&mut q, &object, &field).unwrap();
+        assert_eq!(
+            q.range.after,
+            Some(EntityCursor {
+                values: vec![
+                    Value::String("Bob".to_string()),
+                    Value::String("bob@example.com".to_string())
+                ],
+                id: Value::String("1".to_string()),
+            })
+        );
+
+        // The cursor has a value for `name` which a query that sorts by
+        // `id` can not use
+        let field = default_field_with("before", r::Value::String(cursor_name));
+        let mut q = query(&field);
+        assert!(build_cursors(&mut q, &object, &field).is_err());
+
+        let field = default_field_with("after", r::Value::String("garbage".to_string()));
+        let mut q = query(&field);
+        assert!(build_cursors(&mut q, &object, &field).is_err());
+    }
+
     #[test]
     fn build_query_yields_filters() {
         let query_field = default_field_with(
@@ -946,6 +1662,68 @@ mod tests {
         )
     }
 
+    #[test]
+    fn build_query_yields_nocase_regex_and_list_filters() {
+        let filter = |key: &str, value: r::Value| {
+            let field = field_with(
+                "Post",
+                "where",
+                r::Value::Object(Object::from_iter(vec![(key.into(), value)])),
+            );
+            match query(&field).filter {
+                Some(EntityFilter::And(mut filters)) if filters.len() == 1 => {
+                    filters.pop().unwrap()
+                }
+                filter => panic!("unexpected filter {filter:?}"),
+            }
+        };
+        let string = |s: &str| r::Value::String(s.to_string());
+        let strings = |ss: &[&str]| r::Value::List(ss.iter().map(|s| string(s)).collect());
+        let values =
+            |ss: &[&str]| Value::List(ss.iter().map(|s| Value::String(s.to_string())).collect());
+
+        assert_eq!(
+            filter("title_eq_nocase", string("Hello")),
+            EntityFilter::EqualNoCase("title".to_string(), Value::String("Hello".to_string()))
+        );
+        assert_eq!(
+            filter("title_in_nocase", strings(&["a", "B"])),
+            EntityFilter::InNoCase(
+                "title".to_string(),
+                vec![
+                    Value::String("a".to_string()),
+                    Value::String("B".to_string())
+                ]
+            )
+        );
+        assert_eq!(
+            filter("title_matches", string("^h.*o$")),
+            EntityFilter::Matches("title".to_string(), Value::String("^h.*o$".to_string()))
+        );
+        assert_eq!(
+            filter("tags_overlaps", strings(&["a", "b"])),
+            EntityFilter::Overlaps("tags".to_string(), values(&["a", "b"]))
+        );
+        assert_eq!(
+            filter("tags_contained_by", strings(&["a", "b"])),
+            EntityFilter::ContainedBy("tags".to_string(), values(&["a", "b"]))
+        );
+        assert_eq!(
+            filter("tags_length_gt", r::Value::Int(2)),
+            EntityFilter::LengthGreaterThan("tags".to_string(), Value::Int(2))
+        );
+        assert_eq!(
+            filter("tags_length_lt", r::Value::Int(5)),
+            EntityFilter::LengthLessThan("tags".to_string(), Value::Int(5))
+        );
+
+        // `score` is not a list, so this is a comparison on `score_length`
+        assert_eq!(
+            filter("score_length_gt", r::Value::Int(2)),
+            EntityFilter::GreaterThan("score_length".to_string(), Value::Int(2))
+        );
+    }
+
     #[test]
     fn build_query_yields_block_change_gte_filter() {
         let query_field = default_field_with(
@@ -963,4 +1741,72 @@ mod tests {
             Some(EntityFilter::And(vec![EntityFilter::ChangeBlockGte(10)]))
         )
     }
+
+    #[test]
+    fn build_query_rolls_up_aggregations() {
+        use graph::data::query::QueryExecutionError;
+        use graph::data::store::scalar::Timestamp;
+
+        let object = INPUT_SCHEMA
+            .object_or_interface("Stats", Some(AggregationInterval::Hour))
+            .unwrap();
+        let build = |args: Vec<(&str, r::Value)>, selected: Option<&str>| {
+            let mut field = field_with_vec("Stats", args);
+            if let Some(name) = selected {
+                let selected = a::Field {
+                    name: name.to_string(),
+                    selection_set: a::SelectionSet::new(vec![]),
+                    ..field_with_vec("Stats", vec![])
+                };
+                field.selection_set.push(&selected).unwrap();
+            }
+            build_query(
+                &object,
+                BLOCK_NUMBER_MAX,
+                &field,
+                std::u32::MAX,
+                std::u32::MAX,
+                &INPUT_SCHEMA,
+            )
+        };
+        let ts = |secs| r::Value::Timestamp(Timestamp::since_epoch(secs, 0).unwrap());
+        let range = |ops: &[&str]| {
+            r::Value::Object(Object::from_iter(
+                ops.iter()
+                    .map(|op| (format!("timestamp_{op}").into(), ts(1_700_000_000))),
+            ))
+        };
+
+        let query = build(vec![("where", range(&["gte", "lt"]))], None).unwrap();
+        assert!(!query.rollup);
+
+        let query = build(
+            vec![
+                ("rollup", r::Value::Boolean(true)),
+                ("where", range(&["gte", "lt"])),
+            ],
+            Some("sum"),
+        )
+        .unwrap();
+        assert!(query.rollup);
+
+        // The timestamp must be bounded from below and above
+        for ops in [&["gte"][..], &["lt"], &[]] {
+            let res = build(
+                vec![("rollup", r::Value::Boolean(true)), ("where", range(ops))],
+                None,
+            );
+            assert!(matches!(res, Err(QueryExecutionError::NotSupported(_))));
+        }
+
+        // Non-cumulative averages can not be combined across buckets
+        let res = build(
+            vec![
+                ("rollup", r::Value::Boolean(true)),
+                ("where", range(&["gte", "lt"])),
+            ],
+            Some("avg"),
+        );
+        assert!(matches!(res, Err(QueryExecutionError::NotSupported(_))));
+    }
 }
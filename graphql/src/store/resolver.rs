@@ -1,7 +1,7 @@
 use std::collections::BTreeMap;
 use std::sync::Arc;
 
-use graph::components::graphql::GraphQLMetrics as _;
+use graph::components::graphql::{GraphQLMetrics as _, QueryExport};
 use graph::components::store::QueryPermit;
 use graph::data::graphql::load_manager::LoadManager;
 use graph::data::graphql::{object, ObjectOrInterface};
@@ -24,7 +24,6 @@ use crate::query::ext::BlockConstraint;
 /// A resolver that fetches entities from a `Store`.
 #[derive(Clone, CheapClone)]
 pub struct StoreResolver {
-    #[allow(dead_code)]
     logger: Logger,
     pub(crate) store: Arc<dyn QueryStore>,
     pub(crate) block_ptr: Option<BlockPtr>,
@@ -76,6 +75,33 @@ impl StoreResolver {
             .unwrap_or(BLOCK_NUMBER_MAX)
     }
 
+    /// Start an export of all entities that the collection which is the
+    /// only field in `selection_set` matches. An export sends entities as
+    /// it reads them and has no way to report indexing errors alongside
+    /// them; unless the query allows them, we therefore refuse to export
+    /// from a subgraph with indexing errors
+    pub fn export(
+        &self,
+        selection_set: &a::SelectionSet,
+        query_id: String,
+        page_size: usize,
+    ) -> Result<QueryExport, QueryExecutionError> {
+        if self.has_non_fatal_errors && self.error_policy == ErrorPolicy::Deny {
+            return Err(QueryExecutionError::NotSupported(format!(
+                "the subgraph has indexing errors at block {}; use `subgraphError: allow` \
+                 to export its entities anyway",
+                self.block_number()
+            )));
+        }
+        let input_schema = self.store.input_schema()?;
+        let (mut query, fields) =
+            super::query::build_export_query(selection_set, self.block_number(), &input_schema)?;
+        query.query_id = Some(query_id);
+        query.logger = Some(self.logger.cheap_clone());
+        let pages = self.store.export(query, page_size)?;
+        Ok(QueryExport::new(fields, pages))
+    }
+
     /// Locate all the blocks needed for the query by resolving block
     /// constraints and return the selection sets with the blocks at which
     /// they should be executed
@@ -142,6 +168,24 @@ impl StoreResolver {
                     }
                     ptr
                 }
+                BlockConstraint::Timestamp(timestamp) => {
+                    let number = store
+                        .block_number_for_timestamp(timestamp)
+                        .await?
+                        .ok_or_else(|| {
+                            QueryExecutionError::ValueParseError(
+                                "block.timestamp_lte".to_owned(),
+                                format!(
+                                    "subgraph {} has no block at or before timestamp {}",
+                                    state.id, timestamp
+                                ),
+                            )
+                        })?;
+                    block_queryable(state, number)?;
+                    // Like for queries by number, we do not know the hash
+                    // See 7a7b9708-adb7-4fc2-acec-88680cb07ec1
+                    BlockPtr::new(BlockHash::zero(), number)
+                }
                 BlockConstraint::Latest => state.latest_block.cheap_clone(),
             };
             ptrs_and_sels.push((ptr, sel));
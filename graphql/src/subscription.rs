@@ -0,0 +1,260 @@
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use graph::components::store::EntityOperation;
+use graph::data::query::{QueryResults, QueryTarget};
+use graph::prelude::{
+    debug, lazy_static, warn, BlockNumber, CheapClone, DeploymentState, GraphQlRunner, Logger,
+    Query, QueryExecutionError, QueryStore, ENV_VARS,
+};
+use graph::tokio::{
+    self,
+    sync::{mpsc, OnceCell},
+};
+use lru_time_cache::LruCache;
+
+/// How many blocks we remember the changed entity types for, across all
+/// deployments
+const BLOCK_CHANGES_CACHE_SIZE: usize = 1000;
+
+/// The deployment id, its reorg count, and the block number
+type BlockKey = (i32, u32, BlockNumber);
+
+lazy_static! {
+    /// The entity types that changed in a block. All subscriptions share
+    /// them so that the changes in a block of a deployment are only read
+    /// once, no matter how many subscriptions are watching it. Since a
+    /// revert can change what happened at a block number, the reorg count
+    /// of the deployment is part of the key
+    static ref BLOCK_CHANGES: Mutex<LruCache<BlockKey, Arc<OnceCell<Arc<BTreeSet<String>>>>>> =
+        Mutex::new(LruCache::with_capacity(BLOCK_CHANGES_CACHE_SIZE));
+}
+
+/// Run `query` every time the deployment behind `store` changes in a way
+/// that might change its result, and send the results to `sender`. The
+/// query is run once right away. `types` are the entity types the query
+/// reads, or `None` if any change should cause the query to be rerun.
+///
+/// Since we wait for `sender` to accept each result before looking for
+/// changes again, a slow subscriber only ever misses intermediate
+/// results, and we never run the query more often than the subscriber
+/// can keep up with. Watching stops as soon as the subscriber goes away
+pub(crate) async fn watch<R: GraphQlRunner>(
+    logger: Logger,
+    runner: Arc<R>,
+    store: Arc<dyn QueryStore>,
+    query: Query,
+    target: QueryTarget,
+    types: Option<BTreeSet<String>>,
+    mut state: DeploymentState,
+    sender: mpsc::Sender<QueryResults>,
+) {
+    let mut rerun = true;
+    loop {
+        if rerun {
+            let result = runner
+                .cheap_clone()
+                .run_query(query.clone(), target.clone())
+                .await;
+            if sender.send(result).await.is_err() {
+                break;
+            }
+        }
+
+        tokio::select! {
+            _ = sender.closed() => break,
+            _ = tokio::time::sleep(ENV_VARS.graphql.subscription_poll_interval) => {}
+        }
+
+        let next = match store.deployment_state().await {
+            Ok(next) => next,
+            Err(e) => {
+                warn!(logger, "Failed to check subscription for changes";
+                      "error" => e.to_string());
+                rerun = false;
+                continue;
+            }
+        };
+        let block_types =
+            |block: BlockNumber| changed_types(store.as_ref(), next.reorg_count, block);
+        rerun = match changed(types.as_ref(), &state, &next, block_types).await {
+            Ok(changed) => changed,
+            Err(e) => {
+                // Rather send the same result again than miss a change
+                warn!(logger, "Failed to look up entity changes for subscription";
+                      "error" => e.to_string());
+                true
+            }
+        };
+        state = next;
+    }
+    debug!(logger, "Subscription ended"; "block" => state.latest_block.number);
+}
+
+/// Check whether going from `prev` to `next` might change the result of a
+/// query that reads the entity types `types`. `block_types` returns the
+/// entity types that changed in a block
+async fn changed<F, Fut>(
+    types: Option<&BTreeSet<String>>,
+    prev: &DeploymentState,
+    next: &DeploymentState,
+    mut block_types: F,
+) -> Result<bool, QueryExecutionError>
+where
+    F: FnMut(BlockNumber) -> Fut,
+    Fut: Future<Output = Result<Arc<BTreeSet<String>>, QueryExecutionError>>,
+{
+    // Reverted blocks can have changed anything that we already sent
+    if next.reorg_count != prev.reorg_count {
+        return Ok(true);
+    }
+    if next.latest_block.number <= prev.latest_block.number {
+        return Ok(next.latest_block != prev.latest_block);
+    }
+
+    let types = match types {
+        Some(types) => types,
+        None => return Ok(true),
+    };
+    let blocks = prev.latest_block.number + 1..=next.latest_block.number;
+    if blocks.clone().count() > ENV_VARS.graphql.subscription_max_block_scan as usize {
+        return Ok(true);
+    }
+    for block in blocks {
+        if !block_types(block).await?.is_disjoint(types) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// The names of the entity types that changed in `block`. Concurrent
+/// callers for the same block wait for the first one to read the changes
+/// from the store instead of reading them themselves
+async fn changed_types(
+    store: &dyn QueryStore,
+    reorg_count: u32,
+    block: BlockNumber,
+) -> Result<Arc<BTreeSet<String>>, QueryExecutionError> {
+    let key = (store.deployment_id().0, reorg_count, block);
+    let cell = {
+        let mut cache = BLOCK_CHANGES.lock().unwrap();
+        match cache.get(&key).cloned() {
+            Some(cell) => cell,
+            None => {
+                let cell = Arc::new(OnceCell::new());
+                cache.insert(key, cell.clone());
+                cell
+            }
+        }
+    };
+    cell.get_or_try_init(|| async {
+        let types = store
+            .entity_changes_in_block(block)
+            .await?
+            .iter()
+            .map(|op| match op {
+                EntityOperation::Set { key, .. } | EntityOperation::Remove { key } => {
+                    key.entity_type.typename().to_string()
+                }
+            })
+            .collect();
+        Ok::<_, QueryExecutionError>(Arc::new(types))
+    })
+    .await
+    .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeSet, HashMap};
+    use std::sync::Arc;
+
+    use graph::prelude::{BlockNumber, BlockPtr, DeploymentHash, DeploymentState};
+
+    use super::changed;
+
+    fn state(reorg_count: u32, block: BlockNumber, hash: u8) -> DeploymentState {
+        DeploymentState {
+            id: DeploymentHash::new("subscription").unwrap(),
+            reorg_count,
+            max_reorg_depth: 0,
+            latest_block: BlockPtr::from((vec![hash], block)),
+            earliest_block_number: 0,
+            first_error_block: None,
+        }
+    }
+
+    fn set(names: &[&str]) -> BTreeSet<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    /// Run `changed` for a query that reads `types`, where `blocks` maps
+    /// block numbers to the entity types that changed in them
+    fn check(
+        types: Option<&[&str]>,
+        prev: &DeploymentState,
+        next: &DeploymentState,
+        blocks: &[(BlockNumber, &[&str])],
+    ) -> bool {
+        let types = types.map(set);
+        let blocks: HashMap<_, _> = blocks
+            .iter()
+            .map(|(block, names)| (*block, Arc::new(set(names))))
+            .collect();
+        let block_types = |block: BlockNumber| {
+            let types = blocks.get(&block).cloned().unwrap_or_default();
+            async move { Ok(types) }
+        };
+        graph::futures03::executor::block_on(changed(types.as_ref(), prev, next, block_types))
+            .unwrap()
+    }
+
+    #[test]
+    fn changed_checks_blocks_for_types() {
+        let musician: Option<&[&str]> = Some(&["Musician"]);
+        let blocks: &[(BlockNumber, &[&str])] = &[(11, &["Band"]), (12, &["Musician"])];
+
+        // Nothing happened
+        assert!(!check(
+            musician,
+            &state(0, 10, 10),
+            &state(0, 10, 10),
+            blocks
+        ));
+        // Only other types changed
+        assert!(!check(
+            musician,
+            &state(0, 10, 10),
+            &state(0, 11, 11),
+            blocks
+        ));
+        // A block changed a type the query reads
+        assert!(check(
+            musician,
+            &state(0, 10, 10),
+            &state(0, 12, 12),
+            blocks
+        ));
+        assert!(check(
+            Some(&["Band", "Musician"]),
+            &state(0, 10, 10),
+            &state(0, 11, 11),
+            blocks
+        ));
+        // Without types, any new block is a change
+        assert!(check(None, &state(0, 10, 10), &state(0, 11, 11), blocks));
+    }
+
+    #[test]
+    fn changed_after_revert() {
+        let musician: Option<&[&str]> = Some(&["Musician"]);
+
+        // A revert can change anything we already sent
+        assert!(check(musician, &state(0, 10, 10), &state(1, 11, 11), &[]));
+        assert!(check(musician, &state(0, 10, 10), &state(1, 9, 9), &[]));
+        // Same block number, but a different block
+        assert!(check(musician, &state(0, 10, 10), &state(0, 10, 20), &[]));
+    }
+}
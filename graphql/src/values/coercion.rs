@@ -154,6 +154,19 @@ pub(crate) fn coerce_input_value<'a>(
     ))
 }
 
+/// Expand the shorthand `orderByFields: name` into the list
+/// `orderByFields: [{ field: name }]` that the type of the argument calls
+/// for. Values of other arguments must not be passed through this since
+/// GraphQL does not allow the shorthand in general
+pub(crate) fn expand_order_by_fields(value: r::Value) -> r::Value {
+    match value {
+        r::Value::Enum(name) | r::Value::String(name) => r::Value::List(vec![r::Value::object(
+            BTreeMap::from([("field".into(), r::Value::Enum(name))]),
+        )]),
+        value => value,
+    }
+}
+
 /// On error, the `value` is returned as `Err(value)`.
 pub(crate) fn coerce_value<'a>(
     value: r::Value,
@@ -213,7 +226,7 @@ pub(crate) fn coerce_value<'a>(
 mod tests {
     use graph::prelude::{r::Value, s};
 
-    use super::coerce_to_definition;
+    use super::{coerce_to_definition, coerce_value, expand_order_by_fields};
 
     #[test]
     fn coercion_using_enum_type_definitions_is_correct() {
@@ -444,4 +457,76 @@ mod tests {
             Ok(Value::Int((-13289123_i32).into()))
         );
     }
+
+    #[test]
+    fn expand_order_by_fields_shorthand() {
+        let input_value = |name: &str, value_type: s::Type| s::InputValue {
+            position: s::Pos::default(),
+            description: None,
+            name: name.to_string(),
+            value_type,
+            default_value: None,
+            directives: vec![],
+        };
+        let order_by = s::TypeDefinition::Enum(s::EnumType {
+            name: "User_orderBy".to_string(),
+            description: None,
+            directives: vec![],
+            position: s::Pos::default(),
+            values: vec![s::EnumValue {
+                name: "name".to_string(),
+                position: s::Pos::default(),
+                description: None,
+                directives: vec![],
+            }],
+        });
+        let order_by_field = s::TypeDefinition::InputObject(s::InputObjectType {
+            name: "User_orderByField".to_string(),
+            description: None,
+            directives: vec![],
+            position: s::Pos::default(),
+            fields: vec![
+                input_value(
+                    "field",
+                    s::Type::NonNullType(Box::new(s::Type::NamedType("User_orderBy".to_string()))),
+                ),
+                input_value(
+                    "direction",
+                    s::Type::NamedType("OrderDirection".to_string()),
+                ),
+            ],
+        });
+        let resolver = |name: &str| match name {
+            "User_orderBy" => Some(&order_by),
+            "User_orderByField" => Some(&order_by_field),
+            _ => None,
+        };
+        let ty = s::Type::ListType(Box::new(s::Type::NonNullType(Box::new(
+            s::Type::NamedType("User_orderByField".to_string()),
+        ))));
+        let entry = Value::List(vec![Value::object(
+            vec![("field".into(), Value::Enum("name".to_string()))]
+                .into_iter()
+                .collect(),
+        )]);
+
+        // `orderByFields: name` is the same as `orderByFields: [{ field: name }]`,
+        // and variables pass enum values as strings
+        for shorthand in [
+            Value::Enum("name".to_string()),
+            Value::String("name".to_string()),
+        ] {
+            let expanded = expand_order_by_fields(shorthand);
+            assert_eq!(expanded, entry);
+            assert_eq!(coerce_value(expanded, &ty, &resolver), Ok(entry.clone()));
+        }
+        // Lists are left alone
+        assert_eq!(expand_order_by_fields(entry.clone()), entry);
+
+        // Without expanding it, a single value can not be used for a list
+        assert!(coerce_value(Value::Enum("name".to_string()), &ty, &resolver).is_err());
+        // Values that are not in the enum can not be used
+        let expanded = expand_order_by_fields(Value::Enum("age".to_string()));
+        assert!(coerce_value(expanded, &ty, &resolver).is_err());
+    }
 }
@@ -22,10 +22,12 @@ use graph_graphql::prelude::GraphQlRunner;
 use graph_node::config::{self, Config as Cfg};
 use graph_node::manager::color::Terminal;
 use graph_node::manager::commands;
+use graph_node::manager::commands::prune::History;
 use graph_node::network_setup::Networks;
 use graph_node::{
     manager::deployment::DeploymentSearch, store_builder::StoreBuilder, MetricsContext,
 };
+use graph_store_postgres::command_support::index::AdviceOptions;
 use graph_store_postgres::{
     BlockStore, ChainStore, ConnectionPool, NotificationSender, PoolCoordinator, Shard, Store,
     SubgraphStore, SubscriptionManager, PRIMARY_SHARD,
@@ -34,7 +36,7 @@ use itertools::Itertools;
 use lazy_static::lazy_static;
 use std::env;
 use std::str::FromStr;
-use std::{collections::HashMap, num::ParseIntError, sync::Arc, time::Duration};
+use std::{collections::HashMap, num::ParseIntError, path::PathBuf, sync::Arc, time::Duration};
 const VERSION_LABEL_KEY: &str = "version";
 
 git_testament!(TESTAMENT);
@@ -309,6 +311,14 @@ pub enum Command {
     #[clap(subcommand)]
     Database(DatabaseCommand),
 
+    /// Manage aggregations
+    #[clap(subcommand)]
+    Aggregation(AggregationCommand),
+
+    /// Change the schema of a deployment
+    #[clap(subcommand)]
+    Schema(SchemaCommand),
+
     /// Deploy a subgraph
     Deploy {
         name: DeploymentSearch,
@@ -668,10 +678,11 @@ pub enum PruneCommand {
         /// GRAPH_STORE_HISTORY_DELETE_THRESHOLD
         #[clap(long, short)]
         delete_threshold: Option<f64>,
-        /// How much history to keep in blocks. Defaults to
-        /// GRAPH_MIN_HISTORY_BLOCKS
+        /// How much history to keep, either in blocks or as a duration
+        /// like `30d`, `12h`, `90m` or `3600s`. Defaults to
+        /// GRAPH_MIN_HISTORY_BLOCKS blocks
         #[clap(long, short = 'y')]
-        history: Option<usize>,
+        history: Option<History>,
         /// Prune only this once
         #[clap(long, short)]
         once: bool,
@@ -695,10 +706,11 @@ pub enum PruneCommand {
         /// GRAPH_STORE_HISTORY_DELETE_THRESHOLD
         #[clap(long, short)]
         delete_threshold: Option<f64>,
-        /// How much history to keep in blocks. Defaults to
-        /// GRAPH_MIN_HISTORY_BLOCKS
+        /// How much history to keep, either in blocks or as a duration
+        /// like `30d`, `12h`, `90m` or `3600s`. Defaults to
+        /// GRAPH_MIN_HISTORY_BLOCKS blocks
         #[clap(long, short = 'y')]
-        history: Option<usize>,
+        history: Option<History>,
     },
     /// Show the status of a pruning operation
     Status {
@@ -784,6 +796,83 @@ pub enum IndexCommand {
         #[clap(value_parser = clap::builder::NonEmptyStringValueParser::new())]
         index_name: String,
     },
+
+    /// Recommend indexes based on the queries that were sampled for a
+    /// deployment
+    ///
+    /// Queries are only sampled when `GRAPH_STORE_INDEX_ADVISOR_SAMPLE_RATE`
+    /// is set on the query nodes. Besides indexes that would speed up slow
+    /// queries, this also lists attribute indexes that have never been used
+    Advise {
+        /// Only consider query shapes that were sampled at least this often
+        #[clap(long, default_value = "10")]
+        min_queries: i64,
+        /// Only consider query shapes whose queries took at least this many
+        /// milliseconds on average
+        #[clap(long, default_value = "50")]
+        min_avg_ms: i64,
+        /// Only consider query shapes that were sampled in the last this
+        /// many hours
+        #[clap(long)]
+        max_age_hours: Option<u64>,
+        /// Recommend at most this many indexes
+        #[clap(long)]
+        max_indexes: Option<usize>,
+        /// Never recommend this index again, for example after dropping an
+        /// index that was created automatically. Can be given more than once
+        #[clap(long, value_name = "INDEX")]
+        exclude: Vec<String>,
+        /// Recommend an index that was excluded with `--exclude` again. Can
+        /// be given more than once
+        #[clap(long, value_name = "INDEX")]
+        include: Vec<String>,
+        /// Create the recommended indexes concurrently
+        #[clap(long)]
+        create: bool,
+        /// Print SQL statements instead of a more human readable overview
+        #[clap(long, conflicts_with = "create")]
+        sql: bool,
+        /// The deployment (see `help info`).
+        deployment: DeploymentSearch,
+    },
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum SchemaCommand {
+    /// Add to the schema of a deployment without redeploying it
+    ///
+    /// The new schema may only add entity types, nullable attributes,
+    /// fulltext fields and enum values to the current schema of the
+    /// deployment. New attributes are null for all existing entities. The
+    /// deployment must be paused or unassigned; when it is resumed, it
+    /// uses the new schema. Changes that need different mappings still
+    /// require a new deployment
+    Patch {
+        /// Only print the changes to the database schema
+        #[clap(long, short)]
+        dry_run: bool,
+        /// The deployment (see `help info`)
+        deployment: DeploymentSearch,
+        /// The file with the new GraphQL schema
+        schema: PathBuf,
+    },
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum AggregationCommand {
+    /// Fill an aggregation from the data in its source timeseries
+    ///
+    /// This is meant for aggregations that were added in a graft or copy
+    /// of an existing deployment; such aggregations only contain data from
+    /// the point where the new deployment started indexing. Any data that
+    /// the aggregation already has is replaced. The deployment must be
+    /// paused or unassigned while the backfill runs.
+    Backfill {
+        /// The deployment (see `help info`)
+        deployment: DeploymentSearch,
+        /// The name of the aggregation, e.g., `Stats`
+        aggregation: String,
+    },
 }
 
 #[derive(Clone, Debug, Subcommand)]
@@ -1609,6 +1698,78 @@ async fn main() -> anyhow::Result<()> {
                     commands::index::drop(subgraph_store, primary_pool, deployment, &index_name)
                         .await
                 }
+                Advise {
+                    deployment,
+                    min_queries,
+                    min_avg_ms,
+                    max_age_hours,
+                    max_indexes,
+                    exclude,
+                    include,
+                    create,
+                    sql,
+                } => {
+                    let opts = AdviceOptions {
+                        min_queries,
+                        min_avg_ms,
+                        max_age: max_age_hours.map(|hours| Duration::from_secs(hours * 3600)),
+                        max_indexes,
+                    };
+                    commands::index::exclude_advice(
+                        &subgraph_store,
+                        &primary_pool,
+                        &deployment,
+                        &exclude,
+                        &include,
+                    )?;
+                    commands::index::advise(
+                        subgraph_store,
+                        primary_pool,
+                        deployment,
+                        opts,
+                        create,
+                        sql,
+                    )
+                    .await
+                }
+            }
+        }
+        Aggregation(cmd) => {
+            use AggregationCommand::*;
+            match cmd {
+                Backfill {
+                    deployment,
+                    aggregation,
+                } => {
+                    let (store, primary_pool) = ctx.store_and_primary();
+                    let deployment = make_deployment_selector(deployment);
+                    commands::aggregation::backfill::run(
+                        primary_pool,
+                        store,
+                        deployment,
+                        aggregation,
+                    )
+                    .await
+                }
+            }
+        }
+        Schema(cmd) => {
+            use SchemaCommand::*;
+            match cmd {
+                Patch {
+                    dry_run,
+                    deployment,
+                    schema,
+                } => {
+                    let (store, primary_pool) = ctx.store_and_primary();
+                    commands::schema::patch(
+                        store.subgraph_store(),
+                        primary_pool,
+                        deployment,
+                        schema,
+                        dry_run,
+                    )
+                }
             }
         }
         Database(cmd) => {
@@ -1640,7 +1801,10 @@ async fn main() -> anyhow::Result<()> {
                     once,
                 } => {
                     let (store, primary_pool) = ctx.store_and_primary();
-                    let history = history.unwrap_or(ENV_VARS.min_history_blocks.try_into()?);
+                    let history = match history {
+                        Some(history) => history,
+                        None => History::Blocks(ENV_VARS.min_history_blocks.try_into()?),
+                    };
                     commands::prune::run(
                         store,
                         primary_pool,
@@ -1659,7 +1823,10 @@ async fn main() -> anyhow::Result<()> {
                     history,
                 } => {
                     let (store, primary_pool) = ctx.store_and_primary();
-                    let history = history.unwrap_or(ENV_VARS.min_history_blocks.try_into()?);
+                    let history = match history {
+                        Some(history) => history,
+                        None => History::Blocks(ENV_VARS.min_history_blocks.try_into()?),
+                    };
                     commands::prune::set(
                         store,
                         primary_pool,
@@ -31,8 +31,8 @@ use graph_server_index_node::IndexNodeServer;
 use graph_server_json_rpc::JsonRpcServer;
 use graph_server_metrics::PrometheusMetricsServer;
 use graph_store_postgres::{
-    register_jobs as register_store_jobs, ChainHeadUpdateListener, ConnectionPool,
-    NotificationSender, Store, SubgraphStore, SubscriptionManager,
+    register_jobs as register_store_jobs, register_query_jobs, ChainHeadUpdateListener,
+    ConnectionPool, NotificationSender, Store, SubgraphStore, SubscriptionManager,
 };
 use graphman_server::GraphmanServer;
 use graphman_server::GraphmanServerConfig;
@@ -342,9 +342,10 @@ fn build_graphql_server(
         &logger,
         network_store.clone(),
         load_manager,
-        metrics_registry,
+        metrics_registry.clone(),
     ));
-    let graphql_server = GraphQLQueryServer::new(&logger_factory, graphql_runner.clone());
+    let graphql_server =
+        GraphQLQueryServer::new(&logger_factory, metrics_registry, graphql_runner.clone());
 
     graphql_server
 }
@@ -522,6 +523,12 @@ pub async fn run(opt: Opt, env_vars: Arc<EnvVars>, dev_ctx: Option<DevModeContex
             .await;
         }
 
+        // Every node that runs queries needs to write the query shapes it
+        // samples for the index advisor
+        let mut query_job_runner = graph::util::jobs::Runner::new(&logger);
+        register_query_jobs(&mut query_job_runner, network_store.subgraph_store());
+        graph::spawn_blocking(query_job_runner.start());
+
         let subgraph_registrar = build_subgraph_registrar(
             metrics_registry.clone(),
             &network_store,
@@ -0,0 +1,28 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::Result;
+use graph_store_postgres::ConnectionPool;
+use graph_store_postgres::Store;
+use graphman::commands::aggregation::backfill::{backfill_aggregation, load_inactive_deployment};
+use graphman::deployment::DeploymentSelector;
+
+pub async fn run(
+    primary_pool: ConnectionPool,
+    store: Arc<Store>,
+    deployment: DeploymentSelector,
+    aggregation: String,
+) -> Result<()> {
+    let deployment = load_inactive_deployment(primary_pool, &deployment)?;
+
+    println!(
+        "Backfilling aggregation {} for deployment {} ...",
+        aggregation,
+        deployment.locator()
+    );
+    let start = Instant::now();
+    let count = backfill_aggregation(store, &deployment, &aggregation).await?;
+    println!("Wrote {} rows in {}s", count, start.elapsed().as_secs());
+
+    Ok(())
+}
@@ -5,7 +5,7 @@ use graph::{
     prelude::{anyhow, StoreError},
 };
 use graph_store_postgres::{
-    command_support::index::{CreateIndex, Method},
+    command_support::index::{AdviceOptions, CreateIndex, Method},
     ConnectionPool, SubgraphStore,
 };
 use std::io::Write as _;
@@ -214,3 +214,118 @@ pub async fn drop(
     println!("Dropped index {index_name}");
     Ok(())
 }
+
+/// Stop recommending the indexes in `exclude` for a deployment, and
+/// recommend the ones in `include` again
+pub fn exclude_advice(
+    store: &SubgraphStore,
+    pool: &ConnectionPool,
+    search: &DeploymentSearch,
+    exclude: &[String],
+    include: &[String],
+) -> Result<(), anyhow::Error> {
+    if exclude.is_empty() && include.is_empty() {
+        return Ok(());
+    }
+    let deployment_locator = search.locate_unique(pool)?;
+    if !exclude.is_empty() {
+        store.exclude_index_advice(&deployment_locator, exclude, true)?;
+        println!("Excluded {} from recommendations", exclude.join(", "));
+    }
+    if !include.is_empty() {
+        store.exclude_index_advice(&deployment_locator, include, false)?;
+        println!("Included {} in recommendations again", include.join(", "));
+    }
+    Ok(())
+}
+
+pub async fn advise(
+    store: Arc<SubgraphStore>,
+    pool: ConnectionPool,
+    search: DeploymentSearch,
+    opts: AdviceOptions,
+    create: bool,
+    to_sql: bool,
+) -> Result<(), anyhow::Error> {
+    let deployment_locator = search.locate_unique(&pool)?;
+    if create {
+        println!("Creating recommended indexes. Please wait.");
+    }
+    let advice = store
+        .advise_indexes(&deployment_locator, opts, create)
+        .await?;
+
+    let mut term = Terminal::new();
+
+    if to_sql {
+        for index in &advice.create {
+            writeln!(term, "{};", index.sql)?;
+        }
+        for index in &advice.drop {
+            writeln!(term, "{};", index.sql)?;
+        }
+        return Ok(());
+    }
+
+    term.bold()?;
+    if create {
+        writeln!(term, "Created indexes")?;
+    } else {
+        writeln!(term, "Recommended indexes")?;
+    }
+    term.reset()?;
+    writeln!(term, "{:-^76}", "")?;
+    if advice.create.is_empty() {
+        writeln!(term, "  none")?;
+    }
+    for index in &advice.create {
+        term.green()?;
+        write!(term, "{}", index.name)?;
+        term.reset()?;
+        writeln!(
+            term,
+            " on {}({})",
+            index.table,
+            index.columns.iter().join(", ")
+        )?;
+        if !index.predicates.is_empty() {
+            writeln!(term, "  where {}", index.predicates.iter().join(" and "))?;
+        }
+        writeln!(
+            term,
+            "  {} queries, avg {}ms, max {}ms",
+            index.queries, index.avg_ms, index.max_ms
+        )?;
+    }
+
+    writeln!(term)?;
+    term.bold()?;
+    writeln!(term, "Unused attribute indexes")?;
+    term.reset()?;
+    writeln!(term, "{:-^76}", "")?;
+    if advice.replicas {
+        writeln!(
+            term,
+            "  not checked: the shard has read replicas, and index usage is only \
+             tracked\n  on the primary, so indexes that only replicas use would \
+             look unused"
+        )?;
+    } else if advice.drop.is_empty() {
+        writeln!(term, "  none")?;
+    }
+    for index in &advice.drop {
+        term.green()?;
+        write!(term, "{}", index.name)?;
+        term.reset()?;
+        writeln!(term, " on {} ({})", index.table, index.size)?;
+    }
+    if !advice.drop.is_empty() {
+        writeln!(
+            term,
+            "\nIndex usage is only tracked since the statistics of the database \
+             were last reset.\nCheck that the indexes are really unused before \
+             dropping them with `graphman index drop`"
+        )?;
+    }
+    Ok(())
+}
@@ -1,3 +1,4 @@
+pub mod aggregation;
 pub mod assign;
 pub mod chain;
 pub mod check_blocks;
@@ -15,6 +16,7 @@ pub mod query;
 pub mod remove;
 pub mod rewind;
 pub mod run;
+pub mod schema;
 pub mod stats;
 pub mod txn_speed;
 pub mod unused_deployments;
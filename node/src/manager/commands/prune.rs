@@ -1,6 +1,7 @@
 use std::{
     collections::HashSet,
     io::Write,
+    str::FromStr,
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -162,21 +163,78 @@ impl PruneReporter for Progress {
     }
 }
 
+/// How much history to keep, either as a number of blocks or as a
+/// duration like `30d`, `12h`, `90m` or `3600s`. A duration is resolved to
+/// a number of blocks using the timestamps of the blocks of the chain
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum History {
+    Blocks(usize),
+    Duration(Duration),
+}
+
+impl FromStr for History {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            anyhow!("invalid history `{s}`: expected a number of blocks or a duration like `30d`")
+        };
+
+        let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+            Some(pos) => s.split_at(pos),
+            None => return s.parse().map(History::Blocks).map_err(|_| invalid()),
+        };
+        let number: u64 = number.parse().map_err(|_| invalid())?;
+        let unit = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let secs = number
+            .checked_mul(unit)
+            .filter(|secs| *secs > 0 && *secs <= i32::MAX as u64)
+            .ok_or_else(invalid)?;
+        Ok(History::Duration(Duration::from_secs(secs)))
+    }
+}
+
 struct Args {
     history: BlockNumber,
+    /// Set when the history was given as a duration
+    history_seconds: Option<i32>,
     deployment: DeploymentLocator,
     earliest_block: BlockNumber,
     latest_block: BlockNumber,
 }
 
-fn check_args(
+async fn check_args(
     store: &Arc<Store>,
     primary_pool: ConnectionPool,
     search: DeploymentSearch,
-    history: usize,
+    history: History,
 ) -> Result<Args, anyhow::Error> {
-    let history = history as BlockNumber;
     let deployment = search.locate_unique(&primary_pool)?;
+    let (history, history_seconds) = match history {
+        History::Blocks(blocks) => (blocks as BlockNumber, None),
+        History::Duration(duration) => {
+            let blocks = store
+                .history_blocks_for_duration(&deployment, duration)
+                .await?
+                .ok_or_else(|| {
+                    anyhow!(
+                        "deployment {deployment} does not have {}s of history yet",
+                        duration.as_secs()
+                    )
+                })?;
+            println!(
+                "{}s of history are currently {blocks} blocks",
+                duration.as_secs()
+            );
+            (blocks, Some(duration.as_secs() as i32))
+        }
+    };
     let mut info = store
         .status(status::Filter::DeploymentIds(vec![deployment.id]))?
         .pop()
@@ -197,6 +255,7 @@ fn check_args(
     }
     Ok(Args {
         history,
+        history_seconds,
         deployment,
         earliest_block: status.earliest_block_number,
         latest_block,
@@ -244,25 +303,29 @@ async fn run_inner(
     store: Arc<Store>,
     primary_pool: ConnectionPool,
     search: DeploymentSearch,
-    history: usize,
+    history: History,
     rebuild_threshold: Option<f64>,
     delete_threshold: Option<f64>,
     once: bool,
     do_first_prune: bool,
 ) -> Result<(), anyhow::Error> {
-    let args = check_args(&store, primary_pool, search, history)?;
+    let args = check_args(&store, primary_pool, search, history).await?;
 
     if do_first_prune {
         first_prune(&store, &args, rebuild_threshold, delete_threshold).await?;
     }
 
-    // Only after everything worked out, make the history setting permanent
+    // Only after everything worked out, make the history setting
+    // permanent. For a duration, `history_blocks` is recomputed whenever
+    // the deployment is pruned
     if !once {
-        store.subgraph_store().set_history_blocks(
+        let subgraph_store = store.subgraph_store();
+        subgraph_store.set_history_blocks(
             &args.deployment,
             args.history,
             ENV_VARS.reorg_threshold(),
         )?;
+        subgraph_store.set_history_seconds(&args.deployment, args.history_seconds)?;
     }
 
     Ok(())
@@ -272,7 +335,7 @@ pub async fn run(
     store: Arc<Store>,
     primary_pool: ConnectionPool,
     search: DeploymentSearch,
-    history: usize,
+    history: History,
     rebuild_threshold: Option<f64>,
     delete_threshold: Option<f64>,
     once: bool,
@@ -294,7 +357,7 @@ pub async fn set(
     store: Arc<Store>,
     primary_pool: ConnectionPool,
     search: DeploymentSearch,
-    history: usize,
+    history: History,
     rebuild_threshold: Option<f64>,
     delete_threshold: Option<f64>,
 ) -> Result<(), anyhow::Error> {
@@ -433,3 +496,30 @@ pub async fn status(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_history() {
+        assert_eq!(History::Blocks(10000), "10000".parse().unwrap());
+        assert_eq!(
+            History::Duration(Duration::from_secs(30 * 24 * 60 * 60)),
+            "30d".parse().unwrap()
+        );
+        assert_eq!(
+            History::Duration(Duration::from_secs(90 * 60)),
+            "90m".parse().unwrap()
+        );
+        assert_eq!(
+            History::Duration(Duration::from_secs(3600)),
+            "3600s".parse().unwrap()
+        );
+        assert!("".parse::<History>().is_err());
+        assert!("0h".parse::<History>().is_err());
+        assert!("12w".parse::<History>().is_err());
+        assert!("d".parse::<History>().is_err());
+        assert!("100000000d".parse::<History>().is_err());
+    }
+}
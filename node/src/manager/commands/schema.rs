@@ -0,0 +1,31 @@
+use std::{fs, path::PathBuf, sync::Arc};
+
+use graph::prelude::anyhow::{self, Context as _};
+use graph_store_postgres::{ConnectionPool, SubgraphStore};
+
+use crate::manager::deployment::DeploymentSearch;
+
+pub fn patch(
+    store: Arc<SubgraphStore>,
+    primary_pool: ConnectionPool,
+    search: DeploymentSearch,
+    schema: PathBuf,
+    dry_run: bool,
+) -> Result<(), anyhow::Error> {
+    let deployment = search.locate_unique(&primary_pool)?;
+    let schema = fs::read_to_string(&schema)
+        .with_context(|| format!("failed to read schema from {}", schema.display()))?;
+
+    let ddl = store.patch_schema(&deployment, &schema, dry_run)?;
+    let ddl = if ddl.trim().is_empty() {
+        "-- no changes to the database schema\n"
+    } else {
+        ddl.as_str()
+    };
+    if dry_run {
+        println!("Patching the schema of {} would run:\n{}", deployment, ddl);
+    } else {
+        println!("Patched the schema of {} with:\n{}", deployment, ddl);
+    }
+    Ok(())
+}
@@ -5,4 +5,5 @@ use async_graphql::Enum;
 #[graphql(remote = "graphman_store::CommandKind")]
 pub enum CommandKind {
     RestartDeployment,
+    BackfillAggregation,
 }
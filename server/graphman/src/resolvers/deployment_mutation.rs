@@ -15,6 +15,7 @@ use crate::entities::EmptyResponse;
 use crate::entities::ExecutionId;
 use crate::resolvers::context::GraphmanContext;
 
+mod backfill_aggregation;
 mod create;
 mod pause;
 mod reassign;
@@ -81,6 +82,24 @@ impl DeploymentMutation {
         restart::run_in_background(ctx, store, deployment, delay_seconds).await
     }
 
+    /// Fills an aggregation from the data in its source timeseries.
+    ///
+    /// This is meant for aggregations that were added in a graft or copy of
+    /// a deployment. Any data the aggregation already has is replaced. The
+    /// deployment must be paused or unassigned.
+    pub async fn backfill_aggregation(
+        &self,
+        ctx: &Context<'_>,
+        deployment: DeploymentSelector,
+        #[graphql(desc = "The name of the aggregation, e.g., `Stats`.")] aggregation: String,
+    ) -> Result<ExecutionId> {
+        let store = ctx.data::<Arc<GraphmanStore>>()?.to_owned();
+        let ctx = GraphmanContext::new(ctx)?;
+        let deployment = deployment.try_into()?;
+
+        backfill_aggregation::run_in_background(ctx, store, deployment, aggregation).await
+    }
+
     /// Create a subgraph
     pub async fn create(&self, ctx: &Context<'_>, name: String) -> Result<EmptyResponse> {
         let ctx = GraphmanContext::new(ctx)?;
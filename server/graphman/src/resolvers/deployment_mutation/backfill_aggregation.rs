@@ -0,0 +1,41 @@
+use std::sync::Arc;
+
+use async_graphql::Result;
+use graph_store_postgres::graphman::GraphmanStore;
+use graphman::commands::aggregation::backfill::{backfill_aggregation, load_inactive_deployment};
+use graphman::deployment::DeploymentSelector;
+use graphman::GraphmanExecutionTracker;
+use graphman_store::CommandKind;
+use graphman_store::GraphmanStore as _;
+
+use crate::entities::ExecutionId;
+use crate::resolvers::context::GraphmanContext;
+
+pub async fn run_in_background(
+    ctx: GraphmanContext,
+    store: Arc<GraphmanStore>,
+    deployment: DeploymentSelector,
+    aggregation: String,
+) -> Result<ExecutionId> {
+    // Check that the deployment can be backfilled before we start so that
+    // obvious mistakes are reported right away
+    let deployment = load_inactive_deployment(ctx.primary_pool.clone(), &deployment)?;
+
+    let id = store.new_execution(CommandKind::BackfillAggregation)?;
+
+    graph::spawn(async move {
+        let tracker = GraphmanExecutionTracker::new(store, id);
+        let result = backfill_aggregation(ctx.store.clone(), &deployment, &aggregation).await;
+
+        match result {
+            Ok(_) => {
+                tracker.track_success().unwrap();
+            }
+            Err(err) => {
+                tracker.track_failure(format!("{err:#?}")).unwrap();
+            }
+        };
+    });
+
+    Ok(id.into())
+}
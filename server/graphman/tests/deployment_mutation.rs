@@ -269,6 +269,31 @@ fn graphql_allows_tracking_restart_deployment_executions() {
     });
 }
 
+#[test]
+fn graphql_cannot_backfill_aggregation_of_active_deployment() {
+    run_test(|| async {
+        let deployment_hash = DeploymentHash::new("subgraph_1").unwrap();
+        create_test_subgraph(&deployment_hash, TEST_SUBGRAPH_SCHEMA).await;
+
+        let resp = send_graphql_request(
+            json!({
+                "query": r#"mutation {
+                    deployment {
+                        backfillAggregation(deployment: { hash: "subgraph_1" }, aggregation: "Stats")
+                    }
+                }"#
+            }),
+            VALID_TOKEN,
+        )
+        .await;
+
+        assert!(resp["errors"][0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("pause or unassign it"));
+    });
+}
+
 #[test]
 fn graphql_can_create_new_subgraph() {
     run_test(|| async {
@@ -0,0 +1,321 @@
+//! Exports stream all entities that a query for one collection matches as
+//! newline-delimited JSON or CSV. The store reads the entities through a
+//! server-side cursor a page at a time, and each page is encoded and sent
+//! as soon as it arrives so that only one page is ever held in memory
+
+use graph::components::server::query::ServerError;
+use graph::data::query::{Query, QueryExecutionError};
+use graph::data::value::Object;
+use graph::hyper::body::{Bytes, Frame};
+use graph::prelude::{q, r, serde_json};
+use graph::url::form_urlencoded;
+use serde::Serializer;
+
+/// Arguments that the export sets itself and that clients can not pass
+const PAGING_ARGS: [&str; 4] = ["first", "skip", "after", "before"];
+
+/// The body of the response to an export
+pub type ExportBody = graph::http_body_util::StreamBody<
+    graph::tokio_stream::wrappers::ReceiverStream<Result<Frame<Bytes>, ServerError>>,
+>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    NdJson,
+    Csv,
+}
+
+impl ExportFormat {
+    /// Get the format from the `format` parameter in the query string of
+    /// a request; without one, we export newline-delimited JSON
+    pub fn from_query_string(query: Option<&str>) -> Result<Self, ServerError> {
+        let format = query.and_then(|query| {
+            form_urlencoded::parse(query.as_bytes())
+                .find(|(key, _)| key == "format")
+                .map(|(_, value)| value.into_owned())
+        });
+        match format.as_deref() {
+            None | Some("ndjson") => Ok(ExportFormat::NdJson),
+            Some("csv") => Ok(ExportFormat::Csv),
+            Some(format) => Err(ServerError::ClientError(format!(
+                "Unknown export format `{}`, use `ndjson` or `csv`",
+                format
+            ))),
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::NdJson => "application/x-ndjson",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+}
+
+/// The state of an export between pages
+pub struct Export {
+    format: ExportFormat,
+    page_size: usize,
+    /// Whether we have written the CSV header
+    header: bool,
+    done: bool,
+}
+
+impl Export {
+    /// Check that `query` can be exported: it has to select exactly one
+    /// collection field, and leave paging through it to the export
+    pub fn new(query: &Query, format: ExportFormat, page_size: usize) -> Result<Self, ServerError> {
+        let field = collection_field(&query.document)?;
+        if let Some((arg, _)) = field
+            .arguments
+            .iter()
+            .find(|(name, _)| PAGING_ARGS.contains(&name.as_str()))
+        {
+            return Err(ServerError::ClientError(format!(
+                "Exports page through the entities themselves and do not accept \
+                 the `{}` argument",
+                arg
+            )));
+        }
+
+        Ok(Export {
+            format,
+            page_size,
+            header: false,
+            done: false,
+        })
+    }
+
+    /// Whether all entities have been exported
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Encode the `entities` of one page, whose fields are `columns`. A
+    /// page with fewer than a full page of entities is the last one
+    pub fn page<'a>(
+        &mut self,
+        columns: impl Iterator<Item = &'a str>,
+        entities: &[Object],
+    ) -> Result<Bytes, ServerError> {
+        let mut buf = Vec::new();
+        match self.format {
+            ExportFormat::NdJson => {
+                for entity in entities {
+                    write_json_line(&mut buf, entity)?;
+                }
+            }
+            ExportFormat::Csv => {
+                let columns: Vec<_> = columns.collect();
+                if !self.header {
+                    write_csv_line(&mut buf, columns.iter().copied());
+                    self.header = true;
+                }
+                for entity in entities {
+                    let values: Vec<_> = columns
+                        .iter()
+                        .map(|column| entity.get(column).map(csv_value).unwrap_or_default())
+                        .collect();
+                    write_csv_line(&mut buf, values.iter().map(|value| value.as_str()));
+                }
+            }
+        }
+        self.done = entities.len() < self.page_size;
+        Ok(Bytes::from(buf))
+    }
+
+    /// What we send when reading a page after the first fails. A
+    /// newline-delimited JSON export ends with a line that holds the
+    /// error; there is no way to do that in CSV
+    pub fn error_chunk(&self, error: &QueryExecutionError) -> Option<Bytes> {
+        match self.format {
+            ExportFormat::NdJson => {
+                let errors = serde_json::json!({ "errors": [{ "message": error.to_string() }] });
+                let mut line = serde_json::to_vec(&errors).ok()?;
+                line.push(b'\n');
+                Some(Bytes::from(line))
+            }
+            ExportFormat::Csv => None,
+        }
+    }
+}
+
+pub fn internal_error(msg: &str) -> ServerError {
+    ServerError::InternalError(format!("export failed: {}", msg))
+}
+
+/// The selection set of the only operation in `document`, which has to be
+/// a query
+fn root_selection_set(document: &q::Document) -> Result<&q::SelectionSet, ServerError> {
+    let mut operations = document.definitions.iter().filter_map(|defn| match defn {
+        q::Definition::Operation(op) => Some(op),
+        q::Definition::Fragment(_) => None,
+    });
+    let op = operations
+        .next()
+        .ok_or_else(|| ServerError::ClientError("The export has no query".to_string()))?;
+    if operations.next().is_some() {
+        return Err(ServerError::ClientError(
+            "An export must consist of exactly one query".to_string(),
+        ));
+    }
+    match op {
+        q::OperationDefinition::Query(query) => Ok(&query.selection_set),
+        q::OperationDefinition::SelectionSet(selection_set) => Ok(selection_set),
+        q::OperationDefinition::Mutation(_) | q::OperationDefinition::Subscription(_) => Err(
+            ServerError::ClientError("Only queries can be exported".to_string()),
+        ),
+    }
+}
+
+/// The field that the export pages through
+fn collection_field(document: &q::Document) -> Result<&q::Field, ServerError> {
+    match root_selection_set(document)?.items.as_slice() {
+        [q::Selection::Field(field)] => Ok(field),
+        _ => Err(ServerError::ClientError(
+            "An export must select exactly one collection field".to_string(),
+        )),
+    }
+}
+
+fn write_json_line(buf: &mut Vec<u8>, entity: &Object) -> Result<(), ServerError> {
+    let mut serializer = serde_json::Serializer::new(&mut *buf);
+    serializer
+        .collect_map(entity.iter())
+        .map_err(|e| internal_error(&e.to_string()))?;
+    buf.push(b'\n');
+    Ok(())
+}
+
+fn csv_value(value: &r::Value) -> String {
+    match value {
+        r::Value::Null => String::new(),
+        r::Value::String(s) | r::Value::Enum(s) => s.clone(),
+        r::Value::Int(n) => n.to_string(),
+        r::Value::Float(f) => f.to_string(),
+        r::Value::Boolean(b) => b.to_string(),
+        r::Value::Timestamp(ts) => ts.as_microseconds_since_epoch().to_string(),
+        r::Value::List(_) | r::Value::Object(_) => serde_json::to_string(value).unwrap_or_default(),
+    }
+}
+
+fn write_csv_line<'a>(buf: &mut Vec<u8>, fields: impl Iterator<Item = &'a str>) {
+    for (i, field) in fields.enumerate() {
+        if i > 0 {
+            buf.push(b',');
+        }
+        if field.contains([',', '"', '\n', '\r']) {
+            buf.push(b'"');
+            buf.extend_from_slice(field.replace('"', "\"\"").as_bytes());
+            buf.push(b'"');
+        } else {
+            buf.extend_from_slice(field.as_bytes());
+        }
+    }
+    buf.extend_from_slice(b"\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use graph::data::query::{Query, QueryExecutionError};
+    use graph::data::value::{Object, Word};
+    use graph::prelude::{q, r};
+
+    use super::{write_csv_line, Export, ExportFormat};
+
+    fn query(text: &str) -> Query {
+        let document = q::parse_query(text).unwrap().into_static();
+        Query::new(document, None, false)
+    }
+
+    fn token(id: &str, name: &str) -> Object {
+        Object::from_iter([
+            (Word::from("id"), r::Value::String(id.to_string())),
+            (Word::from("name"), r::Value::String(name.to_string())),
+        ])
+    }
+
+    #[test]
+    fn rejects_paging_arguments_and_several_fields() {
+        for text in [
+            "{ tokens(first: 10) { id } }",
+            "{ tokens(after: \"x\") { id } }",
+            "{ tokens { id } pools { id } }",
+            "mutation { tokens { id } }",
+        ] {
+            let res = Export::new(&query(text), ExportFormat::NdJson, 2);
+            assert!(res.is_err(), "{text} should not be exportable");
+        }
+        // Any order can be exported since the store reads the entities
+        // with a cursor of its own
+        for text in [
+            "{ tokens(orderBy: name) { id } }",
+            "{ tokens(orderBy: owner__name) { id } }",
+            "{ tokens(orderByFields: [{ field: name }, { field: id, direction: desc }]) { id } }",
+        ] {
+            let res = Export::new(&query(text), ExportFormat::NdJson, 2);
+            assert!(res.is_ok(), "{text} should be exportable");
+        }
+    }
+
+    #[test]
+    fn ndjson_has_one_entity_per_line() {
+        let query = query("{ tokens { id name } }");
+        let mut export = Export::new(&query, ExportFormat::NdJson, 2).unwrap();
+        let columns = ["id", "name"];
+
+        let chunk = export
+            .page(columns.into_iter(), &[token("1", "a"), token("2", "a")])
+            .unwrap();
+        assert_eq!(
+            "{\"id\":\"1\",\"name\":\"a\"}\n{\"id\":\"2\",\"name\":\"a\"}\n",
+            std::str::from_utf8(&chunk).unwrap()
+        );
+        assert!(!export.is_done());
+
+        // A short page is the last one
+        let chunk = export
+            .page(columns.into_iter(), &[token("3", "b")])
+            .unwrap();
+        assert_eq!(
+            "{\"id\":\"3\",\"name\":\"b\"}\n",
+            std::str::from_utf8(&chunk).unwrap()
+        );
+        assert!(export.is_done());
+
+        let error = QueryExecutionError::Timeout;
+        let chunk = export.error_chunk(&error).unwrap();
+        assert!(std::str::from_utf8(&chunk)
+            .unwrap()
+            .starts_with("{\"errors\":[{\"message\":"));
+    }
+
+    #[test]
+    fn csv_has_a_header_and_quotes_fields() {
+        let query = query("{ tokens { id name } }");
+        let mut export = Export::new(&query, ExportFormat::Csv, 3).unwrap();
+        let columns = ["id", "name"];
+
+        let chunk = export
+            .page(
+                columns.into_iter(),
+                &[token("1", "plain"), token("2", "a, \"b\"")],
+            )
+            .unwrap();
+        assert_eq!(
+            "id,name\r\n1,plain\r\n2,\"a, \"\"b\"\"\"\r\n",
+            std::str::from_utf8(&chunk).unwrap()
+        );
+        assert!(export.is_done());
+        assert!(export.error_chunk(&QueryExecutionError::Timeout).is_none());
+
+        // An export without entities still has a header
+        let mut export = Export::new(&query, ExportFormat::Csv, 3).unwrap();
+        let chunk = export.page(columns.into_iter(), &[]).unwrap();
+        assert_eq!("id,name\r\n", std::str::from_utf8(&chunk).unwrap());
+
+        let mut buf = Vec::new();
+        write_csv_line(&mut buf, ["", "line\nbreak"].into_iter());
+        assert_eq!(b",\"line\nbreak\"\r\n".as_slice(), buf.as_slice());
+    }
+}
@@ -2,9 +2,13 @@ extern crate graph;
 extern crate graph_graphql;
 extern crate serde;
 
+mod export;
+mod persisted;
+mod rate_limit;
 mod request;
 mod server;
 mod service;
+mod subscription;
 
 pub use self::server::GraphQLServer;
 pub use self::service::GraphQLService;
@@ -0,0 +1,489 @@
+//! Persisted queries: clients can send the sha256 hash of a query instead
+//! of its text once the server knows the query, either because the client
+//! sent the query with its hash before (Automatic Persisted Queries), or
+//! because the query is on the allow-list for the subgraph
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use graph::components::server::query::ServerError;
+use graph::data::query::{QueryError, QueryExecutionError, QueryTarget};
+use graph::prelude::{hex, q, serde_json, DeploymentHash, ENV_VARS};
+use lru_time_cache::LruCache;
+use sha2::{Digest, Sha256};
+
+use crate::request::parse_query_text;
+
+/// How long we use an allow-list before checking whether its file changed
+const ALLOW_LIST_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+fn sha256(text: &str) -> String {
+    hex::encode(Sha256::digest(text.as_bytes()))
+}
+
+fn execution_error(e: QueryExecutionError) -> ServerError {
+    ServerError::QueryError(QueryError::ExecutionError(e))
+}
+
+/// The queries that clients may run against one subgraph, by their hash
+struct AllowList(HashMap<String, q::Document>);
+
+impl AllowList {
+    /// Load an allow-list from a file that contains a JSON object mapping
+    /// the hashes of queries to their text
+    fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read allow-list {}: {}", path.display(), e))?;
+        let queries: HashMap<String, String> = serde_json::from_str(&contents)
+            .map_err(|e| format!("invalid allow-list {}: {}", path.display(), e))?;
+        queries
+            .into_iter()
+            .map(|(hash, text)| {
+                let hash = hash.to_lowercase();
+                if sha256(&text) != hash {
+                    return Err(format!(
+                        "invalid allow-list {}: the hash `{}` does not match its query",
+                        path.display(),
+                        hash
+                    ));
+                }
+                let document = parse_query_text(&text).map_err(|e| {
+                    format!(
+                        "invalid allow-list {}: the query with hash `{}` can not be parsed: {}",
+                        path.display(),
+                        hash,
+                        e
+                    )
+                })?;
+                Ok((hash, document))
+            })
+            .collect::<Result<_, _>>()
+            .map(AllowList)
+    }
+}
+
+/// The names of the allow-lists that apply to queries against a target:
+/// the deployment that the target resolves to and the names of all
+/// subgraphs whose current or pending version is that deployment. That
+/// way, a subgraph whose allow-list is set up by name can not be queried
+/// by its deployment id to get around it, and vice versa
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AllowListKeys(Vec<String>);
+
+impl AllowListKeys {
+    pub fn new(deployment: &DeploymentHash, names: Vec<String>) -> Self {
+        let mut keys = names;
+        keys.push(deployment.to_string());
+        keys.sort();
+        keys.dedup();
+        AllowListKeys(keys)
+    }
+}
+
+/// The allow-list of one subgraph as we last loaded it
+struct CachedAllowList {
+    /// When we last checked whether the file changed
+    checked: Instant,
+    /// The modification time of the file when we loaded it; `None` if there
+    /// is no file, and `Some(None)` if the file system does not report it
+    modified: Option<Option<SystemTime>>,
+    allow_list: Option<Arc<AllowList>>,
+}
+
+/// Parsed persisted queries and the allow-lists for subgraphs
+pub struct PersistedQueries {
+    /// Queries that clients persisted by sending them with their hash.
+    /// `None` if automatic persisted queries are turned off
+    cache: Option<Mutex<LruCache<String, q::Document>>>,
+    allow_list_dir: Option<PathBuf>,
+    /// The allow-list for each subgraph name or deployment that we have
+    /// looked at so far
+    allow_lists: Mutex<HashMap<String, CachedAllowList>>,
+    /// The allow-list keys for the targets we resolved recently, and when
+    /// we resolved them
+    keys: Mutex<HashMap<String, (Instant, AllowListKeys)>>,
+    check_interval: Duration,
+}
+
+impl fmt::Debug for PersistedQueries {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PersistedQueries")
+            .field("allow_list_dir", &self.allow_list_dir)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PersistedQueries {
+    pub fn new() -> Self {
+        Self::with_allow_list_dir(ENV_VARS.graphql.query_allow_list_dir.clone())
+    }
+
+    fn with_allow_list_dir(allow_list_dir: Option<PathBuf>) -> Self {
+        let cache_size = ENV_VARS.graphql.persisted_query_cache_size;
+        PersistedQueries {
+            cache: (cache_size > 0).then(|| Mutex::new(LruCache::with_capacity(cache_size))),
+            allow_list_dir,
+            allow_lists: Mutex::new(HashMap::new()),
+            keys: Mutex::new(HashMap::new()),
+            check_interval: ALLOW_LIST_CHECK_INTERVAL,
+        }
+    }
+
+    /// The keys of the allow-lists that apply to queries against `target`.
+    /// `resolve` finds the deployment that `target` refers to and the names
+    /// of the subgraphs that use it. It is only awaited if allow-lists are
+    /// turned on, and what it returns is reused for a little while
+    pub async fn keys<F>(
+        &self,
+        target: &QueryTarget,
+        resolve: F,
+    ) -> Result<AllowListKeys, ServerError>
+    where
+        F: Future<Output = Result<(DeploymentHash, Vec<String>), QueryExecutionError>>,
+    {
+        if self.allow_list_dir.is_none() {
+            return Ok(AllowListKeys::default());
+        }
+        let key = match target {
+            QueryTarget::Name(name, _) => format!("name/{}", name),
+            QueryTarget::Deployment(id, _) => format!("id/{}", id),
+        };
+        if let Some((checked, keys)) = self.keys.lock().unwrap().get(&key) {
+            if checked.elapsed() < self.check_interval {
+                return Ok(keys.clone());
+            }
+        }
+
+        let (deployment, names) = resolve.await.map_err(execution_error)?;
+        let keys = AllowListKeys::new(&deployment, names);
+        self.keys
+            .lock()
+            .unwrap()
+            .insert(key, (Instant::now(), keys.clone()));
+        Ok(keys)
+    }
+
+    /// The allow-lists for `keys`; a query has to be on all of them
+    fn allow_lists(&self, keys: &AllowListKeys) -> Result<Vec<Arc<AllowList>>, ServerError> {
+        let mut allow_lists = Vec::new();
+        for name in &keys.0 {
+            if let Some(allow_list) = self.allow_list(name)? {
+                allow_lists.push(allow_list);
+            }
+        }
+        Ok(allow_lists)
+    }
+
+    /// Return the allow-list for the subgraph name or deployment `name`,
+    /// reading it again if its file changed since we last read it
+    fn allow_list(&self, name: &str) -> Result<Option<Arc<AllowList>>, ServerError> {
+        let dir = match &self.allow_list_dir {
+            Some(dir) => dir,
+            None => return Ok(None),
+        };
+
+        let mut allow_lists = self.allow_lists.lock().unwrap();
+        if let Some(cached) = allow_lists.get(name) {
+            if cached.checked.elapsed() < self.check_interval {
+                return Ok(cached.allow_list.clone());
+            }
+        }
+
+        let path = dir.join(format!("{}.json", name));
+        let modified = fs::metadata(&path).ok().map(|meta| meta.modified().ok());
+        if let Some(cached) = allow_lists.get_mut(name) {
+            if cached.modified == modified {
+                cached.checked = Instant::now();
+                return Ok(cached.allow_list.clone());
+            }
+        }
+        let allow_list = match modified {
+            Some(_) => Some(Arc::new(
+                AllowList::load(&path).map_err(ServerError::InternalError)?,
+            )),
+            None => None,
+        };
+        allow_lists.insert(
+            name.to_string(),
+            CachedAllowList {
+                checked: Instant::now(),
+                modified,
+                allow_list: allow_list.clone(),
+            },
+        );
+        Ok(allow_list)
+    }
+
+    /// Whether clients can only run the queries on an allow-list against
+    /// the target with `keys`
+    pub fn has_allow_list(&self, keys: &AllowListKeys) -> Result<bool, ServerError> {
+        Ok(!self.allow_lists(keys)?.is_empty())
+    }
+
+    /// Find the document for a request against the target with `keys`
+    /// that has the query text `text` and the hash `hash` from its
+    /// `extensions.persistedQuery.sha256Hash`. Also return the hash of the
+    /// query if it is a persisted query
+    pub fn document(
+        &self,
+        keys: &AllowListKeys,
+        text: Option<&str>,
+        hash: Option<&str>,
+    ) -> Result<(q::Document, Option<String>), ServerError> {
+        let hash = hash.map(str::to_lowercase);
+        if let (Some(text), Some(hash)) = (text, &hash) {
+            if &sha256(text) != hash {
+                return Err(execution_error(
+                    QueryExecutionError::PersistedQueryHashMismatch(hash.clone()),
+                ));
+            }
+        }
+
+        let missing_query = || {
+            ServerError::ClientError(String::from(
+                "The \"query\" field is missing in request data",
+            ))
+        };
+
+        let allow_lists = self.allow_lists(keys)?;
+        if let Some((allow_list, others)) = allow_lists.split_first() {
+            let hash = match (hash, text) {
+                (Some(hash), _) => hash,
+                (None, Some(text)) => sha256(text),
+                (None, None) => return Err(missing_query()),
+            };
+            return match allow_list.0.get(&hash) {
+                Some(document) if others.iter().all(|other| other.0.contains_key(&hash)) => {
+                    Ok((document.clone(), Some(hash)))
+                }
+                _ => Err(execution_error(QueryExecutionError::QueryNotAllowed(hash))),
+            };
+        }
+
+        match (text, hash) {
+            (text, Some(hash)) if self.cache.is_some() => self.persisted(hash, text),
+            (Some(text), _) => Ok((parse_query_text(text)?, None)),
+            (None, Some(_)) => Err(execution_error(QueryExecutionError::PersistedQueryNotFound)),
+            (None, None) => Err(missing_query()),
+        }
+    }
+
+    /// Look up the query with `hash` in the cache of persisted queries, and
+    /// add it to the cache if the client sent its `text`
+    fn persisted(
+        &self,
+        hash: String,
+        text: Option<&str>,
+    ) -> Result<(q::Document, Option<String>), ServerError> {
+        // unwrap: only called when the cache is turned on
+        let cache = self.cache.as_ref().unwrap();
+        if let Some(document) = cache.lock().unwrap().get(&hash) {
+            return Ok((document.clone(), Some(hash)));
+        }
+        let text =
+            text.ok_or_else(|| execution_error(QueryExecutionError::PersistedQueryNotFound))?;
+        let document = parse_query_text(text)?;
+        cache.lock().unwrap().insert(hash.clone(), document.clone());
+        Ok((document, Some(hash)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    use graph::components::server::query::ServerError;
+    use graph::data::query::{QueryError, QueryExecutionError, QueryTarget};
+    use graph::prelude::{lazy_static, DeploymentHash, SubgraphName};
+
+    use super::{sha256, AllowListKeys, PersistedQueries};
+    use crate::request::parse_query_text;
+
+    const QUERY: &str = "{ users { name } }";
+
+    lazy_static! {
+        static ref DEPLOYMENT: DeploymentHash = DeploymentHash::new("QmPersisted").unwrap();
+        static ref KEYS: AllowListKeys =
+            AllowListKeys::new(&DEPLOYMENT, vec!["test/persisted".to_string()]);
+    }
+
+    fn is_execution_error(
+        res: Result<impl std::fmt::Debug, ServerError>,
+        check: impl Fn(&QueryExecutionError) -> bool,
+    ) -> bool {
+        match res {
+            Err(ServerError::QueryError(QueryError::ExecutionError(e))) => check(&e),
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn automatic_persisted_queries() {
+        let queries = PersistedQueries::with_allow_list_dir(None);
+        let hash = sha256(QUERY);
+
+        // The client only sends the hash of a query we don't know yet
+        let res = queries.document(&KEYS, None, Some(&hash));
+        assert!(is_execution_error(res, |e| matches!(
+            e,
+            QueryExecutionError::PersistedQueryNotFound
+        )));
+
+        // The hash doesn't belong to the query
+        let res = queries.document(&KEYS, Some("{ things { id } }"), Some(&hash));
+        assert!(is_execution_error(res, |e| matches!(
+            e,
+            QueryExecutionError::PersistedQueryHashMismatch(_)
+        )));
+
+        // Register the query, after which the hash is enough
+        let (_, persisted) = queries.document(&KEYS, Some(QUERY), Some(&hash)).unwrap();
+        assert_eq!(Some(hash.clone()), persisted);
+        let (document, persisted) = queries.document(&KEYS, None, Some(&hash)).unwrap();
+        assert_eq!(Some(hash), persisted);
+        assert_eq!(parse_query_text(QUERY).unwrap(), document);
+
+        // Queries without a hash are not persisted
+        let (_, persisted) = queries.document(&KEYS, Some(QUERY), None).unwrap();
+        assert_eq!(None, persisted);
+    }
+
+    #[test]
+    fn allow_list() {
+        let dir = std::env::temp_dir().join(format!("graph-allow-list-{}", std::process::id()));
+        fs::create_dir_all(dir.join("test")).unwrap();
+        fs::write(
+            dir.join("test/persisted.json"),
+            format!(r#"{{"{}": "{}"}}"#, sha256(QUERY), QUERY),
+        )
+        .unwrap();
+        let queries = PersistedQueries::with_allow_list_dir(Some(dir.clone()));
+
+        // Allowed queries can be sent by hash or as text
+        let (_, persisted) = queries.document(&KEYS, None, Some(&sha256(QUERY))).unwrap();
+        assert_eq!(Some(sha256(QUERY)), persisted);
+        let (_, persisted) = queries.document(&KEYS, Some(QUERY), None).unwrap();
+        assert_eq!(Some(sha256(QUERY)), persisted);
+
+        // Everything else is rejected
+        let res = queries.document(&KEYS, Some("{ things { id } }"), None);
+        assert!(is_execution_error(res, |e| matches!(
+            e,
+            QueryExecutionError::QueryNotAllowed(_)
+        )));
+
+        // Subgraphs without an allow-list accept any query
+        let other = AllowListKeys::new(
+            &DeploymentHash::new("QmOther").unwrap(),
+            vec!["test/other".to_string()],
+        );
+        queries
+            .document(&other, Some("{ things { id } }"), None)
+            .unwrap();
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn allow_list_reload() {
+        const OTHER: &str = "{ things { id } }";
+
+        let dir =
+            std::env::temp_dir().join(format!("graph-allow-list-reload-{}", std::process::id()));
+        let path = dir.join("test/persisted.json");
+        let write = |query: &str, age: u64| {
+            fs::write(&path, format!(r#"{{"{}": "{}"}}"#, sha256(query), query)).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(SystemTime::now() - Duration::from_secs(age))
+                .unwrap();
+        };
+        fs::create_dir_all(dir.join("test")).unwrap();
+        write(QUERY, 60);
+        let mut queries = PersistedQueries::with_allow_list_dir(Some(dir.clone()));
+        queries.check_interval = Duration::ZERO;
+
+        queries.document(&KEYS, Some(QUERY), None).unwrap();
+        let res = queries.document(&KEYS, Some(OTHER), None);
+        assert!(is_execution_error(res, |e| matches!(
+            e,
+            QueryExecutionError::QueryNotAllowed(_)
+        )));
+
+        // Changing the file changes which queries are allowed
+        write(OTHER, 0);
+        queries.document(&KEYS, Some(OTHER), None).unwrap();
+        let res = queries.document(&KEYS, Some(QUERY), None);
+        assert!(is_execution_error(res, |e| matches!(
+            e,
+            QueryExecutionError::QueryNotAllowed(_)
+        )));
+
+        // Removing the file removes the allow-list
+        fs::remove_file(&path).unwrap();
+        queries.document(&KEYS, Some(QUERY), None).unwrap();
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn allow_list_by_name_and_deployment() {
+        const OTHER: &str = "{ things { id } }";
+
+        let dir =
+            std::env::temp_dir().join(format!("graph-allow-list-keys-{}", std::process::id()));
+        let write = |name: &str, queries: &[&str]| {
+            let queries: Vec<_> = queries
+                .iter()
+                .map(|query| format!(r#""{}": "{}""#, sha256(query), query))
+                .collect();
+            fs::write(
+                dir.join(format!("{}.json", name)),
+                format!("{{{}}}", queries.join(",")),
+            )
+            .unwrap();
+        };
+        fs::create_dir_all(dir.join("test")).unwrap();
+        write("test/persisted", &[QUERY]);
+        let queries = PersistedQueries::with_allow_list_dir(Some(dir.clone()));
+        let resolve = || async {
+            Ok::<_, QueryExecutionError>((DEPLOYMENT.clone(), vec!["test/persisted".to_string()]))
+        };
+
+        // Querying by id is subject to the allow-list set up by name
+        let by_id = QueryTarget::Deployment(DEPLOYMENT.clone(), Default::default());
+        let keys = queries.keys(&by_id, resolve()).await.unwrap();
+        assert!(queries.has_allow_list(&keys).unwrap());
+        queries.document(&keys, Some(QUERY), None).unwrap();
+        let res = queries.document(&keys, Some(OTHER), None);
+        assert!(is_execution_error(res, |e| matches!(
+            e,
+            QueryExecutionError::QueryNotAllowed(_)
+        )));
+
+        // With allow-lists for the name and the deployment, queries have to
+        // be on both of them, no matter how the subgraph is queried
+        write(DEPLOYMENT.as_str(), &[QUERY, OTHER]);
+        let by_name = QueryTarget::Name(
+            SubgraphName::new("test/persisted").unwrap(),
+            Default::default(),
+        );
+        let keys = queries.keys(&by_name, resolve()).await.unwrap();
+        queries.document(&keys, Some(QUERY), None).unwrap();
+        let res = queries.document(&keys, Some(OTHER), None);
+        assert!(is_execution_error(res, |e| matches!(
+            e,
+            QueryExecutionError::QueryNotAllowed(_)
+        )));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,500 @@
+//! Per-client rate limits for the query server. Each client has two token
+//! buckets, one for the number of requests and one for the estimated cost
+//! of its queries, that refill over the course of a minute. Clients are
+//! identified by the address they connect from or by the value of a header
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use graph::anyhow::{anyhow, Error};
+use graph::components::server::server::RemoteAddr;
+use graph::data::query::QueryResults;
+use graph::hyper::header::HeaderName;
+use graph::hyper::Request;
+use graph::prelude::{MetricsRegistry, ENV_VARS};
+use graph::prometheus::{Counter, CounterVec};
+use lru_time_cache::LruCache;
+
+/// Buckets of clients that have not made a request for this long are
+/// forgotten; they would have refilled completely by now
+const IDLE_CLIENT_EXPIRY: Duration = Duration::from_secs(600);
+
+/// What identifies a client
+#[derive(Clone, Debug, PartialEq)]
+enum ClientKey {
+    Ip,
+    Header(HeaderName),
+}
+
+impl ClientKey {
+    fn parse(key: &str) -> Result<Self, Error> {
+        if key == "ip" {
+            return Ok(ClientKey::Ip);
+        }
+        match key.strip_prefix("header:") {
+            Some(name) => HeaderName::try_from(name)
+                .map(ClientKey::Header)
+                .map_err(|e| anyhow!("invalid header `{}` for rate limits: {}", name, e)),
+            None => Err(anyhow!(
+                "GRAPH_GRAPHQL_RATE_LIMIT_KEY must be `ip` or `header:<name>` but is `{}`",
+                key
+            )),
+        }
+    }
+}
+
+/// A token bucket that holds up to `capacity` tokens and refills at a rate
+/// of `capacity` tokens per minute
+#[derive(Debug)]
+struct Bucket {
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(capacity: u64, now: Instant) -> Self {
+        Bucket {
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.capacity / 60.0).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// How long it takes until the bucket holds at least `tokens` tokens
+    fn wait_for(&self, tokens: f64) -> Duration {
+        if self.tokens >= tokens || self.capacity == 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((tokens - self.tokens) * 60.0 / self.capacity)
+    }
+}
+
+#[derive(Debug)]
+struct Usage {
+    requests: Option<Bucket>,
+    cost: Option<Bucket>,
+}
+
+/// Totals across all clients. Client keys can be API keys, and there can
+/// be any number of them, so we do not label these metrics with them; see
+/// `KeyMetrics` for metrics for the keys that are configured
+struct Metrics {
+    requests: Counter,
+    cost: Counter,
+    rejected: Counter,
+}
+
+impl Metrics {
+    fn new(registry: &MetricsRegistry) -> Result<Self, Error> {
+        Ok(Metrics {
+            requests: registry.global_counter(
+                "query_rate_limit_requests",
+                "The number of requests that clients made to the query server",
+                HashMap::new(),
+            )?,
+            cost: registry.global_counter(
+                "query_rate_limit_cost",
+                "The total estimated cost of the queries that clients ran",
+                HashMap::new(),
+            )?,
+            rejected: registry.global_counter(
+                "query_rate_limit_rejected",
+                "The number of requests that were rejected by the rate limit",
+                HashMap::new(),
+            )?,
+        })
+    }
+}
+
+/// Usage per configured client key. Only keys from
+/// `GRAPH_GRAPHQL_RATE_LIMIT_KEYS` are used as labels, which keeps the
+/// number of series bounded
+struct KeyMetrics {
+    requests: CounterVec,
+    cost: CounterVec,
+    rejected: CounterVec,
+}
+
+impl KeyMetrics {
+    fn new(registry: &MetricsRegistry) -> Result<Self, Error> {
+        Ok(KeyMetrics {
+            requests: registry.global_counter_vec(
+                "query_rate_limit_key_requests",
+                "The number of requests that clients with a configured key made",
+                &["key"],
+            )?,
+            cost: registry.global_counter_vec(
+                "query_rate_limit_key_cost",
+                "The total estimated cost of the queries that clients with a configured key ran",
+                &["key"],
+            )?,
+            rejected: registry.global_counter_vec(
+                "query_rate_limit_key_rejected",
+                "The number of requests from clients with a configured key that were rejected",
+                &["key"],
+            )?,
+        })
+    }
+}
+
+pub struct RateLimiter {
+    key: ClientKey,
+    /// The header values that identify a client. If this is empty, any
+    /// value does, but clients are then also limited by their address
+    /// so that they can not escape their limits by sending a new value
+    /// with every request
+    keys: HashSet<String>,
+    requests_per_minute: Option<u64>,
+    cost_per_minute: Option<u64>,
+    clients: Mutex<LruCache<String, Usage>>,
+    metrics: Metrics,
+    key_metrics: Option<KeyMetrics>,
+}
+
+impl RateLimiter {
+    /// Create a rate limiter from the environment; if no limit is set,
+    /// return `None`
+    pub fn from_env(registry: &MetricsRegistry) -> Result<Option<Arc<Self>>, Error> {
+        let requests = ENV_VARS.graphql.rate_limit_requests;
+        let cost = ENV_VARS.graphql.rate_limit_cost;
+        if requests.is_none() && cost.is_none() {
+            return Ok(None);
+        }
+        let key = ClientKey::parse(&ENV_VARS.graphql.rate_limit_key)?;
+        Self::new(
+            key,
+            ENV_VARS.graphql.rate_limit_keys.iter().cloned().collect(),
+            requests,
+            cost,
+            ENV_VARS.graphql.rate_limit_max_clients,
+            ENV_VARS.graphql.rate_limit_key_metrics,
+            registry,
+        )
+        .map(|limiter| Some(Arc::new(limiter)))
+    }
+
+    fn new(
+        key: ClientKey,
+        keys: HashSet<String>,
+        requests_per_minute: Option<u64>,
+        cost_per_minute: Option<u64>,
+        max_clients: usize,
+        key_metrics: bool,
+        registry: &MetricsRegistry,
+    ) -> Result<Self, Error> {
+        let key_metrics = if key_metrics && !keys.is_empty() {
+            Some(KeyMetrics::new(registry)?)
+        } else {
+            None
+        };
+        Ok(RateLimiter {
+            key,
+            keys,
+            requests_per_minute,
+            cost_per_minute,
+            clients: Mutex::new(LruCache::with_expiry_duration_and_capacity(
+                IDLE_CLIENT_EXPIRY,
+                max_clients.max(1),
+            )),
+            metrics: Metrics::new(registry)?,
+            key_metrics,
+        })
+    }
+
+    /// The client that sent `request`. Requests that do not have the
+    /// header that identifies clients, or whose header value is not one
+    /// of the configured keys, are identified by their address
+    pub fn client<T>(self: &Arc<Self>, request: &Request<T>) -> Client {
+        let ip = request
+            .extensions()
+            .get::<RemoteAddr>()
+            .map(|RemoteAddr(addr)| addr.ip().to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        let ip = format!("ip:{}", ip);
+
+        let value = match &self.key {
+            ClientKey::Header(name) => request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok()),
+            ClientKey::Ip => None,
+        };
+        let (buckets, key) = match value {
+            Some(value) if self.keys.contains(value) => {
+                (vec![format!("key:{}", value)], Some(value.to_string()))
+            }
+            Some(value) if self.keys.is_empty() => (vec![format!("key:{}", value), ip], None),
+            Some(_) | None => (vec![ip], None),
+        };
+        Client {
+            limiter: self.clone(),
+            buckets,
+            key,
+        }
+    }
+
+    fn admit_at(&self, client: &Client, now: Instant) -> Result<(), Duration> {
+        let mut clients = self.clients.lock().unwrap();
+
+        // A client that is in debt for the cost of its queries has to wait
+        // until the debt is paid off. When a client is limited by more
+        // than one bucket, it has to wait for all of them
+        let wait = client
+            .buckets
+            .iter()
+            .filter_map(|bucket| {
+                let usage = self.usage(&mut clients, bucket, now);
+                usage
+                    .requests
+                    .iter()
+                    .map(|bucket| bucket.wait_for(1.0))
+                    .chain(usage.cost.iter().map(|bucket| bucket.wait_for(0.0)))
+                    .max()
+            })
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            self.metrics.rejected.inc();
+            self.with_key_metrics(client, |metrics, key| {
+                metrics.rejected.with_label_values(&[key]).inc()
+            });
+            return Err(wait);
+        }
+        for bucket in &client.buckets {
+            if let Some(bucket) = &mut self.usage(&mut clients, bucket, now).requests {
+                bucket.tokens -= 1.0;
+            }
+        }
+        self.metrics.requests.inc();
+        self.with_key_metrics(client, |metrics, key| {
+            metrics.requests.with_label_values(&[key]).inc()
+        });
+        Ok(())
+    }
+
+    fn charge_at(&self, client: &Client, cost: u64, now: Instant) {
+        let mut clients = self.clients.lock().unwrap();
+        for bucket in &client.buckets {
+            if let Some(bucket) = &mut self.usage(&mut clients, bucket, now).cost {
+                bucket.tokens -= cost as f64;
+            }
+        }
+        self.metrics.cost.inc_by(cost as f64);
+        self.with_key_metrics(client, |metrics, key| {
+            metrics.cost.with_label_values(&[key]).inc_by(cost as f64)
+        });
+    }
+
+    fn with_key_metrics(&self, client: &Client, f: impl FnOnce(&KeyMetrics, &str)) {
+        if let (Some(metrics), Some(key)) = (&self.key_metrics, &client.key) {
+            f(metrics, key)
+        }
+    }
+
+    fn usage<'a>(
+        &self,
+        clients: &'a mut LruCache<String, Usage>,
+        client: &str,
+        now: Instant,
+    ) -> &'a mut Usage {
+        let usage = clients.entry(client.to_string()).or_insert_with(|| Usage {
+            requests: self.requests_per_minute.map(|cap| Bucket::new(cap, now)),
+            cost: self.cost_per_minute.map(|cap| Bucket::new(cap, now)),
+        });
+        for bucket in usage.requests.iter_mut().chain(usage.cost.iter_mut()) {
+            bucket.refill(now);
+        }
+        usage
+    }
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RateLimiter {{ key: {:?} }}", self.key)
+    }
+}
+
+/// A client of the query server, and the limits that apply to it
+#[derive(Clone)]
+pub struct Client {
+    limiter: Arc<RateLimiter>,
+    /// The buckets that this client is limited by
+    buckets: Vec<String>,
+    /// The configured key that identifies this client, if any
+    key: Option<String>,
+}
+
+impl Client {
+    /// Take one request from the budget of the client. If the client has
+    /// used up its budget, return how long it needs to wait before it can
+    /// make another request
+    pub fn admit(&self) -> Result<(), Duration> {
+        self.limiter.admit_at(self, Instant::now())
+    }
+
+    /// Charge the estimated cost of the queries in `results` to the client
+    pub fn charge(&self, results: &QueryResults) {
+        if let Some(cost) = results.cost.as_ref().filter(|cost| !cost.dry_run) {
+            self.charge_cost(cost.estimated);
+        }
+    }
+
+    /// Charge `cost` to the client. Exports have no estimate and are
+    /// charged for the number of entities they read
+    pub fn charge_cost(&self, cost: u64) {
+        self.limiter.charge_at(self, cost, Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use graph::components::server::server::RemoteAddr;
+    use graph::hyper::header::HeaderName;
+    use graph::hyper::Request;
+    use graph::prelude::MetricsRegistry;
+
+    use super::{Client, ClientKey, RateLimiter};
+
+    fn limiter(requests: Option<u64>, cost: Option<u64>) -> Arc<RateLimiter> {
+        keyed_limiter(ClientKey::Ip, &[], requests, cost, 100)
+    }
+
+    fn keyed_limiter(
+        key: ClientKey,
+        keys: &[&str],
+        requests: Option<u64>,
+        cost: Option<u64>,
+        max_clients: usize,
+    ) -> Arc<RateLimiter> {
+        let keys: HashSet<_> = keys.iter().map(|key| key.to_string()).collect();
+        let limiter = RateLimiter::new(
+            key,
+            keys,
+            requests,
+            cost,
+            max_clients,
+            true,
+            &MetricsRegistry::mock(),
+        )
+        .unwrap();
+        Arc::new(limiter)
+    }
+
+    fn client(limiter: &Arc<RateLimiter>, name: &str) -> Client {
+        Client {
+            limiter: limiter.clone(),
+            buckets: vec![name.to_string()],
+            key: None,
+        }
+    }
+
+    fn request(ip: &str, api_key: Option<&str>) -> Request<()> {
+        let mut builder = Request::builder();
+        if let Some(api_key) = api_key {
+            builder = builder.header("x-api-key", api_key);
+        }
+        let mut request = builder.body(()).unwrap();
+        let addr: SocketAddr = format!("{}:4000", ip).parse().unwrap();
+        request.extensions_mut().insert(RemoteAddr(addr));
+        request
+    }
+
+    fn api_key() -> ClientKey {
+        ClientKey::Header(HeaderName::from_static("x-api-key"))
+    }
+
+    #[test]
+    fn parse_client_key() {
+        assert_eq!(ClientKey::Ip, ClientKey::parse("ip").unwrap());
+        assert_eq!(api_key(), ClientKey::parse("header:X-Api-Key").unwrap());
+        assert!(ClientKey::parse("cookie").is_err());
+        assert!(ClientKey::parse("header:not a header").is_err());
+    }
+
+    #[test]
+    fn request_limit_refills_over_a_minute() {
+        let limiter = limiter(Some(2), None);
+        let a = client(&limiter, "a");
+        let now = Instant::now();
+
+        assert!(limiter.admit_at(&a, now).is_ok());
+        assert!(limiter.admit_at(&a, now).is_ok());
+        assert_eq!(Err(Duration::from_secs(30)), limiter.admit_at(&a, now));
+        // Other clients have their own budget
+        assert!(limiter.admit_at(&client(&limiter, "b"), now).is_ok());
+
+        assert!(limiter.admit_at(&a, now + Duration::from_secs(30)).is_ok());
+        assert!(limiter.admit_at(&a, now + Duration::from_secs(30)).is_err());
+    }
+
+    #[test]
+    fn cost_limit_rejects_clients_in_debt() {
+        let limiter = limiter(None, Some(600));
+        let a = client(&limiter, "a");
+        let now = Instant::now();
+
+        assert!(limiter.admit_at(&a, now).is_ok());
+        limiter.charge_at(&a, 500, now);
+        assert!(limiter.admit_at(&a, now).is_ok());
+        limiter.charge_at(&a, 700, now);
+        // The client is 600 in debt, and gets 10 per second back
+        assert_eq!(Err(Duration::from_secs(60)), limiter.admit_at(&a, now));
+        assert!(limiter.admit_at(&a, now + Duration::from_secs(60)).is_ok());
+    }
+
+    #[test]
+    fn unlisted_keys_are_limited_by_address() {
+        let now = Instant::now();
+
+        // Without a list of keys, a new key for every request does not
+        // get around the limit for the address
+        let limiter = keyed_limiter(api_key(), &[], Some(1), None, 100);
+        let first = limiter.client(&request("10.0.0.1", Some("first")));
+        let second = limiter.client(&request("10.0.0.1", Some("second")));
+        assert!(limiter.admit_at(&first, now).is_ok());
+        assert!(limiter.admit_at(&second, now).is_err());
+        let other = limiter.client(&request("10.0.0.2", Some("second")));
+        assert!(limiter.admit_at(&other, now).is_err());
+
+        // With a list of keys, configured keys have their own budget, and
+        // everything else is limited by its address
+        let limiter = keyed_limiter(api_key(), &["known"], Some(1), None, 100);
+        let known = limiter.client(&request("10.0.0.1", Some("known")));
+        let unknown = limiter.client(&request("10.0.0.1", Some("unknown")));
+        let missing = limiter.client(&request("10.0.0.1", None));
+        assert_eq!(Some("known"), known.key.as_deref());
+        assert_eq!(None, unknown.key);
+        assert!(limiter.admit_at(&known, now).is_ok());
+        assert!(limiter.admit_at(&known, now).is_err());
+        assert!(limiter.admit_at(&unknown, now).is_ok());
+        assert!(limiter.admit_at(&missing, now).is_err());
+    }
+
+    #[test]
+    fn number_of_clients_is_capped() {
+        let limiter = keyed_limiter(ClientKey::Ip, &[], Some(1), None, 2);
+        let now = Instant::now();
+
+        for name in ["a", "b", "c"] {
+            assert!(limiter.admit_at(&client(&limiter, name), now).is_ok());
+        }
+        assert_eq!(2, limiter.clients.lock().unwrap().len());
+        // `a` was the least recently used client and has been forgotten
+        assert!(limiter.admit_at(&client(&limiter, "a"), now).is_ok());
+        assert!(limiter.admit_at(&client(&limiter, "c"), now).is_err());
+    }
+}
@@ -4,32 +4,95 @@ use graph::components::server::query::ServerError;
 use graph::hyper::body::Bytes;
 use graph::prelude::*;
 
-pub fn parse_graphql_request(body: &Bytes, trace: bool) -> Result<Query, ServerError> {
+use crate::persisted::{AllowListKeys, PersistedQueries};
+
+/// The body of a GraphQL request over HTTP
+#[derive(Debug)]
+pub enum GraphQLRequest {
+    Single(Query),
+    /// A JSON array of requests. Each operation in the batch can fail to
+    /// parse on its own
+    Batch(Vec<Result<Query, ServerError>>),
+}
+
+pub fn parse_graphql_request(
+    body: &Bytes,
+    trace: bool,
+    persisted_queries: &PersistedQueries,
+    keys: &AllowListKeys,
+) -> Result<GraphQLRequest, ServerError> {
     // Parse request body as JSON
     let json: serde_json::Value =
         serde_json::from_slice(body).map_err(|e| ServerError::ClientError(format!("{}", e)))?;
 
+    let operations = match json {
+        serde_json::Value::Array(operations) => operations,
+        json => {
+            return parse_graphql_json(&json, trace, persisted_queries, keys)
+                .map(GraphQLRequest::Single)
+        }
+    };
+
+    let max_batch_size = ENV_VARS.graphql.max_batch_size;
+    if max_batch_size == 0 {
+        return Err(ServerError::ClientError(String::from(
+            "Batched requests are not supported",
+        )));
+    }
+    if operations.is_empty() {
+        return Err(ServerError::ClientError(String::from(
+            "A batched request must contain at least one operation",
+        )));
+    }
+    if operations.len() > max_batch_size {
+        return Err(ServerError::ClientError(format!(
+            "A batched request can contain at most {} operations but this one has {}",
+            max_batch_size,
+            operations.len()
+        )));
+    }
+    Ok(GraphQLRequest::Batch(
+        operations
+            .iter()
+            .map(|operation| parse_graphql_json(operation, trace, persisted_queries, keys))
+            .collect(),
+    ))
+}
+
+/// Turn a JSON object with a `query` and optional `variables` and
+/// `extensions` fields into a `Query`. This is the body of a GraphQL
+/// request, but also the payload of a `subscribe` message on a WebSocket
+/// connection. `keys` are the allow-lists that apply to the target of
+/// the request
+pub fn parse_graphql_json(
+    json: &serde_json::Value,
+    trace: bool,
+    persisted_queries: &PersistedQueries,
+    keys: &AllowListKeys,
+) -> Result<Query, ServerError> {
     // Ensure the JSON data is an object
     let obj = json
         .as_object()
         .ok_or_else(|| ServerError::ClientError(String::from("Request data is not an object")))?;
 
-    // Ensure the JSON data has a "query" field
-    let query_value = obj.get("query").ok_or_else(|| {
-        ServerError::ClientError(String::from(
-            "The \"query\" field is missing in request data",
-        ))
-    })?;
+    // Ensure the "query" field is a string if it is present; persisted
+    // queries can be sent without it
+    let query_string = obj
+        .get("query")
+        .map(|query_value| {
+            query_value.as_str().ok_or_else(|| {
+                ServerError::ClientError(String::from("The \"query\" field is not a string"))
+            })
+        })
+        .transpose()?;
 
-    // Ensure the "query" field is a string
-    let query_string = query_value.as_str().ok_or_else(|| {
-        ServerError::ClientError(String::from("The \"query\" field is not a string"))
-    })?;
+    let hash = obj
+        .get("extensions")
+        .and_then(|extensions| extensions.get("persistedQuery"))
+        .and_then(|persisted| persisted.get("sha256Hash"))
+        .and_then(serde_json::Value::as_str);
 
-    // Parse the "query" field of the JSON body
-    let document = q::parse_query(query_string)
-        .map_err(|e| ServerError::from(QueryError::ParseError(Arc::new(e.into()))))?
-        .into_static();
+    let (document, hash) = persisted_queries.document(keys, query_string, hash)?;
 
     // Parse the "variables" field of the JSON body, if present
     let variables = match obj.get("variables") {
@@ -42,7 +105,14 @@ pub fn parse_graphql_request(body: &Bytes, trace: bool) -> Result<Query, ServerE
         )),
     }?;
 
-    Ok(Query::new(document, variables, trace))
+    Ok(Query::new(document, variables, trace).with_persisted_hash(hash))
+}
+
+/// Parse the text of a GraphQL query
+pub fn parse_query_text(query_string: &str) -> Result<q::Document, ServerError> {
+    Ok(q::parse_query(query_string)
+        .map_err(|e| ServerError::from(QueryError::ParseError(Arc::new(e.into()))))?
+        .into_static())
 }
 
 #[cfg(test)]
@@ -51,51 +121,55 @@ mod tests {
     use std::collections::HashMap;
 
     use graph::{
-        data::{
-            query::QueryTarget,
-            value::{Object, Word},
-        },
+        data::value::{Object, Word},
         hyper::body::Bytes,
         prelude::*,
     };
 
-    use super::parse_graphql_request;
+    use graph::components::server::query::ServerError;
+
+    use super::{parse_graphql_request, GraphQLRequest};
+    use crate::persisted::{AllowListKeys, PersistedQueries};
 
     lazy_static! {
-        static ref TARGET: QueryTarget = QueryTarget::Name(
-            SubgraphName::new("test/request").unwrap(),
-            Default::default()
-        );
+        static ref KEYS: AllowListKeys = AllowListKeys::default();
+        static ref PERSISTED_QUERIES: PersistedQueries = PersistedQueries::new();
+    }
+
+    fn parse_single(body: &'static str) -> Result<Query, ServerError> {
+        match parse_graphql_request(&Bytes::from(body), false, &PERSISTED_QUERIES, &KEYS)? {
+            GraphQLRequest::Single(query) => Ok(query),
+            GraphQLRequest::Batch(_) => panic!("expected a single request but got a batch"),
+        }
     }
 
     #[test]
     fn rejects_invalid_json() {
-        let request = parse_graphql_request(&Bytes::from("!@#)%"), false);
+        let request = parse_single("!@#)%");
         request.expect_err("Should reject invalid JSON");
     }
 
     #[test]
     fn rejects_json_without_query_field() {
-        let request = parse_graphql_request(&Bytes::from("{}"), false);
+        let request = parse_single("{}");
         request.expect_err("Should reject JSON without query field");
     }
 
     #[test]
     fn rejects_json_with_non_string_query_field() {
-        let request = parse_graphql_request(&Bytes::from("{\"query\": 5}"), false);
+        let request = parse_single("{\"query\": 5}");
         request.expect_err("Should reject JSON with a non-string query field");
     }
 
     #[test]
     fn rejects_broken_queries() {
-        let request = parse_graphql_request(&Bytes::from("{\"query\": \"foo\"}"), false);
+        let request = parse_single("{\"query\": \"foo\"}");
         request.expect_err("Should reject broken queries");
     }
 
     #[test]
     fn accepts_valid_queries() {
-        let request =
-            parse_graphql_request(&Bytes::from("{\"query\": \"{ user { name } }\"}"), false);
+        let request = parse_single("{\"query\": \"{ user { name } }\"}");
         let query = request.expect("Should accept valid queries");
         assert_eq!(
             query.document,
@@ -105,15 +179,12 @@ mod tests {
 
     #[test]
     fn accepts_null_variables() {
-        let request = parse_graphql_request(
-            &Bytes::from(
-                "\
+        let request = parse_single(
+            "\
                  {\
                  \"query\": \"{ user { name } }\", \
                  \"variables\": null \
                  }",
-            ),
-            false,
         );
         let query = request.expect("Should accept null variables");
 
@@ -124,32 +195,26 @@ mod tests {
 
     #[test]
     fn rejects_non_map_variables() {
-        let request = parse_graphql_request(
-            &Bytes::from(
-                "\
+        let request = parse_single(
+            "\
                  {\
                  \"query\": \"{ user { name } }\", \
                  \"variables\": 5 \
                  }",
-            ),
-            false,
         );
         request.expect_err("Should reject non-map variables");
     }
 
     #[test]
     fn parses_variables() {
-        let request = parse_graphql_request(
-            &Bytes::from(
-                "\
+        let request = parse_single(
+            "\
                  {\
                  \"query\": \"{ user { name } }\", \
                  \"variables\": { \
                  \"string\": \"s\", \"map\": {\"k\": \"v\"}, \"int\": 5 \
                  } \
                  }",
-            ),
-            false,
         );
         let query = request.expect("Should accept valid queries");
 
@@ -171,4 +236,32 @@ mod tests {
         assert_eq!(query.document, expected_query);
         assert_eq!(query.variables, Some(expected_variables));
     }
+
+    #[test]
+    fn parses_batches() {
+        let body = r#"[{"query": "{ user { name } }"}, {"query": 5}]"#;
+        let request = parse_graphql_request(&Bytes::from(body), false, &PERSISTED_QUERIES, &KEYS)
+            .expect("Should accept batches");
+        match request {
+            GraphQLRequest::Batch(operations) => {
+                assert_eq!(2, operations.len());
+                assert!(operations[0].is_ok());
+                operations[1]
+                    .as_ref()
+                    .expect_err("Should reject invalid operations in a batch");
+            }
+            GraphQLRequest::Single(_) => panic!("expected a batch but got a single request"),
+        }
+
+        parse_graphql_request(&Bytes::from("[]"), false, &PERSISTED_QUERIES, &KEYS)
+            .expect_err("Should reject empty batches");
+
+        let operation = r#"{"query": "{ user { name } }"}"#;
+        let body = format!(
+            "[{}]",
+            vec![operation; ENV_VARS.graphql.max_batch_size + 1].join(",")
+        );
+        parse_graphql_request(&Bytes::from(body), false, &PERSISTED_QUERIES, &KEYS)
+            .expect_err("Should reject batches that are too big");
+    }
 }
@@ -6,18 +6,24 @@ use graph::components::server::server::{start, ServerHandle};
 use graph::log::factory::{ComponentLoggerConfig, ElasticComponentLoggerConfig};
 use graph::slog::info;
 
+use crate::rate_limit::RateLimiter;
 use crate::service::GraphQLService;
-use graph::prelude::{GraphQlRunner, Logger, LoggerFactory};
+use graph::prelude::{GraphQlRunner, Logger, LoggerFactory, MetricsRegistry};
 
 /// A GraphQL server based on Hyper.
 pub struct GraphQLServer<Q> {
     logger: Logger,
+    metrics_registry: Arc<MetricsRegistry>,
     graphql_runner: Arc<Q>,
 }
 
 impl<Q: GraphQlRunner> GraphQLServer<Q> {
     /// Creates a new GraphQL server.
-    pub fn new(logger_factory: &LoggerFactory, graphql_runner: Arc<Q>) -> Self {
+    pub fn new(
+        logger_factory: &LoggerFactory,
+        metrics_registry: Arc<MetricsRegistry>,
+        graphql_runner: Arc<Q>,
+    ) -> Self {
         let logger = logger_factory.component_logger(
             "GraphQLServer",
             Some(ComponentLoggerConfig {
@@ -28,6 +34,7 @@ impl<Q: GraphQlRunner> GraphQLServer<Q> {
         );
         GraphQLServer {
             logger,
+            metrics_registry,
             graphql_runner,
         }
     }
@@ -42,11 +49,14 @@ impl<Q: GraphQlRunner> GraphQLServer<Q> {
 
         let graphql_runner = self.graphql_runner.clone();
 
-        let service = Arc::new(GraphQLService::new(logger.clone(), graphql_runner));
+        let rate_limiter = RateLimiter::from_env(&self.metrics_registry)?;
+        let service = Arc::new(
+            GraphQLService::new(logger.clone(), graphql_runner).with_rate_limiter(rate_limiter),
+        );
 
         start(logger, port, move |req| {
             let service = service.cheap_clone();
-            async move { Ok::<_, _>(service.cheap_clone().call(req).await) }
+            async move { Ok::<_, _>(service.cheap_clone().serve(req).await) }
         })
         .await
     }
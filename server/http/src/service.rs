@@ -1,33 +1,48 @@
 use std::convert::TryFrom;
 use std::env;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use graph::cheap_clone::CheapClone;
 use graph::components::graphql::GraphQlRunner;
 use graph::components::server::query::ServerResponse;
 use graph::components::server::query::ServerResult;
 use graph::components::versions::ApiVersion;
-use graph::data::query::QueryResult;
+use graph::data::query::{Federation, Query, QueryError, QueryResult, QueryResults};
 use graph::data::subgraph::DeploymentHash;
 use graph::data::subgraph::SubgraphName;
 use graph::env::ENV_VARS;
-use graph::http_body_util::{BodyExt, Full};
+use graph::http_body_util::{BodyExt, Either, Full, StreamBody};
+use graph::hyper::body::{Bytes, Frame};
+use graph::hyper::header::RETRY_AFTER;
 use graph::hyper::header::{
     ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
-    CONTENT_LENGTH, CONTENT_TYPE, LOCATION,
+    CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, IF_NONE_MATCH, LOCATION, SEC_WEBSOCKET_ACCEPT,
+    SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL, UPGRADE,
 };
 use graph::hyper::{body::Body, header::HeaderValue};
 use graph::hyper::{Method, Request, Response, StatusCode};
+use graph::hyper_util::rt::TokioIo;
+use graph::prelude::hex;
 use graph::prelude::serde_json;
 use graph::prelude::serde_json::json;
 use graph::semver::VersionReq;
-use graph::slog::error;
 use graph::slog::Logger;
+use graph::slog::{error, warn};
+use graph::tokio::sync::mpsc;
+use graph::tokio_stream::wrappers::ReceiverStream;
 use graph::url::form_urlencoded;
 use graph::{components::server::query::ServerError, data::query::QueryTarget};
+use sha2::{Digest, Sha256};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::WebSocketStream;
 
-use crate::request::parse_graphql_request;
+use crate::export::{internal_error, Export, ExportBody, ExportFormat};
+use crate::persisted::{AllowListKeys, PersistedQueries};
+use crate::rate_limit::{Client, RateLimiter};
+use crate::request::{parse_graphql_request, GraphQLRequest};
+use crate::subscription::{self, GraphQlConnection};
 
 fn client_error(msg: impl Into<String>) -> ServerResponse {
     let response_obj = json!({
@@ -43,11 +58,74 @@ fn client_error(msg: impl Into<String>) -> ServerResponse {
         .unwrap()
 }
 
-/// A Hyper Service that serves GraphQL over a POST / endpoint.
+/// Tell a client that it has used up its rate limit and when it can try
+/// again
+fn too_many_requests(wait: Duration) -> ServerResponse {
+    let response_obj = json!({
+        "error": "Rate limit exceeded"
+    });
+    let response_str = serde_json::to_string(&response_obj).unwrap();
+
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header(CONTENT_TYPE, "application/json")
+        .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+        .header(RETRY_AFTER, wait.as_secs_f64().ceil().to_string())
+        .body(Full::from(response_str))
+        .unwrap()
+}
+
+/// Identifies a request for HTTP caching. The body of a `POST` or the URL
+/// of a `GET` request contains the query and its variables; tracing and
+/// dry runs change the response to the same request, and therefore have
+/// to be part of the key
+fn http_cache_key(body: &[u8], trace: bool, dry_run: bool) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(body);
+    hasher.update([trace as u8, dry_run as u8]);
+    hex::encode(hasher.finalize())
+}
+
+/// The GraphQL request in the query string of a `GET` request, as the
+/// body of the equivalent `POST` request. The `variables` and
+/// `extensions` parameters hold JSON
+fn graphql_request_from_url<T>(request: &Request<T>) -> Result<Bytes, ServerError> {
+    let params = request.uri().query().unwrap_or_default();
+    let mut json = serde_json::Map::new();
+    for (key, value) in form_urlencoded::parse(params.as_bytes()) {
+        match key.as_ref() {
+            "query" => {
+                json.insert(
+                    key.into_owned(),
+                    serde_json::Value::String(value.into_owned()),
+                );
+            }
+            "variables" | "extensions" => {
+                let value = serde_json::from_str(&value).map_err(|e| {
+                    ServerError::ClientError(format!("Invalid `{}` parameter: {}", key, e))
+                })?;
+                json.insert(key.into_owned(), value);
+            }
+            _ => {}
+        }
+    }
+    Ok(Bytes::from(
+        serde_json::to_vec(&serde_json::Value::Object(json)).unwrap(),
+    ))
+}
+
+/// The body of responses from `GraphQLService::serve`; only exports are
+/// streamed
+pub type HttpBody = Either<Full<Bytes>, ExportBody>;
+
+/// A Hyper Service that serves GraphQL queries sent with POST, or with GET
+/// and the query in the URL
 #[derive(Debug)]
 pub struct GraphQLService<Q> {
     logger: Logger,
     graphql_runner: Arc<Q>,
+    persisted_queries: Arc<PersistedQueries>,
+    rate_limiter: Option<Arc<RateLimiter>>,
 }
 
 impl<Q> GraphQLService<Q>
@@ -59,9 +137,17 @@ where
         GraphQLService {
             logger,
             graphql_runner,
+            persisted_queries: Arc::new(PersistedQueries::new()),
+            rate_limiter: None,
         }
     }
 
+    /// Limit the requests and the cost of the queries of each client
+    pub(crate) fn with_rate_limiter(mut self, rate_limiter: Option<Arc<RateLimiter>>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
     fn graphiql_html(&self) -> String {
         include_str!("../assets/index.html").to_string()
     }
@@ -120,18 +206,36 @@ where
         Ok(version)
     }
 
-    async fn handle_graphql_query_by_name<T: Body>(
+    fn target_by_name<T>(
         &self,
         subgraph_name: String,
-        request: Request<T>,
-    ) -> ServerResult {
-        let version = self.resolve_api_version(&request)?;
+        request: &Request<T>,
+    ) -> Result<QueryTarget, ServerError> {
+        let version = self.resolve_api_version(request)?;
         let subgraph_name = SubgraphName::new(subgraph_name.as_str()).map_err(|()| {
             ServerError::ClientError(format!("Invalid subgraph name {:?}", subgraph_name))
         })?;
+        Ok(QueryTarget::Name(subgraph_name, version))
+    }
 
-        self.handle_graphql_query(QueryTarget::Name(subgraph_name, version), request)
-            .await
+    fn target_by_id<T>(
+        &self,
+        id: String,
+        request: &Request<T>,
+    ) -> Result<QueryTarget, ServerError> {
+        let id = DeploymentHash::new(id)
+            .map_err(|id| ServerError::ClientError(format!("Invalid subgraph id `{}`", id)))?;
+        let version = self.resolve_api_version(request)?;
+        Ok(QueryTarget::Deployment(id, version))
+    }
+
+    async fn handle_graphql_query_by_name<T: Body>(
+        &self,
+        subgraph_name: String,
+        request: Request<T>,
+    ) -> ServerResult {
+        let target = self.target_by_name(subgraph_name, &request)?;
+        self.handle_graphql_query(target, request).await
     }
 
     async fn handle_graphql_query_by_id<T: Body>(
@@ -139,12 +243,94 @@ where
         id: String,
         request: Request<T>,
     ) -> ServerResult {
-        let id = DeploymentHash::new(id)
-            .map_err(|id| ServerError::ClientError(format!("Invalid subgraph id `{}`", id)))?;
-        let version = self.resolve_api_version(&request)?;
+        let target = self.target_by_id(id, &request)?;
+        self.handle_graphql_query(target, request).await
+    }
 
-        self.handle_graphql_query(QueryTarget::Deployment(id, version), request)
-            .await
+    /// The keys of the allow-lists that apply to queries against `target`
+    async fn allow_list_keys(&self, target: &QueryTarget) -> Result<AllowListKeys, ServerError> {
+        let resolve = self
+            .graphql_runner
+            .cheap_clone()
+            .resolve_target(target.clone());
+        self.persisted_queries.keys(target, resolve).await
+    }
+
+    fn is_websocket_upgrade<T>(&self, request: &Request<T>) -> bool {
+        request
+            .headers()
+            .get(UPGRADE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.eq_ignore_ascii_case("websocket"))
+            .unwrap_or(false)
+    }
+
+    /// Accept a WebSocket connection for running subscriptions against
+    /// `target` with the `graphql-transport-ws` protocol
+    async fn handle_websocket<T>(
+        &self,
+        target: QueryTarget,
+        mut request: Request<T>,
+    ) -> ServerResult {
+        let key = request.headers().get(SEC_WEBSOCKET_KEY).ok_or_else(|| {
+            ServerError::ClientError("Sec-WebSocket-Key header is required".into())
+        })?;
+        let accept = derive_accept_key(key.as_bytes());
+        let supports_protocol = request
+            .headers()
+            .get_all(SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|protocol| protocol.trim() == subscription::PROTOCOL);
+        if !supports_protocol {
+            return Err(ServerError::ClientError(format!(
+                "WebSocket connections must use the `{}` protocol",
+                subscription::PROTOCOL
+            )));
+        }
+        let client = self.client(&request);
+        if let Some(Err(wait)) = client.as_ref().map(Client::admit) {
+            return Ok(too_many_requests(wait));
+        }
+        let keys = self.allow_list_keys(&target).await?;
+
+        let on_upgrade = graph::hyper::upgrade::on(&mut request);
+        let logger = self.logger.clone();
+        let graphql_runner = self.graphql_runner.cheap_clone();
+        let persisted_queries = self.persisted_queries.cheap_clone();
+        graph::spawn(async move {
+            match on_upgrade.await {
+                Ok(upgraded) => {
+                    let ws = WebSocketStream::from_raw_socket(
+                        TokioIo::new(upgraded),
+                        Role::Server,
+                        None,
+                    )
+                    .await;
+                    GraphQlConnection::new(
+                        logger,
+                        graphql_runner,
+                        persisted_queries,
+                        target,
+                        keys,
+                        client,
+                    )
+                    .serve(ws)
+                    .await
+                }
+                Err(e) => error!(logger, "WebSocket upgrade failed"; "error" => e.to_string()),
+            }
+        });
+
+        Ok(Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(CONNECTION, "Upgrade")
+            .header(UPGRADE, "websocket")
+            .header(SEC_WEBSOCKET_ACCEPT, accept)
+            .header(SEC_WEBSOCKET_PROTOCOL, subscription::PROTOCOL)
+            .body(Full::from(""))
+            .unwrap())
     }
 
     async fn handle_graphql_query<T: Body>(
@@ -165,12 +351,49 @@ where
                     })
                     .unwrap_or(false)
         };
-        let body = request
-            .collect()
-            .await
-            .map_err(|_| ServerError::InternalError("Failed to read request body".into()))?
-            .to_bytes();
-        let query = parse_graphql_request(&body, trace);
+        // With `X-GraphDryRun: true`, only report the estimated cost of
+        // the query without running it
+        let dry_run = request
+            .headers()
+            .get("X-GraphDryRun")
+            .map(|v| v.to_str().map(|s| s == "true").unwrap_or(false))
+            .unwrap_or(false);
+        let if_none_match = request
+            .headers()
+            .get(IF_NONE_MATCH)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        // Shared caches key responses by their URL, so only responses to
+        // `GET` requests, which have the whole query in the URL, are public
+        let public = request.method() == Method::GET;
+        let client = self.client(&request);
+        let (body, cache_key) = if public {
+            let body = graphql_request_from_url(&request)?;
+            let url = request.uri().to_string();
+            (body, http_cache_key(url.as_bytes(), trace, dry_run))
+        } else {
+            let body = request
+                .collect()
+                .await
+                .map_err(|_| ServerError::InternalError("Failed to read request body".into()))?
+                .to_bytes();
+            let cache_key = http_cache_key(&body, trace, dry_run);
+            (body, cache_key)
+        };
+        let query =
+            match self.allow_list_keys(&target).await.and_then(|keys| {
+                parse_graphql_request(&body, trace, &self.persisted_queries, &keys)
+            }) {
+                Ok(GraphQLRequest::Single(query)) => Ok(query
+                    .with_dry_run(dry_run)
+                    .with_http_cache(cache_key, if_none_match)),
+                Ok(GraphQLRequest::Batch(operations)) => {
+                    return self
+                        .handle_graphql_batch(target, operations, dry_run, start, client)
+                        .await;
+                }
+                Err(e) => Err(e),
+            };
         let query_parsing_time = start.elapsed();
 
         let mut result = match query {
@@ -185,6 +408,12 @@ where
         };
 
         result.trace.query_parsing(query_parsing_time);
+        if let Some(cache) = &mut result.http_cache {
+            cache.public = public;
+        }
+        if let Some(client) = &client {
+            client.charge(&result);
+        }
         self.graphql_runner
             .metrics()
             .observe_query_parsing(query_parsing_time, &result);
@@ -195,12 +424,387 @@ where
         Ok(result.as_http_response())
     }
 
+    /// Run the operations of a batch and respond with a JSON array of their
+    /// results, in the order of the operations. Operations that can't be
+    /// parsed get their errors in place of a result
+    async fn handle_graphql_batch(
+        &self,
+        target: QueryTarget,
+        operations: Vec<Result<Query, ServerError>>,
+        dry_run: bool,
+        start: Instant,
+        client: Option<Client>,
+    ) -> ServerResult {
+        let mut queries = Vec::new();
+        let mut errors = Vec::with_capacity(operations.len());
+        for operation in operations {
+            match operation {
+                Ok(query) => {
+                    queries.push(query.with_dry_run(dry_run));
+                    errors.push(None);
+                }
+                Err(ServerError::ClientError(msg)) => {
+                    errors.push(Some(json!({ "errors": [{ "message": msg }] })));
+                }
+                Err(ServerError::QueryError(e)) => {
+                    let result = QueryResults::from(QueryResult::from(e));
+                    errors.push(Some(serde_json::to_value(&result).unwrap()));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let results = self
+            .graphql_runner
+            .cheap_clone()
+            .run_batch(queries, target)
+            .await;
+        let metrics = self.graphql_runner.metrics();
+        let attestable = errors.iter().all(Option::is_none)
+            && results.iter().all(|result| result.is_attestable());
+        // All operations run against the same block
+        let indexed_block = results
+            .iter()
+            .find_map(|result| result.indexed_block.as_ref());
+        let indexed_block = serde_json::to_string(&indexed_block).unwrap();
+        let mut results = results.into_iter();
+        let responses: Vec<_> = errors
+            .into_iter()
+            .map(|error| {
+                error.unwrap_or_else(|| {
+                    // unwrap: the runner returns one result for each query
+                    let result = results.next().unwrap();
+                    metrics.observe_query_execution(start.elapsed(), &result);
+                    if let Some(client) = &client {
+                        client.charge(&result);
+                    }
+                    serde_json::to_value(&result).unwrap()
+                })
+            })
+            .collect();
+
+        Ok(Response::builder()
+            .status(200)
+            .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+            .header(CONTENT_TYPE, "application/json")
+            .header(
+                ACCESS_CONTROL_ALLOW_HEADERS,
+                "Content-Type, User-Agent, If-None-Match",
+            )
+            .header(ACCESS_CONTROL_ALLOW_METHODS, "GET, OPTIONS, POST")
+            .header("graph-attestable", attestable.to_string())
+            .header("graph-indexed", indexed_block)
+            .body(Full::from(serde_json::to_string(&responses).unwrap()))
+            .unwrap())
+    }
+
+    /// The subgraphs and the timestamp of a federated request. Each
+    /// `subgraph` parameter in the query string has the form
+    /// `<namespace>:name/<subgraph name>` or `<namespace>:id/<deployment>`
+    fn federation<T>(&self, request: &Request<T>) -> Result<Federation, ServerError> {
+        let max_subgraphs = ENV_VARS.graphql.max_federated_subgraphs;
+        if max_subgraphs == 0 {
+            return Err(ServerError::ClientError(
+                "Federated queries are not enabled".to_string(),
+            ));
+        }
+
+        let mut subgraphs: Vec<(String, QueryTarget)> = Vec::new();
+        let mut timestamp = None;
+        let params = request.uri().query().unwrap_or_default();
+        for (key, value) in form_urlencoded::parse(params.as_bytes()) {
+            match key.as_ref() {
+                "subgraph" => {
+                    let invalid = || {
+                        ServerError::ClientError(format!(
+                            "Invalid subgraph `{}`, expected `<namespace>:name/<subgraph name>` \
+                             or `<namespace>:id/<deployment>`",
+                            value
+                        ))
+                    };
+                    let (namespace, subgraph) = value.split_once(':').ok_or_else(invalid)?;
+                    let valid_namespace = namespace
+                        .chars()
+                        .next()
+                        .is_some_and(|c| c.is_ascii_alphabetic())
+                        && namespace.chars().all(|c| c.is_ascii_alphanumeric());
+                    if !valid_namespace {
+                        return Err(ServerError::ClientError(format!(
+                            "Invalid namespace `{}`, namespaces must start with a letter \
+                             and only contain letters and digits",
+                            namespace
+                        )));
+                    }
+                    if subgraphs.iter().any(|(ns, _)| ns == namespace) {
+                        return Err(ServerError::ClientError(format!(
+                            "The namespace `{}` is used more than once",
+                            namespace
+                        )));
+                    }
+                    let target = match subgraph.split_once('/').ok_or_else(invalid)? {
+                        ("name", name) => self.target_by_name(name.to_string(), request)?,
+                        ("id", id) => self.target_by_id(id.to_string(), request)?,
+                        _ => return Err(invalid()),
+                    };
+                    subgraphs.push((namespace.to_string(), target));
+                }
+                "timestamp" => {
+                    timestamp = Some(value.parse::<u64>().map_err(|_| {
+                        ServerError::ClientError(format!("Invalid timestamp `{}`", value))
+                    })?);
+                }
+                _ => {}
+            }
+        }
+
+        if subgraphs.is_empty() {
+            return Err(ServerError::ClientError(
+                "Federated queries need at least one `subgraph` parameter".to_string(),
+            ));
+        }
+        if subgraphs.len() > max_subgraphs {
+            return Err(ServerError::ClientError(format!(
+                "Federated queries can combine at most {} subgraphs",
+                max_subgraphs
+            )));
+        }
+        Ok(Federation {
+            subgraphs,
+            timestamp,
+        })
+    }
+
+    /// Run a query against the federated schema of the subgraphs in the
+    /// query string
+    async fn handle_federated_query<T: Body>(&self, request: Request<T>) -> ServerResult {
+        let start = Instant::now();
+        let federation = self.federation(&request)?;
+        // Subgraphs with an allow-list only run the queries on it, which we
+        // can not check for the parts of a federated query
+        let mut keys = Vec::with_capacity(federation.subgraphs.len());
+        for (namespace, target) in &federation.subgraphs {
+            let target_keys = self.allow_list_keys(target).await?;
+            if self.persisted_queries.has_allow_list(&target_keys)? {
+                return Err(ServerError::ClientError(format!(
+                    "The subgraph for namespace `{}` only allows queries from its \
+                     allow-list and can not be used in federated queries",
+                    namespace
+                )));
+            }
+            keys.push(target_keys);
+        }
+        let dry_run = request
+            .headers()
+            .get("X-GraphDryRun")
+            .map(|v| v.to_str().map(|s| s == "true").unwrap_or(false))
+            .unwrap_or(false);
+        let client = self.client(&request);
+        let body = request
+            .collect()
+            .await
+            .map_err(|_| ServerError::InternalError("Failed to read request body".into()))?
+            .to_bytes();
+
+        // unwrap: `federation` checks that there is at least one subgraph
+        let keys = keys.first().unwrap();
+        let result = match parse_graphql_request(&body, false, &self.persisted_queries, keys) {
+            Ok(GraphQLRequest::Single(query)) => {
+                self.graphql_runner
+                    .cheap_clone()
+                    .run_federated(query.with_dry_run(dry_run), federation)
+                    .await
+            }
+            Ok(GraphQLRequest::Batch(_)) => {
+                return Err(ServerError::ClientError(
+                    "Federated queries can not be batched".to_string(),
+                ))
+            }
+            Err(ServerError::QueryError(e)) => QueryResult::from(e).into(),
+            Err(e) => return Err(e),
+        };
+
+        if let Some(client) = &client {
+            client.charge(&result);
+        }
+        self.graphql_runner
+            .metrics()
+            .observe_query_execution(start.elapsed(), &result);
+        Ok(result.as_http_response())
+    }
+
+    /// Respond with the federated schema of the subgraphs in the query
+    /// string as GraphQL SDL
+    async fn handle_federated_schema<T>(&self, request: Request<T>) -> ServerResult {
+        let federation = self.federation(&request)?;
+        let schema = self
+            .graphql_runner
+            .cheap_clone()
+            .federated_schema(federation)
+            .await
+            .map_err(|e| ServerError::QueryError(QueryError::ExecutionError(e)))?;
+
+        Ok(Response::builder()
+            .status(200)
+            .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(Full::from(schema.to_string()))
+            .unwrap())
+    }
+
+    /// The client that sent `request` if requests are rate limited
+    fn client<T>(&self, request: &Request<T>) -> Option<Client> {
+        self.rate_limiter
+            .as_ref()
+            .map(|limiter| limiter.client(request))
+    }
+
+    /// The target of an export, if `request` asks for one. Exports are
+    /// sent to `/subgraphs/id/<deployment>/export` and
+    /// `/subgraphs/name/<subgraph name>/export`
+    fn export_target<T>(&self, request: &Request<T>) -> Result<Option<QueryTarget>, ServerError> {
+        if request.method() != Method::POST {
+            return Ok(None);
+        }
+        let segments: Vec<_> = request
+            .uri()
+            .path()
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect();
+        match segments.as_slice() {
+            ["subgraphs", "id", id, "export"] => {
+                self.target_by_id(id.to_string(), request).map(Some)
+            }
+            ["subgraphs", "name", name @ .., "export"] if !name.is_empty() => {
+                self.target_by_name(name.join("/"), request).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Stream all entities that the query in the request matches. The
+    /// first page is fetched before we respond so that a query that fails
+    /// gets a normal GraphQL response; later pages are fetched by a
+    /// background task that stops as soon as the client goes away
+    async fn handle_export<T: Body>(
+        &self,
+        target: QueryTarget,
+        request: Request<T>,
+    ) -> Result<Response<HttpBody>, ServerError> {
+        if ENV_VARS.graphql.export_page_size == 0 {
+            return Err(ServerError::ClientError(
+                "Exports are not enabled".to_string(),
+            ));
+        }
+        // Exports change the query, which would take it off the allow-list
+        let keys = self.allow_list_keys(&target).await?;
+        if self.persisted_queries.has_allow_list(&keys)? {
+            return Err(ServerError::ClientError(
+                "This subgraph only allows queries from its allow-list and can not be exported"
+                    .to_string(),
+            ));
+        }
+        let client = self.client(&request);
+        if let Some(Err(wait)) = client.as_ref().map(Client::admit) {
+            return Ok(too_many_requests(wait).map(Either::Left));
+        }
+        let format = ExportFormat::from_query_string(request.uri().query())?;
+        let body = request
+            .collect()
+            .await
+            .map_err(|_| ServerError::InternalError("Failed to read request body".into()))?
+            .to_bytes();
+        let query = match parse_graphql_request(&body, false, &self.persisted_queries, &keys)? {
+            GraphQLRequest::Single(query) => query,
+            GraphQLRequest::Batch(_) => {
+                return Err(ServerError::ClientError(
+                    "Exports can not be batched".to_string(),
+                ))
+            }
+        };
+        let page_size = ENV_VARS.graphql.export_page_size;
+        let mut export = Export::new(&query, format, page_size)?;
+
+        let mut pages = match self
+            .graphql_runner
+            .cheap_clone()
+            .run_export(query, target, page_size)
+            .await
+        {
+            Ok(pages) => pages,
+            Err(result) => return Ok(result.as_http_response().map(Either::Left)),
+        };
+        let chunk = match pages.next_page().await {
+            Some(Ok(page)) => {
+                if let Some(client) = &client {
+                    client.charge_cost(page.len() as u64);
+                }
+                export.page(pages.columns(), &page)?
+            }
+            Some(Err(e)) => return Ok(QueryResults::from(e).as_http_response().map(Either::Left)),
+            None => return Err(internal_error("the store did not read any entities")),
+        };
+
+        let (sender, receiver) = mpsc::channel(1);
+        // unwrap: the channel is empty and the receiver is still around
+        sender.try_send(Ok(Frame::data(chunk))).unwrap();
+
+        let logger = self.logger.clone();
+        graph::spawn(async move {
+            // Stop once the client goes away and drops the receiver; that
+            // also drops `pages`, which ends the cursor in the store
+            while !export.is_done() && !sender.is_closed() {
+                let chunk = match pages.next_page().await {
+                    Some(Ok(page)) => {
+                        if let Some(client) = &client {
+                            client.charge_cost(page.len() as u64);
+                        }
+                        export.page(pages.columns(), &page)
+                    }
+                    Some(Err(e)) => {
+                        if let Some(chunk) = export.error_chunk(&e) {
+                            let _ = sender.send(Ok(Frame::data(chunk))).await;
+                        }
+                        Err(ServerError::QueryError(e.into()))
+                    }
+                    None => Err(internal_error(
+                        "the store stopped reading entities before the end",
+                    )),
+                };
+                match chunk {
+                    Ok(chunk) => {
+                        if sender.send(Ok(Frame::data(chunk))).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        warn!(logger, "Export failed"; "error" => e.to_string());
+                        let _ = sender.send(Err(e)).await;
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Response::builder()
+            .status(200)
+            .header(CONTENT_TYPE, format.content_type())
+            .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+            .body(Either::Right(StreamBody::new(ReceiverStream::new(
+                receiver,
+            ))))
+            .unwrap())
+    }
+
     // Handles OPTIONS requests
     fn handle_graphql_options<T>(&self, _request: Request<T>) -> ServerResult {
         Ok(Response::builder()
             .status(200)
             .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
-            .header(ACCESS_CONTROL_ALLOW_HEADERS, "Content-Type, User-Agent")
+            .header(
+                ACCESS_CONTROL_ALLOW_HEADERS,
+                "Content-Type, User-Agent, If-None-Match",
+            )
             .header(ACCESS_CONTROL_ALLOW_METHODS, "GET, OPTIONS, POST")
             .header(CONTENT_TYPE, "text/html; charset=utf-8")
             .body(Full::from(""))
@@ -288,6 +892,26 @@ where
             }
         }
 
+        let url_param = |name: &str| {
+            req.uri().query().and_then(|query_str| {
+                form_urlencoded::parse(query_str.as_bytes())
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value.into_owned())
+            })
+        };
+        let url_query = url_param("query");
+        // `GET` requests run a query if they have one in the URL; persisted
+        // queries only need the `extensions`
+        let is_query =
+            method == Method::GET && (url_query.is_some() || url_param("extensions").is_some());
+
+        // Only requests that run queries count against the rate limit
+        if method == Method::POST || is_query {
+            if let Some(Err(wait)) = self.client(&req).as_ref().map(Client::admit) {
+                return Ok(too_many_requests(wait));
+            }
+        }
+
         // Filter out empty strings from path segments
         fn filter_and_join_segments(segments: &[&str]) -> String {
             segments
@@ -298,15 +922,8 @@ where
                 .join("/")
         }
 
-        let is_mutation = req
-            .uri()
-            .query()
-            .and_then(|query_str| {
-                form_urlencoded::parse(query_str.as_bytes())
-                    .find(|(key, _)| key == "query")
-                    .map(|(_, value)| value.into_owned())
-            })
-            .unwrap_or_else(|| String::new())
+        let is_mutation = url_query
+            .unwrap_or_default()
             .trim()
             .to_lowercase()
             .starts_with("mutation");
@@ -317,9 +934,30 @@ where
             | (Method::GET, &["subgraphs", "network", _, _, "graphql"])
             | (Method::GET, &["subgraphs", "graphql"]) => self.handle_graphiql(),
 
-            (Method::GET, _path @ ["subgraphs", "name", ..]) if is_mutation => {
+            (Method::GET, &["subgraphs", "id", subgraph_id]) if self.is_websocket_upgrade(&req) => {
+                let target = self.target_by_id(subgraph_id.to_owned(), &req)?;
+                self.handle_websocket(target, req).await
+            }
+            (Method::GET, path @ ["subgraphs", "name", ..]) if self.is_websocket_upgrade(&req) => {
+                let subgraph_name = filter_and_join_segments(&path[2..]);
+                let target = self.target_by_name(subgraph_name, &req)?;
+                self.handle_websocket(target, req).await
+            }
+
+            (Method::GET, _path @ ["subgraphs", "id", _])
+            | (Method::GET, _path @ ["subgraphs", "name", ..])
+                if is_mutation =>
+            {
                 self.handle_mutations()
             }
+            (Method::GET, &["subgraphs", "id", subgraph_id]) if is_query => {
+                self.handle_graphql_query_by_id(subgraph_id.to_owned(), req)
+                    .await
+            }
+            (Method::GET, path @ ["subgraphs", "name", ..]) if is_query => {
+                let subgraph_name = filter_and_join_segments(&path[2..]);
+                self.handle_graphql_query_by_name(subgraph_name, req).await
+            }
             (Method::GET, path @ ["subgraphs", "id", _])
             | (Method::GET, path @ ["subgraphs", "name", ..])
             | (Method::GET, path @ ["subgraphs", "network", _, _]) => {
@@ -340,18 +978,36 @@ where
 
             (Method::OPTIONS, ["subgraphs", "name", ..]) => self.handle_graphql_options(req),
 
+            (Method::POST, ["federated"]) => self.handle_federated_query(req).await,
+            (Method::GET, ["federated", "schema"]) => self.handle_federated_schema(req).await,
+            (Method::OPTIONS, ["federated"]) => self.handle_graphql_options(req),
+
             _ => self.handle_not_found(),
         }
     }
 
+    /// Like `call`, but also serves exports, whose responses are streamed
+    pub async fn serve<T: Body + std::fmt::Debug>(&self, req: Request<T>) -> Response<HttpBody> {
+        let result = match self.export_target(&req) {
+            Ok(Some(target)) => self.handle_export(target, req).await,
+            Ok(None) => return self.call(req).await.map(Either::Left),
+            Err(e) => Err(e),
+        };
+        result.unwrap_or_else(|e| self.error_response(e).map(Either::Left))
+    }
+
     pub async fn call<T: Body + std::fmt::Debug>(&self, req: Request<T>) -> ServerResponse {
         // Returning Err here will prevent the client from receiving any response.
         // Instead, we generate a Response with an error code and return Ok
-        let result = self.handle_call(req).await;
-
-        match result {
+        match self.handle_call(req).await {
             Ok(response) => response,
-            Err(err @ ServerError::ClientError(_)) => {
+            Err(err) => self.error_response(err),
+        }
+    }
+
+    fn error_response(&self, err: ServerError) -> ServerResponse {
+        match err {
+            err @ ServerError::ClientError(_) => {
                 let response_obj = json!({
                     "error": err.to_string()
                 });
@@ -364,7 +1020,7 @@ where
                     .body(Full::from(response_str))
                     .unwrap()
             }
-            Err(err @ ServerError::QueryError(_)) => {
+            err @ ServerError::QueryError(_) => {
                 error!(self.logger, "GraphQLService call failed: {}", err);
 
                 let response_obj = json!({
@@ -379,7 +1035,7 @@ where
                     .body(Full::from(response_str))
                     .unwrap()
             }
-            Err(err @ ServerError::InternalError(_)) => {
+            err @ ServerError::InternalError(_) => {
                 error!(self.logger, "GraphQLService call failed: {}", err);
 
                 Response::builder()
@@ -398,16 +1054,17 @@ mod tests {
     use graph::data::value::{Object, Word};
     use graph::http_body_util::{BodyExt, Full};
     use graph::hyper::body::Bytes;
-    use graph::hyper::header::{CONTENT_LENGTH, CONTENT_TYPE};
+    use graph::hyper::header::{CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, ETAG, LOCATION};
     use graph::hyper::{Method, Request, StatusCode};
     use graph::prelude::serde_json::json;
 
-    use graph::data::query::{QueryResults, QueryTarget};
+    use graph::components::graphql::{QueryExport, QueryResultStream};
+    use graph::data::query::{Federation, HttpCache, LatestBlockInfo, QueryResults, QueryTarget};
     use graph::prelude::*;
 
     use crate::test_utils;
 
-    use super::GraphQLService;
+    use super::{http_cache_key, GraphQLService};
 
     /// A simple stupid query runner for testing.
     pub struct TestGraphQlRunner;
@@ -440,10 +1097,77 @@ mod tests {
             unimplemented!();
         }
 
-        async fn run_query(self: Arc<Self>, _query: Query, _target: QueryTarget) -> QueryResults {
-            QueryResults::from(Object::from_iter(
-                vec![(Word::from("name"), r::Value::String(String::from("Jordi")))].into_iter(),
-            ))
+        async fn run_query(self: Arc<Self>, query: Query, _target: QueryTarget) -> QueryResults {
+            // Answer with the `name` variable if there is one
+            let name = query
+                .variables
+                .as_ref()
+                .and_then(|variables| variables.get("name").cloned())
+                .unwrap_or_else(|| r::Value::String(String::from("Jordi")));
+            let mut result = QueryResults::from(Object::from_iter(
+                vec![(Word::from("name"), name)].into_iter(),
+            ));
+            result.http_cache = query
+                .cache_key
+                .map(|key| HttpCache::new(&key, &USERS, std::iter::empty(), None, false));
+            result
+        }
+
+        async fn run_batch(
+            self: Arc<Self>,
+            queries: Vec<Query>,
+            target: QueryTarget,
+        ) -> Vec<QueryResults> {
+            let mut results = Vec::new();
+            for query in queries {
+                let mut result = self.cheap_clone().run_query(query, target.clone()).await;
+                result.indexed_block = Some(LatestBlockInfo {
+                    hash: BlockHash::zero(),
+                    number: 17,
+                    timestamp: None,
+                });
+                results.push(result);
+            }
+            results
+        }
+
+        async fn run_subscription(
+            self: Arc<Self>,
+            _query: Query,
+            _target: QueryTarget,
+        ) -> Result<QueryResultStream, QueryResults> {
+            unimplemented!();
+        }
+
+        async fn run_export(
+            self: Arc<Self>,
+            _query: Query,
+            _target: QueryTarget,
+            _page_size: usize,
+        ) -> Result<QueryExport, QueryResults> {
+            unimplemented!();
+        }
+
+        async fn run_federated(
+            self: Arc<Self>,
+            _query: Query,
+            _federation: Federation,
+        ) -> QueryResults {
+            unimplemented!();
+        }
+
+        async fn federated_schema(
+            self: Arc<Self>,
+            _federation: Federation,
+        ) -> Result<s::Document, QueryExecutionError> {
+            unimplemented!();
+        }
+
+        async fn resolve_target(
+            self: Arc<Self>,
+            _target: QueryTarget,
+        ) -> Result<(DeploymentHash, Vec<String>), QueryExecutionError> {
+            Ok((USERS.clone(), vec![]))
         }
 
         fn metrics(&self) -> Arc<dyn GraphQLMetrics> {
@@ -451,6 +1175,16 @@ mod tests {
         }
     }
 
+    #[test]
+    fn http_cache_key_depends_on_trace_and_dry_run() {
+        let body = br#"{"query": "{ name }"}"#;
+        let key = http_cache_key(body, false, false);
+        assert_eq!(key, http_cache_key(body, false, false));
+        assert_ne!(key, http_cache_key(br#"{"query": "{ id }"}"#, false, false));
+        assert_ne!(key, http_cache_key(body, true, false));
+        assert_ne!(key, http_cache_key(body, false, true));
+    }
+
     #[tokio::test]
     async fn querying_not_found_routes_responds_correctly() {
         let logger = Logger::root(slog::Discard, o!());
@@ -546,4 +1280,81 @@ mod tests {
             .expect("Query result field \"name\" is not a string");
         assert_eq!(name, "Jordi".to_string());
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn posting_batch_reports_indexed_block() {
+        let logger = Logger::root(slog::Discard, o!());
+        let graphql_runner = Arc::new(TestGraphQlRunner);
+
+        let service = GraphQLService::new(logger, graphql_runner);
+
+        let request: Request<Full<Bytes>> = Request::builder()
+            .method(Method::POST)
+            .header(CONTENT_TYPE, "application/json")
+            .header(CONTENT_LENGTH, 100)
+            .uri(format!("http://localhost:8000/subgraphs/id/{}", *USERS))
+            .body(Full::from(
+                r#"[{"query": "{ name }"}, {"query": "{ name }"}]"#,
+            ))
+            .unwrap();
+
+        let response = service.call(request).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let indexed: serde_json::Value =
+            serde_json::from_str(response.headers()["graph-indexed"].to_str().unwrap()).unwrap();
+        assert_eq!(17, indexed["number"]);
+
+        let body = response.body().clone().collect().await.unwrap().to_bytes();
+        let results: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(2, results.len());
+        assert_eq!("Jordi", results[1]["data"]["name"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn get_runs_query_from_url() {
+        let logger = Logger::root(slog::Discard, o!());
+        let graphql_runner = Arc::new(TestGraphQlRunner);
+
+        let service = GraphQLService::new(logger, graphql_runner);
+        let get = |params: &str| {
+            let request: Request<Full<Bytes>> = Request::builder()
+                .method(Method::GET)
+                .uri(format!(
+                    "http://localhost:8000/subgraphs/id/{}{}",
+                    *USERS, params
+                ))
+                .body(Full::from(""))
+                .unwrap();
+            service.call(request)
+        };
+
+        let query = "?query=query%28%24name%3A%20String%29%20%7B%20name%20%7D";
+        let response = get(query).await;
+        let etag = response.headers()[ETAG].clone();
+        assert!(response.headers()[CACHE_CONTROL]
+            .to_str()
+            .unwrap()
+            .starts_with("public"));
+        let data = test_utils::assert_successful_response(response).await;
+        assert_eq!("Jordi", data["name"]);
+
+        // Variables are passed as JSON, and responses are cached by URL
+        let with_name = format!("{}&variables=%7B%22name%22%3A%22John%22%7D", query);
+        let response = get(&with_name).await;
+        assert_ne!(etag, response.headers()[ETAG]);
+        let data = test_utils::assert_successful_response(response).await;
+        assert_eq!("John", data["name"]);
+        assert_eq!(etag, get(query).await.headers()[ETAG]);
+
+        let response = get(&format!("{}&variables=%7Bname", query)).await;
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+        // Without a query, we still redirect to GraphiQL
+        let response = get("").await;
+        assert_eq!(StatusCode::FOUND, response.status());
+        assert_eq!(
+            format!("/subgraphs/id/{}/graphql", *USERS),
+            response.headers()[LOCATION]
+        );
+    }
 }
@@ -0,0 +1,353 @@
+//! GraphQL subscriptions over WebSockets using the `graphql-transport-ws`
+//! protocol, see
+//! https://github.com/enisdenjo/graphql-ws/blob/master/PROTOCOL.md
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use graph::cheap_clone::CheapClone;
+use graph::components::graphql::GraphQlRunner;
+use graph::data::query::{QueryResults, QueryTarget};
+use graph::futures03::{SinkExt, StreamExt};
+use graph::prelude::serde_json::{self, json};
+use graph::prelude::{debug, Logger, Query, ENV_VARS};
+use graph::tokio::io::{AsyncRead, AsyncWrite};
+use graph::tokio::sync::mpsc;
+use graph::tokio::task::JoinHandle;
+use graph::tokio::{self, time};
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::persisted::{AllowListKeys, PersistedQueries};
+use crate::rate_limit::Client;
+use crate::request::parse_graphql_json;
+
+/// The WebSocket subprotocol that clients need to ask for
+pub const PROTOCOL: &str = "graphql-transport-ws";
+
+/// How long clients have to send `connection_init` after connecting
+const CONNECTION_INIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Messages that clients send. We ignore the `payload` of the messages
+/// that have an optional one
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    ConnectionInit {},
+    Ping {},
+    Pong {},
+    Subscribe {
+        id: String,
+        payload: serde_json::Value,
+    },
+    Complete {
+        id: String,
+    },
+}
+
+/// Messages that we send to clients
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage<'a> {
+    ConnectionAck,
+    Pong,
+    Next {
+        id: &'a str,
+        payload: &'a QueryResults,
+    },
+    Error {
+        id: &'a str,
+        payload: serde_json::Value,
+    },
+    Complete {
+        id: &'a str,
+    },
+}
+
+impl ServerMessage<'_> {
+    /// An `error` message for `id`; `errors` must be the results of a
+    /// request that failed
+    fn errors(id: &str, errors: &QueryResults) -> Message {
+        let mut errors = serde_json::to_value(errors).unwrap_or_default();
+        ServerMessage::Error {
+            id,
+            payload: errors["errors"].take(),
+        }
+        .into()
+    }
+
+    fn error(id: &str, msg: impl ToString) -> Message {
+        ServerMessage::Error {
+            id,
+            payload: json!([{ "message": msg.to_string() }]),
+        }
+        .into()
+    }
+}
+
+impl From<ServerMessage<'_>> for Message {
+    fn from(msg: ServerMessage<'_>) -> Self {
+        // unwrap: serializing our own messages can not fail
+        Message::text(serde_json::to_string(&msg).unwrap())
+    }
+}
+
+fn close(code: u16, reason: impl Into<String>) -> Message {
+    Message::Close(Some(CloseFrame {
+        code: CloseCode::from(code),
+        reason: reason.into().into(),
+    }))
+}
+
+/// One WebSocket connection on which clients can run any number of
+/// subscriptions against `target`
+pub(crate) struct GraphQlConnection<Q> {
+    logger: Logger,
+    graphql_runner: Arc<Q>,
+    persisted_queries: Arc<PersistedQueries>,
+    target: QueryTarget,
+    /// The allow-lists that apply to queries against `target`
+    keys: AllowListKeys,
+    /// The client on the other end if requests are rate limited
+    client: Option<Client>,
+}
+
+impl<Q: GraphQlRunner> GraphQlConnection<Q> {
+    pub fn new(
+        logger: Logger,
+        graphql_runner: Arc<Q>,
+        persisted_queries: Arc<PersistedQueries>,
+        target: QueryTarget,
+        keys: AllowListKeys,
+        client: Option<Client>,
+    ) -> Self {
+        GraphQlConnection {
+            logger,
+            graphql_runner,
+            persisted_queries,
+            target,
+            keys,
+            client,
+        }
+    }
+
+    /// Serve the connection until either side closes it. Everything we
+    /// send goes through a bounded channel; when the client reads slower
+    /// than we produce results, subscriptions wait for room in the
+    /// channel, and skip results for intermediate blocks
+    pub async fn serve<S>(self, ws: WebSocketStream<S>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (mut sink, mut stream) = ws.split();
+        let (out, mut out_rx) =
+            mpsc::channel::<Message>(ENV_VARS.graphql.subscription_buffer_size.max(1));
+        let writer = graph::spawn(async move {
+            while let Some(msg) = out_rx.recv().await {
+                if sink.send(msg).await.is_err() {
+                    break;
+                }
+            }
+            let _ = sink.close().await;
+        });
+
+        let mut subscriptions: HashMap<String, JoinHandle<()>> = HashMap::new();
+        let mut acknowledged = false;
+        let init_timeout = time::sleep(CONNECTION_INIT_TIMEOUT);
+        tokio::pin!(init_timeout);
+
+        loop {
+            let msg = tokio::select! {
+                _ = &mut init_timeout, if !acknowledged => {
+                    let _ = out.send(close(4408, "Connection initialisation timeout")).await;
+                    break;
+                }
+                msg = stream.next() => msg,
+            };
+            let msg = match msg {
+                Some(Ok(Message::Text(text))) => text,
+                // Pings are answered by the WebSocket library
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+                Some(Ok(Message::Binary(_))) => {
+                    let _ = out
+                        .send(close(4400, "Binary messages are not supported"))
+                        .await;
+                    break;
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+            };
+            let msg = match serde_json::from_str::<ClientMessage>(&msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    let _ = out
+                        .send(close(4400, format!("Invalid message: {}", e)))
+                        .await;
+                    break;
+                }
+            };
+
+            match msg {
+                ClientMessage::ConnectionInit {} if acknowledged => {
+                    let _ = out
+                        .send(close(4429, "Too many initialisation requests"))
+                        .await;
+                    break;
+                }
+                ClientMessage::ConnectionInit {} => {
+                    acknowledged = true;
+                    let _ = out.send(ServerMessage::ConnectionAck.into()).await;
+                }
+                ClientMessage::Ping {} => {
+                    let _ = out.send(ServerMessage::Pong.into()).await;
+                }
+                ClientMessage::Pong {} => {}
+                ClientMessage::Subscribe { .. } if !acknowledged => {
+                    let _ = out.send(close(4401, "Unauthorized")).await;
+                    break;
+                }
+                ClientMessage::Subscribe { id, payload } => {
+                    subscriptions.retain(|_, handle| !handle.is_finished());
+                    if subscriptions.contains_key(&id) {
+                        let _ = out
+                            .send(close(4409, format!("Subscriber for {} already exists", id)))
+                            .await;
+                        break;
+                    }
+                    if subscriptions.len() >= ENV_VARS.graphql.max_subscriptions_per_connection {
+                        let msg = ServerMessage::error(
+                            &id,
+                            format!(
+                                "too many subscriptions on this connection, the limit is {}",
+                                ENV_VARS.graphql.max_subscriptions_per_connection
+                            ),
+                        );
+                        let _ = out.send(msg).await;
+                        continue;
+                    }
+                    // Every operation counts as a request of the client
+                    if let Some(Err(wait)) = self.client.as_ref().map(Client::admit) {
+                        let msg = ServerMessage::error(
+                            &id,
+                            format!(
+                                "rate limit exceeded, retry in {} seconds",
+                                wait.as_secs_f64().ceil()
+                            ),
+                        );
+                        let _ = out.send(msg).await;
+                        continue;
+                    }
+                    match parse_graphql_json(&payload, false, &self.persisted_queries, &self.keys) {
+                        Ok(query) => {
+                            let handle = graph::spawn(Self::subscribe(
+                                self.graphql_runner.cheap_clone(),
+                                self.target.clone(),
+                                id.clone(),
+                                query,
+                                out.clone(),
+                                self.client.clone(),
+                            ));
+                            subscriptions.insert(id, handle);
+                        }
+                        Err(e) => {
+                            let _ = out.send(ServerMessage::error(&id, e)).await;
+                        }
+                    }
+                }
+                ClientMessage::Complete { id } => {
+                    if let Some(handle) = subscriptions.remove(&id) {
+                        handle.abort();
+                    }
+                }
+            }
+        }
+
+        for handle in subscriptions.into_values() {
+            handle.abort();
+        }
+        // The writer finishes once all senders are gone
+        drop(out);
+        let _ = writer.await;
+        debug!(self.logger, "WebSocket connection closed");
+    }
+
+    /// Run the operation `query` and send its results to `out` until it
+    /// is done or the client stops listening. Anything other than a
+    /// subscription is run once
+    async fn subscribe(
+        graphql_runner: Arc<Q>,
+        target: QueryTarget,
+        id: String,
+        query: Query,
+        out: mpsc::Sender<Message>,
+        client: Option<Client>,
+    ) {
+        if query.is_subscription() {
+            let mut results = match graphql_runner.run_subscription(query, target).await {
+                Ok(results) => results,
+                Err(errors) => {
+                    let _ = out.send(ServerMessage::errors(&id, &errors)).await;
+                    return;
+                }
+            };
+            while let Some(result) = results.next().await {
+                if let Some(client) = &client {
+                    client.charge(&result);
+                }
+                let msg = ServerMessage::Next {
+                    id: &id,
+                    payload: &result,
+                };
+                if out.send(msg.into()).await.is_err() {
+                    return;
+                }
+            }
+        } else {
+            let result = graphql_runner.run_query(query, target).await;
+            if let Some(client) = &client {
+                client.charge(&result);
+            }
+            let msg = ServerMessage::Next {
+                id: &id,
+                payload: &result,
+            };
+            if out.send(msg.into()).await.is_err() {
+                return;
+            }
+        }
+        let _ = out.send(ServerMessage::Complete { id: &id }.into()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use graph::prelude::serde_json;
+
+    use super::ClientMessage;
+
+    #[test]
+    fn parses_client_messages() {
+        let msg = serde_json::from_str(r#"{"type": "connection_init", "payload": {}}"#).unwrap();
+        assert!(matches!(msg, ClientMessage::ConnectionInit {}));
+
+        let msg = serde_json::from_str(
+            r#"{"type": "subscribe", "id": "1", "payload": {"query": "subscription { things { id } }"}}"#,
+        )
+        .unwrap();
+        match msg {
+            ClientMessage::Subscribe { id, payload } => {
+                assert_eq!("1", id);
+                assert_eq!("subscription { things { id } }", payload["query"]);
+            }
+            _ => panic!("expected a subscribe message but got {:?}", msg),
+        }
+
+        let msg = serde_json::from_str(r#"{"type": "complete", "id": "1"}"#).unwrap();
+        assert!(matches!(msg, ClientMessage::Complete { id } if id == "1"));
+
+        assert!(serde_json::from_str::<ClientMessage>(r#"{"type": "start", "id": "1"}"#).is_err());
+    }
+}
@@ -1,8 +1,9 @@
 use graph::http::StatusCode;
 use std::time::Duration;
 
+use graph::components::graphql::{QueryExport, QueryResultStream};
 use graph::data::{
-    query::{QueryResults, QueryTarget},
+    query::{Federation, QueryResults, QueryTarget},
     value::{Object, Word},
 };
 use graph::prelude::*;
@@ -63,6 +64,57 @@ impl GraphQlRunner for TestGraphQlRunner {
         .into()
     }
 
+    async fn run_batch(
+        self: Arc<Self>,
+        queries: Vec<Query>,
+        target: QueryTarget,
+    ) -> Vec<QueryResults> {
+        let mut results = Vec::new();
+        for query in queries {
+            results.push(self.cheap_clone().run_query(query, target.clone()).await);
+        }
+        results
+    }
+
+    async fn run_subscription(
+        self: Arc<Self>,
+        _query: Query,
+        _target: QueryTarget,
+    ) -> Result<QueryResultStream, QueryResults> {
+        unimplemented!();
+    }
+
+    async fn run_export(
+        self: Arc<Self>,
+        _query: Query,
+        _target: QueryTarget,
+        _page_size: usize,
+    ) -> Result<QueryExport, QueryResults> {
+        unimplemented!();
+    }
+
+    async fn run_federated(
+        self: Arc<Self>,
+        _query: Query,
+        _federation: Federation,
+    ) -> QueryResults {
+        unimplemented!();
+    }
+
+    async fn federated_schema(
+        self: Arc<Self>,
+        _federation: Federation,
+    ) -> Result<s::Document, QueryExecutionError> {
+        unimplemented!();
+    }
+
+    async fn resolve_target(
+        self: Arc<Self>,
+        _target: QueryTarget,
+    ) -> Result<(DeploymentHash, Vec<String>), QueryExecutionError> {
+        unimplemented!();
+    }
+
     fn metrics(&self) -> Arc<dyn GraphQLMetrics> {
         Arc::new(TestGraphQLMetrics)
     }
@@ -144,7 +196,7 @@ mod test {
             assert_header(
                 response,
                 ACCESS_CONTROL_ALLOW_HEADERS.as_str(),
-                "Content-Type, User-Agent",
+                "Content-Type, User-Agent, If-None-Match",
             );
             assert_header(
                 response,
@@ -163,7 +215,11 @@ mod test {
         let logger_factory = LoggerFactory::new(logger, None, Arc::new(MetricsRegistry::mock()));
         let id = USERS.clone();
         let query_runner = Arc::new(TestGraphQlRunner);
-        let server = HyperGraphQLServer::new(&logger_factory, query_runner);
+        let server = HyperGraphQLServer::new(
+            &logger_factory,
+            Arc::new(MetricsRegistry::mock()),
+            query_runner,
+        );
         let server_handle = server
             .start(8007)
             .await
@@ -195,7 +251,11 @@ mod test {
         let logger_factory = LoggerFactory::new(logger, None, Arc::new(MetricsRegistry::mock()));
         let id = USERS.clone();
         let query_runner = Arc::new(TestGraphQlRunner);
-        let server = HyperGraphQLServer::new(&logger_factory, query_runner);
+        let server = HyperGraphQLServer::new(
+            &logger_factory,
+            Arc::new(MetricsRegistry::mock()),
+            query_runner,
+        );
         let server_handle = server
             .start(8002)
             .await
@@ -267,7 +327,11 @@ mod test {
         let logger_factory = LoggerFactory::new(logger, None, Arc::new(MetricsRegistry::mock()));
         let id = USERS.clone();
         let query_runner = Arc::new(TestGraphQlRunner);
-        let server = HyperGraphQLServer::new(&logger_factory, query_runner);
+        let server = HyperGraphQLServer::new(
+            &logger_factory,
+            Arc::new(MetricsRegistry::mock()),
+            query_runner,
+        );
         let server_handle = server
             .start(8003)
             .await
@@ -304,7 +368,11 @@ mod test {
         let logger_factory = LoggerFactory::new(logger, None, Arc::new(MetricsRegistry::mock()));
         let id = USERS.clone();
         let query_runner = Arc::new(TestGraphQlRunner);
-        let server = HyperGraphQLServer::new(&logger_factory, query_runner);
+        let server = HyperGraphQLServer::new(
+            &logger_factory,
+            Arc::new(MetricsRegistry::mock()),
+            query_runner,
+        );
         let server_handle = server
             .start(8005)
             .await
@@ -345,4 +413,57 @@ mod test {
             .expect("Query result field \"name\" is not a string");
         assert_eq!(name, "John".to_string());
     }
+
+    #[tokio::test]
+    async fn accepts_batched_queries() {
+        let logger = Logger::root(slog::Discard, o!());
+        let logger_factory = LoggerFactory::new(logger, None, Arc::new(MetricsRegistry::mock()));
+        let id = USERS.clone();
+        let query_runner = Arc::new(TestGraphQlRunner);
+        let server = HyperGraphQLServer::new(
+            &logger_factory,
+            Arc::new(MetricsRegistry::mock()),
+            query_runner,
+        );
+        let server_handle = server
+            .start(8008)
+            .await
+            .expect("Failed to start GraphQL server");
+        while !server_handle.accepting.load(Ordering::SeqCst) {
+            sleep(Duration::from_millis(20)).await;
+        }
+
+        // Send a batch with two valid queries and a broken one
+        let client = Client::new();
+        let request = client
+            .post(format!("http://localhost:8008/subgraphs/id/{}", id))
+            .header(CONTENT_TYPE, "plain/text")
+            .body(
+                r#"[
+                    { "query": "{ name }" },
+                    { "query": "<L<G<>M>" },
+                    {
+                      "query": "query name($equals: String!) { name(equals: $equals) }",
+                      "variables": { "equals": "John" }
+                    }
+                ]"#,
+            )
+            .build()
+            .unwrap();
+
+        // The response must be a 200 with one result per operation, in the
+        // order of the operations
+        let response = client.execute(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.bytes().await.unwrap().to_vec();
+        let json: serde_json::Value =
+            serde_json::from_slice(&body).expect("GraphQL response is not valid JSON");
+        let results = json.as_array().expect("Batch response must be an array");
+        assert_eq!(results.len(), 3);
+
+        assert_eq!(results[0]["data"]["name"], "Jordi");
+        assert!(results[1]["data"].is_null());
+        assert!(results[1]["errors"].is_array());
+        assert_eq!(results[2]["data"]["name"], "John");
+    }
 }
@@ -8,7 +8,9 @@ use web3::types::Address;
 
 use git_testament::{git_testament, CommitKind};
 use graph::blockchain::{Blockchain, BlockchainKind, BlockchainMap};
-use graph::components::store::{BlockPtrForNumber, BlockStore, QueryPermit, Store};
+use graph::components::store::{
+    BlockPtrForNumber, BlockStore, EntityDiffCursor, QueryPermit, Store,
+};
 use graph::components::versions::VERSIONS;
 use graph::data::graphql::{object, IntoValue, ObjectOrInterface, ValueMap};
 use graph::data::subgraph::{status, DeploymentFeatures};
@@ -187,6 +189,75 @@ impl<S: Store> IndexNodeResolver<S> {
         Ok(entity_changes_to_graphql(entity_changes))
     }
 
+    fn resolve_entity_changes_in_range(
+        &self,
+        field: &a::Field,
+    ) -> Result<r::Value, QueryExecutionError> {
+        let subgraph_id = field
+            .get_required::<DeploymentHash>("subgraphId")
+            .expect("Valid subgraphId required");
+        let entity_type = field
+            .get_required::<String>("entityType")
+            .expect("Valid entityType required");
+        let from_block = field
+            .get_required::<BlockNumber>("fromBlock")
+            .expect("Valid fromBlock required");
+        let to_block = field
+            .get_required::<BlockNumber>("toBlock")
+            .expect("Valid toBlock required");
+        let first = field
+            .get_required::<i32>("first")
+            .expect("Valid first required");
+        let with_values = field
+            .get_required::<bool>("withValues")
+            .expect("Valid withValues required");
+
+        let max_first = ENV_VARS.graphql.max_first;
+        if first < 1 || first as u32 > max_first {
+            return Err(QueryExecutionError::RangeArgumentsError(
+                "first",
+                max_first,
+                first as i64,
+            ));
+        }
+        let after = field
+            .get_optional::<String>("after")
+            .expect("after must be a string")
+            .map(|after| {
+                after.parse::<EntityDiffCursor>().map_err(|e| {
+                    QueryExecutionError::ValueParseError("after".to_owned(), e.to_string())
+                })
+            })
+            .transpose()?;
+
+        let changes = self.store.subgraph_store().entity_diff(
+            &subgraph_id,
+            &entity_type,
+            from_block..=to_block,
+            after,
+            first as usize,
+            with_values,
+        )?;
+
+        let cursor = changes.last().map(|change| change.cursor.to_string());
+        let changes: Vec<_> = changes
+            .into_iter()
+            .map(|change| {
+                object! {
+                    id: change.id.to_string(),
+                    operation: r::Value::Enum(change.operation.as_str().to_string()),
+                    block: change.cursor.block,
+                    cursor: change.cursor.to_string(),
+                    entity: change.data.map(r::Value::Object),
+                }
+            })
+            .collect();
+        Ok(object! {
+            changes: changes,
+            cursor: cursor,
+        })
+    }
+
     async fn resolve_block_data(&self, field: &a::Field) -> Result<r::Value, QueryExecutionError> {
         let network = field
             .get_required::<String>("network")
@@ -862,6 +933,7 @@ impl<S: Store> Resolver for IndexNodeResolver<S> {
             }
             (None, "subgraphFeatures") => self.resolve_subgraph_features(field).await,
             (None, "entityChangesInBlock") => self.resolve_entity_changes_in_block(field),
+            (None, "entityChangesInRange") => self.resolve_entity_changes_in_range(field),
             // The top-level `subgraphVersions` field
             (None, "apiVersions") => self.resolve_api_versions(field),
             (None, "version") => self.version(),
@@ -0,0 +1,6 @@
+alter table public.graphman_command_executions
+    drop constraint graphman_command_executions_kind_check;
+
+alter table public.graphman_command_executions
+    add constraint graphman_command_executions_kind_check
+        check (kind in ('restart_deployment', 'backfill_aggregation'));
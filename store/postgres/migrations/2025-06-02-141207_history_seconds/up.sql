@@ -0,0 +1,2 @@
+alter table subgraphs.subgraph_manifest
+  add column history_seconds int4 check (history_seconds > 0);
@@ -0,0 +1,2 @@
+alter table subgraphs.subgraph_manifest
+  add column schema_patches int4 not null default 0;
@@ -0,0 +1,16 @@
+create table subgraphs.query_shape(
+  deployment    int4        not null
+                references subgraphs.subgraph_deployment(id)
+                on delete cascade,
+  table_name    text        not null,
+  eq_columns    text[]      not null,
+  range_columns text[]      not null,
+  order_columns text[]      not null,
+  predicates    text[]      not null,
+  queries       int8        not null,
+  total_ms      int8        not null,
+  max_ms        int8        not null,
+  last_seen     timestamptz not null,
+  primary key(deployment, table_name, eq_columns, range_columns,
+              order_columns, predicates)
+);
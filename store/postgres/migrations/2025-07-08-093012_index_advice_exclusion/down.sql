@@ -0,0 +1 @@
+drop table subgraphs.index_advice_exclusion;
@@ -0,0 +1,7 @@
+create table subgraphs.index_advice_exclusion(
+  deployment int4 not null
+             references subgraphs.subgraph_deployment(id)
+             on delete cascade,
+  index_name text not null,
+  primary key(deployment, index_name)
+);
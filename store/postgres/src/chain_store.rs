@@ -716,6 +716,41 @@ mod data {
             }
         }
 
+        /// Return the number and hash of all blocks with one of the given
+        /// numbers
+        pub(super) fn block_hashes_by_block_numbers(
+            &self,
+            conn: &mut PgConnection,
+            chain: &str,
+            numbers: &[BlockNumber],
+        ) -> Result<Vec<(BlockNumber, BlockHash)>, Error> {
+            let numbers = Vec::from_iter(numbers.iter().map(|&n| n as i64));
+            match self {
+                Storage::Shared => {
+                    use public::ethereum_blocks as b;
+
+                    b::table
+                        .select((b::number, b::hash))
+                        .filter(b::network_name.eq(&chain))
+                        .filter(b::number.eq_any(numbers))
+                        .get_results::<(i64, String)>(conn)?
+                        .into_iter()
+                        .map(|(number, hash)| {
+                            Ok((number as BlockNumber, hash.parse::<BlockHash>()?))
+                        })
+                        .collect::<Result<Vec<_>, Error>>()
+                }
+                Storage::Private(Schema { blocks, .. }) => Ok(blocks
+                    .table()
+                    .select((blocks.number(), blocks.hash()))
+                    .filter(blocks.number().eq_any(numbers))
+                    .get_results::<(i64, Vec<u8>)>(conn)?
+                    .into_iter()
+                    .map(|(number, hash)| (number as BlockNumber, BlockHash::from(hash)))
+                    .collect()),
+            }
+        }
+
         pub(super) fn confirm_block_hash(
             &self,
             conn: &mut PgConnection,
@@ -808,6 +843,39 @@ mod data {
             }
         }
 
+        /// Find the hash of the block with the highest number at or below
+        /// `number`. The blocks table does not necessarily have every block
+        /// of the chain, and old blocks may have been removed from it, which
+        /// makes it impossible to look blocks up by their exact number
+        pub(super) fn block_hash_at_or_before(
+            &self,
+            conn: &mut PgConnection,
+            chain: &str,
+            number: BlockNumber,
+        ) -> Result<Option<BlockHash>, StoreError> {
+            let hash = match self {
+                Storage::Shared => {
+                    use public::ethereum_blocks as b;
+
+                    b::table
+                        .select(b::hash)
+                        .filter(b::network_name.eq(chain))
+                        .filter(b::number.le(number as i64))
+                        .order_by(b::number.desc())
+                        .first::<BlockHash>(conn)
+                        .optional()?
+                }
+                Storage::Private(Schema { blocks, .. }) => blocks
+                    .table()
+                    .select(blocks.hash())
+                    .filter(blocks.number().le(number as i64))
+                    .order_by(blocks.number().desc())
+                    .first::<BlockHash>(conn)
+                    .optional()?,
+            };
+            Ok(hash)
+        }
+
         pub(super) fn block_numbers(
             &self,
             conn: &mut PgConnection,
@@ -1752,6 +1820,46 @@ impl ChainStore {
         }
     }
 
+    /// Find the hash of the block with the highest number at or below
+    /// `number` that we have
+    pub(crate) async fn block_hash_at_or_before(
+        &self,
+        number: BlockNumber,
+    ) -> Result<Option<BlockHash>, StoreError> {
+        let storage = self.storage.clone();
+        let chain = self.chain.clone();
+        self.pool
+            .with_conn(move |conn, _| {
+                storage
+                    .block_hash_at_or_before(conn, &chain, number)
+                    .map_err(|e| e.into())
+            })
+            .await
+    }
+
+    /// Return the hashes of the blocks with the given `numbers`. Numbers
+    /// for which we do not have exactly one block, for example because
+    /// blocks from a reorg have not been cleaned up yet, are left out
+    pub(crate) fn unique_block_hashes(
+        &self,
+        numbers: &[BlockNumber],
+    ) -> Result<BTreeMap<BlockNumber, BlockHash>, StoreError> {
+        let mut conn = self.get_conn()?;
+        let mut hashes: BTreeMap<BlockNumber, Vec<BlockHash>> = BTreeMap::new();
+        for (number, hash) in
+            self.storage
+                .block_hashes_by_block_numbers(&mut conn, &self.chain, numbers)?
+        {
+            hashes.entry(number).or_default().push(hash);
+        }
+        Ok(hashes
+            .into_iter()
+            .filter_map(|(number, mut hashes)| {
+                (hashes.len() == 1).then(|| (number, hashes.remove(0)))
+            })
+            .collect())
+    }
+
     pub fn is_ingestible(&self) -> bool {
         matches!(self.status, ChainStatus::Ingestible)
     }
@@ -1226,6 +1226,16 @@ impl Connection {
             }
         }
 
+        // Finally create the indexes declared with `@index` in the schema
+        // of the destination unless they were already created together
+        // with the tables
+        for table in self.dst.tables.values() {
+            for (_, sql) in table.declared_indexes(false, true)? {
+                let query = sql_query(sql);
+                self.transaction(|conn| query.execute(conn).map_err(StoreError::from))?;
+            }
+        }
+
         self.copy_private_data_sources(&state)?;
 
         self.transaction(|conn| state.finished(conn))?;
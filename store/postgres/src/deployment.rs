@@ -192,6 +192,11 @@ table! {
         // How many blocks of history to keep, defaults to `i32::max` for
         // unlimited history
         history_blocks -> Integer,
+        // How many seconds of history to keep; when set, `history_blocks`
+        // is periodically recomputed from block timestamps
+        history_seconds -> Nullable<Integer>,
+        // How many times `schema` was changed with a schema patch
+        schema_patches -> Integer,
     }
 }
 
@@ -317,6 +322,47 @@ pub fn schema(conn: &mut PgConnection, site: &Site) -> Result<(InputSchema, bool
         .map(|schema| (schema, use_bytea_prefix))
 }
 
+/// Return how many schema patches have been applied to the deployment
+pub fn schema_patches(conn: &mut PgConnection, site: &Site) -> Result<i32, StoreError> {
+    use subgraph_manifest as sm;
+    sm::table
+        .select(sm::schema_patches)
+        .filter(sm::id.eq(site.id))
+        .first::<i32>(conn)
+        .map_err(StoreError::from)
+}
+
+/// Parse `raw` as a schema with the spec version of the deployment
+pub fn parse_schema(
+    conn: &mut PgConnection,
+    site: &Site,
+    raw: &str,
+) -> Result<InputSchema, StoreError> {
+    use subgraph_manifest as sm;
+    let spec_ver = sm::table
+        .select(sm::spec_version)
+        .filter(sm::id.eq(site.id))
+        .first::<String>(conn)?;
+    let spec_version =
+        Version::parse(spec_ver.as_str()).map_err(|err| StoreError::Unknown(err.into()))?;
+    InputSchema::parse(&spec_version, raw, site.deployment.clone()).map_err(StoreError::Unknown)
+}
+
+/// Replace the schema of the deployment with `schema` and count that as
+/// one more schema patch
+pub fn patch_schema(conn: &mut PgConnection, site: &Site, schema: &str) -> Result<(), StoreError> {
+    use subgraph_manifest as sm;
+
+    update(sm::table.filter(sm::id.eq(site.id)))
+        .set((
+            sm::schema.eq(schema),
+            sm::schema_patches.eq(sm::schema_patches + 1),
+        ))
+        .execute(conn)
+        .map(|_| ())
+        .map_err(StoreError::from)
+}
+
 pub struct ManifestInfo {
     pub description: Option<String>,
     pub repository: Option<String>,
@@ -380,6 +426,31 @@ pub fn set_history_blocks(
         .map_err(StoreError::from)
 }
 
+pub fn set_history_seconds(
+    conn: &mut PgConnection,
+    site: &Site,
+    history_seconds: Option<i32>,
+) -> Result<(), StoreError> {
+    use subgraph_manifest as sm;
+
+    update(sm::table.filter(sm::id.eq(site.id)))
+        .set(sm::history_seconds.eq(history_seconds))
+        .execute(conn)
+        .map(|_| ())
+        .map_err(StoreError::from)
+}
+
+/// How many seconds of history `site` keeps, if its history retention was
+/// set as a duration
+pub fn history_seconds(conn: &mut PgConnection, site: &Site) -> Result<Option<i32>, StoreError> {
+    use subgraph_manifest as sm;
+    sm::table
+        .select(sm::history_seconds)
+        .filter(sm::id.eq(site.id))
+        .first::<Option<i32>>(conn)
+        .map_err(StoreError::from)
+}
+
 #[allow(dead_code)]
 pub fn features(
     conn: &mut PgConnection,
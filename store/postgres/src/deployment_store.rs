@@ -8,12 +8,13 @@ use graph::blockchain::block_stream::{EntitySourceOperation, FirehoseCursor};
 use graph::blockchain::BlockTime;
 use graph::components::store::write::RowGroup;
 use graph::components::store::{
-    Batch, DeploymentLocator, DerivedEntityQuery, PrunePhase, PruneReporter, PruneRequest,
-    PruningStrategy, QueryPermit, StoredDynamicDataSource, VersionStats,
+    Batch, DeploymentLocator, DerivedEntityQuery, EntityAggregate, EntityDiff, EntityDiffCursor,
+    EntityVersion, ExportPages, PrunePhase, PruneReporter, PruneRequest, PruningStrategy,
+    QueryPermit, StoredDynamicDataSource, VersionStats,
 };
 use graph::components::versions::VERSIONS;
 use graph::data::query::Trace;
-use graph::data::store::IdList;
+use graph::data::store::{Id, IdList};
 use graph::data::subgraph::{status, SPEC_VERSION_0_0_6};
 use graph::data_source::CausalityRegion;
 use graph::derive::CheapClone;
@@ -23,6 +24,7 @@ use graph::prelude::{
     SubgraphDeploymentEntity,
 };
 use graph::semver::Version;
+use graph::tokio::sync::{mpsc, Semaphore};
 use graph::tokio::task::JoinHandle;
 use itertools::Itertools;
 use lru_time_cache::LruCache;
@@ -30,7 +32,7 @@ use rand::{rng, seq::SliceRandom};
 use std::collections::{BTreeMap, HashMap};
 use std::convert::Into;
 use std::ops::{Bound, DerefMut};
-use std::ops::{Deref, Range};
+use std::ops::{Deref, Range, RangeInclusive};
 use std::str::FromStr;
 use std::sync::{atomic::AtomicUsize, Arc, Mutex};
 use std::time::{Duration, Instant};
@@ -40,7 +42,7 @@ use graph::components::subgraph::{ProofOfIndexingFinisher, ProofOfIndexingVersio
 use graph::data::subgraph::schema::{DeploymentCreate, SubgraphError};
 use graph::internal_error;
 use graph::prelude::{
-    anyhow, debug, info, o, warn, web3, AttributeNames, BlockNumber, BlockPtr, CheapClone,
+    anyhow, debug, info, o, r, warn, web3, AttributeNames, BlockNumber, BlockPtr, CheapClone,
     DeploymentHash, DeploymentState, Entity, EntityQuery, Error, Logger, QueryExecutionError,
     StopwatchMetrics, StoreError, UnfailOutcome, Value, ENV_VARS,
 };
@@ -52,11 +54,12 @@ use crate::deployment::{self, OnSync};
 use crate::detail::ErrorDetail;
 use crate::dynds::DataSourcesTable;
 use crate::primary::{DeploymentId, Primary};
+use crate::relational::advisor::{self, Advice, AdviceOptions, QueryShape, Workload};
 use crate::relational::index::{CreateIndex, IndexList, Method};
 use crate::relational::{self, Layout, LayoutCache, SqlName, Table};
 use crate::relational_queries::FromEntityData;
 use crate::{advisory_lock, catalog, retry};
-use crate::{detail, ConnectionPool};
+use crate::{detail, query_store::QueryStore, ConnectionPool};
 use crate::{dynds, primary::Site};
 
 /// When connected to read replicas, this allows choosing which DB server to use for an operation.
@@ -71,7 +74,9 @@ pub enum ReplicaId {
 
 /// Commonly needed information about a subgraph that we cache in
 /// `Store.subgraph_cache`. Only immutable subgraph data can be cached this
-/// way as the cache lives for the lifetime of the `Store` object
+/// way as the cache lives for the lifetime of the `Store` object. The one
+/// exception is the API schema, which we rebuild when the schema of the
+/// deployment gets patched
 #[derive(Clone)]
 pub(crate) struct SubgraphInfo {
     /// The schema we derive from `input` with `graphql::schema::api::api_schema`
@@ -86,6 +91,8 @@ pub(crate) struct SubgraphInfo {
     pub(crate) repository: Option<String>,
     pub(crate) poi_version: ProofOfIndexingVersion,
     pub(crate) instrument: bool,
+    /// The `schema_patches` of the layout from which `api` was derived
+    pub(crate) schema_patches: i32,
 }
 
 type PruneHandle = JoinHandle<Result<(), StoreError>>;
@@ -115,6 +122,14 @@ pub struct StoreInner {
     pub(crate) layout_cache: LayoutCache,
 
     prune_handles: Mutex<HashMap<DeploymentId, PruneHandle>>,
+
+    /// The shapes of sampled queries that still need to be written to
+    /// the database
+    workload: Workload,
+
+    /// Exports hold on to a connection until they are done, and we
+    /// therefore limit how many of them can run at once
+    export_permits: Arc<Semaphore>,
 }
 
 /// Storage of the data for individual deployments. Each `DeploymentStore`
@@ -171,6 +186,8 @@ impl DeploymentStore {
             subgraph_cache: Mutex::new(LruCache::with_capacity(100)),
             layout_cache: LayoutCache::new(ENV_VARS.store.query_stats_refresh_interval),
             prune_handles: Mutex::new(HashMap::new()),
+            workload: Workload::new(),
+            export_permits: Arc::new(Semaphore::new(ENV_VARS.graphql.max_concurrent_exports)),
         };
 
         DeploymentStore(Arc::new(store))
@@ -287,7 +304,195 @@ impl DeploymentStore {
             .logger
             .cheap_clone()
             .unwrap_or_else(|| self.logger.cheap_clone());
-        layout.query(&logger, conn, query)
+
+        if !Workload::sample() {
+            return layout.query(&logger, conn, query);
+        }
+
+        let shapes = QueryShape::for_query(&layout, &query);
+        let start = Instant::now();
+        let res = layout.query(&logger, conn, query);
+        self.workload.record(&layout.site, shapes, start.elapsed());
+        res
+    }
+
+    /// Add the query shapes sampled since the last call to what is
+    /// recorded in the database. This is called from a background job so
+    /// that queries never wait for it
+    pub(crate) fn write_workload(&self) -> Result<(), StoreError> {
+        let samples = self.workload.take();
+        if samples.is_empty() {
+            return Ok(());
+        }
+        let mut conn = self.get_conn()?;
+        conn.transaction(|conn| Workload::write(conn, samples))
+    }
+
+    pub(crate) fn export(
+        &self,
+        site: Arc<Site>,
+        replica: ReplicaId,
+        query: EntityQuery,
+        page_size: usize,
+    ) -> Result<ExportPages, QueryExecutionError> {
+        let export_permit = self
+            .export_permits
+            .cheap_clone()
+            .try_acquire_owned()
+            .map_err(|_| QueryExecutionError::Throttled)?;
+        let layout = self.find_layout(site)?;
+        let pool = match replica {
+            ReplicaId::Main => self.pool.clone(),
+            ReplicaId::ReadOnly(idx) => self.read_only_pools[idx].clone(),
+        };
+        let timeout = ENV_VARS.graphql.export_timeout;
+        let deadline = graph::tokio::time::Instant::now() + timeout;
+
+        // The cursor needs a transaction that stays open until all pages
+        // have been read, and we therefore read them on a blocking thread
+        // that holds on to the connection. The channel only buffers one
+        // page so that we don't read ahead of the client
+        let (sender, receiver) = mpsc::channel(1);
+        graph::spawn_blocking_allow_panic(move || {
+            // Wait for the client to make room for `page`, but not past the
+            // deadline so that a client that stops reading can't hold on to
+            // the connection
+            let send =
+                |page| graph::block_on(graph::tokio::time::timeout_at(deadline, sender.send(page)));
+            let export = || -> Result<(), StoreError> {
+                let mut conn = pool.get()?;
+                conn.transaction(|conn| {
+                    layout.declare_export_cursor(conn, query)?;
+                    loop {
+                        if graph::tokio::time::Instant::now() >= deadline {
+                            return Err(StoreError::QueryExecutionError(format!(
+                                "the export was stopped since it took longer than {}s",
+                                timeout.as_secs()
+                            )));
+                        }
+                        let permit = graph::block_on(pool.query_permit());
+                        let page = layout.fetch_export_page(conn, page_size)?;
+                        drop(permit);
+
+                        let done = page.len() < page_size;
+                        match send(Ok(page)) {
+                            Ok(Ok(())) if !done => {}
+                            // Stop once we are done, or the receiver has
+                            // gone away
+                            Ok(_) => return Ok(()),
+                            Err(_) => {
+                                return Err(StoreError::QueryExecutionError(format!(
+                                    "the export was stopped since the client did not read \
+                                     it within {}s",
+                                    timeout.as_secs()
+                                )))
+                            }
+                        }
+                    }
+                })
+            };
+            if let Err(e) = export() {
+                let _ = send(Err(e.into()));
+            }
+            drop(export_permit);
+        });
+        Ok(receiver)
+    }
+
+    pub(crate) fn execute_aggregates(
+        &self,
+        conn: &mut PgConnection,
+        site: Arc<Site>,
+        query: EntityQuery,
+        aggregates: &[EntityAggregate],
+        max_entities: usize,
+    ) -> Result<(Vec<r::Value>, Trace), QueryExecutionError> {
+        let layout = self.layout(conn, site)?;
+
+        let logger = query
+            .logger
+            .cheap_clone()
+            .unwrap_or_else(|| self.logger.cheap_clone());
+        layout.aggregate(&logger, conn, query, aggregates, max_entities)
+    }
+
+    /// Return the block from which the history of `site` can be queried
+    /// when the query asks for it from `from`, together with the earliest
+    /// block of the deployment. Pruning removes versions that were
+    /// replaced before the earliest block, and we can therefore only
+    /// produce the history from there
+    fn history_start(
+        conn: &mut PgConnection,
+        site: &Site,
+        from: Option<BlockNumber>,
+    ) -> Result<(BlockNumber, BlockNumber), QueryExecutionError> {
+        let earliest_block =
+            deployment::state(conn, site.deployment.clone())?.earliest_block_number;
+        let from = from.unwrap_or(earliest_block);
+        if from < earliest_block {
+            return Err(QueryExecutionError::ValueParseError(
+                "fromBlock".to_owned(),
+                format!(
+                    "subgraph {} only has history starting at block number {} \
+                     and versions for block number {} are therefore not available",
+                    site.deployment, earliest_block, from
+                ),
+            ));
+        }
+        Ok((from, earliest_block))
+    }
+
+    pub(crate) fn execute_entity_history(
+        &self,
+        conn: &mut PgConnection,
+        site: Arc<Site>,
+        entity_type: &EntityType,
+        id: &Id,
+        from: Option<BlockNumber>,
+        to: BlockNumber,
+        first: usize,
+        trace: bool,
+    ) -> Result<(Vec<EntityVersion>, Trace), QueryExecutionError> {
+        let (from, earliest_block) = Self::history_start(conn, &site, from)?;
+
+        let layout = self.layout(conn, site)?;
+        layout.entity_history(
+            &self.logger,
+            conn,
+            entity_type,
+            id,
+            from..=to,
+            first,
+            earliest_block,
+            trace,
+        )
+    }
+
+    pub(crate) fn execute_entity_diff(
+        &self,
+        conn: &mut PgConnection,
+        site: Arc<Site>,
+        entity_type: &EntityType,
+        from: Option<BlockNumber>,
+        to: BlockNumber,
+        after: Option<EntityDiffCursor>,
+        first: usize,
+        with_data: bool,
+        trace: bool,
+    ) -> Result<(Vec<EntityDiff>, Trace), QueryExecutionError> {
+        let (from, _) = Self::history_start(conn, &site, from)?;
+
+        let layout = self.layout(conn, site)?;
+        layout.entity_diff(
+            &self.logger,
+            conn,
+            entity_type,
+            from..=to,
+            after,
+            first,
+            with_data,
+            trace,
+        )
     }
 
     fn check_intf_uniqueness(
@@ -460,16 +665,36 @@ impl DeploymentStore {
         self.layout(&mut conn, site)
     }
 
+    /// Return the cached info for `site` unless the schema of the
+    /// deployment was patched since it was cached
+    fn cached_subgraph_info(&self, site: &Site, layout: Option<&Layout>) -> Option<SubgraphInfo> {
+        let info = self
+            .subgraph_cache
+            .lock()
+            .unwrap()
+            .get(&site.deployment)
+            .cloned()?;
+        let layout = layout.map(|layout| layout.schema_patches).or_else(|| {
+            self.layout_cache
+                .find(site)
+                .map(|layout| layout.schema_patches)
+        });
+        match layout {
+            Some(schema_patches) if schema_patches != info.schema_patches => None,
+            _ => Some(info),
+        }
+    }
+
     fn subgraph_info_with_conn(
         &self,
         conn: &mut PgConnection,
         site: Arc<Site>,
     ) -> Result<SubgraphInfo, StoreError> {
-        if let Some(info) = self.subgraph_cache.lock().unwrap().get(&site.deployment) {
-            return Ok(info.clone());
+        let layout = self.layout(conn, site.cheap_clone())?;
+        if let Some(info) = self.cached_subgraph_info(&site, Some(&layout)) {
+            return Ok(info);
         }
 
-        let layout = self.layout(conn, site.cheap_clone())?;
         let manifest_info = deployment::ManifestInfo::load(conn, &site)?;
 
         let graft_block =
@@ -503,6 +728,7 @@ impl DeploymentStore {
             repository: manifest_info.repository,
             poi_version,
             instrument: manifest_info.instrument,
+            schema_patches: layout.schema_patches,
         };
 
         if ENV_VARS.store.query_stats_refresh_interval > Duration::ZERO {
@@ -515,8 +741,8 @@ impl DeploymentStore {
     }
 
     pub(crate) fn subgraph_info(&self, site: Arc<Site>) -> Result<SubgraphInfo, StoreError> {
-        if let Some(info) = self.subgraph_cache.lock().unwrap().get(&site.deployment) {
-            return Ok(info.clone());
+        if let Some(info) = self.cached_subgraph_info(&site, None) {
+            return Ok(info);
         }
 
         let mut conn = self.get_conn()?;
@@ -740,6 +966,63 @@ impl DeploymentStore {
         .await
     }
 
+    pub(crate) fn advised_deployments(&self) -> Result<Vec<DeploymentId>, StoreError> {
+        let mut conn = self.get_conn()?;
+        advisor::deployments(&mut conn)
+    }
+
+    /// Stop recommending the indexes `names` for `site`, or recommend
+    /// them again if `exclude` is `false`
+    pub(crate) fn exclude_index_advice(
+        &self,
+        site: &Site,
+        names: &[String],
+        exclude: bool,
+    ) -> Result<(), StoreError> {
+        let mut conn = self.get_conn()?;
+        advisor::exclude(&mut conn, site, names, exclude)
+    }
+
+    /// Recommend indexes for `site` based on the shapes of the queries
+    /// that were sampled for it. If `create` is set, also create the
+    /// recommended indexes; recommendations for which index creation
+    /// failed are left out of the result
+    pub(crate) async fn advise_indexes(
+        &self,
+        site: Arc<Site>,
+        opts: AdviceOptions,
+        create: bool,
+    ) -> Result<Advice, StoreError> {
+        let store = self.clone();
+        let replicas = !self.read_only_pools.is_empty();
+        self.with_conn(move |conn, cancel| {
+            let schema_name = site.namespace.clone();
+            let layout = store.layout(conn, site.cheap_clone())?;
+            let indexes = IndexList::load(conn, site, store.clone())?;
+            let mut advice = advisor::advise(conn, &layout, &indexes.indexes, &opts, replicas)?;
+            if create {
+                let mut created = Vec::new();
+                for index in advice.create {
+                    cancel.check_cancel()?;
+                    // This might take a long time.
+                    sql_query(&index.sql).execute(conn)?;
+                    if catalog::check_index_is_valid(conn, schema_name.as_str(), &index.name)? {
+                        created.push(index);
+                    } else {
+                        let drop_index_sql = format!(
+                            "drop index concurrently if exists {schema_name}.{}",
+                            index.name
+                        );
+                        sql_query(drop_index_sql).execute(conn)?;
+                    }
+                }
+                advice.create = created;
+            }
+            Ok(advice)
+        })
+        .await
+    }
+
     /// Returns a list of all existing indexes for the specified Entity table.
     pub(crate) async fn indexes_for_entity(
         &self,
@@ -799,6 +1082,29 @@ impl DeploymentStore {
         .await
     }
 
+    /// Recompute the aggregation `name` from its source timeseries. See
+    /// `Layout::backfill_aggregation` for details
+    pub(crate) async fn backfill_aggregation(
+        &self,
+        site: Arc<Site>,
+        name: &str,
+    ) -> Result<usize, StoreError> {
+        let store = self.clone();
+        let name = name.to_string();
+        self.with_conn(move |conn, _| {
+            let layout = store.layout(conn, site.clone())?;
+            let head = Self::block_ptr_with_conn(conn, site)?
+                .map(|ptr| ptr.number)
+                .ok_or_else(|| {
+                    internal_error!("can not backfill a deployment that has not started yet")
+                })?;
+            layout
+                .backfill_aggregation(conn, &name, head)
+                .map_err(Into::into)
+        })
+        .await
+    }
+
     pub(crate) fn set_history_blocks(
         &self,
         site: &Site,
@@ -822,6 +1128,118 @@ impl DeploymentStore {
         deployment::set_history_blocks(&mut conn, site, history_blocks)
     }
 
+    /// Change the schema of the deployment to `schema`, which may only add
+    /// to the current schema, and change the database schema to match.
+    /// Return the DDL that makes that change. With `dry_run`, only check
+    /// the new schema and return the DDL without running it
+    pub(crate) fn patch_schema(
+        &self,
+        site: Arc<Site>,
+        schema: &str,
+        dry_run: bool,
+    ) -> Result<String, StoreError> {
+        let mut conn = self.get_conn()?;
+        let ddl = conn.transaction(|conn| -> Result<_, StoreError> {
+            // Make sure we start from the schema that is in the database
+            self.layout_cache.remove(&site);
+            let layout = self.layout(conn, site.cheap_clone())?;
+
+            let new_schema = deployment::parse_schema(conn, &site, schema)?;
+            let new_layout = Layout::new(site.cheap_clone(), &new_schema, layout.catalog.clone())?;
+            let errors = new_layout.can_patch_from(&layout);
+            if !errors.is_empty() {
+                return Err(StoreError::Unknown(anyhow!(
+                    "the schema of {} can not be patched: {}",
+                    site.deployment,
+                    errors.join(", ")
+                )));
+            }
+
+            let ddl = new_layout.patch_ddl(&layout).map_err(|_| {
+                internal_error!("failed to generate DDL to patch {}", site.deployment)
+            })?;
+            if !dry_run {
+                conn.batch_execute(&ddl)?;
+                deployment::patch_schema(conn, &site, schema)?;
+            }
+            Ok(ddl)
+        })?;
+
+        self.layout_cache.remove(&site);
+        Ok(ddl)
+    }
+
+    pub(crate) fn set_history_seconds(
+        &self,
+        site: &Site,
+        history_seconds: Option<i32>,
+    ) -> Result<(), StoreError> {
+        let mut conn = self.get_conn()?;
+        deployment::set_history_seconds(&mut conn, site, history_seconds)
+    }
+
+    /// The number of blocks of history that pruning `site` should keep.
+    /// If the deployment keeps history for a duration, translate that
+    /// into a number of blocks with the block timestamps in `chain_store`
+    /// and store that as the deployment's `history_blocks`. Return `None`
+    /// if the deployment does not have that much history yet. If the
+    /// duration can not be translated, for example because the chain store
+    /// is missing block timestamps, log that and keep the stored
+    /// `history_blocks`
+    async fn history_blocks_for_prune(
+        self: &Arc<Self>,
+        logger: &Logger,
+        site: Arc<Site>,
+        chain_store: Option<Arc<crate::ChainStore>>,
+        history_blocks: BlockNumber,
+        earliest_block: BlockNumber,
+        latest_block: BlockNumber,
+    ) -> Result<Option<BlockNumber>, StoreError> {
+        let history_seconds = {
+            let mut conn = self.get_conn()?;
+            deployment::history_seconds(&mut conn, &site)?
+        };
+        let (Some(history_seconds), Some(chain_store)) = (history_seconds, chain_store) else {
+            return Ok(Some(history_blocks));
+        };
+
+        let reorg_threshold = ENV_VARS.reorg_threshold();
+        let store = QueryStore::new(
+            self.cheap_clone(),
+            chain_store,
+            site.cheap_clone(),
+            ReplicaId::Main,
+            Arc::new(ApiVersion::default()),
+        );
+        let duration = Duration::from_secs(history_seconds as u64);
+        match relational::prune::history_blocks_for_duration(&store, duration).await {
+            Ok(Some(resolved)) => {
+                let resolved = resolved.max(reorg_threshold + 1);
+                if resolved != history_blocks {
+                    self.set_history_blocks(&site, resolved, reorg_threshold)?;
+                }
+                Ok(Some(resolved))
+            }
+            Ok(None) => {
+                // Keep all the history we have, and look again once the
+                // deployment has accumulated more of it
+                let kept = (latest_block - earliest_block).max(reorg_threshold + 1);
+                self.set_history_blocks(&site, kept, reorg_threshold)?;
+                Ok(None)
+            }
+            Err(e) => {
+                warn!(
+                    logger,
+                    "Failed to translate the history duration into blocks; pruning with the stored history_blocks instead";
+                    "history_seconds" => history_seconds,
+                    "history_blocks" => history_blocks,
+                    "error" => e.to_string(),
+                );
+                Ok(Some(history_blocks))
+            }
+        }
+    }
+
     pub(crate) async fn prune(
         self: &Arc<Self>,
         reporter: Box<dyn PruneReporter>,
@@ -1090,6 +1508,38 @@ impl DeploymentStore {
         Ok(changes)
     }
 
+    pub(crate) fn get_entity_diff(
+        &self,
+        site: Arc<Site>,
+        entity_type: &str,
+        blocks: RangeInclusive<BlockNumber>,
+        after: Option<EntityDiffCursor>,
+        first: usize,
+        with_data: bool,
+    ) -> Result<Vec<EntityDiff>, StoreError> {
+        let mut conn = self.get_conn()?;
+        // Versions that are still current end at the deployment's latest
+        // block; blocks after it haven't happened yet
+        let latest = match Self::block_ptr_with_conn(&mut conn, site.cheap_clone())? {
+            Some(ptr) => ptr.number,
+            None => return Ok(vec![]),
+        };
+        let blocks = *blocks.start()..=(*blocks.end()).min(latest);
+        let layout = self.layout(&mut conn, site)?;
+        let entity_type = layout.input_schema.entity_type(entity_type)?;
+        let (changes, _) = layout.entity_diff(
+            &self.logger,
+            &mut conn,
+            &entity_type,
+            blocks,
+            after,
+            first,
+            with_data,
+            false,
+        )?;
+        Ok(changes)
+    }
+
     // Only used by tests
     #[cfg(debug_assertions)]
     pub(crate) fn find(
@@ -1110,6 +1560,7 @@ impl DeploymentStore {
         last_rollup: Option<BlockTime>,
         stopwatch: &StopwatchMetrics,
         manifest_idx_and_name: &[(u32, String)],
+        chain_store: Option<Arc<crate::ChainStore>>,
     ) -> Result<(), StoreError> {
         let mut conn = {
             let _section = stopwatch.start_section("transact_blocks_get_conn");
@@ -1184,6 +1635,7 @@ impl DeploymentStore {
             if let Err(res) = self.spawn_prune(
                 logger,
                 site.cheap_clone(),
+                chain_store,
                 layout.history_blocks,
                 earliest_block,
                 batch.block_ptr.number,
@@ -1204,6 +1656,7 @@ impl DeploymentStore {
         self: &Arc<Self>,
         logger: &Logger,
         site: Arc<Site>,
+        chain_store: Option<Arc<crate::ChainStore>>,
         history_blocks: BlockNumber,
         earliest_block: BlockNumber,
         latest_block: BlockNumber,
@@ -1246,7 +1699,10 @@ impl DeploymentStore {
             logger: Logger,
             store: Arc<DeploymentStore>,
             site: Arc<Site>,
-            req: PruneRequest,
+            chain_store: Option<Arc<crate::ChainStore>>,
+            history_blocks: BlockNumber,
+            earliest_block: BlockNumber,
+            latest_block: BlockNumber,
         ) -> Result<(), StoreError> {
             {
                 if store.is_source(&site)? {
@@ -1257,6 +1713,33 @@ impl DeploymentStore {
                     return Ok(());
                 }
             }
+            let history_blocks = match store
+                .history_blocks_for_prune(
+                    &logger,
+                    site.cheap_clone(),
+                    chain_store,
+                    history_blocks,
+                    earliest_block,
+                    latest_block,
+                )
+                .await?
+            {
+                Some(history_blocks) => history_blocks,
+                None => {
+                    debug!(
+                        logger,
+                        "Skipping pruning since the deployment does not have enough history yet"
+                    );
+                    return Ok(());
+                }
+            };
+            let req = PruneRequest::new(
+                &site.as_ref().into(),
+                history_blocks,
+                ENV_VARS.reorg_threshold(),
+                earliest_block,
+                latest_block,
+            )?;
             let logger2 = logger.cheap_clone();
             retry::forever_async(&logger2, "prune", move || {
                 let store = store.cheap_clone();
@@ -1268,17 +1751,17 @@ impl DeploymentStore {
         }
 
         if !prune_in_progress(&self, &site)? {
-            let req = PruneRequest::new(
-                &site.as_ref().into(),
+            let deployment_id = site.id;
+            let logger = Logger::new(&logger, o!("component" => "Prune"));
+            let handle = graph::spawn(run(
+                logger,
+                self.clone(),
+                site,
+                chain_store,
                 history_blocks,
-                ENV_VARS.reorg_threshold(),
                 earliest_block,
                 latest_block,
-            )?;
-
-            let deployment_id = site.id;
-            let logger = Logger::new(&logger, o!("component" => "Prune"));
-            let handle = graph::spawn(run(logger, self.clone(), site, req));
+            ));
             self.prune_handles
                 .lock()
                 .unwrap()
@@ -1445,17 +1928,65 @@ impl DeploymentStore {
         use std::sync::atomic::Ordering;
 
         // Pick a weighted ReplicaId. `replica_order` contains a list of
-        // replicas with repetitions according to their weight
-        let replica_id = {
-            let weights_count = self.replica_order.len();
-            let index =
-                self.conn_round_robin_counter.fetch_add(1, Ordering::SeqCst) % weights_count;
-            *self.replica_order.get(index).unwrap()
-        };
+        // replicas with repetitions according to their weight. Replicas
+        // that lag too far behind the primary are skipped, and if all of
+        // them do, we use the primary
+        let weights_count = self.replica_order.len();
+        let start = self.conn_round_robin_counter.fetch_add(1, Ordering::SeqCst);
+        let replica_id = (0..weights_count)
+            .map(|i| self.replica_order[(start + i) % weights_count])
+            .find(|replica| match replica {
+                ReplicaId::Main => true,
+                ReplicaId::ReadOnly(idx) => {
+                    // Always check the lag so that it gets reported
+                    let lag = self.read_only_pools[*idx].replica_lag(&self.pool);
+                    match ENV_VARS.store.replica_max_lag {
+                        Some(max_lag) => lag.is_some_and(|lag| lag <= max_lag),
+                        None => true,
+                    }
+                }
+            })
+            .unwrap_or(ReplicaId::Main);
 
         Ok(replica_id)
     }
 
+    /// The number of the latest block of `site` that `replica` has, or
+    /// `None` if the deployment has not processed any blocks there yet
+    pub(crate) async fn replica_block_number(
+        &self,
+        site: Arc<Site>,
+        replica: ReplicaId,
+    ) -> Result<Option<BlockNumber>, StoreError> {
+        let pool = match replica {
+            ReplicaId::Main => &self.pool,
+            ReplicaId::ReadOnly(idx) => &self.read_only_pools[idx],
+        };
+        pool.with_conn(move |conn, _| {
+            deployment::block_ptr(conn, &site.deployment)
+                .map(|ptr| ptr.map(|ptr| ptr.number))
+                .map_err(Into::into)
+        })
+        .await
+    }
+
+    /// All entity changes that happened in `block` of `site`, read from
+    /// `replica`
+    pub(crate) async fn replica_changes(
+        &self,
+        site: Arc<Site>,
+        replica: ReplicaId,
+        block: BlockNumber,
+    ) -> Result<Vec<EntityOperation>, StoreError> {
+        let layout = self.find_layout(site)?;
+        let pool = match replica {
+            ReplicaId::Main => &self.pool,
+            ReplicaId::ReadOnly(idx) => &self.read_only_pools[idx],
+        };
+        pool.with_conn(move |conn, _| layout.find_changes(conn, block).map_err(Into::into))
+            .await
+    }
+
     pub(crate) async fn load_dynamic_data_sources(
         &self,
         site: Arc<Site>,
@@ -186,6 +186,7 @@ pub(crate) fn info_from_details(
     non_fatal: Vec<ErrorDetail>,
     sites: &[Arc<Site>],
     subgraph_history_blocks: i32,
+    subgraph_history_seconds: Option<i32>,
 ) -> Result<status::Info, StoreError> {
     let DeploymentDetail {
         id,
@@ -251,6 +252,7 @@ pub(crate) fn info_from_details(
         entity_count,
         node: None,
         history_blocks: subgraph_history_blocks,
+        history_seconds: subgraph_history_seconds,
     })
 }
 
@@ -333,18 +335,19 @@ pub(crate) fn deployment_statuses(
         .into_group_map()
     };
 
-    let mut history_blocks_map: HashMap<_, _> = {
+    let mut history_map: HashMap<_, _> = {
         if sites.is_empty() {
             sm::table
-                .select((sm::id, sm::history_blocks))
-                .load::<(DeploymentId, i32)>(conn)?
+                .select((sm::id, sm::history_blocks, sm::history_seconds))
+                .load::<(DeploymentId, i32, Option<i32>)>(conn)?
         } else {
             sm::table
                 .filter(sm::id.eq_any(sites.iter().map(|site| site.id)))
-                .select((sm::id, sm::history_blocks))
-                .load::<(DeploymentId, i32)>(conn)?
+                .select((sm::id, sm::history_blocks, sm::history_seconds))
+                .load::<(DeploymentId, i32, Option<i32>)>(conn)?
         }
         .into_iter()
+        .map(|(id, blocks, seconds)| (id, (blocks, seconds)))
         .collect()
     };
 
@@ -352,8 +355,16 @@ pub(crate) fn deployment_statuses(
         .into_iter()
         .map(|(detail, fatal)| {
             let non_fatal = non_fatal_errors.remove(&detail.id).unwrap_or_default();
-            let subgraph_history_blocks = history_blocks_map.remove(&detail.id).unwrap_or_default();
-            info_from_details(detail, fatal, non_fatal, sites, subgraph_history_blocks)
+            let (history_blocks, history_seconds) =
+                history_map.remove(&detail.id).unwrap_or_default();
+            info_from_details(
+                detail,
+                fatal,
+                non_fatal,
+                sites,
+                history_blocks,
+                history_seconds,
+            )
         })
         .collect()
 }
@@ -379,6 +390,8 @@ struct StoredSubgraphManifest {
     entities_with_causality_region: Vec<String>,
     on_sync: Option<String>,
     history_blocks: i32,
+    history_seconds: Option<i32>,
+    schema_patches: i32,
 }
 
 impl StoredSubgraphManifest {
@@ -6,10 +6,11 @@ use std::time::{Duration, Instant};
 use async_trait::async_trait;
 use diesel::{prelude::RunQueryDsl, sql_query, sql_types::Double};
 
-use graph::prelude::{error, Logger, MetricsRegistry, StoreError, ENV_VARS};
+use graph::prelude::{error, info, warn, Logger, MetricsRegistry, StoreError, ENV_VARS};
 use graph::prometheus::Gauge;
 use graph::util::jobs::{Job, Runner};
 
+use crate::relational::advisor::AdviceOptions;
 use crate::ConnectionPool;
 use crate::{unused, Store, SubgraphStore};
 
@@ -47,6 +48,21 @@ pub fn register(
         Arc::new(RefreshMaterializedView::new(store.subgraph_store())),
         6 * ONE_HOUR,
     );
+
+    if ENV_VARS.store.index_advisor_auto_create {
+        runner.register(
+            Arc::new(IndexAdvisor::new(store.subgraph_store())),
+            ONE_HOUR,
+        );
+    }
+}
+
+/// Register the jobs that every node that runs queries needs, whether it
+/// runs the jobs from `register` or not
+pub fn register_query_jobs(runner: &mut Runner, store: Arc<SubgraphStore>) {
+    const FIVE_MINUTES: Duration = Duration::from_secs(5 * 60);
+
+    runner.register(Arc::new(RecordWorkload::new(store)), FIVE_MINUTES);
 }
 
 /// A job that vacuums `subgraphs.subgraph_deployment`. With a large number
@@ -156,6 +172,32 @@ impl Job for MirrorPrimary {
     }
 }
 
+/// A job that writes the shapes of the queries that were sampled for the
+/// index advisor to the database. Writing them can take a while, and we
+/// therefore do it here rather than while running a query
+struct RecordWorkload {
+    store: Arc<SubgraphStore>,
+}
+
+impl RecordWorkload {
+    fn new(store: Arc<SubgraphStore>) -> Self {
+        Self { store }
+    }
+}
+
+#[async_trait]
+impl Job for RecordWorkload {
+    fn name(&self) -> &str {
+        "Record query shapes for the index advisor"
+    }
+
+    async fn run(&self, logger: &Logger) {
+        if let Err(e) = self.store.write_workload() {
+            error!(logger, "failed to record query shapes for the index advisor"; "error" => e.to_string());
+        }
+    }
+}
+
 struct RefreshMaterializedView {
     store: Arc<SubgraphStore>,
 }
@@ -177,6 +219,66 @@ impl Job for RefreshMaterializedView {
     }
 }
 
+/// A job that creates the indexes that the index advisor recommends based
+/// on the queries it sampled. Each run creates at most
+/// `GRAPH_STORE_INDEX_ADVISOR_MAX_CREATE` indexes across all deployments
+struct IndexAdvisor {
+    store: Arc<SubgraphStore>,
+}
+
+impl IndexAdvisor {
+    fn new(store: Arc<SubgraphStore>) -> Self {
+        Self { store }
+    }
+}
+
+#[async_trait]
+impl Job for IndexAdvisor {
+    fn name(&self) -> &str {
+        "Create indexes recommended by the index advisor"
+    }
+
+    async fn run(&self, logger: &Logger) {
+        let deployments = match self.store.advised_deployments() {
+            Ok(deployments) => deployments,
+            Err(e) => {
+                error!(logger, "failed to list deployments for the index advisor"; "error" => e.to_string());
+                return;
+            }
+        };
+
+        let mut remaining = ENV_VARS.store.index_advisor_max_create;
+        for deployment in deployments {
+            if remaining == 0 {
+                info!(logger, "created the maximum number of indexes for this run of the index advisor";
+                              "max_create" => ENV_VARS.store.index_advisor_max_create);
+                break;
+            }
+            let opts = AdviceOptions {
+                max_age: Some(ENV_VARS.store.index_advisor_max_age),
+                max_indexes: Some(remaining),
+                ..AdviceOptions::default()
+            };
+            match self.store.advise_indexes(&deployment, opts, true).await {
+                Ok(advice) => {
+                    remaining = remaining.saturating_sub(advice.create.len());
+                    for index in advice.create {
+                        info!(logger, "created index recommended by the index advisor";
+                                      "sgd" => deployment.id.to_string(),
+                                      "index" => &index.name,
+                                      "avg_ms" => index.avg_ms);
+                    }
+                }
+                Err(e) => {
+                    warn!(logger, "failed to create recommended indexes";
+                                  "sgd" => deployment.id.to_string(),
+                                  "error" => e.to_string());
+                }
+            }
+        }
+    }
+}
+
 struct UnusedJob {
     store: Arc<SubgraphStore>,
 }
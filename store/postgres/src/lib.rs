@@ -59,7 +59,7 @@ pub use self::block_store::ChainStatus;
 pub use self::chain_head_listener::ChainHeadUpdateListener;
 pub use self::chain_store::{ChainStore, ChainStoreMetrics, Storage};
 pub use self::detail::DeploymentDetail;
-pub use self::jobs::register as register_jobs;
+pub use self::jobs::{register as register_jobs, register_query_jobs};
 pub use self::notification_listener::NotificationSender;
 pub use self::pool::{ConnectionPool, ForeignServer, PoolCoordinator, PoolRole};
 pub use self::primary::{db_version, UnusedDeployment};
@@ -81,6 +81,7 @@ pub mod command_support {
         pub use crate::primary::{Connection, Mirror};
     }
     pub mod index {
+        pub use crate::relational::advisor::{Advice, AdviceOptions, IndexAdvice, UnusedIndex};
         pub use crate::relational::index::{CreateIndex, Method};
     }
     pub use crate::deployment::{on_sync, OnSync};
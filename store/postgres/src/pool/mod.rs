@@ -32,10 +32,12 @@ use crate::{Shard, PRIMARY_SHARD};
 
 mod coordinator;
 mod foreign_server;
+mod replica_lag;
 mod state_tracker;
 
 pub use coordinator::PoolCoordinator;
 pub use foreign_server::ForeignServer;
+use replica_lag::ReplicaLag;
 use state_tracker::{ErrorHandler, EventHandler, StateTracker};
 
 /// The namespace under which the `PRIMARY_TABLES` are mapped into each
@@ -208,6 +210,9 @@ pub struct ConnectionPool {
     inner: PoolState,
     pub shard: Shard,
     state_tracker: StateTracker,
+    /// How far the database lags behind the primary; only set for
+    /// replicas
+    replica_lag: Option<Arc<ReplicaLag>>,
 }
 
 impl fmt::Debug for ConnectionPool {
@@ -257,6 +262,20 @@ impl ConnectionPool {
         let state_tracker = StateTracker::new();
         let shard =
             Shard::new(shard_name.to_string()).expect("shard_name is a valid name for a shard");
+        let replica_lag = pool_name.is_replica().then(|| {
+            let const_labels = HashMap::from([
+                ("pool".to_string(), pool_name.as_str().to_string()),
+                ("shard".to_string(), shard.to_string()),
+            ]);
+            let gauge = registry
+                .new_gauge(
+                    "store_replica_lag_seconds",
+                    "How far a read replica lags behind the primary database, -1 if unknown",
+                    const_labels,
+                )
+                .expect("failed to create `store_replica_lag_seconds` gauge");
+            Arc::new(ReplicaLag::new(gauge))
+        });
         let inner = {
             let pool = PoolInner::create(
                 shard.clone(),
@@ -278,6 +297,7 @@ impl ConnectionPool {
             inner,
             shard,
             state_tracker,
+            replica_lag,
         }
     }
 
@@ -412,6 +432,50 @@ impl ConnectionPool {
         self.inner.get_unready().wait_stats.cheap_clone()
     }
 
+    /// How far the database of this pool lags behind the `primary`
+    /// database, or `None` if that is not known (yet). The main pool never
+    /// lags. The lag is measured every few seconds by a background task
+    /// that the first call starts; this only reads the last measurement
+    /// and never waits for the database
+    pub(crate) fn replica_lag(&self, primary: &ConnectionPool) -> Option<Duration> {
+        let Some(replica_lag) = &self.replica_lag else {
+            return Some(Duration::ZERO);
+        };
+        if replica_lag.start_monitoring() {
+            self.monitor_replica_lag(replica_lag.cheap_clone(), primary.clone());
+        }
+        replica_lag.get()
+    }
+
+    fn monitor_replica_lag(&self, replica_lag: Arc<ReplicaLag>, primary: ConnectionPool) {
+        let replica = self.clone();
+        let logger = self.inner.get_unready().logger.clone();
+        graph::spawn(async move {
+            loop {
+                let replica = replica.clone();
+                let primary = primary.clone();
+                let logger = logger.clone();
+                let lag = replica_lag.cheap_clone();
+                let measure = move || -> Result<Duration, StoreError> {
+                    let mut primary = primary.get()?;
+                    let mut conn = replica.get()?;
+                    replica_lag::measure(&mut primary, &mut conn)
+                };
+                // Getting connections and measuring blocks
+                let _ = graph::spawn_blocking_allow_panic(move || {
+                    lag.update(|| {
+                        measure().map_err(|e| {
+                            warn!(logger, "Failed to determine replica lag"; "error" => e.to_string());
+                            e
+                        })
+                    })
+                })
+                .await;
+                tokio::time::sleep(replica_lag::CHECK_INTERVAL).await;
+            }
+        });
+    }
+
     /// Mirror key tables from the primary into our own schema. We do this
     /// by manually inserting or deleting rows through comparing it with the
     /// table on the primary. Once we drop support for PG 9.6, we can
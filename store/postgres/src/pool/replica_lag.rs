@@ -0,0 +1,130 @@
+//! Track how far a read replica lags behind the primary database
+
+use diesel::sql_types::{Double, Nullable, Text};
+use diesel::{sql_query, PgConnection, QueryableByName, RunQueryDsl};
+
+use graph::internal_error;
+use graph::prelude::{Gauge, StoreError};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How often we measure the lag
+pub(super) const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+pub(super) struct ReplicaLag {
+    /// What we found when we last measured the lag; `None` if we have not
+    /// measured it yet or could not determine it
+    lag: Mutex<Option<Duration>>,
+    /// Set once a background task measures the lag
+    monitoring: AtomicBool,
+    gauge: Box<Gauge>,
+}
+
+impl ReplicaLag {
+    pub(super) fn new(gauge: Box<Gauge>) -> Self {
+        Self {
+            lag: Mutex::new(None),
+            monitoring: AtomicBool::new(false),
+            gauge,
+        }
+    }
+
+    /// The lag of the replica from the last measurement. This never waits
+    /// for a measurement, which happens in the background
+    pub(super) fn get(&self) -> Option<Duration> {
+        *self.lag.lock().unwrap()
+    }
+
+    /// Return `true` if the caller should start measuring the lag in the
+    /// background, which is the case for exactly one caller
+    pub(super) fn start_monitoring(&self) -> bool {
+        !self.monitoring.swap(true, Ordering::SeqCst)
+    }
+
+    /// Remember the result of `measure` as the current lag
+    pub(super) fn update<F>(&self, measure: F)
+    where
+        F: FnOnce() -> Result<Duration, StoreError>,
+    {
+        let lag = measure().ok();
+        *self.lag.lock().unwrap() = lag;
+        // We report a lag we could not determine as -1
+        self.gauge
+            .set(lag.map(|lag| lag.as_secs_f64()).unwrap_or(-1.0));
+    }
+}
+
+/// Determine how far the database `conn` is connected to lags behind the
+/// `primary` database. We first get the position of the primary's WAL and
+/// then check whether the replica has replayed everything up to that
+/// position. If it has, it is not lagging, even if the last transaction it
+/// replayed is old, since that only means that nothing was written on the
+/// primary. If it has not, it lags by the time since it last replayed a
+/// transaction, which keeps growing when replication has stalled, even if
+/// the replica has replayed everything it received. Databases that are not
+/// in recovery do not lag
+pub(super) fn measure(
+    primary: &mut PgConnection,
+    conn: &mut PgConnection,
+) -> Result<Duration, StoreError> {
+    #[derive(QueryableByName)]
+    struct Lsn {
+        #[diesel(sql_type = Text)]
+        lsn: String,
+    }
+
+    #[derive(QueryableByName)]
+    struct Lag {
+        #[diesel(sql_type = Nullable<Double>)]
+        lag: Option<f64>,
+    }
+
+    let lsn = sql_query("select pg_current_wal_lsn()::text as lsn")
+        .get_result::<Lsn>(primary)?
+        .lsn;
+    let lag = sql_query(
+        "select case when not pg_is_in_recovery() then 0 \
+                     when pg_last_wal_replay_lsn() >= $1::pg_lsn then 0 \
+                     else extract(epoch from now() - pg_last_xact_replay_timestamp())::float8 \
+                 end as lag",
+    )
+    .bind::<Text, _>(lsn)
+    .get_result::<Lag>(conn)?
+    .lag
+    .ok_or_else(|| {
+        internal_error!("the replica is behind but has not replayed any transactions")
+    })?;
+    Ok(Duration::from_secs_f64(lag.max(0.0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use graph::prelude::{Gauge, StoreError};
+
+    use super::ReplicaLag;
+
+    #[test]
+    fn lag_is_the_last_measurement() {
+        let gauge = Box::new(Gauge::new("replica_lag", "lag").unwrap());
+        let lag = ReplicaLag::new(gauge.clone());
+
+        // Until the lag has been measured, it is unknown
+        assert_eq!(None, lag.get());
+
+        lag.update(|| Ok(Duration::from_secs(3)));
+        assert_eq!(Some(Duration::from_secs(3)), lag.get());
+        assert_eq!(3.0, gauge.get());
+
+        lag.update(|| Err(StoreError::DatabaseUnavailable));
+        assert_eq!(None, lag.get());
+        assert_eq!(-1.0, gauge.get());
+
+        // Only one caller starts measuring
+        assert!(lag.start_monitoring());
+        assert!(!lag.start_monitoring());
+    }
+}
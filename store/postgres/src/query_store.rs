@@ -2,11 +2,14 @@ use std::collections::HashMap;
 use std::time::Instant;
 
 use crate::deployment_store::{DeploymentStore, ReplicaId};
-use graph::components::store::{DeploymentId, QueryPermit, QueryStore as QueryStoreTrait};
+use graph::components::store::{
+    DeploymentId, EntityAggregate, EntityDiff, EntityDiffCursor, EntityVersion, ExportPages,
+    QueryPermit, QueryStore as QueryStoreTrait,
+};
 use graph::data::query::Trace;
-use graph::data::store::QueryObject;
+use graph::data::store::{Id, QueryObject};
 use graph::prelude::*;
-use graph::schema::{ApiSchema, InputSchema};
+use graph::schema::{ApiSchema, EntityType, InputSchema};
 
 use crate::primary::Site;
 
@@ -34,6 +37,43 @@ impl QueryStore {
             api_version,
         }
     }
+
+    /// The block with the highest number at or below `number` that the
+    /// chain store has, together with its timestamp
+    async fn known_block(
+        &self,
+        number: BlockNumber,
+    ) -> Result<Option<(BlockNumber, u64)>, QueryExecutionError> {
+        let hash = match self.chain_store.block_hash_at_or_before(number).await? {
+            Some(hash) => hash,
+            None => return Ok(None),
+        };
+        match self
+            .block_number_with_timestamp_and_parent_hash(&hash)
+            .await?
+        {
+            Some((number, Some(ts), _)) => Ok(Some((number, ts))),
+            // The chain does not record timestamps, or the block was
+            // removed since we looked for it
+            _ => Err(self.timestamps_unavailable(number)),
+        }
+    }
+
+    /// The error for `block: { timestamp_lte }` when the chain store can't
+    /// tell which block that is. Deployments don't store block timestamps
+    /// themselves, so there is nothing to fall back to
+    fn timestamps_unavailable(&self, number: BlockNumber) -> QueryExecutionError {
+        QueryExecutionError::StoreError(
+            anyhow!(
+                "the timestamps of blocks before block {} of network {} are not available, \
+                 either because the network does not record them or because the blocks were \
+                 pruned from the block cache; query by block `number` or `hash` instead",
+                number,
+                self.site.network
+            )
+            .into(),
+        )
+    }
 }
 
 #[async_trait]
@@ -57,6 +97,115 @@ impl QueryStoreTrait for QueryStore {
             })
     }
 
+    fn export(
+        &self,
+        query: EntityQuery,
+        page_size: usize,
+    ) -> Result<ExportPages, QueryExecutionError> {
+        assert_eq!(&self.site.deployment, &query.subgraph_id);
+        self.store
+            .export(self.site.clone(), self.replica_id, query, page_size)
+    }
+
+    fn find_aggregates(
+        &self,
+        query: EntityQuery,
+        aggregates: &[EntityAggregate],
+        max_entities: usize,
+    ) -> Result<(Vec<r::Value>, Trace), QueryExecutionError> {
+        assert_eq!(&self.site.deployment, &query.subgraph_id);
+        let start = Instant::now();
+        let mut conn = self
+            .store
+            .get_replica_conn(self.replica_id)
+            .map_err(|e| QueryExecutionError::StoreError(e.into()))?;
+        let wait = start.elapsed();
+        self.store
+            .execute_aggregates(
+                &mut conn,
+                self.site.clone(),
+                query,
+                aggregates,
+                max_entities,
+            )
+            .map(|(values, mut trace)| {
+                trace.conn_wait(wait);
+                (values, trace)
+            })
+    }
+
+    fn entity_history(
+        &self,
+        entity_type: &EntityType,
+        id: &Id,
+        from: Option<BlockNumber>,
+        to: BlockNumber,
+        first: usize,
+        trace: bool,
+    ) -> Result<(Vec<EntityVersion>, Trace), QueryExecutionError> {
+        let start = Instant::now();
+        let mut conn = self
+            .store
+            .get_replica_conn(self.replica_id)
+            .map_err(|e| QueryExecutionError::StoreError(e.into()))?;
+        let wait = start.elapsed();
+        let (mut versions, mut trace) = self.store.execute_entity_history(
+            &mut conn,
+            self.site.clone(),
+            entity_type,
+            id,
+            from,
+            to,
+            first,
+            trace,
+        )?;
+        trace.conn_wait(wait);
+
+        let numbers: Vec<_> = versions
+            .iter()
+            .flat_map(|version| std::iter::once(version.from_block).chain(version.to_block))
+            .collect();
+        let hashes = self.chain_store.unique_block_hashes(&numbers)?;
+        for version in &mut versions {
+            version.from_hash = hashes.get(&version.from_block).cloned();
+            version.to_hash = version
+                .to_block
+                .and_then(|number| hashes.get(&number).cloned());
+        }
+        Ok((versions, trace))
+    }
+
+    fn entity_diff(
+        &self,
+        entity_type: &EntityType,
+        from: Option<BlockNumber>,
+        to: BlockNumber,
+        after: Option<EntityDiffCursor>,
+        first: usize,
+        with_data: bool,
+        trace: bool,
+    ) -> Result<(Vec<EntityDiff>, Trace), QueryExecutionError> {
+        let start = Instant::now();
+        let mut conn = self
+            .store
+            .get_replica_conn(self.replica_id)
+            .map_err(|e| QueryExecutionError::StoreError(e.into()))?;
+        let wait = start.elapsed();
+        let (changes, mut trace) = self.store.execute_entity_diff(
+            &mut conn,
+            self.site.clone(),
+            entity_type,
+            from,
+            to,
+            after,
+            first,
+            with_data,
+            trace,
+        )?;
+        trace.conn_wait(wait);
+        Ok((changes, trace))
+    }
+
     /// Return true if the deployment with the given id is fully synced,
     /// and return false otherwise. Errors from the store are passed back up
     async fn is_deployment_synced(&self) -> Result<bool, Error> {
@@ -112,6 +261,54 @@ impl QueryStoreTrait for QueryStore {
         self.chain_store.block_numbers(block_hashes).await
     }
 
+    async fn block_number_for_timestamp(
+        &self,
+        timestamp: u64,
+    ) -> Result<Option<BlockNumber>, QueryExecutionError> {
+        let state = self.deployment_state().await?;
+        let earliest = state.earliest_block_number;
+        let latest = state.latest_block.number;
+
+        match self.known_block(latest).await? {
+            Some((number, ts)) if number >= earliest => {
+                if ts <= timestamp {
+                    return Ok(Some(number));
+                }
+            }
+            _ => return Err(self.timestamps_unavailable(latest)),
+        }
+
+        // The chain store does not necessarily have all blocks, and it
+        // might have removed old ones. We therefore binary search over the
+        // blocks it does have: `below(x)` is true if the last block with a
+        // timestamp at or below `x` is at or before `timestamp`, or if
+        // there is no such block at all. That keeps `below(lo)` true and
+        // `below(hi)` false
+        let mut lo = earliest;
+        let mut hi = latest;
+        match self.known_block(lo).await? {
+            // No block of the deployment is that old
+            Some((_, ts)) if ts > timestamp => return Ok(None),
+            _ => {}
+        }
+        while hi - lo > 1 {
+            let mid = lo + (hi - lo) / 2;
+            match self.known_block(mid).await? {
+                Some((_, ts)) if ts > timestamp => hi = mid,
+                _ => lo = mid,
+            }
+        }
+
+        // `hi` is the first block after `timestamp`. When the chain store
+        // is missing the blocks right before it, we settle for the latest
+        // block we know is at or before `timestamp`, as long as the
+        // deployment has data for it
+        match self.known_block(lo).await? {
+            Some((number, _)) if number >= earliest => Ok(Some(number)),
+            _ => Err(self.timestamps_unavailable(hi)),
+        }
+    }
+
     fn wait_stats(&self) -> PoolWaitStats {
         self.store.wait_stats(self.replica_id)
     }
@@ -133,6 +330,21 @@ impl QueryStoreTrait for QueryStore {
         Ok(layout.input_schema.cheap_clone())
     }
 
+    fn entity_estimates(&self) -> Result<HashMap<String, u64>, QueryExecutionError> {
+        let layout = self.store.find_layout(self.site.cheap_clone())?;
+        Ok(layout.entity_estimates())
+    }
+
+    async fn entity_changes_in_block(
+        &self,
+        block: BlockNumber,
+    ) -> Result<Vec<EntityOperation>, QueryExecutionError> {
+        Ok(self
+            .store
+            .replica_changes(self.site.cheap_clone(), self.replica_id, block)
+            .await?)
+    }
+
     fn network_name(&self) -> &str {
         &self.site.network
     }
@@ -148,4 +360,27 @@ impl QueryStoreTrait for QueryStore {
     fn deployment_id(&self) -> DeploymentId {
         self.site.id.into()
     }
+
+    async fn for_block(
+        self: Arc<Self>,
+        block: BlockNumber,
+    ) -> Result<Arc<dyn QueryStoreTrait + Send + Sync>, QueryExecutionError> {
+        if self.replica_id == ReplicaId::Main {
+            return Ok(self);
+        }
+        let head = self
+            .store
+            .replica_block_number(self.site.cheap_clone(), self.replica_id)
+            .await?;
+        if head.is_some_and(|head| head >= block) {
+            return Ok(self);
+        }
+        Ok(Arc::new(QueryStore::new(
+            self.store.cheap_clone(),
+            self.chain_store.cheap_clone(),
+            self.site.cheap_clone(),
+            ReplicaId::Main,
+            self.api_version.cheap_clone(),
+        )))
+    }
 }
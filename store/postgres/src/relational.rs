@@ -14,10 +14,11 @@ mod ddl_tests;
 #[cfg(test)]
 mod query_tests;
 
+pub(crate) mod advisor;
 pub(crate) mod dsl;
 pub(crate) mod index;
 pub(crate) mod prune;
-mod rollup;
+pub(crate) mod rollup;
 pub(crate) mod value;
 
 use diesel::deserialize::FromSql;
@@ -37,9 +38,9 @@ use graph::data::query::Trace;
 use graph::data::value::Word;
 use graph::data_source::CausalityRegion;
 use graph::internal_error;
-use graph::prelude::{q, EntityQuery, StopwatchMetrics, ENV_VARS};
+use graph::prelude::{q, r, EntityCollection, EntityQuery, StopwatchMetrics, ENV_VARS};
 use graph::schema::{
-    EntityKey, EntityType, Field, FulltextConfig, FulltextDefinition, InputSchema,
+    EntityKey, EntityType, Field, FulltextConfig, FulltextDefinition, IndexDefinition, InputSchema,
 };
 use graph::slog::warn;
 use index::IndexList;
@@ -50,15 +51,17 @@ use std::borrow::Borrow;
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::convert::{From, TryFrom};
 use std::fmt::{self, Write};
-use std::ops::Range;
+use std::ops::{Range, RangeInclusive};
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use crate::relational::value::{FromOidRow, OidRow};
 use crate::relational_queries::{
-    ConflictingEntitiesData, ConflictingEntitiesQuery, EntityDataExt, FindChangesQuery,
-    FindDerivedQuery, FindPossibleDeletionsQuery, ReturnedEntityData,
+    AggregateData, AggregateQuery, ConflictingEntitiesData, ConflictingEntitiesQuery,
+    DeclareCursorQuery, EntityDataExt, EntityDiffData, EntityDiffQuery, EntityHistoryQuery,
+    EntityVersionData, FindChangesQuery, FindDerivedQuery, FindPossibleDeletionsQuery,
+    ReturnedEntityData, EXPORT_CURSOR,
 };
 use crate::{
     primary::{Namespace, Site},
@@ -67,8 +70,11 @@ use crate::{
         FindRangeQuery, InsertQuery, RevertClampQuery, RevertRemoveQuery,
     },
 };
-use graph::components::store::{AttributeNames, DerivedEntityQuery};
-use graph::data::store::{IdList, IdType, BYTES_SCALAR};
+use graph::components::store::{
+    AttributeNames, DerivedEntityQuery, EntityAggregate, EntityDiff, EntityDiffCursor,
+    EntityDiffOperation, EntityVersion,
+};
+use graph::data::store::{Id, IdList, IdType, QueryObject, BYTES_SCALAR};
 use graph::data::subgraph::schema::POI_TABLE;
 use graph::prelude::{
     anyhow, info, BlockNumber, DeploymentHash, Entity, EntityOperation, Logger,
@@ -98,6 +104,16 @@ lazy_static! {
         .graphql
         .sql_statement_timeout
         .map(|duration| format!("set local statement_timeout={}", duration.as_millis()));
+    /// The statement timeout for queries that use a regular expression;
+    /// it is never longer than the general statement timeout
+    static ref REGEX_STATEMENT_TIMEOUT: String = {
+        let timeout = ENV_VARS.graphql.regex_timeout;
+        let timeout = ENV_VARS
+            .graphql
+            .sql_statement_timeout
+            .map_or(timeout, |general| general.min(timeout));
+        format!("set local statement_timeout={}", timeout.as_millis())
+    };
 }
 
 /// A string we use as a SQL name for a table or column. The important thing
@@ -232,11 +248,19 @@ pub struct Layout {
     pub catalog: Catalog,
     /// How many blocks of history the subgraph should keep
     pub history_blocks: BlockNumber,
+    /// How many schema patches had been applied to `input_schema` when
+    /// this layout was loaded
+    pub schema_patches: i32,
 
     pub input_schema: InputSchema,
 
     /// The rollups for aggregations in this layout
     rollups: Vec<Rollup>,
+
+    /// Estimates of how many entities each table holds, based on
+    /// Postgres' statistics. Tables for which Postgres has no statistics
+    /// yet are not listed
+    entity_estimates: Arc<HashMap<EntityType, u64>>,
 }
 
 impl Layout {
@@ -301,8 +325,10 @@ impl Layout {
             catalog,
             tables,
             history_blocks: i32::MAX,
+            schema_patches: 0,
             input_schema: schema.cheap_clone(),
             rollups,
+            entity_estimates: Arc::new(HashMap::new()),
         })
     }
 
@@ -407,6 +433,60 @@ impl Layout {
             .collect()
     }
 
+    /// Determine if the schema of `old` can be changed to the schema of
+    /// `self` by only adding entity types, nullable attributes, fulltext
+    /// fields and enum values, without changing anything that `old`
+    /// already has. Returns a list of errors if that is not possible. An
+    /// empty vector indicates that the change is possible
+    pub fn can_patch_from(&self, old: &Layout) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        // Aggregations are filled while indexing from their timeseries and
+        // can therefore not be added or changed after the fact
+        let agg_types = |layout: &Layout| -> BTreeSet<String> {
+            layout
+                .input_schema
+                .ts_entity_types()
+                .iter()
+                .map(|entity_type| entity_type.to_string())
+                .collect()
+        };
+        let aggs = agg_types(old);
+        if aggs != agg_types(self) {
+            errors.push("aggregations can not be added or removed".to_string());
+        }
+
+        for old_table in old.tables.values() {
+            match self.table(&old_table.name) {
+                Some(table) if aggs.contains(old_table.object.as_str()) => {
+                    if table.columns.len() != old_table.columns.len() {
+                        errors.push(format!(
+                            "the aggregation {} can not be changed",
+                            old_table.object
+                        ));
+                    }
+                }
+                Some(table) => errors.extend(table.can_patch_from(old_table)),
+                None => errors.push(format!(
+                    "the entity type {} can not be removed",
+                    old_table.object
+                )),
+            }
+        }
+
+        for name in old.input_schema.enum_types() {
+            let old_values = old.input_schema.enum_values(name).unwrap_or_default();
+            match self.input_schema.enum_values(name) {
+                Some(values) if old_values.is_subset(values.as_ref()) => { /* ok */ }
+                Some(_) => errors.push(format!("values can not be removed from the enum {name}")),
+                None => errors.push(format!("the enum {name} can not be removed")),
+            }
+        }
+
+        errors.sort();
+        errors
+    }
+
     /// Import the database schema for this layout from its own database
     /// shard (in `self.site.shard`) into the database represented by `conn`
     /// if the schema for this layout does not exist yet
@@ -833,6 +913,11 @@ impl Layout {
         }
 
         let trace = query.trace;
+        let timeout_sql = if query.filter.as_ref().map_or(false, |f| f.uses_regex()) {
+            Some(&*REGEX_STATEMENT_TIMEOUT)
+        } else {
+            STATEMENT_TIMEOUT.as_ref()
+        };
 
         let filter_collection =
             FilterCollection::new(self, query.collection, query.filter.as_ref(), query.block)?;
@@ -841,7 +926,8 @@ impl Layout {
             self,
             query.filter.as_ref(),
             query.order,
-            query.range,
+            &query.range,
+            query.rollup,
             query.block,
             query.query_id,
             &self.site,
@@ -852,7 +938,7 @@ impl Layout {
         let start = Instant::now();
         let values = conn
             .transaction(|conn| {
-                if let Some(ref timeout_sql) = *STATEMENT_TIMEOUT {
+                if let Some(timeout_sql) = timeout_sql {
                     conn.batch_execute(timeout_sql)?;
                 }
                 query.load::<EntityData>(conn)
@@ -897,6 +983,349 @@ impl Layout {
             .map(|values| (values, trace))
     }
 
+    /// Compute `aggregates` over the entities that `query` matches. The
+    /// query must be for a single entity type, and may not match more than
+    /// `max_entities` entities
+    pub fn aggregate(
+        &self,
+        logger: &Logger,
+        conn: &mut PgConnection,
+        query: EntityQuery,
+        aggregates: &[EntityAggregate],
+        max_entities: usize,
+    ) -> Result<(Vec<r::Value>, Trace), QueryExecutionError> {
+        let entity_type = match &query.collection {
+            EntityCollection::All(types) if types.len() == 1 => &types[0].0,
+            _ => {
+                return Err(QueryExecutionError::NotSupported(
+                    "aggregates can only be computed for a single entity type".to_string(),
+                ))
+            }
+        };
+
+        let timeout_sql = if query.filter.as_ref().map_or(false, |f| f.uses_regex()) {
+            Some(&*REGEX_STATEMENT_TIMEOUT)
+        } else {
+            STATEMENT_TIMEOUT.as_ref()
+        };
+
+        let agg_query = AggregateQuery::new(
+            self,
+            entity_type,
+            query.filter.as_ref(),
+            aggregates,
+            query.block,
+            max_entities.saturating_add(1),
+        )?;
+        let types = agg_query.value_types();
+
+        let start = Instant::now();
+        let data = conn
+            .transaction(|conn| {
+                if let Some(timeout_sql) = timeout_sql {
+                    conn.batch_execute(timeout_sql)?;
+                }
+                agg_query.get_result::<AggregateData>(conn)
+            })
+            .map_err(|e| {
+                QueryExecutionError::ResolveEntitiesError(format!(
+                    "{e}, query = {}",
+                    debug_query(&agg_query)
+                ))
+            })?;
+        let elapsed = start.elapsed();
+
+        let text = debug_query(&agg_query).to_string().replace('\n', "\t");
+        if ENV_VARS.log_sql_timing() {
+            info!(
+                logger,
+                "Query timing (SQL)";
+                "query" => &text,
+                "time_ms" => elapsed.as_millis(),
+                "entity_count" => 1
+            );
+        }
+        let trace = if query.trace {
+            Trace::query(&text, elapsed, 1)
+        } else {
+            Trace::None
+        };
+
+        let (count, values) = data.into_values(&types)?;
+        if count < 0 || count as usize > max_entities {
+            return Err(QueryExecutionError::AggregateTooLarge(
+                entity_type.to_string(),
+                max_entities,
+            ));
+        }
+        let mut values = values.into_iter();
+        let values = aggregates
+            .iter()
+            .map(|agg| match agg {
+                EntityAggregate::Count => r::Value::Int(count),
+                _ => values.next().unwrap_or(r::Value::Null),
+            })
+            .collect();
+        Ok((values, trace))
+    }
+
+    /// Declare a server-side cursor for all entities that `query` matches.
+    /// The entities can then be read with `fetch_export_page` until the
+    /// end of the current transaction, which must therefore already have
+    /// been started
+    pub fn declare_export_cursor(
+        &self,
+        conn: &mut PgConnection,
+        query: EntityQuery,
+    ) -> Result<(), QueryExecutionError> {
+        let timeout_sql = if query.filter.as_ref().map_or(false, |f| f.uses_regex()) {
+            Some(&*REGEX_STATEMENT_TIMEOUT)
+        } else {
+            STATEMENT_TIMEOUT.as_ref()
+        };
+
+        let filter_collection =
+            FilterCollection::new(self, query.collection, query.filter.as_ref(), query.block)?;
+        let query = FilterQuery::new(
+            &filter_collection,
+            self,
+            query.filter.as_ref(),
+            query.order,
+            &query.range,
+            query.rollup,
+            query.block,
+            query.query_id,
+            &self.site,
+        )?;
+
+        // The statement timeout applies to each page that we fetch
+        if let Some(timeout_sql) = timeout_sql {
+            conn.batch_execute(timeout_sql).map_err(StoreError::from)?;
+        }
+        DeclareCursorQuery::new(query.clone())
+            .execute(conn)
+            .map_err(|e| {
+                QueryExecutionError::ResolveEntitiesError(format!(
+                    "{e}, query = {}",
+                    debug_query(&query)
+                ))
+            })?;
+        Ok(())
+    }
+
+    /// Fetch the next `page_size` entities from the cursor that
+    /// `declare_export_cursor` declared. Fewer than `page_size` entities
+    /// mean that there are no more entities
+    pub fn fetch_export_page(
+        &self,
+        conn: &mut PgConnection,
+        page_size: usize,
+    ) -> Result<Vec<QueryObject>, QueryExecutionError> {
+        let fetch = format!("fetch forward {} from {}", page_size, EXPORT_CURSOR);
+        sql_query(fetch)
+            .load::<EntityData>(conn)
+            .map_err(|e| QueryExecutionError::ResolveEntitiesError(e.to_string()))?
+            .into_iter()
+            .map(|entity_data| {
+                entity_data
+                    .deserialize_with_layout(self, None)
+                    .map_err(QueryExecutionError::from)
+            })
+            .collect()
+    }
+
+    /// Find up to `first` versions of the entity of type `entity_type`
+    /// with the given `id` that were current at some block in `blocks`.
+    /// We can only tell which attributes a version changed if we still
+    /// have the version it replaced; for versions written before
+    /// `earliest_block`, pruning might have removed that
+    pub fn entity_history(
+        &self,
+        logger: &Logger,
+        conn: &mut PgConnection,
+        entity_type: &EntityType,
+        id: &Id,
+        blocks: RangeInclusive<BlockNumber>,
+        first: usize,
+        earliest_block: BlockNumber,
+        trace: bool,
+    ) -> Result<(Vec<EntityVersion>, Trace), QueryExecutionError> {
+        let table = self.table_for_entity(entity_type)?;
+        let query = EntityHistoryQuery::new(table, id, *blocks.start(), *blocks.end(), first);
+
+        let start = Instant::now();
+        let rows = conn
+            .transaction(|conn| {
+                if let Some(ref timeout_sql) = *STATEMENT_TIMEOUT {
+                    conn.batch_execute(timeout_sql)?;
+                }
+                query.load::<EntityVersionData>(conn)
+            })
+            .map_err(|e| {
+                QueryExecutionError::ResolveEntitiesError(format!(
+                    "{e}, query = {}",
+                    debug_query(&query)
+                ))
+            })?;
+        let elapsed = start.elapsed();
+
+        let text = debug_query(&query).to_string().replace('\n', "\t");
+        if ENV_VARS.log_sql_timing() {
+            info!(
+                logger,
+                "Query timing (SQL)";
+                "query" => &text,
+                "time_ms" => elapsed.as_millis(),
+                "entity_count" => rows.len()
+            );
+        }
+        let trace = if trace {
+            Trace::query(&text, elapsed, rows.len())
+        } else {
+            Trace::None
+        };
+
+        let to_object = |entity: &str, data: graph::prelude::serde_json::Value| {
+            EntityData::new(entity.to_string(), data)
+                .deserialize_with_layout::<QueryObject>(self, None)
+                .map(|obj| {
+                    let mut data = obj.entity;
+                    data.remove("__typename");
+                    data.remove(VID_COLUMN);
+                    data
+                })
+        };
+        let versions = rows
+            .into_iter()
+            .map(|row| {
+                let data = to_object(&row.entity, row.data)?;
+                let changed = match row.prev {
+                    Some(prev) => {
+                        let prev = to_object(&row.entity, prev)?;
+                        Some(
+                            table
+                                .columns
+                                .iter()
+                                .filter(|column| !column.is_fulltext())
+                                .filter(|column| data.get(&column.field) != prev.get(&column.field))
+                                .map(|column| column.field.clone())
+                                .collect(),
+                        )
+                    }
+                    None if !table.immutable && row.from_block < earliest_block => None,
+                    None => Some(
+                        table
+                            .columns
+                            .iter()
+                            .filter(|column| !column.is_fulltext())
+                            .map(|column| column.field.clone())
+                            .collect(),
+                    ),
+                };
+                Ok(EntityVersion {
+                    from_block: row.from_block,
+                    from_hash: None,
+                    to_block: row.to_block,
+                    to_hash: None,
+                    changed,
+                    data,
+                })
+            })
+            .collect::<Result<Vec<_>, StoreError>>()?;
+        Ok((versions, trace))
+    }
+
+    /// Find up to `first` changes to entities of type `entity_type` in
+    /// `blocks` that come after `after`, ordered by block and `vid`
+    pub fn entity_diff(
+        &self,
+        logger: &Logger,
+        conn: &mut PgConnection,
+        entity_type: &EntityType,
+        blocks: RangeInclusive<BlockNumber>,
+        after: Option<EntityDiffCursor>,
+        first: usize,
+        with_data: bool,
+        trace: bool,
+    ) -> Result<(Vec<EntityDiff>, Trace), QueryExecutionError> {
+        let table = self.table_for_entity(entity_type)?;
+        let id_type = entity_type.id_type()?;
+        let query = EntityDiffQuery::new(table, blocks, after, first, with_data);
+
+        let start = Instant::now();
+        let rows = conn
+            .transaction(|conn| {
+                if let Some(ref timeout_sql) = *STATEMENT_TIMEOUT {
+                    conn.batch_execute(timeout_sql)?;
+                }
+                query.load::<EntityDiffData>(conn)
+            })
+            .map_err(|e| {
+                QueryExecutionError::ResolveEntitiesError(format!(
+                    "{e}, query = {}",
+                    debug_query(&query)
+                ))
+            })?;
+        let elapsed = start.elapsed();
+
+        let text = debug_query(&query).to_string().replace('\n', "\t");
+        if ENV_VARS.log_sql_timing() {
+            info!(
+                logger,
+                "Query timing (SQL)";
+                "query" => &text,
+                "time_ms" => elapsed.as_millis(),
+                "entity_count" => rows.len()
+            );
+        }
+        let trace = if trace {
+            Trace::query(&text, elapsed, rows.len())
+        } else {
+            Trace::None
+        };
+
+        let changes = rows
+            .into_iter()
+            .map(|row| {
+                let operation = match row.operation.as_str() {
+                    "insert" => EntityDiffOperation::Insert,
+                    "update" => EntityDiffOperation::Update,
+                    "delete" => EntityDiffOperation::Delete,
+                    op => return Err(internal_error!("unknown entity diff operation `{}`", op)),
+                };
+                // Casting `bytea` to `text` produces `\x..`
+                let id = match id_type {
+                    IdType::Bytes => row.id.trim_start_matches("\\x"),
+                    IdType::String | IdType::Int8 => row.id.as_str(),
+                };
+                let id = id_type.parse(Word::from(id))?;
+                let data = row
+                    .data
+                    .map(|data| {
+                        EntityData::new(row.entity, data)
+                            .deserialize_with_layout::<QueryObject>(self, None)
+                            .map(|obj| {
+                                let mut data = obj.entity;
+                                data.remove("__typename");
+                                data.remove(VID_COLUMN);
+                                data
+                            })
+                    })
+                    .transpose()?;
+                Ok(EntityDiff {
+                    id,
+                    operation,
+                    cursor: EntityDiffCursor {
+                        block: row.block,
+                        vid: row.vid,
+                    },
+                    data,
+                })
+            })
+            .collect::<Result<Vec<_>, StoreError>>()?;
+        Ok((changes, trace))
+    }
+
     pub fn update<'a>(
         &'a self,
         conn: &mut PgConnection,
@@ -1067,6 +1496,15 @@ impl Layout {
         Ok(())
     }
 
+    /// Estimates of how many entities of each type the subgraph holds,
+    /// keyed by the name of the entity type
+    pub fn entity_estimates(&self) -> HashMap<String, u64> {
+        self.entity_estimates
+            .iter()
+            .map(|(entity_type, entities)| (entity_type.to_string(), *entities))
+            .collect()
+    }
+
     pub fn is_cacheable(&self) -> bool {
         // This would be false if we still needed to migrate the Layout, but
         // since there are no migrations in the code right now, it is always
@@ -1076,8 +1514,9 @@ impl Layout {
 
     /// Update the layout with the latest information from the database; an
     /// update can only change the `is_account_like` flag for tables, the
-    /// layout's site, or the `history_blocks`. If no update is needed, just
-    /// return `self`.
+    /// layout's site, the `history_blocks`, or the entity estimates. If no
+    /// update is needed, just return `self`. If the schema was patched
+    /// since `self` was loaded, load the layout from scratch.
     ///
     /// This is tied closely to how the `LayoutCache` works and called from
     /// it right after creating a `Layout`, and periodically to update the
@@ -1087,8 +1526,29 @@ impl Layout {
         conn: &mut PgConnection,
         site: Arc<Site>,
     ) -> Result<Arc<Self>, StoreError> {
+        // A schema patch changes tables and columns; we need to start over
+        // with the patched schema
+        let schema_patches = deployment::schema_patches(conn, &self.site)?;
+        if schema_patches != self.schema_patches {
+            return LayoutCache::load(conn, site);
+        }
+
         let account_like = crate::catalog::account_like(conn, &self.site)?;
         let history_blocks = deployment::history_blocks(conn, &self.site)?;
+        let entity_estimates: HashMap<_, _> = {
+            let stats: HashMap<_, _> = crate::catalog::stats(conn, &self.site)?
+                .into_iter()
+                .map(|stats| (stats.tablename, stats.entities.max(0) as u64))
+                .collect();
+            self.tables
+                .values()
+                .filter_map(|table| {
+                    stats
+                        .get(table.name.as_str())
+                        .map(|entities| (table.object.clone(), *entities))
+                })
+                .collect()
+        };
 
         let is_account_like = { |table: &Table| account_like.contains(table.name.as_str()) };
 
@@ -1097,7 +1557,11 @@ impl Layout {
             .values()
             .filter(|table| table.is_account_like != is_account_like(table.as_ref()))
             .collect();
-        if changed_tables.is_empty() && site == self.site && history_blocks == self.history_blocks {
+        if changed_tables.is_empty()
+            && site == self.site
+            && history_blocks == self.history_blocks
+            && entity_estimates == *self.entity_estimates
+        {
             return Ok(self);
         }
 
@@ -1109,6 +1573,7 @@ impl Layout {
         }
         layout.site = site;
         layout.history_blocks = history_blocks;
+        layout.entity_estimates = Arc::new(entity_estimates);
         Ok(Arc::new(layout))
     }
 
@@ -1126,6 +1591,13 @@ impl Layout {
         Rollup::last_rollup(&self.rollups, conn)
     }
 
+    /// The rollup that fills `table` if `table` holds an aggregation
+    pub(crate) fn rollup_for(&self, table: &Table) -> Option<&Rollup> {
+        self.rollups
+            .iter()
+            .find(|rollup| rollup.agg_table.object == table.object)
+    }
+
     /// Construct `Rolllup` for each of the aggregation mappings
     /// `schema.agg_mappings()` and return them in the same order as the
     /// aggregation mappings
@@ -1205,7 +1677,6 @@ impl Layout {
         // very complicated and is left for a future improvement.
         for (block, block_time) in block_times {
             for rollup in &self.rollups {
-                let buckets = rollup.interval.buckets(last_rollup, *block_time);
                 // We only need to pay attention to the first bucket; if
                 // there are more buckets, there's nothing to rollup for
                 // them as the next changes we wrote are for `block_time`,
@@ -1224,23 +1695,88 @@ impl Layout {
                 // that, which will roll up the bucket `t5 <= b2 < t6`. So
                 // there's no need to worry about the buckets starting at
                 // `t2`, `t3`, and `t4`.
-                match buckets.first() {
-                    None => {
-                        // The rollups are in increasing order of interval size, so
-                        // if a smaller interval doesn't have a bucket between
-                        // last_rollup and block_time, a larger one can't either and
-                        // we are done with this rollup.
-                        break;
-                    }
-                    Some(bucket) => {
-                        rollup.insert(conn, &bucket, *block)?;
-                    }
+                //
+                // Even though the rollups are in increasing order of
+                // interval size, a smaller interval not having a bucket
+                // between `last_rollup` and `block_time` does not mean that
+                // a larger one doesn't either since the bucket boundaries
+                // of custom intervals like `45m` don't line up with those
+                // of larger intervals like `hour`
+                if let Some(bucket) = rollup.interval.first_bucket(last_rollup, *block_time) {
+                    rollup.insert(conn, &bucket, *block)?;
                 }
             }
             last_rollup = *block_time;
         }
         Ok(())
     }
+
+    /// Recompute the aggregation `name` for all intervals from the data in
+    /// its source timeseries. This is meant for aggregations that were
+    /// added in a graft or copy of a deployment, since those only get
+    /// rolled up from the point where the new deployment started indexing.
+    /// All buckets that live indexing would have filled by now are filled,
+    /// replacing any data the aggregation already has for them.
+    ///
+    /// Each bucket is written in its own transaction, going from the oldest
+    /// to the newest bucket so that cumulative aggregates can build on the
+    /// buckets before them. If the backfill is interrupted, the buckets
+    /// that were written so far are complete, and running the backfill
+    /// again replaces them and continues with the remaining ones.
+    ///
+    /// Each bucket is marked with the block of the first data point in the
+    /// source that comes after the bucket. That is the block at which
+    /// indexing would have rolled up the bucket if the subgraph only
+    /// writes to the source timeseries. If there is no such data point, the
+    /// bucket is marked with `head`, the current block of the subgraph.
+    ///
+    /// Returns the number of rows that were inserted into the tables for
+    /// the aggregation
+    pub(crate) fn backfill_aggregation(
+        &self,
+        conn: &mut PgConnection,
+        name: &str,
+        head: BlockNumber,
+    ) -> Result<usize, StoreError> {
+        let rollups: Vec<_> = self
+            .rollups
+            .iter()
+            .filter(|rollup| {
+                self.input_schema
+                    .object_or_aggregation(name, Some(rollup.interval))
+                    .map_or(false, |et| et == rollup.agg_table.object)
+            })
+            .collect();
+        if rollups.is_empty() {
+            return Err(StoreError::Unknown(anyhow!(
+                "deployment {} does not have an aggregation named `{}`",
+                self.site.deployment,
+                name
+            )));
+        }
+
+        // Live indexing has rolled up every bucket that ended before the
+        // last time the subgraph wrote anything; both the last rollup of
+        // any aggregation and the latest data point in the source are
+        // lower bounds for that time
+        let last_rollup = self.last_rollup(conn)?;
+
+        let mut count = 0;
+        for rollup in rollups {
+            let Some((first, last)) = rollup.source_range(conn)? else {
+                continue;
+            };
+            let end = last_rollup.map_or(last, |last_rollup| last_rollup.max(last));
+            for bucket in rollup.interval.buckets(first, end) {
+                count += conn.transaction::<_, StoreError, _>(|conn| {
+                    let block = rollup.block_after(conn, bucket.end)?.unwrap_or(head);
+                    rollup.clear(conn, &bucket)?;
+                    Ok(rollup.insert(conn, &bucket, block)?)
+                })?;
+            }
+        }
+        Ok(count)
+    }
 }
 
 /// A user-defined enum
@@ -1415,9 +1951,19 @@ impl Column {
         field: &Field,
         catalog: &Catalog,
     ) -> Result<Column, StoreError> {
-        SqlName::check_valid_identifier(&field.name, "attribute")?;
-
-        let sql_name = SqlName::from(&*field.name);
+        // Fields that hold the state of cumulative aggregates have names
+        // like `avgPrice$count`; we keep the `$` in the column name so
+        // that it can not clash with the column for any other field
+        let sql_name = match field.name.split_once('$') {
+            Some((name, state)) => {
+                SqlName::check_valid_identifier(name, "attribute")?;
+                SqlName::verbatim(format!("{}${}", SqlName::from(name), state))
+            }
+            None => {
+                SqlName::check_valid_identifier(&field.name, "attribute")?;
+                SqlName::from(&*field.name)
+            }
+        };
 
         let is_reference = schema.is_reference(&field.field_type.get_base_type());
 
@@ -1462,6 +2008,12 @@ impl Column {
         })
     }
 
+    /// Whether this column holds the state of a cumulative aggregate
+    /// rather than a value that is visible to queries
+    pub fn is_aggregate_state(&self) -> bool {
+        self.field.contains('$')
+    }
+
     pub fn pseudo_column(name: &str, column_type: ColumnType) -> Column {
         let field_type = q::Type::NamedType(column_type.to_string());
         let name = SqlName::verbatim(name.to_string());
@@ -1674,6 +2226,14 @@ impl Table {
             .find(|column| &column.name == name)
     }
 
+    /// The indexes declared with `@index` on the entity type for this table
+    pub(crate) fn index_definitions(&self) -> &[IndexDefinition] {
+        self.object
+            .object_type()
+            .map(|object_type| object_type.indexes.as_ref())
+            .unwrap_or(&[])
+    }
+
     /// Find the column for `field` in this table. The name must be the
     /// GraphQL name of an entity field
     pub fn column_for_field(&self, field: &str) -> Result<&Column, StoreError> {
@@ -1683,6 +2243,72 @@ impl Table {
             .ok_or_else(|| StoreError::UnknownField(self.name.to_string(), field.to_string()))
     }
 
+    fn can_patch_from(&self, old: &Self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.immutable != old.immutable {
+            errors.push(format!(
+                "the entity type {} can not change whether it is immutable",
+                self.object
+            ));
+        }
+        if !self
+            .index_definitions()
+            .starts_with(old.index_definitions())
+        {
+            errors.push(format!(
+                "the @index directives on {} can only be added to, not changed or removed",
+                self.object
+            ));
+        }
+
+        // We can't use `column` here since that ignores fulltext columns
+        fn find<'a>(table: &'a Table, name: &SqlName) -> Option<&'a Column> {
+            table.columns.iter().find(|column| &column.name == name)
+        }
+
+        for old_col in &old.columns {
+            let Some(col) = find(self, &old_col.name) else {
+                errors.push(format!(
+                    "the attribute {}.{} can not be removed",
+                    self.object, old_col.field
+                ));
+                continue;
+            };
+            let same_type = match (&col.column_type, &old_col.column_type) {
+                // New enum values are checked for the enum as a whole
+                (ColumnType::Enum(enum_type), ColumnType::Enum(old_enum_type)) => {
+                    enum_type.name == old_enum_type.name
+                }
+                (column_type, old_column_type) => column_type == old_column_type,
+            };
+            if old_col.is_fulltext() {
+                if col.column_type != old_col.column_type
+                    || col.fulltext_fields != old_col.fulltext_fields
+                {
+                    errors.push(format!(
+                        "the fulltext field {} on {} can not be changed",
+                        old_col.field, self.object
+                    ));
+                }
+            } else if !same_type || col.field_type != old_col.field_type {
+                errors.push(format!(
+                    "the attribute {}.{} can not change its type from {} to {}",
+                    self.object, old_col.field, old_col.field_type, col.field_type
+                ));
+            }
+        }
+
+        for col in &self.columns {
+            if find(old, &col.name).is_none() && !col.is_nullable() {
+                errors.push(format!(
+                    "the new attribute {}.{} must be nullable",
+                    self.object, col.field
+                ));
+            }
+        }
+        errors
+    }
+
     fn can_copy_from(&self, source: &Self) -> Vec<String> {
         self.columns
             .iter()
@@ -1760,12 +2386,16 @@ impl LayoutCache {
     }
 
     fn load(conn: &mut PgConnection, site: Arc<Site>) -> Result<Arc<Layout>, StoreError> {
+        // Read this before the schema so that a concurrent schema patch
+        // leads to loading the layout again rather than never
+        let schema_patches = deployment::schema_patches(conn, &site)?;
         let (subgraph_schema, use_bytea_prefix) = deployment::schema(conn, site.as_ref())?;
         let has_causality_region =
             deployment::entities_with_causality_region(conn, site.id, &subgraph_schema)?;
         let catalog = Catalog::load(conn, site.clone(), use_bytea_prefix, has_causality_region)?;
-        let layout = Arc::new(Layout::new(site.clone(), &subgraph_schema, catalog)?);
-        layout.refresh(conn, site)
+        let mut layout = Layout::new(site.clone(), &subgraph_schema, catalog)?;
+        layout.schema_patches = schema_patches;
+        Arc::new(layout).refresh(conn, site)
     }
 
     fn cache(&self, layout: Arc<Layout>) {
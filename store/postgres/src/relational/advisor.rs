@@ -0,0 +1,921 @@
+//! Recommend indexes based on the queries that are run against a
+//! deployment.
+//!
+//! When `GRAPH_STORE_INDEX_ADVISOR_SAMPLE_RATE` is set, nodes sample the
+//! `EntityQuery`s they execute and reduce them to their shape: which
+//! columns a query compares with `=`, which ones it compares with range
+//! operators, which ones it orders by, and which boolean or enum columns
+//! it compares with a constant. Shapes and how long the queries took are
+//! collected in memory and periodically added to `subgraphs.query_shape`
+//! in the shard of the deployment.
+//!
+//! The advisor turns shapes that were slow on average into composite and
+//! partial indexes and suggests removing attribute indexes that Postgres
+//! has never used. Operators can exclude recommendations, for example after
+//! dropping an index that was created automatically; those are kept in
+//! `subgraphs.index_advice_exclusion`.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use diesel::sql_types::{Array, BigInt, Integer, Nullable, Text};
+use diesel::{sql_query, PgConnection, QueryableByName, RunQueryDsl};
+use graph::components::store::{
+    EntityCollection, EntityFilter, EntityLink, EntityOrder, EntityQuery, WindowAttribute,
+};
+use graph::prelude::{StoreError, Value, ENV_VARS};
+use itertools::Itertools;
+
+use crate::primary::{DeploymentId, Site};
+
+use super::index::{CreateIndex, Expr, Method};
+use super::{Layout, SqlName, Table};
+
+/// The maximum number of shapes we keep in memory between writes; shapes
+/// beyond that are dropped until the next write
+const MAX_SHAPES: usize = 10_000;
+/// Postgres truncates identifiers that are longer than this
+const MAX_IDENTIFIER_LEN: usize = 63;
+
+/// The columns that a query against one table uses in a way that an
+/// index could help with. All columns are SQL column names
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct QueryShape {
+    pub table: String,
+    /// Columns compared with `=` or `in`, or used to join with a parent
+    pub eq: Vec<String>,
+    /// Columns compared with `<`, `<=`, `>`, `>=` or `starts_with`
+    pub range: Vec<String>,
+    /// The columns the query is ordered by
+    pub order: Vec<String>,
+    /// Comparisons of boolean or enum columns with a constant, as SQL;
+    /// these are good conditions for partial indexes
+    pub predicates: Vec<String>,
+}
+
+impl QueryShape {
+    /// The shapes of `query`, one for each table it queries
+    pub(crate) fn for_query(layout: &Layout, query: &EntityQuery) -> Vec<QueryShape> {
+        let tables: Vec<(&Table, Option<&str>)> = match &query.collection {
+            EntityCollection::All(types) => types
+                .iter()
+                .filter_map(|(et, _)| layout.table_for_entity(et).ok())
+                .map(|table| (table.as_ref(), None))
+                .collect(),
+            EntityCollection::Window(windows) => windows
+                .iter()
+                .filter_map(|window| {
+                    let link = match &window.link {
+                        EntityLink::Direct(WindowAttribute::Scalar(attr), _) => Some(attr.as_str()),
+                        _ => None,
+                    };
+                    layout
+                        .table_for_entity(&window.child_type)
+                        .ok()
+                        .map(|table| (table.as_ref(), link))
+                })
+                .collect(),
+        };
+
+        let mut shapes: Vec<_> = tables
+            .into_iter()
+            .map(|(table, link)| Self::for_table(table, link, query.filter.as_ref(), &query.order))
+            .collect();
+        shapes.sort();
+        shapes.dedup();
+        shapes
+    }
+
+    fn for_table(
+        table: &Table,
+        link: Option<&str>,
+        filter: Option<&EntityFilter>,
+        order: &EntityOrder,
+    ) -> QueryShape {
+        let mut eq = BTreeSet::new();
+        let mut range = BTreeSet::new();
+        let mut predicates = BTreeSet::new();
+
+        if let Some(column) = link.and_then(|link| table.column_for_field(link).ok()) {
+            eq.insert(column.name.to_string());
+        }
+        if let Some(filter) = filter {
+            add_filter(table, filter, &mut eq, &mut range, &mut predicates);
+        }
+        let range = range.difference(&eq).cloned().collect();
+
+        let mut order_columns = Vec::new();
+        add_order(table, order, &mut order_columns);
+
+        QueryShape {
+            table: table.name.to_string(),
+            eq: eq.into_iter().collect(),
+            range,
+            order: order_columns,
+            predicates: predicates.into_iter().collect(),
+        }
+    }
+}
+
+/// Add the columns that `filter` uses to the sets of columns. We only look
+/// at conditions that all rows must satisfy, i.e., we descend into `and`
+/// but not into `or` or child filters
+fn add_filter(
+    table: &Table,
+    filter: &EntityFilter,
+    eq: &mut BTreeSet<String>,
+    range: &mut BTreeSet<String>,
+    predicates: &mut BTreeSet<String>,
+) {
+    use EntityFilter::*;
+
+    match filter {
+        And(filters) => {
+            for filter in filters {
+                add_filter(table, filter, eq, range, predicates);
+            }
+        }
+        Equal(attr, value) => {
+            let Ok(column) = table.column_for_field(attr) else {
+                return;
+            };
+            match value {
+                Value::Bool(b) => {
+                    predicates.insert(format!("{} = {}", column.name.quoted(), b));
+                }
+                Value::String(s) if column.is_enum() => {
+                    predicates.insert(format!(
+                        "{} = '{}'",
+                        column.name.quoted(),
+                        s.replace('\'', "''")
+                    ));
+                }
+                _ => {
+                    eq.insert(column.name.to_string());
+                }
+            }
+        }
+        In(attr, _) => {
+            if let Ok(column) = table.column_for_field(attr) {
+                eq.insert(column.name.to_string());
+            }
+        }
+        GreaterThan(attr, _)
+        | LessThan(attr, _)
+        | GreaterOrEqual(attr, _)
+        | LessOrEqual(attr, _)
+        | StartsWith(attr, _) => {
+            if let Ok(column) = table.column_for_field(attr) {
+                range.insert(column.name.to_string());
+            }
+        }
+        _ => { /* Indexes on plain columns do not help with these */ }
+    }
+}
+
+fn add_order(table: &Table, order: &EntityOrder, columns: &mut Vec<String>) {
+    match order {
+        EntityOrder::Ascending(attr, _) | EntityOrder::Descending(attr, _) => {
+            if let Ok(column) = table.column_for_field(attr) {
+                columns.push(column.name.to_string());
+            }
+        }
+        EntityOrder::Default => columns.push("id".to_string()),
+        EntityOrder::Multi(orders) => {
+            for order in orders {
+                add_order(table, order, columns);
+            }
+        }
+        EntityOrder::ChildAscending(_)
+        | EntityOrder::ChildDescending(_)
+        | EntityOrder::Unordered => { /* nothing to index on this table */ }
+    }
+}
+
+/// How often queries of one shape were sampled and how long they took
+#[derive(Clone, Debug, Default)]
+pub struct Sample {
+    pub queries: i64,
+    pub total_ms: i64,
+    pub max_ms: i64,
+}
+
+impl Sample {
+    fn add(&mut self, elapsed: Duration) {
+        let ms = elapsed.as_millis() as i64;
+        self.queries += 1;
+        self.total_ms += ms;
+        self.max_ms = self.max_ms.max(ms);
+    }
+}
+
+/// The shapes of the queries a `DeploymentStore` sampled since they were
+/// last written to the database
+pub(crate) struct Workload {
+    samples: Mutex<HashMap<(DeploymentId, QueryShape), Sample>>,
+}
+
+impl Workload {
+    pub fn new() -> Self {
+        Workload {
+            samples: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether the next query should be sampled
+    pub fn sample() -> bool {
+        let rate = ENV_VARS.store.index_advisor_sample_rate;
+        rate > 0.0 && rand::random::<f64>() < rate
+    }
+
+    pub fn record(&self, site: &Site, shapes: Vec<QueryShape>, elapsed: Duration) {
+        let mut samples = self.samples.lock().unwrap();
+        for shape in shapes {
+            let key = (site.id, shape);
+            match samples.get_mut(&key) {
+                Some(sample) => sample.add(elapsed),
+                None if samples.len() < MAX_SHAPES => samples.entry(key).or_default().add(elapsed),
+                None => { /* too many shapes, drop it */ }
+            }
+        }
+    }
+
+    /// Return the samples collected so far and start collecting afresh
+    pub fn take(&self) -> HashMap<(DeploymentId, QueryShape), Sample> {
+        std::mem::take(&mut *self.samples.lock().unwrap())
+    }
+
+    /// Add `samples` to what is recorded in `subgraphs.query_shape`
+    pub fn write(
+        conn: &mut PgConnection,
+        samples: HashMap<(DeploymentId, QueryShape), Sample>,
+    ) -> Result<(), StoreError> {
+        const QUERY: &str = "\
+            insert into subgraphs.query_shape(deployment, table_name, eq_columns, \
+                   range_columns, order_columns, predicates, queries, total_ms, \
+                   max_ms, last_seen) \
+            values ($1, $2, $3, $4, $5, $6, $7, $8, $9, now()) \
+            on conflict(deployment, table_name, eq_columns, range_columns, \
+                        order_columns, predicates) \
+            do update set queries = query_shape.queries + excluded.queries, \
+                          total_ms = query_shape.total_ms + excluded.total_ms, \
+                          max_ms = greatest(query_shape.max_ms, excluded.max_ms), \
+                          last_seen = excluded.last_seen";
+
+        for ((id, shape), sample) in samples {
+            sql_query(QUERY)
+                .bind::<Integer, _>(id)
+                .bind::<Text, _>(&shape.table)
+                .bind::<Array<Text>, _>(&shape.eq)
+                .bind::<Array<Text>, _>(&shape.range)
+                .bind::<Array<Text>, _>(&shape.order)
+                .bind::<Array<Text>, _>(&shape.predicates)
+                .bind::<BigInt, _>(sample.queries)
+                .bind::<BigInt, _>(sample.total_ms)
+                .bind::<BigInt, _>(sample.max_ms)
+                .execute(conn)?;
+        }
+        Ok(())
+    }
+}
+
+/// Which shapes are slow enough to warrant an index
+#[derive(Clone, Debug)]
+pub struct AdviceOptions {
+    /// Only consider shapes that were sampled at least this often
+    pub min_queries: i64,
+    /// Only consider shapes whose queries took at least this long on
+    /// average
+    pub min_avg_ms: i64,
+    /// Only consider shapes that were sampled within this long; queries
+    /// that are no longer run do not need an index
+    pub max_age: Option<Duration>,
+    /// Recommend at most this many indexes, starting with the ones for
+    /// the shapes whose queries took the most time in total
+    pub max_indexes: Option<usize>,
+}
+
+impl Default for AdviceOptions {
+    fn default() -> Self {
+        Self {
+            min_queries: 10,
+            min_avg_ms: 50,
+            max_age: None,
+            max_indexes: None,
+        }
+    }
+}
+
+/// An index that would help a slow query shape
+#[derive(Clone, Debug)]
+pub struct IndexAdvice {
+    pub table: String,
+    pub name: String,
+    pub columns: Vec<String>,
+    pub predicates: Vec<String>,
+    pub queries: i64,
+    pub avg_ms: i64,
+    pub max_ms: i64,
+    /// The statement that creates the index
+    pub sql: String,
+}
+
+/// An attribute index that has not been used since statistics were last
+/// reset
+#[derive(Clone, Debug)]
+pub struct UnusedIndex {
+    pub table: String,
+    pub name: String,
+    pub size: String,
+    /// The statement that drops the index
+    pub sql: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Advice {
+    pub create: Vec<IndexAdvice>,
+    pub drop: Vec<UnusedIndex>,
+    /// Whether the shard has read replicas. Index usage on them is not
+    /// known, and `drop` is therefore always empty
+    pub replicas: bool,
+}
+
+#[derive(QueryableByName)]
+struct ShapeRow {
+    #[diesel(sql_type = Text)]
+    table_name: String,
+    #[diesel(sql_type = Array<Text>)]
+    eq_columns: Vec<String>,
+    #[diesel(sql_type = Array<Text>)]
+    range_columns: Vec<String>,
+    #[diesel(sql_type = Array<Text>)]
+    order_columns: Vec<String>,
+    #[diesel(sql_type = Array<Text>)]
+    predicates: Vec<String>,
+    #[diesel(sql_type = BigInt)]
+    queries: i64,
+    #[diesel(sql_type = BigInt)]
+    total_ms: i64,
+    #[diesel(sql_type = BigInt)]
+    max_ms: i64,
+}
+
+impl ShapeRow {
+    fn into_parts(self) -> (QueryShape, Sample) {
+        let shape = QueryShape {
+            table: self.table_name,
+            eq: self.eq_columns,
+            range: self.range_columns,
+            order: self.order_columns,
+            predicates: self.predicates,
+        };
+        let sample = Sample {
+            queries: self.queries,
+            total_ms: self.total_ms,
+            max_ms: self.max_ms,
+        };
+        (shape, sample)
+    }
+}
+
+#[derive(QueryableByName)]
+struct UnusedRow {
+    #[diesel(sql_type = Text)]
+    table_name: String,
+    #[diesel(sql_type = Text)]
+    name: String,
+    #[diesel(sql_type = Text)]
+    defn: String,
+    #[diesel(sql_type = Text)]
+    size: String,
+}
+
+/// The columns of an index for `shape`: the columns compared for equality
+/// followed by the first range column, or, if there is none, the first
+/// column the query is ordered by. Returns `None` if there is nothing
+/// worth indexing
+fn index_columns(table: &Table, shape: &QueryShape) -> Option<Vec<String>> {
+    let mut columns = shape.eq.clone();
+    let next = shape
+        .range
+        .first()
+        .or_else(|| shape.order.iter().find(|col| !shape.eq.contains(col)));
+    if let Some(next) = next {
+        columns.push(next.clone());
+    }
+    // Only plain btree indexes can be composite; list and fulltext columns
+    // need other index methods
+    let btree = columns.iter().all(|name| {
+        table
+            .columns
+            .iter()
+            .find(|column| column.name.as_str() == name)
+            .map(|column| Table::calculate_index_method_and_expression(column).0 == "btree")
+            .unwrap_or(false)
+    });
+    if columns.is_empty() || !btree {
+        return None;
+    }
+    Some(columns)
+}
+
+/// Whether `index` can be used for queries that compare `eq` for equality
+/// and then use `next`. That is the case for btree indexes that start with
+/// the `eq` columns, in any order, followed by `next`
+fn covers(index: &CreateIndex, eq: &[String], next: Option<&String>) -> bool {
+    let CreateIndex::Parsed {
+        method: Method::BTree,
+        columns,
+        cond: None,
+        ..
+    } = index
+    else {
+        return false;
+    };
+    let names: Vec<_> = columns
+        .iter()
+        .map(|expr| match expr {
+            Expr::Column(name) | Expr::Prefix(name, _) => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    let n = eq.len();
+    if names.len() < n + next.map(|_| 1).unwrap_or(0) {
+        return false;
+    }
+    let leading: BTreeSet<_> = names[..n].iter().copied().collect();
+    let eq: BTreeSet<_> = eq.iter().map(|col| Some(col.as_str())).collect();
+    leading == eq && next.map_or(true, |next| names[n] == Some(next.as_str()))
+}
+
+fn index_name(table: &Table, columns: &[String], predicates: &[String]) -> String {
+    let mut name = format!("manual_{}_{}", table.name, columns.join("_"));
+    if !predicates.is_empty() {
+        name.push_str("_partial");
+    }
+    // Postgres would truncate the name anyway, but we need to know the
+    // actual name to check whether the index already exists
+    name.truncate(MAX_IDENTIFIER_LEN);
+    name
+}
+
+/// The deployments in this shard for which query shapes were recorded
+pub(crate) fn deployments(conn: &mut PgConnection) -> Result<Vec<DeploymentId>, StoreError> {
+    #[derive(QueryableByName)]
+    struct Row {
+        #[diesel(sql_type = Integer)]
+        deployment: DeploymentId,
+    }
+
+    let rows: Vec<Row> =
+        sql_query("select distinct deployment from subgraphs.query_shape").load(conn)?;
+    Ok(rows.into_iter().map(|row| row.deployment).collect())
+}
+
+/// Stop recommending the indexes `names` for `site`, or, if `exclude` is
+/// `false`, recommend them again
+pub(crate) fn exclude(
+    conn: &mut PgConnection,
+    site: &Site,
+    names: &[String],
+    exclude: bool,
+) -> Result<(), StoreError> {
+    let query = if exclude {
+        "insert into subgraphs.index_advice_exclusion(deployment, index_name) \
+         select $1, unnest($2::text[]) \
+         on conflict do nothing"
+    } else {
+        "delete from subgraphs.index_advice_exclusion \
+          where deployment = $1 and index_name = any($2)"
+    };
+    sql_query(query)
+        .bind::<Integer, _>(site.id)
+        .bind::<Array<Text>, _>(names)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// The names of the indexes that should not be recommended for `site`
+fn excluded(conn: &mut PgConnection, site: &Site) -> Result<BTreeSet<String>, StoreError> {
+    #[derive(QueryableByName)]
+    struct Row {
+        #[diesel(sql_type = Text)]
+        index_name: String,
+    }
+
+    let rows: Vec<Row> =
+        sql_query("select index_name from subgraphs.index_advice_exclusion where deployment = $1")
+            .bind::<Integer, _>(site.id)
+            .load(conn)?;
+    Ok(rows.into_iter().map(|row| row.index_name).collect())
+}
+
+/// Turn `shapes`, which must be sorted by the total time their queries
+/// took, into index recommendations. Shapes that are covered by one of
+/// the existing `indexes`, and indexes that already exist or that are
+/// `excluded` are skipped
+fn recommend(
+    layout: &Layout,
+    indexes: &HashMap<String, Vec<CreateIndex>>,
+    shapes: Vec<(QueryShape, Sample)>,
+    excluded: &BTreeSet<String>,
+    max_indexes: Option<usize>,
+) -> Vec<IndexAdvice> {
+    let site = &layout.site;
+    let mut create: Vec<IndexAdvice> = Vec::new();
+    for (shape, sample) in shapes {
+        let Some(table) = layout.table(&SqlName::verbatim(shape.table.clone())) else {
+            continue;
+        };
+        let Some(columns) = index_columns(table, &shape) else {
+            continue;
+        };
+        let name = index_name(table, &columns, &shape.predicates);
+        let existing = indexes
+            .get(table.name.as_str())
+            .map(|indexes| indexes.as_slice())
+            .unwrap_or_default();
+        let next = columns.get(shape.eq.len());
+        let covered = shape.predicates.is_empty()
+            && existing.iter().any(|index| covers(index, &shape.eq, next));
+        let exists = existing
+            .iter()
+            .any(|index| index.name().as_deref() == Some(name.as_str()));
+        if covered || exists || excluded.contains(&name) {
+            continue;
+        }
+
+        // Several shapes can lead to the same index; the shapes are sorted
+        // by total time, so we keep the first one
+        if let Some(advice) = create.iter_mut().find(|advice| advice.name == name) {
+            advice.queries += sample.queries;
+            continue;
+        }
+
+        let exprs = columns
+            .iter()
+            .filter_map(|name| table.columns.iter().find(|col| col.name.as_str() == name))
+            .map(|column| Table::calculate_index_method_and_expression(column).1)
+            .join(", ");
+        let mut sql = format!(
+            "create index concurrently if not exists {name} on {}.{} using btree({exprs})",
+            site.namespace,
+            table.name.quoted()
+        );
+        if !shape.predicates.is_empty() {
+            sql.push_str(" where ");
+            sql.push_str(&shape.predicates.join(" and "));
+        }
+        create.push(IndexAdvice {
+            table: table.name.to_string(),
+            name,
+            columns,
+            predicates: shape.predicates,
+            queries: sample.queries,
+            avg_ms: sample.total_ms / sample.queries.max(1),
+            max_ms: sample.max_ms,
+            sql,
+        });
+    }
+    if let Some(max_indexes) = max_indexes {
+        create.truncate(max_indexes);
+    }
+    create
+}
+
+/// Recommend indexes for the shapes recorded for `site` and list attribute
+/// indexes that are never used. The usage of indexes is taken from
+/// `pg_stat_user_indexes` and therefore only reflects queries that ran
+/// against the database we are connected to since its statistics were
+/// last reset. When the shard has read `replicas`, queries that ran there
+/// are not visible to us, and we do not list any unused indexes
+pub(crate) fn advise(
+    conn: &mut PgConnection,
+    layout: &Layout,
+    indexes: &HashMap<String, Vec<CreateIndex>>,
+    opts: &AdviceOptions,
+    replicas: bool,
+) -> Result<Advice, StoreError> {
+    let site = &layout.site;
+    let rows: Vec<ShapeRow> = sql_query(
+        "select table_name, eq_columns, range_columns, order_columns, predicates, \
+                queries, total_ms, max_ms \
+           from subgraphs.query_shape \
+          where deployment = $1 \
+            and queries >= $2 \
+            and total_ms / queries >= $3 \
+            and ($4::int8 is null or last_seen >= now() - $4::int8 * interval '1 second') \
+          order by total_ms desc",
+    )
+    .bind::<Integer, _>(site.id)
+    .bind::<BigInt, _>(opts.min_queries)
+    .bind::<BigInt, _>(opts.min_avg_ms)
+    .bind::<Nullable<BigInt>, _>(opts.max_age.map(|age| age.as_secs() as i64))
+    .load(conn)?;
+
+    let excluded = excluded(conn, site)?;
+    let shapes = rows.into_iter().map(ShapeRow::into_parts).collect();
+    let create = recommend(layout, indexes, shapes, &excluded, opts.max_indexes);
+
+    if replicas {
+        return Ok(Advice {
+            create,
+            drop: Vec::new(),
+            replicas,
+        });
+    }
+
+    let rows: Vec<UnusedRow> = sql_query(
+        "select s.relname::text as table_name, s.indexrelname::text as name, \
+                i.indexdef as defn, \
+                pg_size_pretty(pg_relation_size(s.indexrelid)) as size \
+           from pg_stat_user_indexes s \
+           join pg_indexes i \
+             on i.schemaname = s.schemaname and i.indexname = s.indexrelname \
+          where s.schemaname = $1 \
+            and s.idx_scan = 0 \
+          order by pg_relation_size(s.indexrelid) desc",
+    )
+    .bind::<Text, _>(site.namespace.as_str())
+    .load(conn)?;
+
+    let drop = rows
+        .into_iter()
+        .filter(|row| row.name.starts_with("attr_"))
+        .filter(|row| {
+            let index = CreateIndex::parse(row.defn.clone());
+            index.is_attribute_index() && !index.is_id()
+        })
+        .map(|row| UnusedIndex {
+            sql: format!(
+                "drop index concurrently if exists {}.{}",
+                site.namespace, row.name
+            ),
+            table: row.table_name,
+            name: row.name,
+            size: row.size,
+        })
+        .collect();
+
+    Ok(Advice {
+        create,
+        drop,
+        replicas,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use graph::components::store::{AttributeNames, ChildMultiplicity, EntityWindow};
+    use graph::data::store::IdList;
+    use graph::prelude::{DeploymentHash, ValueType, BLOCK_NUMBER_MAX};
+    use graph::schema::InputSchema;
+
+    use crate::layout_for_tests::{make_dummy_site, Namespace};
+    use crate::relational::Catalog;
+
+    use super::*;
+
+    const SCHEMA: &str = "
+        enum Kind { big, small }
+        type Token @entity {
+            id: ID!,
+            owner: String!,
+            amount: BigInt!,
+            active: Boolean!,
+            kind: Kind!,
+            tags: [String!]!
+        }
+        type Transfer @entity {
+            id: ID!,
+            token: Token!,
+            value: BigInt!
+        }";
+
+    fn layout() -> Layout {
+        let subgraph = DeploymentHash::new("subgraph").unwrap();
+        let schema = InputSchema::parse_latest(SCHEMA, subgraph.clone()).unwrap();
+        let namespace = Namespace::new("sgd0815".to_owned()).unwrap();
+        let site = Arc::new(make_dummy_site(subgraph, namespace, "anet".to_string()));
+        let catalog = Catalog::for_tests(site.clone(), BTreeSet::new()).unwrap();
+        Layout::new(site, &schema, catalog).unwrap()
+    }
+
+    fn parse(defn: &str) -> CreateIndex {
+        CreateIndex::parse(defn.to_string())
+    }
+
+    fn cols(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    fn shape(table: &str, eq: &[&str], range: &[&str], order: &[&str]) -> QueryShape {
+        QueryShape {
+            table: table.to_string(),
+            eq: cols(eq),
+            range: cols(range),
+            order: cols(order),
+            predicates: vec![],
+        }
+    }
+
+    fn sample(queries: i64, total_ms: i64) -> Sample {
+        Sample {
+            queries,
+            total_ms,
+            max_ms: total_ms,
+        }
+    }
+
+    #[test]
+    fn shape_for_query() {
+        use EntityFilter::*;
+
+        let layout = layout();
+        let token = layout.input_schema.entity_type("Token").unwrap();
+        let transfer = layout.input_schema.entity_type("Transfer").unwrap();
+        let all = |et: &graph::schema::EntityType| {
+            EntityCollection::All(vec![(et.clone(), AttributeNames::All)])
+        };
+
+        // Conditions inside nested `and` count, `or` and attributes that
+        // are compared for equality are not ranges
+        let filter = And(vec![
+            Equal("owner".into(), Value::from("0x01")),
+            And(vec![
+                GreaterThan("amount".into(), Value::Int(10)),
+                Equal("active".into(), Value::Bool(true)),
+                Equal("kind".into(), Value::from("big")),
+            ]),
+            LessThan("owner".into(), Value::from("0x05")),
+            Or(vec![Equal("id".into(), Value::from("1"))]),
+        ]);
+        let query = EntityQuery::new(subgraph(), BLOCK_NUMBER_MAX, all(&token))
+            .filter(filter)
+            .order(EntityOrder::Multi(vec![
+                EntityOrder::Descending("amount".into(), ValueType::BigInt),
+                EntityOrder::Ascending("owner".into(), ValueType::String),
+            ]));
+        let exp = QueryShape {
+            table: "token".to_string(),
+            eq: cols(&["owner"]),
+            range: cols(&["amount"]),
+            order: cols(&["amount", "owner"]),
+            predicates: cols(&["\"active\" = true", "\"kind\" = 'big'"]),
+        };
+        assert_eq!(vec![exp], QueryShape::for_query(&layout, &query));
+
+        // Without a filter or an explicit order, queries are ordered by id
+        let query = EntityQuery::new(subgraph(), BLOCK_NUMBER_MAX, all(&token));
+        assert_eq!(
+            vec![shape("token", &[], &[], &["id"])],
+            QueryShape::for_query(&layout, &query)
+        );
+
+        // Windows join children with their parent through the link
+        let window = EntityWindow {
+            child_type: transfer,
+            ids: IdList::String(vec![]),
+            link: EntityLink::Direct(
+                WindowAttribute::Scalar("token".to_string()),
+                ChildMultiplicity::Many,
+            ),
+            column_names: AttributeNames::All,
+        };
+        let query = EntityQuery::new(
+            subgraph(),
+            BLOCK_NUMBER_MAX,
+            EntityCollection::Window(vec![window]),
+        )
+        .filter(GreaterOrEqual("value".into(), Value::Int(1)));
+        assert_eq!(
+            vec![shape("transfer", &["token"], &["value"], &["id"])],
+            QueryShape::for_query(&layout, &query)
+        );
+    }
+
+    fn subgraph() -> DeploymentHash {
+        DeploymentHash::new("subgraph").unwrap()
+    }
+
+    #[test]
+    fn columns_for_index() {
+        let layout = layout();
+        let table = layout
+            .table(&SqlName::verbatim("token".to_string()))
+            .unwrap();
+        let columns = |shape: QueryShape| index_columns(table, &shape);
+
+        // The first range column wins over the order
+        assert_eq!(
+            Some(cols(&["owner", "amount"])),
+            columns(shape("token", &["owner"], &["amount"], &["id"]))
+        );
+        // Without a range, use the first order column that is not
+        // compared for equality already
+        assert_eq!(
+            Some(cols(&["owner", "amount"])),
+            columns(shape("token", &["owner"], &[], &["owner", "amount"]))
+        );
+        assert_eq!(
+            Some(cols(&["owner"])),
+            columns(shape("token", &["owner"], &[], &[]))
+        );
+        // List columns can not be part of a btree index
+        assert_eq!(None, columns(shape("token", &["tags"], &[], &[])));
+        assert_eq!(None, columns(shape("token", &[], &[], &[])));
+    }
+
+    #[test]
+    fn recommend_indexes() {
+        let layout = layout();
+        let indexes = HashMap::from([(
+            "token".to_string(),
+            vec![parse(
+                "CREATE INDEX attr_1_1_token_amount ON sgd0815.token USING btree (amount)",
+            )],
+        )]);
+        let partial = QueryShape {
+            predicates: cols(&["\"active\" = true"]),
+            ..shape("token", &["owner"], &[], &["amount"])
+        };
+        let shapes = vec![
+            (
+                shape("token", &["owner"], &["amount"], &[]),
+                sample(10, 1000),
+            ),
+            (partial, sample(20, 900)),
+            // Covered by the existing index on `amount`
+            (shape("token", &["amount"], &[], &[]), sample(10, 800)),
+            // Excluded by an operator
+            (
+                shape("transfer", &["token"], &[], &["value"]),
+                sample(10, 700),
+            ),
+            // Leads to the same index as the first shape
+            (
+                shape("token", &["owner"], &["amount"], &["id"]),
+                sample(5, 600),
+            ),
+            // Not a btree index
+            (shape("token", &["tags"], &[], &[]), sample(10, 500)),
+        ];
+        let excluded = BTreeSet::from(["manual_transfer_token_value".to_string()]);
+
+        let advice = recommend(&layout, &indexes, shapes.clone(), &excluded, None);
+        let names: Vec<_> = advice.iter().map(|advice| advice.name.as_str()).collect();
+        assert_eq!(
+            vec![
+                "manual_token_owner_amount",
+                "manual_token_owner_amount_partial"
+            ],
+            names
+        );
+        assert_eq!(
+            "create index concurrently if not exists manual_token_owner_amount \
+             on sgd0815.\"token\" using btree(left(\"owner\", 256), \"amount\")",
+            advice[0].sql
+        );
+        assert_eq!(15, advice[0].queries);
+        assert_eq!(100, advice[0].avg_ms);
+        assert_eq!(
+            "create index concurrently if not exists manual_token_owner_amount_partial \
+             on sgd0815.\"token\" using btree(left(\"owner\", 256), \"amount\") \
+             where \"active\" = true",
+            advice[1].sql
+        );
+
+        let advice = recommend(&layout, &indexes, shapes, &BTreeSet::new(), Some(1));
+        assert_eq!(1, advice.len());
+        assert_eq!("manual_token_owner_amount", advice[0].name);
+    }
+
+    #[test]
+    fn covering_indexes() {
+        let attr = parse("CREATE INDEX attr_1_0_token_owner ON sgd1.token USING btree (owner)");
+        let composite = parse(
+            "CREATE INDEX manual_token_owner_kind_amount ON sgd1.token USING btree (kind, owner, amount)",
+        );
+        let partial = parse(
+            "CREATE INDEX manual_token_owner ON sgd1.token USING btree (owner) WHERE (active = true)",
+        );
+        let gist = parse(
+            "CREATE INDEX attr_1_0_token_owner ON sgd1.token USING gist (owner, block_range)",
+        );
+
+        let owner = cols(&["owner"]);
+        let owner_kind = cols(&["kind", "owner"]);
+        let amount = "amount".to_string();
+
+        assert!(covers(&attr, &owner, None));
+        assert!(!covers(&attr, &owner, Some(&amount)));
+        assert!(covers(&composite, &cols(&["owner", "kind"]), Some(&amount)));
+        assert!(covers(&composite, &owner_kind, None));
+        assert!(!covers(&composite, &owner, None));
+        assert!(!covers(&partial, &owner, None));
+        assert!(!covers(&gist, &owner, None));
+    }
+}
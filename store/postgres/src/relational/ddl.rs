@@ -4,8 +4,11 @@ use std::{
 };
 
 use graph::{
+    components::store::StoreError,
+    internal_error,
     prelude::{BLOCK_NUMBER_MAX, ENV_VARS},
-    schema::InputSchema,
+    schema::{ExprVisitor, FulltextConfig, InputSchema, VisitExpr},
+    sqlparser::{ast as p, parser::ParserError},
 };
 
 use crate::block_range::CAUSALITY_REGION_COLUMN;
@@ -54,23 +57,70 @@ impl Layout {
 
     pub(crate) fn write_enum_ddl(&self, out: &mut dyn Write) -> Result<(), fmt::Error> {
         for name in self.input_schema.enum_types() {
-            let values = self.input_schema.enum_values(name).unwrap();
-            let mut sep = "";
-            let name = SqlName::from(name);
-            write!(
-                out,
-                "create type {}.{}\n    as enum (",
-                self.catalog.site.namespace,
-                name.quoted()
-            )?;
-            for value in values.iter() {
-                write!(out, "{}'{}'", sep, value)?;
-                sep = ", "
-            }
-            writeln!(out, ");")?;
+            self.write_enum_type_ddl(name, out)?;
         }
         Ok(())
     }
+
+    fn write_enum_type_ddl(&self, name: &str, out: &mut dyn Write) -> Result<(), fmt::Error> {
+        let values = self.input_schema.enum_values(name).unwrap();
+        let mut sep = "";
+        let name = SqlName::from(name);
+        write!(
+            out,
+            "create type {}.{}\n    as enum (",
+            self.catalog.site.namespace,
+            name.quoted()
+        )?;
+        for value in values.iter() {
+            write!(out, "{}'{}'", sep, value)?;
+            sep = ", "
+        }
+        writeln!(out, ");")
+    }
+
+    /// Generate the DDL that changes the database schema for `old` into
+    /// the one for `self` by adding enum types and values, tables, and
+    /// columns. Callers must make sure with `Layout::can_patch_from` that
+    /// `self` only adds to `old`
+    ///
+    /// New columns are `null` for all existing rows, except for fulltext
+    /// columns, which are filled from the data that is already there
+    pub(crate) fn patch_ddl(&self, old: &Layout) -> Result<String, fmt::Error> {
+        let mut out = String::new();
+
+        for name in self.input_schema.enum_types() {
+            match old.input_schema.enum_values(name) {
+                None => self.write_enum_type_ddl(name, &mut out)?,
+                Some(old_values) => {
+                    let values = self.input_schema.enum_values(name).unwrap();
+                    for value in values.difference(old_values.as_ref()) {
+                        writeln!(
+                            out,
+                            "alter type {}.{} add value '{}';",
+                            self.catalog.site.namespace,
+                            SqlName::from(name).quoted(),
+                            value
+                        )?;
+                    }
+                }
+            }
+        }
+
+        let mut tables = self.tables.values().collect::<Vec<_>>();
+        tables.sort_by_key(|table| table.position);
+        for table in tables {
+            match old.table(&table.name) {
+                Some(old_table) => {
+                    table.add_columns_ddl(old_table, &mut out)?;
+                    table.add_declared_indexes_ddl(old_table, &mut out)?;
+                }
+                None => table.as_ddl(&self.input_schema, &self.catalog, None, &mut out)?,
+            }
+        }
+
+        Ok(out)
+    }
 }
 
 impl Table {
@@ -300,9 +350,23 @@ impl Table {
     }
 
     fn create_attribute_indexes(&self, out: &mut String) -> fmt::Result {
+        self.create_attribute_indexes_for(out, |_| true)
+    }
+
+    /// Create attribute indexes for the columns for which `include`
+    /// returns `true`. The names of the indexes are the same as the ones
+    /// `create_attribute_indexes` uses
+    fn create_attribute_indexes_for(
+        &self,
+        out: &mut String,
+        include: impl Fn(&Column) -> bool,
+    ) -> fmt::Result {
         let columns = self.columns_to_index();
 
         for (column_index, column) in columns.enumerate() {
+            if !include(column) {
+                continue;
+            }
             let (method, index_expr) =
                 Self::calculate_attr_index_method_and_expression(self.immutable, column);
 
@@ -343,15 +407,151 @@ impl Table {
                 && [ColumnType::BigDecimal, ColumnType::BigInt, ColumnType::Int]
                     .contains(&col.column_type))
         };
+        // The state of cumulative aggregates is never used in queries
+        let not_aggregate_state = |col: &&Column| !col.is_aggregate_state();
+
         let columns = self
             .columns
             .iter()
             .filter(not_enum_list)
             .filter(not_immutable_pk)
+            .filter(not_aggregate_state)
             .filter(not_numeric_list);
         columns
     }
 
+    /// Generate the `create index` statements for the indexes declared
+    /// with `@index` on the entity type of this table and return them
+    /// together with the names of the indexes. The names only depend on
+    /// the position of the table and of the declaration so that they are
+    /// the same every time we generate them
+    pub(crate) fn declared_indexes(
+        &self,
+        concurrently: bool,
+        if_not_exists: bool,
+    ) -> Result<Vec<(String, String)>, StoreError> {
+        let conc = if concurrently { "concurrently " } else { "" };
+        let if_not_exists = if if_not_exists { "if not exists " } else { "" };
+
+        let mut indexes = Vec::new();
+        for (index, defn) in self.index_definitions().iter().enumerate() {
+            let exprs = defn
+                .fields
+                .iter()
+                .map(|field| {
+                    self.column_for_field(field)
+                        .map(|column| Self::calculate_index_method_and_expression(column).1)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let name = format!(
+                "decl_{table_index}_{index}_{table_name}",
+                table_index = self.position,
+                table_name = self.name
+            );
+            let mut sql = format!(
+                "create index {conc}{if_not_exists}{name}\n    on {qname} using {method}({exprs})",
+                qname = self.qualified_name,
+                method = defn.method.as_str(),
+                exprs = exprs.join(", ")
+            );
+            if let Some(filter) = &defn.filter {
+                sql.push_str("\n    where ");
+                sql.push_str(&self.rewrite_filter(filter)?);
+            }
+            sql.push_str(";\n");
+            indexes.push((name, sql));
+        }
+        Ok(indexes)
+    }
+
+    /// Rewrite the `where` expression of an `@index` directive by
+    /// replacing field names with column names
+    fn rewrite_filter(&self, filter: &str) -> Result<String, StoreError> {
+        struct Rewriter<'a> {
+            table: &'a Table,
+            // The first error we encounter. Schema validation should have
+            // caught all of them
+            error: Option<StoreError>,
+        }
+
+        impl<'a> ExprVisitor for Rewriter<'a> {
+            fn visit_ident(&mut self, ident: &mut p::Ident) -> Result<(), ()> {
+                match self.table.column_for_field(&ident.value) {
+                    Ok(column) => {
+                        ident.value = column.name.to_string();
+                        ident.quote_style = Some('"');
+                        Ok(())
+                    }
+                    Err(e) => {
+                        self.not_supported(e.to_string());
+                        Err(())
+                    }
+                }
+            }
+
+            fn visit_timestamp_ident(&mut self, ident: &mut p::Ident) -> Result<(), ()> {
+                self.visit_ident(ident)
+            }
+
+            fn visit_func_name(&mut self, _func: &mut p::Ident) -> Result<(), ()> {
+                Ok(())
+            }
+
+            fn not_supported(&mut self, msg: String) {
+                if self.error.is_none() {
+                    self.error = Some(internal_error!(
+                        "Schema validation should have found index expression errors: {}",
+                        msg
+                    ));
+                }
+            }
+
+            fn parse_error(&mut self, e: ParserError) {
+                self.not_supported(e.to_string())
+            }
+        }
+
+        let mut visitor = Rewriter {
+            table: self,
+            error: None,
+        };
+        let expr = VisitExpr::visit(filter, &mut visitor);
+        match (expr, visitor.error) {
+            (_, Some(e)) => Err(e),
+            (Ok(expr), None) => Ok(expr.to_string()),
+            (Err(()), None) => Err(internal_error!(
+                "failed to rewrite index expression `{}`",
+                filter
+            )),
+        }
+    }
+
+    fn create_declared_indexes(&self, out: &mut String) -> fmt::Result {
+        for (_, sql) in self
+            .declared_indexes(false, false)
+            .map_err(|_| fmt::Error)?
+        {
+            out.push_str(&sql);
+        }
+        Ok(())
+    }
+
+    /// Generate the DDL for the indexes declared on `self` that `old`
+    /// does not have. `Table::can_patch_from` makes sure that declarations
+    /// are only ever appended
+    fn add_declared_indexes_ddl(&self, old: &Table, out: &mut String) -> fmt::Result {
+        let old_count = old.index_definitions().len();
+        for (_, sql) in self
+            .declared_indexes(false, false)
+            .map_err(|_| fmt::Error)?
+            .into_iter()
+            .skip(old_count)
+        {
+            out.push_str(&sql);
+        }
+        Ok(())
+    }
+
     /// If `self` is an aggregation and has cumulative aggregates, create an
     /// index on the dimensions. That supports the lookup of previous
     /// aggregation values we do in the rollup query since that filters by
@@ -423,10 +623,82 @@ impl Table {
         } else {
             self.create_attribute_indexes(out)?;
             self.create_aggregate_indexes(schema, out)?;
+            // When we postpone index creation, declared indexes are created
+            // once the data has been copied
+            self.create_declared_indexes(out)?;
         }
         Ok(())
     }
 
+    /// Generate the DDL to add the columns of `self` that `old` does not
+    /// have, and to index them
+    fn add_columns_ddl(&self, old: &Table, out: &mut String) -> fmt::Result {
+        let is_new = |column: &Column| !old.columns.iter().any(|col| col.name == column.name);
+
+        let mut added = false;
+        for column in self.columns.iter().filter(|column| is_new(*column)) {
+            let mut col = String::new();
+            column.as_ddl(&mut col)?;
+            writeln!(
+                out,
+                "alter table {} add column {};",
+                self.qualified_name, col
+            )?;
+            if let (Some(fields), ColumnType::TSVector(config)) =
+                (&column.fulltext_fields, &column.column_type)
+            {
+                self.fill_fulltext_column(column, fields.iter(), config, out)?;
+            }
+            added = true;
+        }
+
+        if added {
+            self.create_attribute_indexes_for(out, is_new)?;
+        }
+        Ok(())
+    }
+
+    /// Compute the values of the fulltext `column` for all existing rows
+    /// the same way that inserting a row does
+    fn fill_fulltext_column<'a>(
+        &self,
+        column: &Column,
+        fields: impl Iterator<Item = &'a String>,
+        config: &FulltextConfig,
+        out: &mut String,
+    ) -> fmt::Result {
+        let mut fields: Vec<_> = fields.collect();
+        fields.sort();
+        let values = fields
+            .into_iter()
+            .map(|field| {
+                self.column_for_field(field)
+                    .map(|col| {
+                        format!(
+                            "to_tsvector({}, coalesce({}, ''))",
+                            config.language.as_sql(),
+                            col.name.quoted()
+                        )
+                    })
+                    // As in `create_aggregate_indexes`, we can only report
+                    // a generic formatting error
+                    .map_err(|_| fmt::Error)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let value = if values.is_empty() {
+            "''::tsvector".to_string()
+        } else {
+            values.join(" || ")
+        };
+        writeln!(
+            out,
+            "update {} set {} = {};",
+            self.qualified_name,
+            column.name.quoted(),
+            value
+        )
+    }
+
     pub fn exclusion_ddl(&self, out: &mut String) -> fmt::Result {
         // Tables with causality regions need to use exclusion constraints for correctness,
         // to catch violations of write isolation.
@@ -246,6 +246,10 @@ fn generate_ddl() {
     let layout = test_layout(LIFETIME_GQL);
     let sql = layout.as_ddl(None).expect("Failed to generate DDL");
     check_eqv(LIFETIME_SQL, &sql);
+
+    let layout = test_layout(INDEX_GQL);
+    let sql = layout.as_ddl(None).expect("Failed to generate DDL");
+    check_eqv(INDEX_DDL, &sql);
 }
 
 #[test]
@@ -352,6 +356,78 @@ fn can_copy_from() {
     );
 }
 
+#[test]
+fn can_patch_from() {
+    let old = test_layout(PATCH_OLD_GQL);
+    // Patching with the same schema is fine
+    assert!(old.can_patch_from(&old).is_empty());
+
+    let new = test_layout(PATCH_NEW_GQL);
+    assert!(new.can_patch_from(&old).is_empty());
+    // We can not remove what a patch added
+    assert_eq!(
+        vec![
+            "the attribute Animal.kind can not be removed",
+            "the attribute Animal.nickname can not be removed",
+            "the attribute Animal.search can not be removed",
+            "the entity type Forest can not be removed",
+            "values can not be removed from the enum Kind",
+        ],
+        old.can_patch_from(&new)
+    );
+
+    let new = test_layout(
+        "type Animal @entity(immutable: true) { id: ID!, name: String, species: String!, age: Int! }
+         enum Kind { cat, dog }",
+    );
+    assert_eq!(
+        vec![
+            "the attribute Animal.name can not change its type from String! to String",
+            "the entity type Animal can not change whether it is immutable",
+            "the new attribute Animal.age must be nullable",
+        ],
+        new.can_patch_from(&old)
+    );
+}
+
+#[test]
+fn patch_ddl() {
+    let old = test_layout(PATCH_OLD_GQL);
+    let new = test_layout(PATCH_NEW_GQL);
+    let sql = new.patch_ddl(&old).expect("Failed to generate DDL");
+    check_eqv(PATCH_DDL, &sql);
+
+    let sql = old.patch_ddl(&old).expect("Failed to generate DDL");
+    assert_eq!("", sql);
+}
+
+#[test]
+fn patch_declared_indexes() {
+    let old = test_layout(
+        r#"type Swap @entity(immutable: true) @index(fields: ["pool"]) {
+            id: Bytes!, pool: Bytes!, amount: BigInt!
+        }"#,
+    );
+    let new = test_layout(
+        r#"type Swap @entity(immutable: true)
+            @index(fields: ["pool"])
+            @index(fields: ["amount"], using: brin) {
+            id: Bytes!, pool: Bytes!, amount: BigInt!
+        }"#,
+    );
+    assert!(new.can_patch_from(&old).is_empty());
+    assert_eq!(
+        vec!["the @index directives on Swap can only be added to, not changed or removed"],
+        old.can_patch_from(&new)
+    );
+
+    let sql = new.patch_ddl(&old).expect("Failed to generate DDL");
+    check_eqv(
+        r#"create index decl_0_1_swap on "sgd0815"."swap" using brin("amount");"#,
+        &sql,
+    );
+}
+
 /// Check that we do not create the index on `block$` twice. There was a bug
 /// that if an immutable entity type had a `block` field and index creation
 /// was postponed, we would emit the index on `block$` twice, once from
@@ -920,6 +996,70 @@ create index attr_2_1_habitat_most_common
 
 "#;
 
+const PATCH_OLD_GQL: &str = r#"
+type Animal @entity {
+    id: ID!,
+    name: String!
+    species: String!
+}
+enum Kind { cat, dog }"#;
+
+const PATCH_NEW_GQL: &str = r#"
+type _Schema_ @fulltext(
+    name: "search"
+    language: en
+    algorithm: rank
+    include: [
+        {
+            entity: "Animal",
+            fields: [
+                {name: "name"},
+                {name: "species"}
+            ]
+        }
+    ]
+)
+type Animal @entity {
+    id: ID!,
+    name: String!
+    species: String!
+    nickname: String
+    kind: Kind
+}
+type Forest @entity {
+    id: ID!,
+}
+enum Kind { cat, dog, bird }"#;
+
+const PATCH_DDL: &str = r#"alter type sgd0815."kind" add value 'bird';
+alter table "sgd0815"."animal" add column "nickname"           text;
+alter table "sgd0815"."animal" add column "kind"               "sgd0815"."kind";
+alter table "sgd0815"."animal" add column "search"             tsvector;
+update "sgd0815"."animal" set "search" = to_tsvector('english', coalesce("name", '')) || to_tsvector('english', coalesce("species", ''));
+create index attr_0_3_animal_nickname
+    on "sgd0815"."animal" using btree(left("nickname", 256));
+create index attr_0_4_animal_kind
+    on "sgd0815"."animal" using btree("kind");
+create index attr_0_5_animal_search
+    on "sgd0815"."animal" using gin("search");
+
+create table "sgd0815"."forest" (
+        vid                  bigint primary key,
+        block_range          int4range not null,
+        "id"                 text not null
+);
+alter table "sgd0815"."forest"
+  add constraint forest_id_block_range_excl exclude using gist (id with =, block_range with &&);
+create index brin_forest
+    on "sgd0815"."forest"
+ using brin(lower(block_range) int4_minmax_ops, coalesce(upper(block_range), 2147483647) int4_minmax_ops, vid int8_minmax_ops);
+create index forest_block_range_closed
+    on "sgd0815"."forest"(coalesce(upper(block_range), 2147483647))
+ where coalesce(upper(block_range), 2147483647) < 2147483647;
+create index attr_1_0_forest_id
+    on "sgd0815"."forest" using btree("id");
+"#;
+
 const FORWARD_ENUM_GQL: &str = r#"
 type Thing @entity  {
     id: ID!,
@@ -1021,6 +1161,84 @@ create index attr_2_1_stats_day_volume
 create index attr_2_2_stats_day_max_price
     on "sgd0815"."stats_day" using btree("max_price");"#;
 
+const INDEX_GQL: &str = r#"
+type Swap @entity(immutable: true)
+    @index(fields: ["pool", "timestamp"])
+    @index(fields: ["timestamp"], using: brin)
+    @index(fields: ["pool", "amount"], where: "amount > 1000 and not reverted") {
+    id: Bytes!
+    pool: Pool!
+    timestamp: Timestamp!
+    amount: BigInt!
+    reverted: Boolean!
+}
+
+type Pool @entity
+    @index(fields: ["tags"], using: gin)
+    @index(fields: ["name"], where: "num_nulls(name) = 0") {
+    id: Bytes!
+    name: String
+    tags: [String!]!
+}
+"#;
+
+const INDEX_DDL: &str = r#"
+create table "sgd0815"."swap" (
+    vid                  bigint primary key,
+    block$               int not null,
+    "id"                 bytea not null,
+    "pool"               bytea not null,
+    "timestamp"          timestamptz not null,
+    "amount"             numeric not null,
+    "reverted"           boolean not null,
+    unique(id)
+);
+create index swap_block
+    on "sgd0815"."swap"(block$);
+create index attr_0_0_swap_pool
+    on "sgd0815"."swap" using btree("pool", block$);
+create index attr_0_1_swap_timestamp
+    on "sgd0815"."swap" using btree("timestamp");
+create index attr_0_2_swap_amount
+    on "sgd0815"."swap" using btree("amount");
+create index attr_0_3_swap_reverted
+    on "sgd0815"."swap" using btree("reverted");
+
+create index decl_0_0_swap
+    on "sgd0815"."swap" using btree("pool", "timestamp");
+create index decl_0_1_swap
+    on "sgd0815"."swap" using brin("timestamp");
+create index decl_0_2_swap
+    on "sgd0815"."swap" using btree("pool", "amount")
+    where "amount" > 1000 AND NOT "reverted";
+
+create table "sgd0815"."pool" (
+    vid                  bigint primary key,
+    block_range          int4range not null,
+    "id"                 bytea not null,
+    "name"               text,
+    "tags"               text[] not null
+);
+alter table "sgd0815"."pool"
+  add constraint pool_id_block_range_excl exclude using gist (id with =, block_range with &&);
+create index brin_pool
+    on "sgd0815"."pool"
+ using brin(lower(block_range) int4_minmax_ops, coalesce(upper(block_range), 2147483647) int4_minmax_ops, vid int8_minmax_ops);
+create index pool_block_range_closed
+    on "sgd0815"."pool"(coalesce(upper(block_range), 2147483647))
+ where coalesce(upper(block_range), 2147483647) < 2147483647;
+create index attr_1_0_pool_id
+    on "sgd0815"."pool" using btree("id");
+create index attr_1_1_pool_name
+    on "sgd0815"."pool" using btree(left("name", 256));
+
+create index decl_1_0_pool
+    on "sgd0815"."pool" using gin("tags");
+create index decl_1_1_pool
+    on "sgd0815"."pool" using btree(left("name", 256))
+    where num_nulls("name") = 0;
+"#;
+
 const LIFETIME_GQL: &str = r#"
     type Data @entity(timeseries: true) {
         id: Int8!
@@ -640,6 +640,18 @@ impl CreateIndex {
         }
     }
 
+    /// Return `true` if this index was declared with `@index` in the
+    /// subgraph schema. Those indexes are always created from the schema
+    /// of a deployment, never by copying them from another deployment
+    pub fn is_declared_index(&self) -> bool {
+        match self {
+            CreateIndex::Unknown { defn } => defn.starts_with("create index decl_"),
+            CreateIndex::Parsed { name, .. } => {
+                name.starts_with("decl_") || name.starts_with("\"decl_")
+            }
+        }
+    }
+
     pub fn name(&self) -> Option<String> {
         match self {
             CreateIndex::Unknown { .. } => None,
@@ -801,6 +813,9 @@ impl IndexList {
                     // Then ID based indexes in the immutable tables are also created initially
                     // and should be skipped.
                     && !(ci.is_id() && dest_table.immutable)
+                    // Indexes declared in the schema are created from the
+                    // destination's schema
+                    && !ci.is_declared_index()
                     // Finally we filter by the criteria is the index to be postponed. The ones
                     // that are not to be postponed we want to create during initial creation of
                     // the copied subgraph
@@ -818,6 +833,9 @@ impl IndexList {
         Ok(arr)
     }
 
+    /// Create the postponed indexes of this list and the indexes declared
+    /// in the schema of `layout` if they are missing or invalid in
+    /// `layout`, dropping invalid ones first
     pub fn recreate_invalid_indexes(
         &self,
         conn: &mut PgConnection,
@@ -831,8 +849,14 @@ impl IndexList {
 
         let namespace = &layout.catalog.site.namespace;
         for table in layout.tables.values() {
-            for (ind_name, create_query) in
-                self.indexes_for_table(namespace, &table.name.to_string(), table, true, true, true)?
+            let declared = table
+                .declared_indexes(true, true)?
+                .into_iter()
+                .map(|(name, sql)| (Some(name), sql));
+            for (ind_name, create_query) in self
+                .indexes_for_table(namespace, &table.name.to_string(), table, true, true, true)?
+                .into_iter()
+                .chain(declared)
             {
                 if let Some(index_name) = ind_name {
                     let table_name = table.name.clone();
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, fmt::Write, sync::Arc};
+use std::{collections::HashMap, fmt::Write, sync::Arc, time::Duration};
 
 use diesel::{
     connection::SimpleConnection,
@@ -7,7 +7,10 @@ use diesel::{
     Connection, PgConnection, RunQueryDsl,
 };
 use graph::{
-    components::store::{PrunePhase, PruneReporter, PruneRequest, PruningStrategy, VersionStats},
+    components::store::{
+        PrunePhase, PruneReporter, PruneRequest, PruningStrategy, QueryStore, VersionStats,
+    },
+    internal_error,
     prelude::{
         BlockNumber, CancelHandle, CancelToken, CancelableError, CheapClone, StoreError,
         BLOCK_NUMBER_MAX,
@@ -501,6 +504,34 @@ impl Layout {
     }
 }
 
+/// Resolve how many blocks of history the deployment of `store` needs to
+/// keep to retain `duration` worth of history, going by the timestamps of
+/// the blocks of its chain and counting back from its latest block. Since
+/// blocks are produced at a varying rate, this needs to be done every time
+/// the deployment is pruned. Return `None` if the deployment does not have
+/// that much history yet
+pub(crate) async fn history_blocks_for_duration(
+    store: &dyn QueryStore,
+    duration: Duration,
+) -> Result<Option<BlockNumber>, StoreError> {
+    let state = store.deployment_state().await?;
+    let latest = state.latest_block;
+    let timestamp = store
+        .block_number_with_timestamp_and_parent_hash(&latest.hash)
+        .await?
+        .and_then(|(_, timestamp, _)| timestamp)
+        .ok_or_else(|| {
+            internal_error!(
+                "the timestamp of block {} of {} is not available",
+                latest.number,
+                state.id
+            )
+        })?;
+    let cutoff = timestamp.saturating_sub(duration.as_secs());
+    let block = store.block_number_for_timestamp(cutoff).await?;
+    Ok(block.map(|block| latest.number - block))
+}
+
 mod status {
     use std::sync::Arc;
 
@@ -2,7 +2,15 @@ use std::{collections::BTreeSet, sync::Arc};
 
 use diesel::{debug_query, pg::Pg};
 use graph::{
-    prelude::{r, serde_json as json, DeploymentHash, EntityFilter},
+    components::store::{
+        AttributeNames, EntityAggregate, EntityCursor, EntityDiffCursor, EntityOrderByChild,
+        EntityOrderByChildInfo,
+    },
+    data::store::Id,
+    prelude::{
+        r, serde_json as json, DeploymentHash, EntityCollection, EntityFilter, EntityOrder,
+        EntityRange, Value, ValueType, BLOCK_NUMBER_MAX,
+    },
     schema::InputSchema,
 };
 
@@ -12,7 +20,9 @@ use crate::{
     relational_queries::FromColumnValue,
 };
 
-use crate::relational_queries::Filter;
+use crate::relational_queries::{
+    AggregateQuery, EntityDiffQuery, EntityHistoryQuery, Filter, FilterCollection, FilterQuery,
+};
 
 #[test]
 fn gql_value_from_bytes() {
@@ -44,7 +54,8 @@ fn filter_contains(filter: EntityFilter, sql: &str) {
     type Thing @entity {
         id: Bytes!,
         address: Bytes!,
-        name: String
+        name: String,
+        tags: [String!]!
     }";
     let layout = test_layout(SCHEMA);
     let table = layout
@@ -86,3 +97,544 @@ fn prefix() {
     let filter = EntityFilter::In("address".to_string(), vec!["0xbeef".into()]);
     filter_contains(filter, r#"substring(c."address", 1, 64) in ($1)"#);
 }
+
+#[test]
+fn nocase_regex_and_list_filters() {
+    let filter = EntityFilter::EqualNoCase("name".to_string(), "Bibi".into());
+    filter_contains(
+        filter,
+        r#"lower(c."name") in (lower($1)) -- binds: ["Bibi"]"#,
+    );
+
+    let filter = EntityFilter::InNoCase("name".to_string(), vec!["Bibi".into(), "Julian".into()]);
+    filter_contains(
+        filter,
+        r#"lower(c."name") in (lower($1), lower($2)) -- binds: ["Bibi", "Julian"]"#,
+    );
+
+    let filter = EntityFilter::Matches("name".to_string(), "^B.*i$".into());
+    filter_contains(filter, r#"c."name" ~ $1 -- binds: ["^B.*i$"]"#);
+
+    let tags = Value::List(vec!["a".into(), "b".into()]);
+    let filter = EntityFilter::Overlaps("tags".to_string(), tags.clone());
+    filter_contains(filter, r#"c."tags" && $1"#);
+
+    let filter = EntityFilter::ContainedBy("tags".to_string(), tags);
+    filter_contains(filter, r#"c."tags" <@ $1"#);
+
+    let filter = EntityFilter::LengthGreaterThan("tags".to_string(), Value::Int(2));
+    filter_contains(
+        filter,
+        r#"coalesce(array_length(c."tags", 1), 0) > $1 -- binds: [2]"#,
+    );
+
+    let filter = EntityFilter::LengthLessThan("tags".to_string(), Value::Int(2));
+    filter_contains(
+        filter,
+        r#"coalesce(array_length(c."tags", 1), 0) < $1 -- binds: [2]"#,
+    );
+}
+
+#[test]
+fn aggregate_query() {
+    const SCHEMA: &str = "
+    type Transfer @entity {
+        id: Bytes!,
+        amount: BigInt!,
+        fee: BigDecimal,
+        count: Int!,
+        memo: String,
+        created: Timestamp!
+    }";
+    let layout = test_layout(SCHEMA);
+    let entity_type = layout.input_schema.entity_type("Transfer").unwrap();
+    let filter = EntityFilter::GreaterThan("count".to_string(), 3.into());
+    let aggregates = vec![
+        EntityAggregate::Count,
+        EntityAggregate::Sum("amount".to_string()),
+        EntityAggregate::Sum("count".to_string()),
+        EntityAggregate::Max("created".to_string()),
+        EntityAggregate::Min("fee".to_string()),
+    ];
+
+    let query =
+        AggregateQuery::new(&layout, &entity_type, Some(&filter), &aggregates, 17, 1001).unwrap();
+    let sql = debug_query::<Pg, _>(&query).to_string();
+    for exp in [
+        r#"select jsonb_build_array(count(*), sum(c."amount"), sum(c."count"), max(c."created"), min(c."fee")) as data"#,
+        r#"from (select c."id", c."amount", c."count", c."created", c."fee""#,
+        r#"c."count" > $"#,
+        "limit $",
+    ] {
+        assert!(
+            sql.contains(exp),
+            "Expected query /{sql}/ to contain /{exp}/"
+        );
+    }
+    assert_eq!(
+        vec![
+            ColumnType::BigInt,
+            ColumnType::BigInt,
+            ColumnType::Timestamp,
+            ColumnType::BigDecimal
+        ],
+        query.value_types()
+    );
+
+    // Attributes that are not numbers can not be aggregated, and
+    // timestamps can not be summed up
+    for agg in [
+        EntityAggregate::Sum("memo".to_string()),
+        EntityAggregate::Max("memo".to_string()),
+        EntityAggregate::Sum("created".to_string()),
+    ] {
+        let res = AggregateQuery::new(&layout, &entity_type, None, &[agg.clone()], 17, 1001);
+        assert!(res.is_err(), "{agg:?} should not be allowed");
+    }
+}
+
+#[test]
+fn keyset_pagination() {
+    const SCHEMA: &str = "
+    type Thing @entity {
+        id: String!,
+        name: String,
+        count: Int!
+    }";
+
+    /// Replace `$1` etc. with `$` so we do not depend on the exact
+    /// numbering of bind variables
+    fn strip_binds(sql: &str) -> String {
+        let mut res = String::with_capacity(sql.len());
+        let mut in_bind = false;
+        for c in sql.chars() {
+            if in_bind && c.is_ascii_digit() {
+                continue;
+            }
+            in_bind = c == '$';
+            res.push(c);
+        }
+        res
+    }
+
+    let layout = test_layout(SCHEMA);
+    let entity_type = layout.input_schema.entity_type("Thing").unwrap();
+    let id = Value::String("t1".to_string());
+    let cursor = |values: Vec<Value>| EntityCursor {
+        values,
+        id: id.clone(),
+    };
+    let sql_for = |order: EntityOrder, range: EntityRange| {
+        let collection = FilterCollection::new(
+            &layout,
+            EntityCollection::All(vec![(entity_type.clone(), AttributeNames::All)]),
+            None,
+            BLOCK_NUMBER_MAX,
+        )
+        .unwrap();
+        let query = FilterQuery::new(
+            &collection,
+            &layout,
+            None,
+            order,
+            &range,
+            false,
+            BLOCK_NUMBER_MAX,
+            None,
+            &layout.site,
+        )
+        .unwrap();
+        strip_binds(&debug_query::<Pg, _>(&query).to_string())
+    };
+    let asc = || EntityOrder::Ascending("count".to_string(), ValueType::Int);
+    let desc = || EntityOrder::Descending("count".to_string(), ValueType::Int);
+
+    #[track_caller]
+    fn check(sql: String, exp: &str) {
+        assert!(
+            sql.contains(exp),
+            "Expected query /{sql}/ to contain /{exp}/"
+        );
+    }
+
+    // Sorting by id only
+    let range = EntityRange {
+        after: Some(cursor(vec![])),
+        ..EntityRange::first(10)
+    };
+    check(
+        sql_for(EntityOrder::Default, range.clone()),
+        r#" and c."id" > $"#,
+    );
+    let range = EntityRange {
+        before: Some(cursor(vec![])),
+        ..EntityRange::first(10)
+    };
+    check(sql_for(EntityOrder::Default, range), r#" and c."id" < $"#);
+
+    // Sorting by an attribute, where nulls sort last in ascending order
+    let range = EntityRange {
+        after: Some(cursor(vec![Value::Int(7)])),
+        ..EntityRange::first(10)
+    };
+    check(
+        sql_for(asc(), range.clone()),
+        r#"(c."count" > $ or c."count" = $ and c."id" > $ or c."count" is null)"#,
+    );
+    check(
+        sql_for(desc(), range),
+        r#"(c."count" < $ or c."count" = $ and c."id" < $)"#,
+    );
+
+    // A cursor at a null value
+    let range = EntityRange {
+        after: Some(cursor(vec![Value::Null])),
+        ..EntityRange::first(10)
+    };
+    check(
+        sql_for(asc(), range.clone()),
+        r#"(c."count" is null and c."id" > $)"#,
+    );
+    check(
+        sql_for(desc(), range),
+        r#"(c."count" is not null or c."id" < $)"#,
+    );
+
+    // Sorting by several attributes nests the comparison of each
+    // attribute inside the one for the attribute before it
+    let multi = || {
+        EntityOrder::Multi(vec![
+            EntityOrder::Descending("name".to_string(), ValueType::String),
+            asc(),
+        ])
+    };
+    let range = EntityRange {
+        after: Some(cursor(vec![Value::String("b".to_string()), Value::Int(7)])),
+        ..EntityRange::first(10)
+    };
+    check(
+        sql_for(multi(), range),
+        r#"(c."name" < $ or c."name" = $ and (c."count" > $ or c."count" = $ and c."id" > $ or c."count" is null))"#,
+    );
+    let range = EntityRange {
+        before: Some(cursor(vec![Value::Null, Value::Int(7)])),
+        ..EntityRange::first(10)
+    };
+    check(
+        sql_for(multi(), range),
+        r#"(c."name" is null and (c."count" < $ or c."count" = $ and c."id" < $))"#,
+    );
+
+    // Both bounds at once
+    let range = EntityRange {
+        after: Some(cursor(vec![])),
+        before: Some(EntityCursor {
+            values: vec![],
+            id: Value::String("t9".to_string()),
+        }),
+        ..EntityRange::first(10)
+    };
+    check(
+        sql_for(EntityOrder::Default, range),
+        r#" and c."id" > $ and c."id" < $"#,
+    );
+
+    // The cursor must match the sort order
+    let range = EntityRange {
+        after: Some(cursor(vec![])),
+        ..EntityRange::first(10)
+    };
+    let collection = FilterCollection::new(
+        &layout,
+        EntityCollection::All(vec![(entity_type.clone(), AttributeNames::All)]),
+        None,
+        BLOCK_NUMBER_MAX,
+    )
+    .unwrap();
+    let res = FilterQuery::new(
+        &collection,
+        &layout,
+        None,
+        asc(),
+        &range,
+        false,
+        BLOCK_NUMBER_MAX,
+        None,
+        &layout.site,
+    );
+    assert!(res.is_err());
+}
+
+#[test]
+fn keyset_pagination_interface() {
+    const SCHEMA: &str = "
+    interface Animal {
+        id: String!,
+        name: String
+    }
+
+    type Cat implements Animal @entity {
+        id: String!,
+        lives: Int!,
+        name: String
+    }
+
+    type Dog implements Animal @entity {
+        id: String!,
+        name: String,
+        breed: String
+    }";
+
+    let layout = test_layout(SCHEMA);
+    let cat = layout.input_schema.entity_type("Cat").unwrap();
+    let dog = layout.input_schema.entity_type("Dog").unwrap();
+    let collection = FilterCollection::new(
+        &layout,
+        EntityCollection::All(vec![(cat, AttributeNames::All), (dog, AttributeNames::All)]),
+        None,
+        BLOCK_NUMBER_MAX,
+    )
+    .unwrap();
+    let range = EntityRange {
+        after: Some(EntityCursor {
+            values: vec![Value::String("Rex".to_string())],
+            id: Value::String("a1".to_string()),
+        }),
+        ..EntityRange::first(10)
+    };
+    let query = FilterQuery::new(
+        &collection,
+        &layout,
+        None,
+        EntityOrder::Ascending("name".to_string(), ValueType::String),
+        &range,
+        false,
+        BLOCK_NUMBER_MAX,
+        None,
+        &layout.site,
+    )
+    .unwrap();
+    let sql = debug_query::<Pg, _>(&query).to_string();
+
+    // Each table of the interface restricts its rows with its own keyset
+    let branches: Vec<_> = sql.split("union all").collect();
+    for (table, sql) in [("cat", branches[0]), ("dog", branches[1])] {
+        assert!(
+            sql.contains(&format!(r#"from "sgd0815"."{table}" as c"#)),
+            "Expected /{sql}/ to select from {table}"
+        );
+        assert_eq!(
+            1,
+            sql.matches(r#"c."name" > $"#).count(),
+            "Expected /{sql}/ to contain the keyset once"
+        );
+    }
+}
+
+#[test]
+fn multi_column_order() {
+    const SCHEMA: &str = "
+    type Thing @entity(immutable: true) {
+        id: String!,
+        name: String,
+        count: Int!,
+        owner: Owner
+    }
+
+    type Owner @entity(immutable: true) {
+        id: String!,
+        name: String!
+    }";
+
+    let layout = test_layout(SCHEMA);
+    let thing = layout.input_schema.entity_type("Thing").unwrap();
+    let owner = layout.input_schema.entity_type("Owner").unwrap();
+    let sql_for = |order: EntityOrder| {
+        let collection = FilterCollection::new(
+            &layout,
+            EntityCollection::All(vec![(thing.clone(), AttributeNames::All)]),
+            None,
+            BLOCK_NUMBER_MAX,
+        )
+        .unwrap();
+        let range = EntityRange::first(10);
+        let query = FilterQuery::new(
+            &collection,
+            &layout,
+            None,
+            order,
+            &range,
+            false,
+            BLOCK_NUMBER_MAX,
+            None,
+            &layout.site,
+        )
+        .unwrap();
+        debug_query::<Pg, _>(&query).to_string()
+    };
+
+    #[track_caller]
+    fn check(sql: String, exp: &str) {
+        assert!(
+            sql.contains(exp),
+            "Expected query /{sql}/ to contain /{exp}/"
+        );
+    }
+
+    // The tie-breaker on `id` uses the direction of the last attribute
+    let order = EntityOrder::Multi(vec![
+        EntityOrder::Descending("name".to_string(), ValueType::String),
+        EntityOrder::Ascending("count".to_string(), ValueType::Int),
+    ]);
+    check(sql_for(order), r#"order by c."name" desc, c."count", "id""#);
+
+    let order = EntityOrder::Multi(vec![
+        EntityOrder::Ascending("count".to_string(), ValueType::Int),
+        EntityOrder::Descending("name".to_string(), ValueType::String),
+    ]);
+    check(
+        sql_for(order),
+        r#"order by c."count", c."name" desc, "id" desc"#,
+    );
+
+    // Attributes after `id` do not matter
+    let order = EntityOrder::Multi(vec![
+        EntityOrder::Ascending("count".to_string(), ValueType::Int),
+        EntityOrder::Descending("id".to_string(), ValueType::String),
+        EntityOrder::Ascending("name".to_string(), ValueType::String),
+    ]);
+    check(sql_for(order), r#"order by c."count", "id" desc"#);
+
+    // Sorting by an attribute of a child entity joins with the child table
+    let order = EntityOrder::Multi(vec![
+        EntityOrder::ChildDescending(EntityOrderByChild::Object(
+            EntityOrderByChildInfo {
+                sort_by_attribute: "name".into(),
+                join_attribute: "owner".into(),
+                derived: false,
+            },
+            owner,
+        )),
+        EntityOrder::Ascending("count".to_string(), ValueType::Int),
+    ]);
+    let sql = sql_for(order);
+    check(sql.clone(), " left join ");
+    check(sql, r#"order by i1."name" desc, c."count", c."id""#);
+}
+
+#[test]
+fn entity_history_query() {
+    const SCHEMA: &str = "
+    type Token @entity {
+        id: ID!,
+        name: String!
+    }
+
+    type Transfer @entity(immutable: true) {
+        id: Bytes!,
+        amount: BigInt!
+    }";
+    let layout = test_layout(SCHEMA);
+    let id = Id::String("t1".into());
+
+    let table = layout
+        .table_for_entity(&layout.input_schema.entity_type("Token").unwrap())
+        .unwrap();
+    let query = EntityHistoryQuery::new(table, &id, 10, 20, 100);
+    let sql = debug_query::<Pg, _>(&query).to_string();
+    for exp in [
+        "lag(to_jsonb(e.*)) over w end as prev",
+        "lower(e.block_range) as from_block",
+        "upper(e.block_range) as to_block",
+        r#"where e."id" = $"#,
+        "window w as (order by lower(e.block_range))",
+        "where v.from_block <= $",
+        "and coalesce(v.to_block, 2147483647) > $",
+        "order by v.from_block",
+        "limit $",
+    ] {
+        assert!(
+            sql.contains(exp),
+            "Expected query /{sql}/ to contain /{exp}/"
+        );
+    }
+
+    // Immutable entities only have the version written at `block$`
+    let id = Id::Bytes("0xbeef".parse().unwrap());
+    let table = layout
+        .table_for_entity(&layout.input_schema.entity_type("Transfer").unwrap())
+        .unwrap();
+    let query = EntityHistoryQuery::new(table, &id, 10, 20, 100);
+    let sql = debug_query::<Pg, _>(&query).to_string();
+    for exp in [
+        "null::jsonb as prev, e.block$ as from_block, null::int4 as to_block",
+        "and e.block$ <= $",
+    ] {
+        assert!(
+            sql.contains(exp),
+            "Expected query /{sql}/ to contain /{exp}/"
+        );
+    }
+    assert!(!sql.contains("block_range"));
+}
+
+#[test]
+fn entity_diff_query() {
+    const SCHEMA: &str = "
+    type Token @entity {
+        id: ID!,
+        name: String!
+    }
+
+    type Transfer @entity(immutable: true) {
+        id: Bytes!,
+        amount: BigInt!
+    }";
+    let layout = test_layout(SCHEMA);
+
+    let table = layout
+        .table_for_entity(&layout.input_schema.entity_type("Token").unwrap())
+        .unwrap();
+    let after = EntityDiffCursor { block: 12, vid: 7 };
+    let query = EntityDiffQuery::new(table, 10..=20, Some(after), 100, false);
+    let sql = debug_query::<Pg, _>(&query).to_string();
+    for exp in [
+        r#"e."id"::text as id"#,
+        "then 'update' else 'insert' end as operation",
+        "upper(v.block_range) = lower(e.block_range)",
+        "lower(e.block_range) as block, e.vid, null::jsonb as data",
+        "where lower(e.block_range) between $",
+        "'delete' as operation",
+        "where coalesce(upper(e.block_range), 2147483647) between $",
+        "and upper(e.block_range) is not null",
+        "and not exists (select 1 from",
+        "lower(v.block_range) = upper(e.block_range)",
+        "where (c.block, c.vid) > ($",
+        "order by c.block, c.vid",
+        "limit $",
+    ] {
+        assert!(
+            sql.contains(exp),
+            "Expected query /{sql}/ to contain /{exp}/"
+        );
+    }
+
+    // Immutable entities can only be inserted
+    let table = layout
+        .table_for_entity(&layout.input_schema.entity_type("Transfer").unwrap())
+        .unwrap();
+    let query = EntityDiffQuery::new(table, 10..=20, None, 100, true);
+    let sql = debug_query::<Pg, _>(&query).to_string();
+    for exp in [
+        "'insert' as operation",
+        "e.block$ as block, e.vid, to_jsonb(e.*) as data",
+        "where e.block$ between $",
+    ] {
+        assert!(
+            sql.contains(exp),
+            "Expected query /{sql}/ to contain /{exp}/"
+        );
+    }
+    assert!(!sql.contains("block_range"));
+    assert!(!sql.contains("c.vid) >"));
+}
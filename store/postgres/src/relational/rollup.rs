@@ -58,7 +58,7 @@ use std::fmt;
 use std::ops::Range;
 use std::sync::Arc;
 
-use diesel::{sql_query, PgConnection, RunQueryDsl as _};
+use diesel::{sql_query, OptionalExtension, PgConnection, RunQueryDsl as _};
 
 use diesel::sql_types::{Integer, Nullable, Timestamptz};
 use graph::blockchain::BlockTime;
@@ -66,16 +66,24 @@ use graph::components::store::{BlockNumber, StoreError};
 use graph::data::store::IdType;
 use graph::internal_error;
 use graph::schema::{
-    Aggregate, AggregateFn, Aggregation, AggregationInterval, ExprVisitor, VisitExpr,
+    Aggregate, AggregateFn, AggregateState, Aggregation, AggregationInterval, ExprVisitor,
+    VisitExpr,
 };
 use graph::sqlparser::ast as p;
 use graph::sqlparser::parser::ParserError;
 use itertools::Itertools;
 
+use crate::block_range::BLOCK_COLUMN;
 use crate::relational::Table;
 
 use super::{Column, SqlName};
 
+/// The name under which the inner query of a rollup exposes the timestamp
+/// of each source row. The inner query replaces `timestamp` with the start
+/// of the bucket, but expressions like `extract(hour from timestamp)` need
+/// to see the original value
+const SRC_TIMESTAMP: &str = "timestamp$src";
+
 /// Rewrite `expr` by replacing field names with column names and return the
 /// rewritten SQL expression and the columns used in the expression
 fn rewrite<'a>(table: &'a Table, expr: &str) -> Result<(String, Vec<&'a str>), StoreError> {
@@ -94,7 +102,11 @@ fn rewrite<'a>(table: &'a Table, expr: &str) -> Result<(String, Vec<&'a str>), S
             match self.table.column_for_field(&ident.value) {
                 Ok(column) => {
                     self.columns.insert(&column.name);
-                    ident.value = column.name.to_string();
+                    ident.value = if column.name.as_str() == "timestamp" {
+                        SRC_TIMESTAMP.to_string()
+                    } else {
+                        column.name.to_string()
+                    };
                     ident.quote_style = Some('"');
                     Ok(())
                 }
@@ -105,6 +117,10 @@ fn rewrite<'a>(table: &'a Table, expr: &str) -> Result<(String, Vec<&'a str>), S
             }
         }
 
+        fn visit_timestamp_ident(&mut self, ident: &mut p::Ident) -> Result<(), ()> {
+            self.visit_ident(ident)
+        }
+
         fn visit_func_name(&mut self, _func: &mut p::Ident) -> Result<(), ()> {
             Ok(())
         }
@@ -146,6 +162,9 @@ pub(crate) struct Agg<'a> {
     src_columns: Vec<&'a str>,
     expr: String,
     agg_column: &'a Column,
+    /// The columns holding the state for cumulative aggregates that need
+    /// it, like `avg`
+    state: Vec<(AggregateState, &'a Column)>,
 }
 
 impl<'a> Agg<'a> {
@@ -156,11 +175,22 @@ impl<'a> Agg<'a> {
     ) -> Result<Self, StoreError> {
         let (expr, src_columns) = rewrite(src_table, &aggregate.arg)?;
         let agg_column = agg_table.column_for_field(&aggregate.name)?;
+        let state: Vec<_> = aggregate
+            .state()
+            .iter()
+            .map(|state| {
+                let name = Aggregate::state_field_name(&aggregate.name, *state);
+                agg_table
+                    .column_for_field(&name)
+                    .map(|column| (*state, column))
+            })
+            .collect::<Result<_, _>>()?;
         Ok(Self {
             aggregate,
             src_columns,
             expr,
             agg_column,
+            state,
         })
     }
 
@@ -180,6 +210,15 @@ impl<'a> Agg<'a> {
                 write!(w, "arg_max_{}(({}, {time}))", sql_type, src)?
             }
             Count => write!(w, "count(*)")?,
+            Avg => write!(w, "avg({})", src)?,
+            Variance => write!(w, "var_samp({})", src)?,
+            Stddev => write!(w, "stddev_samp({})", src)?,
+            Percentile(p) => write!(
+                w,
+                "percentile_disc(0.{:02}) within group (order by {})",
+                p, src
+            )?,
+            CountDistinct => write!(w, "count(distinct {})", src)?,
         }
         write!(w, " as \"{}\"", self.agg_column.name)
     }
@@ -187,9 +226,31 @@ impl<'a> Agg<'a> {
     /// Generate a SQL fragment `func(expr) as agg_column` where
     /// `func` is the aggregation function. The `time` parameter is the name
     /// of the column with respect to which `first` and `last` should decide
-    /// which values are earlier or later
+    /// which values are earlier or later. If the aggregate keeps state,
+    /// also generate fragments that compute the state over `expr`
     fn aggregate(&self, time: &str, w: &mut dyn fmt::Write) -> fmt::Result {
-        self.aggregate_over(&self.expr, time, w)
+        self.aggregate_over(&self.expr, time, w)?;
+        for (state, column) in &self.state {
+            match state {
+                AggregateState::Count => write!(w, ", count({})", self.expr)?,
+                AggregateState::Sum => write!(w, ", coalesce(sum({}), 0)", self.expr)?,
+                AggregateState::SumOfSquares => {
+                    write!(w, ", coalesce(sum(({0}) * ({0})), 0)", self.expr)?
+                }
+            }
+            write!(w, " as \"{}\"", column.name)?;
+        }
+        Ok(())
+    }
+
+    /// The name of the column holding `state`. Must only be called for
+    /// state that this aggregate keeps
+    fn state_column(&self, state: AggregateState) -> &SqlName {
+        self.state
+            .iter()
+            .find(|(s, _)| *s == state)
+            .map(|(_, column)| &column.name)
+            .expect("the aggregate keeps the requested state")
     }
 
     /// Generate a SQL fragment `func(src_column) as agg_column` where
@@ -207,8 +268,80 @@ impl<'a> Agg<'a> {
                 return self.aggregate_over(&name, time, w);
             }
             Count => write!(w, "sum(\"{}\")", self.agg_column.name)?,
+            Avg | Variance | Stddev | Percentile(_) | CountDistinct
+                if !self.aggregate.cumulative =>
+            {
+                // The previous value is always `null` since these are not
+                // cumulative; there is only the value from the bucket
+                write!(w, "max(\"{}\")", self.agg_column.name)?
+            }
+            Avg => write!(
+                w,
+                "sum(\"{sum}\") / nullif(sum(\"{count}\"), 0)",
+                sum = self.state_column(AggregateState::Sum),
+                count = self.state_column(AggregateState::Count)
+            )?,
+            Variance | Stddev => {
+                // The sample variance is `(n * sumsq - sum^2) / (n * (n -
+                // 1))`; computing it with just one division keeps it exact
+                // up to that division
+                let n = format!("sum(\"{}\")", self.state_column(AggregateState::Count));
+                let sum = format!("sum(\"{}\")", self.state_column(AggregateState::Sum));
+                let sumsq = format!(
+                    "sum(\"{}\")",
+                    self.state_column(AggregateState::SumOfSquares)
+                );
+                let variance = format!("({n} * {sumsq} - {sum} * {sum}) / ({n} * ({n} - 1))");
+                if self.aggregate.func == Variance {
+                    write!(w, "case when {n} > 1 then {variance} end")?
+                } else {
+                    write!(
+                        w,
+                        "case when {n} > 1 then sqrt(greatest({variance}, 0)) end"
+                    )?
+                }
+            }
+            Percentile(_) | CountDistinct => {
+                unreachable!("validation prevents cumulative percentiles and distinct counts")
+            }
         }
-        write!(w, " as \"{}\"", self.agg_column.name)
+        write!(w, " as \"{}\"", self.agg_column.name)?;
+        // The state of the combined aggregate is the sum of the state of
+        // the previous and the current bucket
+        for (_, column) in &self.state {
+            write!(w, ", sum(\"{0}\") as \"{0}\"", column.name)?;
+        }
+        Ok(())
+    }
+
+    /// Generate a SQL fragment `func(agg_column) as agg_column` that
+    /// combines the values of any number of buckets in the aggregation
+    /// table into one value. Buckets that are later in time have larger
+    /// ids, and we use that to decide which values are earlier or later.
+    /// Aggregates that can not be combined produce `null`; the GraphQL
+    /// layer makes sure that queries never ask for them
+    fn roll_up(&self, w: &mut dyn fmt::Write) -> fmt::Result {
+        use AggregateFn::*;
+
+        let name = &self.agg_column.name;
+        let sql_type = self.agg_column.column_type.sql_type();
+        if self.aggregate.cumulative {
+            // The value in the latest bucket already covers all the buckets
+            // before it
+            write!(w, "arg_max_{sql_type}((\"{name}\", id))")?;
+        } else {
+            match self.aggregate.func {
+                Sum | Count => write!(w, "sum(\"{name}\")::{sql_type}")?,
+                Max => write!(w, "max(\"{name}\")")?,
+                Min => write!(w, "min(\"{name}\")")?,
+                First => write!(w, "arg_min_{sql_type}((\"{name}\", id))")?,
+                Last => write!(w, "arg_max_{sql_type}((\"{name}\", id))")?,
+                Avg | Variance | Stddev | Percentile(_) | CountDistinct => {
+                    write!(w, "null::{sql_type}")?
+                }
+            }
+        }
+        write!(w, " as \"{name}\"")
     }
 
     /// Generate a SQL fragment that computes that selects the previous
@@ -216,24 +349,48 @@ impl<'a> Agg<'a> {
     /// `null` when it is not
     fn prev_agg(&self, w: &mut dyn fmt::Write) -> fmt::Result {
         if self.aggregate.cumulative {
-            write!(w, "prev.\"{}\"", self.agg_column.name)
+            write!(w, "prev.\"{}\"", self.agg_column.name)?;
+            for (_, column) in &self.state {
+                write!(w, ", prev.\"{}\"", column.name)?;
+            }
+            Ok(())
         } else {
             let sql_type = self.agg_column.column_type.sql_type();
             write!(w, "null::{sql_type} as \"{}\"", self.agg_column.name)
         }
     }
+
+    /// Write the names of the columns that this aggregate produces, i.e.,
+    /// the column for its value and the columns for its state
+    fn write_columns(&self, w: &mut dyn fmt::Write) -> fmt::Result {
+        write!(w, "\"{}\"", self.agg_column.name)?;
+        for (_, column) in &self.state {
+            write!(w, ", \"{}\"", column.name)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
 pub(crate) struct Rollup {
     pub(crate) interval: AggregationInterval,
-    #[allow(dead_code)]
-    agg_table: Arc<Table>,
+    pub(crate) agg_table: Arc<Table>,
     insert_sql: String,
     /// A query that determines the last time a rollup was done. The query
     /// finds the latest timestamp in the aggregation table and adds the
     /// length of the aggregation interval to deduce the last rollup time
     last_rollup_sql: String,
+    /// A query that finds the earliest and latest timestamp in the source
+    /// table. Only used when backfilling
+    source_range_sql: String,
+    /// A query that finds the block of the first data point in the source
+    /// table at or after a given time. Only used when backfilling
+    block_after_sql: String,
+    /// The select list for a query-time rollup that combines all the
+    /// buckets a query matches into one row per combination of dimensions
+    rolled_up_select: String,
+    /// The `group by` clause that goes with `rolled_up_select`
+    rolled_up_group_by: String,
 }
 
 impl Rollup {
@@ -262,14 +419,35 @@ impl Rollup {
         let mut insert_sql = String::new();
         sql.insert(&mut insert_sql)?;
         let last_rollup_sql = sql.last_rollup();
+        let source_range_sql = sql.source_range();
+        let block_after_sql = sql.block_after();
+        let mut rolled_up_select = String::new();
+        sql.select_rolled_up(&mut rolled_up_select)?;
+        let rolled_up_group_by = sql.group_by_rolled_up();
         Ok(Self {
             interval,
             agg_table,
             insert_sql,
             last_rollup_sql,
+            source_range_sql,
+            block_after_sql,
+            rolled_up_select,
+            rolled_up_group_by,
         })
     }
 
+    /// The select list for a query that combines all the buckets of the
+    /// aggregation table that it matches into one row per combination of
+    /// dimensions. The query must alias the aggregation table as `c`
+    pub(crate) fn rolled_up_select(&self) -> &str {
+        &self.rolled_up_select
+    }
+
+    /// The `group by` clause for queries that use `rolled_up_select`
+    pub(crate) fn rolled_up_group_by(&self) -> &str {
+        &self.rolled_up_group_by
+    }
+
     pub(crate) fn insert(
         &self,
         conn: &mut PgConnection,
@@ -308,6 +486,62 @@ impl Rollup {
             .map(|res| res.last_rollup)?;
         Ok(last_rollup)
     }
+
+    /// Return the timestamps of the earliest and the latest data point in
+    /// the source table, or `None` if the source table is empty
+    pub(crate) fn source_range(
+        &self,
+        conn: &mut PgConnection,
+    ) -> Result<Option<(BlockTime, BlockTime)>, StoreError> {
+        #[derive(QueryableByName)]
+        #[diesel(check_for_backend(diesel::pg::Pg))]
+        struct RangeRes {
+            #[diesel(sql_type = Nullable<Timestamptz>)]
+            first: Option<BlockTime>,
+            #[diesel(sql_type = Nullable<Timestamptz>)]
+            last: Option<BlockTime>,
+        }
+
+        let res = sql_query(&self.source_range_sql).get_result::<RangeRes>(conn)?;
+        Ok(res.first.zip(res.last))
+    }
+
+    /// Return the block number of the first data point in the source table
+    /// whose timestamp is at or after `time`
+    pub(crate) fn block_after(
+        &self,
+        conn: &mut PgConnection,
+        time: BlockTime,
+    ) -> Result<Option<BlockNumber>, StoreError> {
+        #[derive(QueryableByName)]
+        #[diesel(check_for_backend(diesel::pg::Pg))]
+        struct BlockRes {
+            #[diesel(sql_type = Integer)]
+            block: BlockNumber,
+        }
+
+        let block = sql_query(&self.block_after_sql)
+            .bind::<Timestamptz, _>(time)
+            .get_result::<BlockRes>(conn)
+            .optional()?
+            .map(|res| res.block);
+        Ok(block)
+    }
+
+    /// Delete the data for `bucket` from the aggregation table
+    pub(crate) fn clear(
+        &self,
+        conn: &mut PgConnection,
+        bucket: &Range<BlockTime>,
+    ) -> Result<usize, StoreError> {
+        let query = format!(
+            "delete from {} where timestamp = $1",
+            self.agg_table.qualified_name
+        );
+        Ok(sql_query(query)
+            .bind::<Timestamptz, _>(bucket.start)
+            .execute(conn)?)
+    }
 }
 
 struct RollupSql<'a> {
@@ -335,6 +569,16 @@ impl<'a> RollupSql<'a> {
         }
     }
 
+    /// The SQL expression for the id of rows that we form by grouping
+    /// several rows together
+    fn max_id(&self) -> &'static str {
+        match self.agg_table.primary_key().column_type.id_type() {
+            Ok(IdType::Bytes) => "max(id::text)::bytea",
+            Ok(IdType::String) | Ok(IdType::Int8) => "max(id)",
+            Err(_) => unreachable!("we make sure that the primary key has an id_type"),
+        }
+    }
+
     fn has_cumulative_aggregates(&self) -> bool {
         self.aggregates.iter().any(|agg| agg.aggregate.cumulative)
     }
@@ -360,22 +604,22 @@ impl<'a> RollupSql<'a> {
     ///   $2: end timestamp (exclusive)
     ///   $3: block number
     fn select_bucket(&self, with_block: bool, w: &mut dyn fmt::Write) -> fmt::Result {
-        let max_id = match self.agg_table.primary_key().column_type.id_type() {
-            Ok(IdType::Bytes) => "max(id::text)::bytea",
-            Ok(IdType::String) | Ok(IdType::Int8) => "max(id)",
-            Err(_) => unreachable!("we make sure that the primary key has an id_type"),
-        };
-        write!(w, "select {max_id} as id, timestamp")?;
+        write!(w, "select {} as id, timestamp", self.max_id())?;
         if with_block {
             write!(w, ", $3")?;
         }
         write_dims(self.dimensions, w)?;
         comma_sep(self.aggregates, w, |w, agg| agg.aggregate("id", w))?;
-        let secs = self.interval.as_duration().as_secs();
-        write!(
-            w,
-            " from (select id, date_bin('{secs}s', timestamp, 'epoch'::timestamptz) as timestamp"
-        )?;
+        write!(w, " from (select id, ")?;
+        bucket_start(self.interval, "timestamp", w)?;
+        write!(w, " as timestamp")?;
+        if self
+            .aggregates
+            .iter()
+            .any(|agg| agg.src_columns.contains(&"timestamp"))
+        {
+            write!(w, ", timestamp as \"{SRC_TIMESTAMP}\"")?;
+        }
         write_dims(self.dimensions, w)?;
         let agg_srcs: Vec<&str> = {
             let mut agg_srcs: Vec<_> = self
@@ -418,9 +662,7 @@ impl<'a> RollupSql<'a> {
             self.agg_table.qualified_name
         )?;
         write_dims(self.dimensions, w)?;
-        comma_sep(self.aggregates, w, |w, agg| {
-            write!(w, "\"{}\"", agg.agg_column.name)
-        })?;
+        comma_sep(self.aggregates, w, |w, agg| agg.write_columns(w))?;
         write!(w, ") ")
     }
 
@@ -499,9 +741,7 @@ impl<'a> RollupSql<'a> {
         self.insert_into(w)?;
         write!(w, "select id, timestamp, $3 as block$")?;
         write_dims(self.dimensions, w)?;
-        comma_sep(self.aggregates, w, |w, agg| {
-            write!(w, "\"{}\"", agg.agg_column.name)
-        })?;
+        comma_sep(self.aggregates, w, |w, agg| agg.write_columns(w))?;
         write!(w, " from combined")
     }
 
@@ -519,12 +759,102 @@ impl<'a> RollupSql<'a> {
         // last bucket. The last rollup was therefore at least
         // `self.interval` after that. We add 1 second to make sure we are
         // well within the next bucket
-        let secs = self.interval.as_duration().as_secs() + 1;
+        match self.interval.as_duration() {
+            Some(length) => format!(
+                "select max(timestamp) + '{} s'::interval as last_rollup from {}",
+                length.as_secs() + 1,
+                self.agg_table.qualified_name
+            ),
+            None => {
+                // Months have different lengths; do the calendar
+                // arithmetic in UTC so that the result does not depend on
+                // the time zone of the connection
+                format!(
+                    "select (max(timestamp) at time zone 'UTC' \
+                            + '1 month'::interval + '1 s'::interval) at time zone 'UTC' \
+                            as last_rollup from {}",
+                    self.agg_table.qualified_name
+                )
+            }
+        }
+    }
+
+    /// Generate the select list for a query-time rollup over the
+    /// aggregation table
+    ///
+    /// select max(id) as id, min(timestamp) as timestamp, <dimensions>,
+    ///        <rolled up aggregates>
+    ///
+    /// The timestamp of the combined row is the start of the earliest
+    /// bucket that went into it
+    fn select_rolled_up(&self, w: &mut dyn fmt::Write) -> fmt::Result {
+        write!(
+            w,
+            "select {} as id, min(timestamp) as timestamp",
+            self.max_id()
+        )?;
+        write_dims(self.dimensions, w)?;
+        comma_sep(self.aggregates, w, |w, agg| agg.roll_up(w))
+    }
+
+    /// Generate the `group by` clause for a query-time rollup. Without
+    /// dimensions, there is no `group by`, and we need to make sure that we
+    /// do not produce a row when the query does not match any buckets
+    fn group_by_rolled_up(&self) -> String {
+        if self.dimensions.is_empty() {
+            " having count(*) > 0".to_string()
+        } else {
+            let dims = self
+                .dimensions
+                .iter()
+                .map(|col| format!("\"{}\"", col.name))
+                .join(", ");
+            format!(" group by {dims}")
+        }
+    }
+
+    /// Generate a query that selects the timestamps of the earliest and
+    /// latest data point in the source table
+    fn source_range(&self) -> String {
         format!(
-            "select max(timestamp) + '{} s'::interval as last_rollup from {}",
-            secs, self.agg_table.qualified_name
+            "select min(timestamp) as first, max(timestamp) as last from {}",
+            self.src_table
         )
     }
+
+    /// Generate a query that selects the block of the first data point in
+    /// the source table at or after a given time
+    ///
+    /// Bind variables:
+    ///   $1: the time
+    fn block_after(&self) -> String {
+        format!(
+            "select {BLOCK_COLUMN} as block from {src_table} \
+              where {src_table}.timestamp >= $1 \
+              order by {src_table}.timestamp limit 1",
+            src_table = self.src_table
+        )
+    }
+}
+
+/// Write a SQL expression that rounds the timestamp in `column` down to
+/// the start of its bucket for `interval`. This must produce the same
+/// buckets as `AggregationInterval::bucket_start`
+fn bucket_start(
+    interval: AggregationInterval,
+    column: &str,
+    w: &mut dyn fmt::Write,
+) -> fmt::Result {
+    match interval.as_duration() {
+        Some(length) => {
+            let secs = length.as_secs();
+            match interval.origin() {
+                0 => write!(w, "date_bin('{secs}s', {column}, 'epoch'::timestamptz)"),
+                origin => write!(w, "date_bin('{secs}s', {column}, to_timestamp({origin}))"),
+            }
+        }
+        None => write!(w, "date_trunc('month', {column}, 'UTC')"),
+    }
 }
 
 /// Write the elements in `list` separated by commas into `w`. The list
@@ -576,6 +906,25 @@ mod tests {
         assert_eq!(left, right);
     }
 
+    /// The layout for `schema` in the namespace `sgd007`
+    fn layout(schema: &str) -> Layout {
+        let hash = DeploymentHash::new("rollup").unwrap();
+        let nsp = Namespace::new("sgd007".to_string()).unwrap();
+        let schema = InputSchema::parse_latest(schema, hash.clone()).unwrap();
+        let site = Arc::new(make_dummy_site(hash, nsp, "rollup".to_string()));
+        let catalog = Catalog::for_tests(site.clone(), BTreeSet::new()).unwrap();
+        Layout::new(site, &schema, catalog).unwrap()
+    }
+
+    #[track_caller]
+    fn rollup_for<'a>(layout: &'a Layout, table_name: &str) -> &'a Rollup {
+        layout
+            .rollups
+            .iter()
+            .find(|rollup| rollup.agg_table.name.as_str() == table_name)
+            .unwrap()
+    }
+
     #[test]
     fn rollup() {
         const SCHEMA: &str = r#"
@@ -704,21 +1053,7 @@ mod tests {
              order by "sgd007"."data".timestamp) data \
         group by timestamp"#;
 
-        #[track_caller]
-        fn rollup_for<'a>(layout: &'a Layout, table_name: &str) -> &'a Rollup {
-            layout
-                .rollups
-                .iter()
-                .find(|rollup| rollup.agg_table.name.as_str() == table_name)
-                .unwrap()
-        }
-
-        let hash = DeploymentHash::new("rollup").unwrap();
-        let nsp = Namespace::new("sgd007".to_string()).unwrap();
-        let schema = InputSchema::parse_latest(SCHEMA, hash.clone()).unwrap();
-        let site = Arc::new(make_dummy_site(hash, nsp, "rollup".to_string()));
-        let catalog = Catalog::for_tests(site.clone(), BTreeSet::new()).unwrap();
-        let layout = Layout::new(site, &schema, catalog).unwrap();
+        let layout = layout(SCHEMA);
         assert_eq!(6, layout.rollups.len());
 
         // Intervals are non-decreasing
@@ -741,5 +1076,260 @@ mod tests {
 
         let count_only = rollup_for(&layout, "count_only_day");
         check_eqv(COUNT_ONLY_SQL, &count_only.insert_sql);
+
+        // Queries for backfilling
+        check_eqv(
+            r#"select min(timestamp) as first, max(timestamp) as last from "sgd007"."data""#,
+            &stats_day.source_range_sql,
+        );
+        check_eqv(
+            r#"select block$ as block from "sgd007"."data"
+                where "sgd007"."data".timestamp >= $1
+                order by "sgd007"."data".timestamp limit 1"#,
+            &stats_day.block_after_sql,
+        );
+
+        // Query-time rollups
+        check_eqv(
+            r#"select max(id) as id, min(timestamp) as timestamp, "token",
+                      sum("sum")::numeric as "sum", max("max") as "max""#,
+            stats_hour.rolled_up_select(),
+        );
+        check_eqv(r#"group by "token""#, stats_hour.rolled_up_group_by());
+        check_eqv(
+            r#"select max(id) as id, min(timestamp) as timestamp,
+                      arg_min_numeric(("open", id)) as "open",
+                      arg_max_numeric(("close", id)) as "close",
+                      arg_min_int4(("first_amt", id)) as "first_amt""#,
+            open_close.rolled_up_select(),
+        );
+        check_eqv(
+            r#"select max(id) as id, min(timestamp) as timestamp,
+                      sum("count")::int8 as "count", sum("sum")::numeric as "sum",
+                      arg_max_int8(("total_count", id)) as "total_count",
+                      arg_max_numeric(("total_sum", id)) as "total_sum""#,
+            lifetime.rolled_up_select(),
+        );
+        check_eqv("having count(*) > 0", lifetime.rolled_up_group_by());
+    }
+
+    #[test]
+    fn rollup_statistics() {
+        const SCHEMA: &str = r#"
+    type Data @entity(timeseries: true) {
+        id: Int8!
+        timestamp: Timestamp!
+        token: Bytes!
+        trader: Bytes!
+        price: BigDecimal!
+        amount: Int!
+      }
+
+      type Stats @aggregation(intervals: ["day"], source: "Data") {
+        id: Int8!
+        timestamp: Timestamp!
+        token: Bytes!
+        avg: BigDecimal! @aggregate(fn: "avg", arg: "price")
+        stddev: BigDecimal! @aggregate(fn: "stddev", arg: "price")
+        p95: BigDecimal! @aggregate(fn: "p95", arg: "price")
+        traders: Int8! @aggregate(fn: "countDistinct", arg: "trader")
+      }
+
+      type CumulativeStats @aggregation(intervals: ["day"], source: "Data") {
+        id: Int8!
+        timestamp: Timestamp!
+        avg: BigDecimal! @aggregate(fn: "avg", arg: "amount", cumulative: true)
+        variance: BigDecimal! @aggregate(fn: "variance", arg: "price", cumulative: true)
+      }
+      "#;
+
+        const STATS_SQL: &str = r#"
+        insert into "sgd007"."stats_day"(id, timestamp, block$, "token", "avg", "stddev", "p95", "traders")
+        select max(id) as id, timestamp, $3, "token",
+               avg("price") as "avg",
+               stddev_samp("price") as "stddev",
+               percentile_disc(0.95) within group (order by "price") as "p95",
+               count(distinct "trader") as "traders"
+          from (select id, date_bin('86400s', timestamp, 'epoch'::timestamptz) as timestamp, "token", "price", "trader"
+                  from "sgd007"."data"
+                 where "sgd007"."data".timestamp >= $1
+                   and "sgd007"."data".timestamp < $2
+                 order by "sgd007"."data".timestamp) data
+         group by timestamp, "token""#;
+
+        const CUMULATIVE_SQL: &str = r#"
+        with bucket as (
+            select max(id) as id, timestamp,
+                   avg("amount") as "avg",
+                   count("amount") as "avg$count",
+                   coalesce(sum("amount"), 0) as "avg$sum",
+                   var_samp("price") as "variance",
+                   count("price") as "variance$count",
+                   coalesce(sum("price"), 0) as "variance$sum",
+                   coalesce(sum(("price") * ("price")), 0) as "variance$sumsq"
+              from (select id, date_bin('86400s', timestamp, 'epoch'::timestamptz) as timestamp, "amount", "price"
+                      from "sgd007"."data"
+                     where "sgd007"."data".timestamp >= $1
+                       and "sgd007"."data".timestamp < $2
+                     order by "sgd007"."data".timestamp) data
+              group by timestamp),
+             prev as (select bucket.id, bucket.timestamp,
+                             prev."avg", prev."avg$count", prev."avg$sum",
+                             prev."variance", prev."variance$count",
+                             prev."variance$sum", prev."variance$sumsq"
+                        from bucket cross join lateral (
+                             select * from "sgd007"."cumulative_stats_day" prev
+                              where prev.timestamp < $1
+                              order by prev.timestamp desc limit 1) prev),
+             combined as (select id, timestamp,
+                                 sum("avg$sum") / nullif(sum("avg$count"), 0) as "avg",
+                                 sum("avg$count") as "avg$count",
+                                 sum("avg$sum") as "avg$sum",
+                                 case when sum("variance$count") > 1
+                                      then (sum("variance$count") * sum("variance$sumsq")
+                                            - sum("variance$sum") * sum("variance$sum"))
+                                           / (sum("variance$count") * (sum("variance$count") - 1)) end as "variance",
+                                 sum("variance$count") as "variance$count",
+                                 sum("variance$sum") as "variance$sum",
+                                 sum("variance$sumsq") as "variance$sumsq" from (
+                            select *, 1 as seq from prev
+                            union all
+                            select *, 2 as seq from bucket) u
+                          group by id, timestamp)
+        insert into "sgd007"."cumulative_stats_day"(id, timestamp, block$, "avg", "avg$count", "avg$sum",
+                                                    "variance", "variance$count", "variance$sum", "variance$sumsq")
+        select id, timestamp, $3 as block$, "avg", "avg$count", "avg$sum",
+               "variance", "variance$count", "variance$sum", "variance$sumsq" from combined
+        "#;
+
+        let layout = layout(SCHEMA);
+
+        let rollup = |name: &str| rollup_for(&layout, name);
+        check_eqv(STATS_SQL, &rollup("stats_day").insert_sql);
+        check_eqv(CUMULATIVE_SQL, &rollup("cumulative_stats_day").insert_sql);
+
+        // Only cumulative statistics can be rolled up at query time
+        check_eqv(
+            r#"select max(id) as id, min(timestamp) as timestamp, "token",
+                      null::numeric as "avg", null::numeric as "stddev",
+                      null::numeric as "p95", null::int8 as "traders""#,
+            rollup("stats_day").rolled_up_select(),
+        );
+        check_eqv(
+            r#"select max(id) as id, min(timestamp) as timestamp,
+                      arg_max_numeric(("avg", id)) as "avg",
+                      arg_max_numeric(("variance", id)) as "variance""#,
+            rollup("cumulative_stats_day").rolled_up_select(),
+        );
+    }
+
+    #[test]
+    fn rollup_expressions() {
+        const SCHEMA: &str = r#"
+    type Data @entity(timeseries: true) {
+        id: Int8!
+        timestamp: Timestamp!
+        token: Bytes!
+        price: BigDecimal!
+        amount: Int!
+      }
+
+      type Stats @aggregation(intervals: ["day"], source: "Data") {
+        id: Int8!
+        timestamp: Timestamp!
+        token: Bytes!
+        small: BigDecimal! @aggregate(fn: "sum", arg: "case when price between 0 and 10 then price else 0 end")
+        listed: Int8! @aggregate(fn: "sum", arg: "case when amount in (1, 2, 3) then 1 else 0 end")
+        clamped: BigDecimal! @aggregate(fn: "max", arg: "least(greatest(coalesce(price, 0), 0), 100)")
+        bucketed: Int! @aggregate(fn: "max", arg: "floor(amount / 10)")
+        night: Int8! @aggregate(fn: "sum", arg: "case when extract(hour from timestamp) < 6 then 1 else 0 end")
+      }
+      "#;
+
+        const STATS_SQL: &str = r#"
+        insert into "sgd007"."stats_day"(id, timestamp, block$, "token", "small", "listed", "clamped", "bucketed", "night")
+        select max(id) as id, timestamp, $3, "token",
+               sum(CASE WHEN "price" BETWEEN 0 AND 10 THEN "price" ELSE 0 END) as "small",
+               sum(CASE WHEN "amount" IN (1, 2, 3) THEN 1 ELSE 0 END) as "listed",
+               max(least(greatest(coalesce("price", 0), 0), 100)) as "clamped",
+               max(FLOOR("amount" / 10)) as "bucketed",
+               sum(CASE WHEN EXTRACT(HOUR FROM "timestamp$src" AT TIME ZONE 'UTC') < 6 THEN 1 ELSE 0 END) as "night"
+          from (select id, date_bin('86400s', timestamp, 'epoch'::timestamptz) as timestamp,
+                       timestamp as "timestamp$src", "token", "amount", "price"
+                  from "sgd007"."data"
+                 where "sgd007"."data".timestamp >= $1
+                   and "sgd007"."data".timestamp < $2
+                 order by "sgd007"."data".timestamp) data
+         group by timestamp, "token""#;
+
+        let layout = layout(SCHEMA);
+
+        check_eqv(STATS_SQL, &layout.rollups[0].insert_sql);
+    }
+
+    #[test]
+    fn rollup_intervals() {
+        const SCHEMA: &str = r#"
+    type Data @entity(timeseries: true) {
+        id: Int8!
+        timestamp: Timestamp!
+        price: BigDecimal!
+      }
+
+      type Stats @aggregation(intervals: ["5m", "week", "month"], source: "Data") {
+        id: Int8!
+        timestamp: Timestamp!
+        sum: BigDecimal! @aggregate(fn: "sum", arg: "price")
+        total: BigDecimal! @aggregate(fn: "sum", arg: "price", cumulative: true)
+      }
+      "#;
+
+        const BUCKET_SQL: &str = r#"
+            select max(id) as id, timestamp, sum("price") as "sum", sum("price") as "total"
+              from (select id, BUCKET as timestamp, "price"
+                      from "sgd007"."data"
+                     where "sgd007"."data".timestamp >= $1
+                       and "sgd007"."data".timestamp < $2
+                     order by "sgd007"."data".timestamp) data
+              group by timestamp"#;
+
+        let layout = layout(SCHEMA);
+
+        let tables = layout
+            .rollups
+            .iter()
+            .map(|rollup| rollup.agg_table.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["stats_5m", "stats_week", "stats_month"], tables);
+
+        for (rollup, bucket, last_rollup) in [
+            (
+                &layout.rollups[0],
+                "date_bin('300s', timestamp, 'epoch'::timestamptz)",
+                r#"select max(timestamp) + '301 s'::interval as last_rollup from "sgd007"."stats_5m""#,
+            ),
+            (
+                &layout.rollups[1],
+                "date_bin('604800s', timestamp, to_timestamp(345600))",
+                r#"select max(timestamp) + '604801 s'::interval as last_rollup from "sgd007"."stats_week""#,
+            ),
+            (
+                &layout.rollups[2],
+                "date_trunc('month', timestamp, 'UTC')",
+                r#"select (max(timestamp) at time zone 'UTC' + '1 month'::interval + '1 s'::interval)
+                          at time zone 'UTC' as last_rollup from "sgd007"."stats_month""#,
+            ),
+        ] {
+            let bucket_sql = BUCKET_SQL.replace("BUCKET", bucket);
+            assert!(
+                rollup
+                    .insert_sql
+                    .contains(&bucket_sql.split_whitespace().join(" ")),
+                "{} does not contain {}",
+                rollup.insert_sql,
+                bucket_sql
+            );
+            check_eqv(last_rollup, &rollup.last_rollup_sql);
+        }
     }
 }
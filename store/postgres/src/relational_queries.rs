@@ -10,10 +10,14 @@ use diesel::query_builder::{AstPass, Query, QueryFragment, QueryId};
 use diesel::query_dsl::RunQueryDsl;
 use diesel::result::{Error as DieselError, QueryResult};
 use diesel::sql_types::Untyped;
-use diesel::sql_types::{Array, BigInt, Binary, Bool, Int8, Integer, Jsonb, Text, Timestamptz};
+use diesel::sql_types::{
+    Array, BigInt, Binary, Bool, Int8, Integer, Jsonb, Nullable, Text, Timestamptz,
+};
 use diesel::QuerySource as _;
 use graph::components::store::write::{EntityWrite, RowGroup, WriteChunk};
-use graph::components::store::{Child as StoreChild, DerivedEntityQuery};
+use graph::components::store::{
+    Child as StoreChild, DerivedEntityQuery, EntityAggregate, EntityCursor, EntityDiffCursor,
+};
 use graph::data::store::{Id, IdType, NULL};
 use graph::data::store::{IdList, IdRef, QueryObject};
 use graph::data::value::{Object, Word};
@@ -31,12 +35,13 @@ use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::convert::TryFrom;
 use std::fmt::{self, Display};
 use std::iter::FromIterator;
-use std::ops::Range;
+use std::ops::{Range, RangeInclusive};
 use std::str::FromStr;
 use std::string::ToString;
 
 use crate::block_range::{BoundSide, EntityBlockRange};
 use crate::relational::dsl::AtBlock;
+use crate::relational::rollup::Rollup;
 use crate::relational::{
     dsl, Column, ColumnType, Layout, SqlName, Table, BYTE_ARRAY_PREFIX_SIZE, PRIMARY_KEY_COLUMN,
     STRING_PREFIX_SIZE, VID_COLUMN,
@@ -63,6 +68,7 @@ const SORT_KEY_COLUMN: &str = "sort_key$";
 pub(crate) const PARENT_ID: &str = "g$parent_id";
 
 /// Describes at what level a `SELECT` statement is used.
+#[derive(Clone, Copy)]
 enum SelectStatementLevel {
     // A `SELECT` statement that is nested inside another `SELECT` statement
     InnerStatement,
@@ -652,7 +658,7 @@ impl std::fmt::Display for SqlValue<'_> {
 
 /// A `QueryValue` makes it possible to bind a `Value` into a SQL query
 /// using the metadata from Column
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct QueryValue<'a> {
     value: SqlValue<'a>,
     column_type: &'a ColumnType,
@@ -1304,6 +1310,20 @@ pub enum Filter<'a> {
         op: &'static str,
         pattern: String,
     },
+    /// Case-insensitive membership test for string columns. The values
+    /// are never null
+    InNoCase(dsl::Column<'a>, Vec<QueryValue<'a>>),
+    /// Match the column against a POSIX regular expression
+    Matches(dsl::Column<'a>, String),
+    /// Compare a list column with a list of values using an array operator
+    /// like `&&` or `<@`
+    ArrayOp {
+        column: dsl::Column<'a>,
+        op: &'static str,
+        values: QueryValue<'a>,
+    },
+    /// Compare the number of elements in a list column with a number
+    ArrayLength(dsl::Column<'a>, Comparison, i32),
     ChangeBlockGte(dsl::ChangedSince<'a>),
     Child(Box<QueryChild<'a>>),
     /// The value is never null for fulltext queries
@@ -1446,6 +1466,102 @@ impl<'a> Filter<'a> {
             })
         }
 
+        fn in_no_case<'s>(
+            table: dsl::Table<'s>,
+            attr: &String,
+            values: &'s [Value],
+            filter: &'static str,
+        ) -> Result<Filter<'s>, StoreError> {
+            let column = table.column_for_field(attr)?;
+            if column.is_list() || !matches!(column.column_type(), ColumnType::String) {
+                return Err(StoreError::UnsupportedFilter(
+                    filter.to_owned(),
+                    format!("attribute `{attr}` of type {}", column.column_type()),
+                ));
+            }
+            if let Some(value) = values
+                .iter()
+                .find(|value| !matches!(value, Value::String(_)))
+            {
+                return Err(StoreError::UnsupportedFilter(
+                    filter.to_owned(),
+                    value.to_string(),
+                ));
+            }
+            let values = values
+                .iter()
+                .map(|value| QueryValue::new(value, column.column_type()))
+                .collect::<Result<_, _>>()?;
+            Ok(Filter::InNoCase(column, values))
+        }
+
+        fn matches<'s>(
+            table: dsl::Table<'s>,
+            attr: &String,
+            value: &Value,
+        ) -> Result<Filter<'s>, StoreError> {
+            let column = table.column_for_field(attr)?;
+            let pattern = match value {
+                Value::String(s)
+                    if !column.is_list() && matches!(column.column_type(), ColumnType::String) =>
+                {
+                    s
+                }
+                _ => {
+                    return Err(StoreError::UnsupportedFilter(
+                        "matches".to_owned(),
+                        value.to_string(),
+                    ))
+                }
+            };
+            // Postgres can spend a lot of time compiling and evaluating
+            // long regular expressions; we also limit how long queries
+            // with a regular expression may run in `Layout::query`
+            let max_len = ENV_VARS.graphql.max_regex_length;
+            if pattern.chars().count() > max_len {
+                return Err(StoreError::UnsupportedFilter(
+                    "matches".to_owned(),
+                    format!("regular expression longer than {max_len} characters"),
+                ));
+            }
+            Ok(Filter::Matches(column, pattern.clone()))
+        }
+
+        fn array_op<'s>(
+            table: dsl::Table<'s>,
+            attr: &String,
+            value: &'s Value,
+            filter: &'static str,
+            op: &'static str,
+        ) -> Result<Filter<'s>, StoreError> {
+            let column = table.column_for_field(attr)?;
+            if !column.is_list() || !matches!(value, Value::List(_)) {
+                return Err(StoreError::UnsupportedFilter(
+                    filter.to_owned(),
+                    value.to_string(),
+                ));
+            }
+            let values = QueryValue::new(value, column.column_type())?;
+            Ok(Filter::ArrayOp { column, op, values })
+        }
+
+        fn array_length<'s>(
+            table: dsl::Table<'s>,
+            attr: &String,
+            value: &Value,
+            op: Comparison,
+            filter: &'static str,
+        ) -> Result<Filter<'s>, StoreError> {
+            let column = table.column_for_field(attr)?;
+            match value {
+                Value::Int(len) if column.is_list() => Ok(Filter::ArrayLength(column, op, *len)),
+                _ => Err(StoreError::UnsupportedFilter(
+                    filter.to_owned(),
+                    value.to_string(),
+                )),
+            }
+        }
+
         use Comparison as C;
         use ContainsOp as K;
         use EntityFilter::*;
@@ -1504,6 +1620,17 @@ impl<'a> Filter<'a> {
             NotEndsWithNoCase(attr, value) => {
                 starts_or_ends_with(table, attr, value, " not ilike ", false)
             }
+            EqualNoCase(attr, value) => {
+                in_no_case(table, attr, std::slice::from_ref(value), "eq_nocase")
+            }
+            InNoCase(attr, values) => in_no_case(table, attr, values, "in_nocase"),
+            Matches(attr, value) => matches(table, attr, value),
+            Overlaps(attr, value) => array_op(table, attr, value, "overlaps", " && "),
+            ContainedBy(attr, value) => array_op(table, attr, value, "contained_by", " <@ "),
+            LengthGreaterThan(attr, value) => {
+                array_length(table, attr, value, C::Greater, "length_gt")
+            }
+            LengthLessThan(attr, value) => array_length(table, attr, value, C::Less, "length_lt"),
 
             ChangeBlockGte(num) => Ok(F::ChangeBlockGte(table.changed_since(*num))),
             Child(child) => {
@@ -1649,6 +1776,45 @@ impl<'a> Filter<'a> {
         Ok(())
     }
 
+    fn in_no_case<'b>(
+        column: &'b dsl::Column<'b>,
+        values: &'b [QueryValue],
+        mut out: AstPass<'_, 'b, Pg>,
+    ) -> QueryResult<()> {
+        if values.is_empty() {
+            out.push_sql("false");
+            return Ok(());
+        }
+
+        out.push_sql("lower(");
+        column.walk_ast(out.reborrow())?;
+        out.push_sql(") in (");
+        for (i, qv) in values.iter().enumerate() {
+            if i > 0 {
+                out.push_sql(", ");
+            }
+            out.push_sql("lower(");
+            qv.walk_ast(out.reborrow())?;
+            out.push_sql(")");
+        }
+        out.push_sql(")");
+        Ok(())
+    }
+
+    fn array_length<'b>(
+        column: &'b dsl::Column<'b>,
+        op: Comparison,
+        len: &'b i32,
+        mut out: AstPass<'_, 'b, Pg>,
+    ) -> QueryResult<()> {
+        // `array_length` is null for empty arrays
+        out.push_sql("coalesce(array_length(");
+        column.walk_ast(out.reborrow())?;
+        out.push_sql(", 1), 0)");
+        out.push_sql(op.as_str());
+        out.push_bind_param::<Integer, _>(len)
+    }
+
     fn in_array<'b>(
         column: &'b dsl::Column<'b>,
         values: &'b [QueryValue],
@@ -1782,6 +1948,14 @@ impl<'a> fmt::Display for Filter<'a> {
             } => {
                 write!(f, "{column} {op} '{pattern}'")
             }
+            InNoCase(a, vs) => write!(
+                f,
+                "{a} in ({})i",
+                vs.iter().map(|v| v.to_string()).join(",")
+            ),
+            Matches(a, pattern) => write!(f, "{a} ~ /{pattern}/"),
+            ArrayOp { column, op, values } => write!(f, "{column} {op} {values}"),
+            ArrayLength(a, op, len) => write!(f, "len({a}) {op} {len}"),
             ChangeBlockGte(b) => write!(f, "{}", b),
             Child(child /* a, et, cf, _ */) => write!(
                 f,
@@ -1822,6 +1996,18 @@ impl<'a> QueryFragment<Pg> for Filter<'a> {
                 out.push_sql(op);
                 out.push_bind_param::<Text, _>(pattern)?;
             }
+            InNoCase(column, values) => Self::in_no_case(column, values, out)?,
+            Matches(column, pattern) => {
+                column.walk_ast(out.reborrow())?;
+                out.push_sql(" ~ ");
+                out.push_bind_param::<Text, _>(pattern)?;
+            }
+            ArrayOp { column, op, values } => {
+                column.walk_ast(out.reborrow())?;
+                out.push_sql(op);
+                values.walk_ast(out)?;
+            }
+            ArrayLength(column, op, len) => Self::array_length(column, *op, len, out)?,
             ChangeBlockGte(changed_since) => changed_since.walk_ast(out.reborrow())?,
             Child(child) => child.walk_ast(out)?,
         }
@@ -3274,6 +3460,10 @@ pub enum SortKey<'a> {
     },
     /// Order by some other column; `column` will never be `id`
     ChildKey(ChildKey<'a>),
+    /// Order by several keys, one after the other. Each key is a `Key`
+    /// without a fulltext value or a `ChildKey`, except for the last one,
+    /// which is always an `Id` that serves as the tie-breaker
+    Multi(Vec<SortKey<'a>>),
 }
 
 /// String representation that is useful for debugging when `walk_ast` fails
@@ -3344,6 +3534,39 @@ impl<'a> fmt::Display for SortKey<'a> {
                     )
                 }
             },
+            SortKey::Multi(keys) => {
+                for (idx, key) in keys.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ", ")?;
+                    }
+                    match key {
+                        SortKey::Key {
+                            column, direction, ..
+                        } => write!(f, "{}{}", column, direction)?,
+                        SortKey::ChildKey(ChildKey::Single(details)) => {
+                            write!(f, "{}{}", details.sort_by_column, details.direction)?
+                        }
+                        SortKey::ChildKey(ChildKey::Many(_, details)) => {
+                            let columns = details.iter().map(|details| &details.sort_by_column);
+                            write!(
+                                f,
+                                "coalesce({}){}",
+                                columns.join(", "),
+                                details[0].direction
+                            )?
+                        }
+                        SortKey::ChildKey(ChildKey::Id(direction, details, _)) => {
+                            write!(f, "{}{}", details.child_pk, direction)?
+                        }
+                        SortKey::ChildKey(ChildKey::ManyId(direction, details, _)) => {
+                            let columns = details.iter().map(|details| &details.child_pk);
+                            write!(f, "coalesce({}){}", columns.join(", "), direction)?
+                        }
+                        key => write!(f, "{}", key)?,
+                    }
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -3436,8 +3659,9 @@ impl<'a> SortKey<'a> {
             attribute: String,
             use_block_column: UseBlockColumn,
             direction: SortDirection,
+            child_idx: u8,
         ) -> Result<SortKey<'a>, QueryExecutionError> {
-            let child_table = child_table.child(1);
+            let child_table = child_table.child(child_idx);
             let sort_by_column = child_table.column_for_field(&attribute)?;
             if sort_by_column.is_fulltext() {
                 Err(QueryExecutionError::NotSupported(
@@ -3490,11 +3714,11 @@ impl<'a> SortKey<'a> {
                     )));
                 }
 
-                let child_table = child_table.child(1);
+                let child_table = child_table.child(child_idx);
                 let child_at_block = child_table.at_block(block);
                 let child_from = child_table.from_clause();
                 Ok(SortKey::ChildKey(ChildKey::Single(ChildKeyDetails {
-                    child_table: child_table.child(1),
+                    child_table: child_table.child(child_idx),
                     child_from,
                     parent_join_column: parent_column,
                     child_join_column: child_column,
@@ -3513,8 +3737,9 @@ impl<'a> SortKey<'a> {
             entity_types: Vec<EntityType>,
             child: EntityOrderByChildInfo,
             direction: SortDirection,
+            child_idx: u8,
         ) -> Result<Vec<ChildKeyAndIdSharedDetails<'a>>, QueryExecutionError> {
-            assert!(entity_types.len() < 255);
+            assert!(entity_types.len() + (child_idx as usize) < 256);
             return entity_types
                 .iter()
                 .enumerate()
@@ -3522,7 +3747,7 @@ impl<'a> SortKey<'a> {
                     let child_table = layout
                         .table_for_entity(entity_type)?
                         .dsl_table()
-                        .child((i + 1) as u8);
+                        .child(i as u8 + child_idx);
                     let sort_by_column = child_table.column_for_field(&child.sort_by_attribute)?;
                     if sort_by_column.is_fulltext() {
                         Err(QueryExecutionError::NotSupported(
@@ -3584,6 +3809,7 @@ impl<'a> SortKey<'a> {
             entity_types: Vec<EntityType>,
             use_block_column: UseBlockColumn,
             direction: SortDirection,
+            child_idx: u8,
         ) -> Result<SortKey<'a>, QueryExecutionError> {
             if entity_types.is_empty() {
                 return Err(QueryExecutionError::InternalError(
@@ -3609,6 +3835,7 @@ impl<'a> SortKey<'a> {
                         entity_types,
                         child,
                         direction,
+                        child_idx,
                     )?
                     .iter()
                     .map(|details| ChildIdDetails {
@@ -3633,6 +3860,7 @@ impl<'a> SortKey<'a> {
                         entity_types,
                         child,
                         direction,
+                        child_idx,
                     )?
                     .iter()
                     .map(|details| ChildKeyDetails {
@@ -3649,6 +3877,143 @@ impl<'a> SortKey<'a> {
             }
         }
 
+        fn with_order<'a>(
+            order: EntityOrder,
+            layout: &'a Layout,
+            block: BlockNumber,
+            table: dsl::Table<'a>,
+            filter: Option<&'a EntityFilter>,
+            use_block_column: UseBlockColumn,
+            child_idx: u8,
+        ) -> Result<SortKey<'a>, QueryExecutionError> {
+            use SortDirection::*;
+            match order {
+                EntityOrder::Ascending(attr, _) => {
+                    with_key(table, attr, filter, Asc, use_block_column)
+                }
+                EntityOrder::Descending(attr, _) => {
+                    with_key(table, attr, filter, Desc, use_block_column)
+                }
+                EntityOrder::Default => Ok(SortKey::Id(Asc, use_block_column.block_column(table))),
+                EntityOrder::Unordered => Ok(SortKey::None),
+                EntityOrder::ChildAscending(kind) => match kind {
+                    EntityOrderByChild::Object(child, entity_type) => with_child_object_key(
+                        block,
+                        table,
+                        layout.table_for_entity(&entity_type)?.dsl_table(),
+                        child.join_attribute,
+                        child.derived,
+                        child.sort_by_attribute,
+                        use_block_column,
+                        Asc,
+                        child_idx,
+                    ),
+                    EntityOrderByChild::Interface(child, entity_types) => with_child_interface_key(
+                        layout,
+                        block,
+                        table,
+                        child,
+                        entity_types,
+                        use_block_column,
+                        Asc,
+                        child_idx,
+                    ),
+                },
+                EntityOrder::ChildDescending(kind) => match kind {
+                    EntityOrderByChild::Object(child, entity_type) => with_child_object_key(
+                        block,
+                        table,
+                        layout.table_for_entity(&entity_type)?.dsl_table(),
+                        child.join_attribute,
+                        child.derived,
+                        child.sort_by_attribute,
+                        use_block_column,
+                        Desc,
+                        child_idx,
+                    ),
+                    EntityOrderByChild::Interface(child, entity_types) => with_child_interface_key(
+                        layout,
+                        block,
+                        table,
+                        child,
+                        entity_types,
+                        use_block_column,
+                        Desc,
+                        child_idx,
+                    ),
+                },
+                EntityOrder::Multi(orders) => {
+                    with_multi_key(orders, layout, block, table, filter, use_block_column)
+                }
+            }
+        }
+
+        /// Build the keys for sorting by several attributes. Since `id`
+        /// is unique, any attributes that follow it can be ignored
+        fn with_multi_key<'a>(
+            orders: Vec<EntityOrder>,
+            layout: &'a Layout,
+            block: BlockNumber,
+            table: dsl::Table<'a>,
+            filter: Option<&'a EntityFilter>,
+            use_block_column: UseBlockColumn,
+        ) -> Result<SortKey<'a>, QueryExecutionError> {
+            let mut keys = Vec::with_capacity(orders.len() + 1);
+            // Each child entity type that we sort by needs its own alias
+            let mut child_idx: u8 = 1;
+            for order in orders {
+                if matches!(
+                    order,
+                    EntityOrder::Default | EntityOrder::Unordered | EntityOrder::Multi(_)
+                ) {
+                    return Err(QueryExecutionError::InternalError(format!(
+                        "invalid order {:?} when sorting by several attributes",
+                        order
+                    )));
+                }
+                let key = with_order(
+                    order,
+                    layout,
+                    block,
+                    table,
+                    filter,
+                    use_block_column,
+                    child_idx,
+                )?;
+                let children = match &key {
+                    SortKey::Id(_, _) => {
+                        keys.push(key);
+                        return Ok(SortKey::Multi(keys));
+                    }
+                    SortKey::Key { value: Some(_), .. } => {
+                        return Err(QueryExecutionError::NotSupported(
+                            "Sorting by fulltext fields together with other attributes".to_string(),
+                        ))
+                    }
+                    SortKey::ChildKey(ChildKey::Single(_))
+                    | SortKey::ChildKey(ChildKey::Id(..)) => 1,
+                    SortKey::ChildKey(ChildKey::Many(_, children)) => children.len(),
+                    SortKey::ChildKey(ChildKey::ManyId(_, children, _)) => children.len(),
+                    SortKey::Key { .. } | SortKey::None | SortKey::Multi(_) => 0,
+                };
+                child_idx = u8::try_from(child_idx as usize + children)
+                    .ok()
+                    .filter(|idx| *idx < u8::MAX)
+                    .ok_or_else(|| {
+                        QueryExecutionError::NotSupported(
+                            "Sorting by attributes of that many child entities".to_string(),
+                        )
+                    })?;
+                keys.push(key);
+            }
+            let direction = keys
+                .last()
+                .and_then(SortKey::direction)
+                .unwrap_or(SortDirection::Asc);
+            keys.push(SortKey::Id(direction, use_block_column.block_column(table)));
+            Ok(SortKey::Multi(keys))
+        }
+
         // If there is more than one table, we are querying an interface,
         // and the order is on an attribute in that interface so that all
         // tables have a column for that. It is therefore enough to just
@@ -3663,59 +4028,41 @@ impl<'a> SortKey<'a> {
             UseBlockColumn::No
         };
 
-        use SortDirection::*;
-        match order {
-            EntityOrder::Ascending(attr, _) => with_key(table, attr, filter, Asc, use_block_column),
-            EntityOrder::Descending(attr, _) => {
-                with_key(table, attr, filter, Desc, use_block_column)
-            }
-            EntityOrder::Default => Ok(SortKey::Id(Asc, use_block_column.block_column(table))),
-            EntityOrder::Unordered => Ok(SortKey::None),
-            EntityOrder::ChildAscending(kind) => match kind {
-                EntityOrderByChild::Object(child, entity_type) => with_child_object_key(
-                    block,
-                    table,
-                    layout.table_for_entity(&entity_type)?.dsl_table(),
-                    child.join_attribute,
-                    child.derived,
-                    child.sort_by_attribute,
-                    use_block_column,
-                    Asc,
-                ),
-                EntityOrderByChild::Interface(child, entity_types) => with_child_interface_key(
-                    layout,
-                    block,
-                    table,
-                    child,
-                    entity_types,
-                    use_block_column,
-                    Asc,
-                ),
-            },
-            EntityOrder::ChildDescending(kind) => match kind {
-                EntityOrderByChild::Object(child, entity_type) => with_child_object_key(
-                    block,
-                    table,
-                    layout.table_for_entity(&entity_type)?.dsl_table(),
-                    child.join_attribute,
-                    child.derived,
-                    child.sort_by_attribute,
-                    use_block_column,
-                    Desc,
-                ),
-                EntityOrderByChild::Interface(child, entity_types) => with_child_interface_key(
-                    layout,
-                    block,
-                    table,
-                    child,
-                    entity_types,
-                    use_block_column,
-                    Desc,
-                ),
+        with_order(order, layout, block, table, filter, use_block_column, 1)
+    }
+
+    /// The direction in which this key sorts, or `None` if it does not
+    /// sort at all
+    fn direction(&self) -> Option<SortDirection> {
+        match self {
+            SortKey::None | SortKey::Multi(_) => None,
+            SortKey::Id(direction, _) => Some(*direction),
+            SortKey::Key { direction, .. } => Some(*direction),
+            SortKey::ChildKey(child) => match child {
+                ChildKey::Single(child) => Some(child.direction),
+                ChildKey::Many(_, children) => children.first().map(|child| child.direction),
+                ChildKey::Id(direction, _, _) | ChildKey::ManyId(direction, _, _) => {
+                    Some(*direction)
+                }
             },
         }
     }
 
+    /// Return `true` if sorting requires joining with child entities
+    fn has_child_key(&self) -> bool {
+        match self {
+            SortKey::ChildKey(_) => true,
+            SortKey::Multi(keys) => keys.iter().any(|key| key.has_child_key()),
+            SortKey::None | SortKey::Id(_, _) | SortKey::Key { .. } => false,
+        }
+    }
+
+    /// The alias under which the key at `idx` of a `SortKey::Multi` is
+    /// selected
+    fn multi_sort_key_column(idx: usize) -> String {
+        format!("{}{}", SORT_KEY_COLUMN, idx + 1)
+    }
+
     /// Generate selecting the sort key if it is needed
     fn select<'b>(
         &'b self,
@@ -3806,6 +4153,20 @@ impl<'a> SortKey<'a> {
                     out.push_sql(SORT_KEY_COLUMN);
                 }
             }
+            SortKey::Multi(keys) => {
+                for (idx, key) in keys.iter().enumerate() {
+                    if let SortKey::Id(_, _) = key {
+                        key.select(out, select_statement_level)?;
+                        continue;
+                    }
+                    out.push_sql(", ");
+                    if let SelectStatementLevel::InnerStatement = select_statement_level {
+                        key.multi_part_expr(out)?;
+                        out.push_sql(" as ");
+                    }
+                    out.push_sql(&SortKey::multi_sort_key_column(idx));
+                }
+            }
         }
         Ok(())
     }
@@ -3877,6 +4238,10 @@ impl<'a> SortKey<'a> {
                     }
                 }
             }
+            SortKey::Multi(keys) => {
+                out.push_sql("order by ");
+                SortKey::multi_key_expr(keys, use_sort_key_alias, true, out)
+            }
         }
     }
 
@@ -3915,6 +4280,109 @@ impl<'a> SortKey<'a> {
             SortKey::ChildKey(_) => Err(diesel::result::Error::QueryBuilderError(
                 "SortKey::ChildKey cannot be used for parent ordering (yet)".into(),
             )),
+            SortKey::Multi(keys) => {
+                if self.has_child_key() {
+                    return Err(diesel::result::Error::QueryBuilderError(
+                        "SortKey::ChildKey cannot be used for parent ordering (yet)".into(),
+                    ));
+                }
+                order_by_parent_id(out);
+                SortKey::multi_key_expr(keys, use_sort_key_alias, false, out)
+            }
+        }
+    }
+
+    /// Generate
+    ///   key1 direction1, key2 direction2, .., id direction
+    /// for the keys of a `SortKey::Multi`. The block column is only
+    /// included in the tie-breaker if `with_block_column` is `true`
+    fn multi_key_expr<'b>(
+        keys: &'b [SortKey<'b>],
+        use_sort_key_alias: bool,
+        with_block_column: bool,
+        out: &mut AstPass<'_, 'b, Pg>,
+    ) -> QueryResult<()> {
+        // When we join with child tables, columns of the parent table
+        // need to be qualified
+        let prefix = if !use_sort_key_alias && keys.iter().any(SortKey::has_child_key) {
+            "c."
+        } else {
+            ""
+        };
+        for (idx, key) in keys.iter().enumerate() {
+            if idx > 0 {
+                out.push_sql(", ");
+            }
+            match key {
+                SortKey::Id(direction, br_column) => {
+                    out.push_sql(prefix);
+                    out.push_identifier(PRIMARY_KEY_COLUMN)?;
+                    out.push_sql(direction.as_sql());
+                    match br_column {
+                        Some(br_column) if with_block_column => {
+                            out.push_sql(", ");
+                            if use_sort_key_alias {
+                                out.push_sql(SORT_KEY_COLUMN);
+                            } else {
+                                out.push_sql(prefix);
+                                out.push_sql(br_column.name());
+                            }
+                            out.push_sql(direction.as_sql());
+                        }
+                        _ => {}
+                    }
+                }
+                key => {
+                    if use_sort_key_alias {
+                        out.push_sql(&SortKey::multi_sort_key_column(idx));
+                    } else {
+                        key.multi_part_expr(out)?;
+                    }
+                    // `multi_part_expr` fails for keys without a direction
+                    out.push_sql(key.direction().unwrap_or(SortDirection::Asc).as_sql());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Generate the expression by which one of the keys of a
+    /// `SortKey::Multi` sorts, without its direction
+    fn multi_part_expr<'b>(&'b self, out: &mut AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        fn coalesce<'b>(
+            columns: impl Iterator<Item = &'b dsl::Column<'b>>,
+            out: &mut AstPass<'_, 'b, Pg>,
+        ) -> QueryResult<()> {
+            out.push_sql("coalesce(");
+            for (i, column) in columns.enumerate() {
+                if i > 0 {
+                    out.push_sql(", ");
+                }
+                column.walk_ast(out.reborrow())?;
+            }
+            out.push_sql(")");
+            Ok(())
+        }
+
+        match self {
+            SortKey::Key {
+                column,
+                value: None,
+                ..
+            } => column.walk_ast(out.reborrow()),
+            SortKey::ChildKey(child) => match child {
+                ChildKey::Single(child) => child.sort_by_column.walk_ast(out.reborrow()),
+                ChildKey::Many(_, children) => {
+                    coalesce(children.iter().map(|child| &child.sort_by_column), out)
+                }
+                ChildKey::Id(_, child, _) => child.child_pk.walk_ast(out.reborrow()),
+                ChildKey::ManyId(_, children, _) => {
+                    coalesce(children.iter().map(|child| &child.child_pk), out)
+                }
+            },
+            SortKey::None | SortKey::Id(_, _) | SortKey::Key { .. } | SortKey::Multi(_) => Err(
+                internal_error!("invalid key {} when sorting by several attributes", self),
+            ),
         }
     }
 
@@ -4147,6 +4615,11 @@ impl<'a> SortKey<'a> {
                     )?;
                 }
             },
+            SortKey::Multi(keys) => {
+                for key in keys {
+                    key.add_child(out)?;
+                }
+            }
             _ => {}
         }
         Ok(())
@@ -4169,6 +4642,12 @@ impl fmt::Display for FilterRange {
         if self.0.skip > 0 {
             write!(f, "skip {}", self.0.skip)?;
         }
+        if self.0.after.is_some() {
+            write!(f, " after cursor")?;
+        }
+        if self.0.before.is_some() {
+            write!(f, " before cursor")?;
+        }
         Ok(())
     }
 }
@@ -4188,7 +4667,189 @@ impl QueryFragment<Pg> for FilterRange {
     }
 }
 
-/// The parallel to `EntityQuery`.
+/// Restrict a query to the entities that sort after or before a cursor so
+/// that paging through a large collection does not need `offset`. The
+/// predicate agrees with `order by {column1} {direction1}, ..,
+/// id {direction}` and has to take into account that Postgres sorts nulls
+/// as if they were larger than any other value. When a query spans several
+/// tables, each of them gets its own keyset
+#[derive(Debug, Clone)]
+struct Keyset<'a> {
+    /// The table to whose rows the predicate applies
+    table: &'a Table,
+    /// The sort columns and their values in the cursor; empty if the query
+    /// is sorted by `id` only
+    keys: Vec<KeysetKey<'a>>,
+    /// `true` if we want the entities whose `id` is greater than the id in
+    /// the cursor
+    id_greater: bool,
+    id_column: dsl::Column<'a>,
+    id: QueryValue<'a>,
+}
+
+/// One of the columns in a `Keyset`
+#[derive(Debug, Clone)]
+struct KeysetKey<'a> {
+    /// `true` if we want the entities that sort after the cursor in
+    /// ascending order for this column, i.e., for `after` when sorting
+    /// ascending and for `before` when sorting descending
+    greater: bool,
+    column: dsl::Column<'a>,
+    value: QueryValue<'a>,
+}
+
+impl<'a> Keyset<'a> {
+    fn new(
+        cursor: &'a EntityCursor,
+        after: bool,
+        table: dsl::Table<'a>,
+        sort_key: &SortKey<'a>,
+    ) -> Result<Self, QueryExecutionError> {
+        let not_supported = |msg: &str| QueryExecutionError::NotSupported(format!("cursors {msg}"));
+
+        // The columns and directions of the sort key, and the direction
+        // for `id`
+        let mut columns = Vec::new();
+        let sort_keys = match sort_key {
+            SortKey::Multi(keys) => keys.as_slice(),
+            sort_key => std::slice::from_ref(sort_key),
+        };
+        let mut id_direction = None;
+        for sort_key in sort_keys {
+            match sort_key {
+                SortKey::Id(direction, _) => id_direction = Some(*direction),
+                SortKey::Key {
+                    column,
+                    value: None,
+                    direction,
+                } => {
+                    columns.push((column, *direction));
+                    id_direction = Some(*direction);
+                }
+                SortKey::Key { value: Some(_), .. } => {
+                    return Err(not_supported(
+                        "can not be used when sorting by fulltext rank",
+                    ))
+                }
+                SortKey::ChildKey(_) => {
+                    return Err(not_supported(
+                        "can not be used when sorting by child attributes",
+                    ))
+                }
+                SortKey::None | SortKey::Multi(_) => {
+                    return Err(not_supported("require a sorted collection"))
+                }
+            }
+        }
+        let id_direction =
+            id_direction.ok_or_else(|| not_supported("require a sorted collection"))?;
+
+        if columns.len() != cursor.values.len() {
+            return Err(QueryExecutionError::ValueParseError(
+                if after { "after" } else { "before" }.to_string(),
+                "the cursor does not match the sort order of the query".to_string(),
+            ));
+        }
+        let keys = columns
+            .into_iter()
+            .zip(cursor.values.iter())
+            .map(|((column, direction), value)| {
+                // The sort key refers to the column in the first table of
+                // the query; use the column of the same name in `table`
+                let column = table.column(column.name()).ok_or_else(|| {
+                    QueryExecutionError::InternalError(format!(
+                        "table {} has no column {} to sort by",
+                        table.name(),
+                        column.name()
+                    ))
+                })?;
+                let value =
+                    QueryValue::new(value, column.column_type()).map_err(StoreError::from)?;
+                Ok(KeysetKey {
+                    greater: after == matches!(direction, SortDirection::Asc),
+                    column,
+                    value,
+                })
+            })
+            .collect::<Result<Vec<_>, QueryExecutionError>>()?;
+        let id_column = table.primary_key();
+        let id = QueryValue::new(&cursor.id, id_column.column_type()).map_err(StoreError::from)?;
+        Ok(Keyset {
+            table: table.meta,
+            keys,
+            id_greater: after == matches!(id_direction, SortDirection::Asc),
+            id_column,
+            id,
+        })
+    }
+
+    /// Generate `id > $cursor_id` or `id < $cursor_id`
+    fn id_cmp<'b>(&'b self, out: &mut AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        self.id_column.walk_ast(out.reborrow())?;
+        out.push_sql(if self.id_greater { " > " } else { " < " });
+        self.id.walk_ast(out.reborrow())
+    }
+
+    /// Generate the predicate for the keys starting at `keys[0]`; the
+    /// predicate for the keys after it is nested inside of it, and the
+    /// comparison of the `id` comes last
+    fn keys_cmp<'b>(
+        &'b self,
+        keys: &'b [KeysetKey<'a>],
+        out: &mut AstPass<'_, 'b, Pg>,
+    ) -> QueryResult<()> {
+        let Some((key, rest)) = keys.split_first() else {
+            return self.id_cmp(out);
+        };
+        let KeysetKey {
+            greater,
+            column,
+            value,
+        } = key;
+
+        out.push_sql("(");
+        if value.is_null() {
+            // Only rows with a null value can sort after a null, and
+            // all rows with a non-null value sort before it
+            column.walk_ast(out.reborrow())?;
+            if *greater {
+                out.push_sql(" is null and ");
+            } else {
+                out.push_sql(" is not null or ");
+            }
+            self.keys_cmp(rest, out)?;
+        } else {
+            // (column > value or column = value and <rest>
+            //    [or column is null])
+            column.walk_ast(out.reborrow())?;
+            out.push_sql(if *greater { " > " } else { " < " });
+            value.walk_ast(out.reborrow())?;
+            out.push_sql(" or ");
+            column.walk_ast(out.reborrow())?;
+            out.push_sql(" = ");
+            value.walk_ast(out.reborrow())?;
+            out.push_sql(" and ");
+            self.keys_cmp(rest, out)?;
+            if *greater {
+                out.push_sql(" or ");
+                column.walk_ast(out.reborrow())?;
+                out.push_sql(" is null");
+            }
+        }
+        out.push_sql(")");
+        Ok(())
+    }
+}
+
+impl<'a> QueryFragment<Pg> for Keyset<'a> {
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        self.keys_cmp(&self.keys, &mut out)
+    }
+}
+
+/// The parallel to `EntityQuery`.
 ///
 /// Details of how query generation for `FilterQuery` works can be found
 /// `https://github.com/graphprotocol/rfcs/blob/master/engineering-plans/0001-graphql-query-prefetching.md`
@@ -4196,6 +4857,12 @@ impl QueryFragment<Pg> for FilterRange {
 pub struct FilterQuery<'a> {
     collection: &'a FilterCollection<'a>,
     limit: ParentLimit<'a>,
+    /// Restrict the query to entities that come after or before the
+    /// cursors in the query's range
+    keysets: Vec<Keyset<'a>>,
+    /// When set, combine the buckets of an aggregation that the query
+    /// matches with this rollup
+    rollup: Option<&'a Rollup>,
     block: BlockNumber,
     query_id: Option<String>,
     site: &'a Site,
@@ -4222,24 +4889,107 @@ impl<'a> FilterQuery<'a> {
         layout: &'a Layout,
         filter: Option<&'a EntityFilter>,
         order: EntityOrder,
-        range: EntityRange,
+        range: &'a EntityRange,
+        rollup: bool,
         block: BlockNumber,
         query_id: Option<String>,
         site: &'a Site,
     ) -> Result<Self, QueryExecutionError> {
         let sort_key = SortKey::new(order, collection, filter, layout, block)?;
-        let range = FilterRange(range);
-        let limit = ParentLimit { sort_key, range };
+        let keysets = Self::keysets_for(collection, range, &sort_key, rollup)?;
+        let limit = ParentLimit {
+            sort_key,
+            range: FilterRange(range.clone()),
+        };
+        let rollup = if rollup {
+            Some(Self::rollup_for(collection, layout, &limit.sort_key)?)
+        } else {
+            None
+        };
 
         Ok(FilterQuery {
             collection,
             limit,
+            keysets,
+            rollup,
             block,
             query_id,
             site,
         })
     }
 
+    /// Turn the `after` and `before` cursors of `range` into keyset
+    /// predicates for each table in `collection`. Cursors can only be used
+    /// at the root of a query and when the query is sorted by `id` or by a
+    /// column of the entity
+    fn keysets_for(
+        collection: &FilterCollection<'a>,
+        range: &'a EntityRange,
+        sort_key: &SortKey<'a>,
+        rollup: bool,
+    ) -> Result<Vec<Keyset<'a>>, QueryExecutionError> {
+        let cursors = [(&range.after, true), (&range.before, false)];
+        if cursors.iter().all(|(cursor, _)| cursor.is_none()) {
+            return Ok(vec![]);
+        }
+
+        let entities = match collection {
+            FilterCollection::All(entities) => entities,
+            FilterCollection::SingleWindow(_) | FilterCollection::MultiWindow(_, _) => {
+                return Err(QueryExecutionError::NotSupported(
+                    "cursors can only be used at the root of a query".to_string(),
+                ))
+            }
+        };
+        if rollup {
+            return Err(QueryExecutionError::NotSupported(
+                "cursors can not be used with rollups".to_string(),
+            ));
+        }
+        let cursors: Vec<_> = cursors
+            .into_iter()
+            .filter_map(|(cursor, after)| cursor.as_ref().map(|cursor| (cursor, after)))
+            .collect();
+        entities
+            .iter()
+            .flat_map(|wh| {
+                cursors
+                    .iter()
+                    .map(|(cursor, after)| Keyset::new(*cursor, *after, wh.table, sort_key))
+            })
+            .collect()
+    }
+
+    /// Find the rollup for a query-time rollup. That is only possible
+    /// for queries of a single aggregation at the root of a query that do
+    /// not sort by child attributes
+    fn rollup_for(
+        collection: &FilterCollection,
+        layout: &'a Layout,
+        sort_key: &SortKey,
+    ) -> Result<&'a Rollup, QueryExecutionError> {
+        let not_supported = |msg: &str| QueryExecutionError::NotSupported(format!("rollups {msg}"));
+
+        let table = match collection {
+            FilterCollection::All(entities) if entities.len() == 1 => entities[0].table.meta,
+            FilterCollection::All(_) => {
+                return Err(not_supported("can only be done for a single aggregation"))
+            }
+            FilterCollection::SingleWindow(_) | FilterCollection::MultiWindow(_, _) => {
+                return Err(not_supported("can only be done at the root of a query"))
+            }
+        };
+        if sort_key.has_child_key() {
+            return Err(not_supported("do not support sorting by child attributes"));
+        }
+        layout.rollup_for(table).ok_or_else(|| {
+            not_supported(&format!(
+                "can only be done for aggregations, but `{}` is not one",
+                table.object
+            ))
+        })
+    }
+
     /// Generate
     ///     from schema.table c
     ///    where block_range @> $block
@@ -4263,6 +5013,14 @@ impl<'a> FilterQuery<'a> {
             out.push_sql(" and ");
             filter.walk_ast(out.reborrow())?;
         }
+        for keyset in self
+            .keysets
+            .iter()
+            .filter(|keyset| keyset.table.name == wh.table.meta.name)
+        {
+            out.push_sql(" and ");
+            keyset.walk_ast(out.reborrow())?;
+        }
         out.push_sql("\n");
         Ok(())
     }
@@ -4301,6 +5059,33 @@ impl<'a> FilterQuery<'a> {
         Ok(())
     }
 
+    /// Only one aggregation, and we combine all the buckets that match the
+    /// filter into one row per combination of dimensions
+    ///
+    /// Generate a query
+    ///   select '..' as entity, to_jsonb(c.*) as data
+    ///     from (select {rolled up columns}
+    ///             from table c
+    ///            where block$ <= $block
+    ///              and filter
+    ///            group by {dimensions}) c
+    ///    order by .. limit .. skip ..
+    fn query_rollup<'b>(
+        &'b self,
+        wh: &'b WholeTable<'a>,
+        rollup: &'b Rollup,
+        out: &mut AstPass<'_, 'b, Pg>,
+    ) -> QueryResult<()> {
+        Self::select_entity_and_data(wh.table, out);
+        out.push_sql(" from (");
+        out.push_sql(rollup.rolled_up_select());
+        self.filtered_rows(wh, out)?;
+        out.push_sql(rollup.rolled_up_group_by());
+        out.push_sql(") c\n ");
+        self.limit.sort_key.order_by(out, false)?;
+        self.limit.range.walk_ast(out.reborrow())
+    }
+
     /// Only one table/filter pair, and a window
     ///
     /// Generate a query
@@ -4522,7 +5307,10 @@ impl<'a> QueryFragment<Pg> for FilterQuery<'a> {
                     let wh = entities
                         .first()
                         .expect("a query always uses at least one table");
-                    self.query_no_window_one_entity(wh, &mut out)
+                    match self.rollup {
+                        Some(rollup) => self.query_rollup(wh, rollup, &mut out),
+                        None => self.query_no_window_one_entity(wh, &mut out),
+                    }
                 } else {
                     self.query_no_window(entities, &mut out)
                 }
@@ -4547,6 +5335,555 @@ impl<'a> Query for FilterQuery<'a> {
 
 impl<'a, Conn> RunQueryDsl<Conn> for FilterQuery<'a> {}
 
+/// The name of the cursor that `DeclareCursorQuery` declares
+pub const EXPORT_CURSOR: &str = "export_cursor";
+
+/// Declares the server-side cursor `EXPORT_CURSOR` for a `FilterQuery` so
+/// that its rows can be read a page at a time with `fetch forward`. The
+/// cursor only lives until the end of the transaction that declares it
+#[derive(Debug)]
+pub struct DeclareCursorQuery<'a> {
+    query: FilterQuery<'a>,
+}
+
+impl<'a> DeclareCursorQuery<'a> {
+    pub fn new(query: FilterQuery<'a>) -> Self {
+        Self { query }
+    }
+}
+
+impl<'a> QueryFragment<Pg> for DeclareCursorQuery<'a> {
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+        out.push_sql("declare ");
+        out.push_identifier(EXPORT_CURSOR)?;
+        out.push_sql(" no scroll cursor for\n");
+        self.query.walk_ast(out)
+    }
+}
+
+impl<'a> QueryId for DeclareCursorQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for DeclareCursorQuery<'a> {}
+
+#[derive(QueryableByName, Debug)]
+pub struct AggregateData {
+    #[diesel(sql_type = Jsonb)]
+    data: serde_json::Value,
+}
+
+impl AggregateData {
+    /// Split the JSON array that `AggregateQuery` produces into the number
+    /// of entities the query looked at and the values of the aggregates,
+    /// using `types` to interpret each aggregate value
+    pub fn into_values(self, types: &[ColumnType]) -> Result<(i64, Vec<r::Value>), StoreError> {
+        let mut values = match self.data {
+            serde_json::Value::Array(values) => values,
+            data => {
+                return Err(graph::internal_error!(
+                    "aggregate query returned {} instead of an array",
+                    data
+                ))
+            }
+        };
+        if values.len() != types.len() + 1 {
+            return Err(graph::internal_error!(
+                "aggregate query returned {} values but we expected {}",
+                values.len(),
+                types.len() + 1
+            ));
+        }
+        let rest = values.split_off(1);
+        let count = values[0]
+            .as_i64()
+            .ok_or_else(|| graph::internal_error!("invalid count {}", values[0]))?;
+        let values = rest
+            .into_iter()
+            .zip(types)
+            .map(|(value, column_type)| r::Value::from_column_value(column_type, value))
+            .collect::<Result<_, _>>()?;
+        Ok((count, values))
+    }
+}
+
+/// A query that computes aggregates like `count` or `sum` over all
+/// entities in one table that match a filter. The query looks at no more
+/// than `limit` entities and always starts with the `count` of the
+/// entities it looked at so that callers can tell if it hit the limit
+///
+///   select jsonb_build_array(count(*), sum(c."x"), max(c."y"), ..) as data
+///     from (select c."id", c."x", c."y"
+///             from table c
+///            where block_range @> $block
+///              and filter
+///            limit $limit) c
+#[derive(Debug)]
+pub struct AggregateQuery<'a> {
+    wh: WholeTable<'a>,
+    aggregates: Vec<(&'static str, dsl::Column<'a>)>,
+    columns: Vec<dsl::Column<'a>>,
+    limit: i64,
+}
+
+impl<'a> AggregateQuery<'a> {
+    pub fn new(
+        layout: &'a Layout,
+        entity_type: &EntityType,
+        filter: Option<&'a EntityFilter>,
+        aggregates: &[EntityAggregate],
+        block: BlockNumber,
+        limit: usize,
+    ) -> Result<Self, QueryExecutionError> {
+        let wh = WholeTable::new(layout, entity_type, filter, AttributeNames::All, block)?;
+
+        let mut columns: Vec<dsl::Column<'a>> = vec![wh.table.primary_key()];
+        let aggregates = aggregates
+            .iter()
+            .filter_map(|agg| match agg {
+                EntityAggregate::Count => None,
+                EntityAggregate::Sum(attr) => Some(("sum", attr, true)),
+                EntityAggregate::Min(attr) => Some(("min", attr, false)),
+                EntityAggregate::Max(attr) => Some(("max", attr, false)),
+            })
+            .map(|(func, attr, sum)| {
+                let column = wh.table.column_for_field(attr)?;
+                let ok = !column.is_list()
+                    && match column.column_type() {
+                        ColumnType::Int
+                        | ColumnType::Int8
+                        | ColumnType::BigInt
+                        | ColumnType::BigDecimal => true,
+                        ColumnType::Timestamp => !sum,
+                        _ => false,
+                    };
+                if !ok {
+                    return Err(QueryExecutionError::NotSupported(format!(
+                        "can not compute {func} of attribute `{attr}` of `{entity_type}`"
+                    )));
+                }
+                if !columns.iter().any(|col| col.name() == column.name()) {
+                    columns.push(column);
+                }
+                Ok((func, column))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        Ok(AggregateQuery {
+            wh,
+            aggregates,
+            columns,
+            limit,
+        })
+    }
+
+    /// The types of the values of all aggregates other than `Count`, in
+    /// the order in which they were passed to `new`
+    pub fn value_types(&self) -> Vec<ColumnType> {
+        self.aggregates
+            .iter()
+            .map(|(func, column)| match (*func, column.column_type()) {
+                ("sum", ColumnType::BigDecimal) => ColumnType::BigDecimal,
+                ("sum", _) => ColumnType::BigInt,
+                (_, column_type) => column_type.clone(),
+            })
+            .collect()
+    }
+}
+
+impl<'a> QueryFragment<Pg> for AggregateQuery<'a> {
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        out.push_sql("select jsonb_build_array(count(*)");
+        for (func, column) in &self.aggregates {
+            out.push_sql(", ");
+            out.push_sql(func);
+            out.push_sql("(");
+            column.walk_ast(out.reborrow())?;
+            out.push_sql(")");
+        }
+        out.push_sql(") as data\n  from (select ");
+        for (i, column) in self.columns.iter().enumerate() {
+            if i > 0 {
+                out.push_sql(", ");
+            }
+            column.walk_ast(out.reborrow())?;
+        }
+        out.push_sql("\n          from ");
+        self.wh.from_table.walk_ast(out.reborrow())?;
+        out.push_sql("\n         where ");
+        self.wh.at_block.walk_ast(out.reborrow())?;
+        if let Some(filter) = &self.wh.filter {
+            out.push_sql(" and ");
+            filter.walk_ast(out.reborrow())?;
+        }
+        out.push_sql("\n         limit ");
+        out.push_bind_param::<BigInt, _>(&self.limit)?;
+        out.push_sql(") c");
+        Ok(())
+    }
+}
+
+impl<'a> QueryId for AggregateQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> Query for AggregateQuery<'a> {
+    type SqlType = Untyped;
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for AggregateQuery<'a> {}
+
+#[derive(QueryableByName, Debug)]
+pub struct EntityVersionData {
+    #[diesel(sql_type = Text)]
+    pub entity: String,
+    #[diesel(sql_type = Jsonb)]
+    pub data: serde_json::Value,
+    /// The version that this version replaced, `None` if this version
+    /// created the entity
+    #[diesel(sql_type = Nullable<Jsonb>)]
+    pub prev: Option<serde_json::Value>,
+    #[diesel(sql_type = Integer)]
+    pub from_block: BlockNumber,
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub to_block: Option<BlockNumber>,
+}
+
+/// A query that lists the versions of one entity that were current at
+/// some block between `from` and `to`, together with the version each of
+/// them replaced so that callers can tell what changed
+///
+///   select $entity as entity, v.data, v.prev, v.from_block, v.to_block
+///     from (select to_jsonb(e.*) as data,
+///                  case when lag(upper(e.block_range)) over w = lower(e.block_range)
+///                       then lag(to_jsonb(e.*)) over w end as prev,
+///                  lower(e.block_range) as from_block,
+///                  upper(e.block_range) as to_block
+///             from table e
+///            where e.id = $id
+///           window w as (order by lower(e.block_range))) v
+///    where v.from_block <= $to
+///      and coalesce(v.to_block, 2147483647) > $from
+///    order by v.from_block
+///    limit $first
+///
+/// Immutable entities only ever have one version, written at `block$`
+#[derive(Debug)]
+pub struct EntityHistoryQuery<'a> {
+    table: &'a Table,
+    id: &'a Id,
+    from: BlockNumber,
+    to: BlockNumber,
+    first: i64,
+}
+
+impl<'a> EntityHistoryQuery<'a> {
+    pub fn new(
+        table: &'a Table,
+        id: &'a Id,
+        from: BlockNumber,
+        to: BlockNumber,
+        first: usize,
+    ) -> Self {
+        let first = i64::try_from(first).unwrap_or(i64::MAX);
+        Self {
+            table,
+            id,
+            from,
+            to,
+            first,
+        }
+    }
+}
+
+impl<'a> QueryFragment<Pg> for EntityHistoryQuery<'a> {
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        out.push_sql("select ");
+        out.push_bind_param::<Text, _>(self.table.object.as_str())?;
+        if self.table.immutable {
+            out.push_sql(" as entity, to_jsonb(e.*) as data, null::jsonb as prev, e.");
+            out.push_sql(BLOCK_COLUMN);
+            out.push_sql(" as from_block, null::int4 as to_block\n  from ");
+            out.push_sql(self.table.qualified_name.as_str());
+            out.push_sql(" e\n where e.");
+            out.push_identifier(&self.table.primary_key().name)?;
+            out.push_sql(" = ");
+            self.id.push_bind_param(&mut out)?;
+            out.push_sql(" and e.");
+            out.push_sql(BLOCK_COLUMN);
+            out.push_sql(" <= ");
+            out.push_bind_param::<Integer, _>(&self.to)?;
+        } else {
+            out.push_sql(" as entity, v.data, v.prev, v.from_block, v.to_block\n");
+            out.push_sql("  from (select to_jsonb(e.*) as data,\n");
+            out.push_sql("               case when lag(upper(e.");
+            out.push_sql(BLOCK_RANGE_COLUMN);
+            out.push_sql(")) over w = lower(e.");
+            out.push_sql(BLOCK_RANGE_COLUMN);
+            out.push_sql(")\n                    then lag(to_jsonb(e.*)) over w end as prev,\n");
+            out.push_sql("               lower(e.");
+            out.push_sql(BLOCK_RANGE_COLUMN);
+            out.push_sql(") as from_block,\n               upper(e.");
+            out.push_sql(BLOCK_RANGE_COLUMN);
+            out.push_sql(") as to_block\n          from ");
+            out.push_sql(self.table.qualified_name.as_str());
+            out.push_sql(" e\n         where e.");
+            out.push_identifier(&self.table.primary_key().name)?;
+            out.push_sql(" = ");
+            self.id.push_bind_param(&mut out)?;
+            out.push_sql("\n        window w as (order by lower(e.");
+            out.push_sql(BLOCK_RANGE_COLUMN);
+            out.push_sql("))) v\n where v.from_block <= ");
+            out.push_bind_param::<Integer, _>(&self.to)?;
+            out.push_sql("\n   and coalesce(v.to_block, 2147483647) > ");
+            out.push_bind_param::<Integer, _>(&self.from)?;
+            out.push_sql("\n order by v.from_block");
+        }
+        out.push_sql("\n limit ");
+        out.push_bind_param::<BigInt, _>(&self.first)
+    }
+}
+
+impl<'a> QueryId for EntityHistoryQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> Query for EntityHistoryQuery<'a> {
+    type SqlType = Untyped;
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for EntityHistoryQuery<'a> {}
+
+#[derive(QueryableByName, Debug)]
+pub struct EntityDiffData {
+    #[diesel(sql_type = Text)]
+    pub entity: String,
+    /// The id of the entity, cast to text
+    #[diesel(sql_type = Text)]
+    pub id: String,
+    /// One of `insert`, `update` or `delete`
+    #[diesel(sql_type = Text)]
+    pub operation: String,
+    #[diesel(sql_type = Integer)]
+    pub block: BlockNumber,
+    #[diesel(sql_type = BigInt)]
+    pub vid: i64,
+    #[diesel(sql_type = Nullable<Jsonb>)]
+    pub data: Option<serde_json::Value>,
+}
+
+/// A query that lists the changes to the entities in `table` at blocks
+/// between `from` and `to`, ordered by `(block, vid)` and starting after
+/// the change `after`. A version that starts in the range is an insert,
+/// unless another version of the entity ends where it starts, in which
+/// case it is an update; a version that ends in the range without a
+/// version that starts where it ends is a delete
+///
+///   select c.*
+///     from (select $entity as entity, e.id::text as id,
+///                  case when exists (select 1 from table v
+///                                     where v.id = e.id
+///                                       and upper(v.block_range) = lower(e.block_range))
+///                       then 'update' else 'insert' end as operation,
+///                  lower(e.block_range) as block, e.vid, to_jsonb(e.*) as data
+///             from table e
+///            where lower(e.block_range) between $from and $to
+///           union all
+///           select $entity as entity, e.id::text as id, 'delete' as operation,
+///                  upper(e.block_range) as block, e.vid, to_jsonb(e.*) as data
+///             from table e
+///            where coalesce(upper(e.block_range), 2147483647) between $from and $to
+///              and upper(e.block_range) is not null
+///              and not exists (select 1 from table v
+///                               where v.id = e.id
+///                                 and lower(v.block_range) = upper(e.block_range))) c
+///    where (c.block, c.vid) > ($after_block, $after_vid)
+///    order by c.block, c.vid
+///    limit $first
+///
+/// Versions are matched within the causality region of the entity. When
+/// `with_data` is `false`, `data` is always null. Immutable entities can
+/// only be inserted, at `block$`
+#[derive(Debug)]
+pub struct EntityDiffQuery<'a> {
+    table: &'a Table,
+    from: BlockNumber,
+    to: BlockNumber,
+    after: Option<EntityDiffCursor>,
+    first: i64,
+    with_data: bool,
+}
+
+impl<'a> EntityDiffQuery<'a> {
+    pub fn new(
+        table: &'a Table,
+        blocks: RangeInclusive<BlockNumber>,
+        after: Option<EntityDiffCursor>,
+        first: usize,
+        with_data: bool,
+    ) -> Self {
+        let first = i64::try_from(first).unwrap_or(i64::MAX);
+        Self {
+            table,
+            from: *blocks.start(),
+            to: *blocks.end(),
+            after,
+            first,
+            with_data,
+        }
+    }
+
+    /// Generate `e.id::text as id`
+    fn id(&self, out: &mut AstPass<'_, '_, Pg>) -> QueryResult<()> {
+        out.push_sql("e.");
+        out.push_identifier(&self.table.primary_key().name)?;
+        out.push_sql("::text as id, ");
+        Ok(())
+    }
+
+    /// Generate `to_jsonb(e.*) as data` or `null::jsonb as data`
+    fn data(&self, out: &mut AstPass<'_, '_, Pg>) {
+        if self.with_data {
+            out.push_sql("to_jsonb(e.*) as data");
+        } else {
+            out.push_sql("null::jsonb as data");
+        }
+    }
+
+    /// Generate `between $from and $to`
+    fn between<'b>(&'b self, out: &mut AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        out.push_sql(" between ");
+        out.push_bind_param::<Integer, _>(&self.from)?;
+        out.push_sql(" and ");
+        out.push_bind_param::<Integer, _>(&self.to)
+    }
+
+    /// Generate a subquery that checks whether there is another version
+    /// `v` of the entity whose `v_bound` block equals the `e_bound` block
+    /// of `e`
+    fn other_version(&self, out: &mut AstPass<'_, '_, Pg>, v_bound: &str, e_bound: &str) {
+        out.push_sql("(select 1 from ");
+        out.push_sql(self.table.qualified_name.as_str());
+        out.push_sql(" v\n                     where v.");
+        out.push_sql(self.table.primary_key().name.quoted().as_str());
+        out.push_sql(" = e.");
+        out.push_sql(self.table.primary_key().name.quoted().as_str());
+        if self.table.has_causality_region {
+            out.push_sql(" and v.");
+            out.push_sql(CAUSALITY_REGION_COLUMN);
+            out.push_sql(" = e.");
+            out.push_sql(CAUSALITY_REGION_COLUMN);
+        }
+        out.push_sql("\n                       and ");
+        out.push_sql(v_bound);
+        out.push_sql("(v.");
+        out.push_sql(BLOCK_RANGE_COLUMN);
+        out.push_sql(") = ");
+        out.push_sql(e_bound);
+        out.push_sql("(e.");
+        out.push_sql(BLOCK_RANGE_COLUMN);
+        out.push_sql("))");
+    }
+}
+
+impl<'a> QueryFragment<Pg> for EntityDiffQuery<'a> {
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        out.unsafe_to_cache_prepared();
+
+        out.push_sql("select c.*\n  from (");
+        if self.table.immutable {
+            out.push_sql("select ");
+            out.push_bind_param::<Text, _>(self.table.object.as_str())?;
+            out.push_sql(" as entity, ");
+            self.id(&mut out)?;
+            out.push_sql("'insert' as operation,\n               e.");
+            out.push_sql(BLOCK_COLUMN);
+            out.push_sql(" as block, e.vid, ");
+            self.data(&mut out);
+            out.push_sql("\n          from ");
+            out.push_sql(self.table.qualified_name.as_str());
+            out.push_sql(" e\n         where e.");
+            out.push_sql(BLOCK_COLUMN);
+            self.between(&mut out)?;
+        } else {
+            out.push_sql("select ");
+            out.push_bind_param::<Text, _>(self.table.object.as_str())?;
+            out.push_sql(" as entity, ");
+            self.id(&mut out)?;
+            out.push_sql("\n               case when exists ");
+            self.other_version(&mut out, "upper", "lower");
+            out.push_sql("\n                    then 'update' else 'insert' end as operation,\n");
+            out.push_sql("               lower(e.");
+            out.push_sql(BLOCK_RANGE_COLUMN);
+            out.push_sql(") as block, e.vid, ");
+            self.data(&mut out);
+            out.push_sql("\n          from ");
+            out.push_sql(self.table.qualified_name.as_str());
+            out.push_sql(" e\n         where lower(e.");
+            out.push_sql(BLOCK_RANGE_COLUMN);
+            out.push_sql(")");
+            self.between(&mut out)?;
+            out.push_sql("\n        union all\n        select ");
+            out.push_bind_param::<Text, _>(self.table.object.as_str())?;
+            out.push_sql(" as entity, ");
+            self.id(&mut out)?;
+            out.push_sql("'delete' as operation,\n               upper(e.");
+            out.push_sql(BLOCK_RANGE_COLUMN);
+            out.push_sql(") as block, e.vid, ");
+            self.data(&mut out);
+            out.push_sql("\n          from ");
+            out.push_sql(self.table.qualified_name.as_str());
+            // Written like the expression in the BRIN index on block
+            // ranges so that the index can be used
+            out.push_sql(" e\n         where coalesce(upper(e.");
+            out.push_sql(BLOCK_RANGE_COLUMN);
+            out.push_sql("), 2147483647)");
+            self.between(&mut out)?;
+            // Current versions have not been deleted, even if `$to` is
+            // the largest block number
+            out.push_sql("\n           and upper(e.");
+            out.push_sql(BLOCK_RANGE_COLUMN);
+            out.push_sql(") is not null");
+            out.push_sql("\n           and not exists ");
+            self.other_version(&mut out, "lower", "upper");
+        }
+        out.push_sql(") c\n");
+        if let Some(after) = &self.after {
+            out.push_sql(" where (c.block, c.vid) > (");
+            out.push_bind_param::<Integer, _>(&after.block)?;
+            out.push_sql(", ");
+            out.push_bind_param::<BigInt, _>(&after.vid)?;
+            out.push_sql(")\n");
+        }
+        out.push_sql(" order by c.block, c.vid\n limit ");
+        out.push_bind_param::<BigInt, _>(&self.first)
+    }
+}
+
+impl<'a> QueryId for EntityDiffQuery<'a> {
+    type QueryId = ();
+
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a> Query for EntityDiffQuery<'a> {
+    type SqlType = Untyped;
+}
+
+impl<'a, Conn> RunQueryDsl<Conn> for EntityDiffQuery<'a> {}
+
 /// Reduce the upper bound of the current entry's block range to `block` as
 /// long as that does not result in an empty block range
 #[derive(Debug)]
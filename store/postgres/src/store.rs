@@ -1,23 +1,23 @@
 use async_trait::async_trait;
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use graph::{
     components::{
         server::index_node::VersionInfo,
         store::{
-            BlockPtrForNumber, BlockStore as BlockStoreTrait, QueryPermit, QueryStoreManager,
-            StatusStore, Store as StoreTrait,
+            BlockPtrForNumber, BlockStore as BlockStoreTrait, DeploymentLocator, QueryPermit,
+            QueryStoreManager, StatusStore, Store as StoreTrait,
         },
     },
-    data::subgraph::status,
+    data::{query::QueryTarget, subgraph::status},
     internal_error,
     prelude::{
-        web3::types::Address, BlockNumber, BlockPtr, CheapClone, DeploymentHash, PartialBlockPtr,
-        QueryExecutionError, StoreError,
+        web3::types::Address, ApiVersion, BlockNumber, BlockPtr, CheapClone, DeploymentHash,
+        PartialBlockPtr, QueryExecutionError, StoreError,
     },
 };
 
-use crate::{block_store::BlockStore, query_store::QueryStore, SubgraphStore};
+use crate::{block_store::BlockStore, query_store::QueryStore, relational::prune, SubgraphStore};
 
 /// The overall store of the system, consisting of a [`SubgraphStore`] and a
 /// [`BlockStore`], each of which multiplex across multiple database shards.
@@ -37,6 +37,7 @@ pub struct Store {
 
 impl Store {
     pub fn new(subgraph_store: Arc<SubgraphStore>, block_store: Arc<BlockStore>) -> Self {
+        subgraph_store.set_block_store(block_store.cheap_clone());
         Self {
             subgraph_store,
             block_store,
@@ -50,6 +51,20 @@ impl Store {
     pub fn block_store(&self) -> Arc<BlockStore> {
         self.block_store.cheap_clone()
     }
+
+    /// Resolve how many blocks of history `deployment` needs to keep to
+    /// retain `duration` worth of history, going by the timestamps of the
+    /// blocks of its chain and counting back from its latest block. Return
+    /// `None` if the deployment does not have that much history yet
+    pub async fn history_blocks_for_duration(
+        &self,
+        deployment: &DeploymentLocator,
+        duration: Duration,
+    ) -> Result<Option<BlockNumber>, StoreError> {
+        let target = QueryTarget::Deployment(deployment.hash.clone(), ApiVersion::default());
+        let store = self.query_store(target).await?;
+        prune::history_blocks_for_duration(store.as_ref(), duration).await
+    }
 }
 
 impl StoreTrait for Store {
@@ -102,6 +117,19 @@ impl QueryStoreManager for Store {
             Arc::new(api_version.clone()),
         )))
     }
+
+    async fn resolve_target(
+        &self,
+        target: QueryTarget,
+    ) -> Result<(DeploymentHash, Vec<String>), QueryExecutionError> {
+        let store = self.subgraph_store.cheap_clone();
+        graph::spawn_blocking_allow_panic(move || {
+            store.resolve_target(target).map_err(|e| e.into())
+        })
+        .await
+        .map_err(|e| QueryExecutionError::Panic(e.to_string()))
+        .and_then(|x| x)
+    }
 }
 
 #[async_trait]
@@ -7,9 +7,9 @@ use diesel::{
 use std::fmt;
 use std::{
     collections::{BTreeMap, HashMap},
-    sync::{atomic::AtomicU8, Arc, Mutex},
+    sync::{atomic::AtomicU8, Arc, Mutex, OnceLock},
 };
-use std::{iter::FromIterator, time::Duration};
+use std::{iter::FromIterator, ops::RangeInclusive, time::Duration};
 
 use graph::futures03::future::join_all;
 use graph::{
@@ -18,7 +18,7 @@ use graph::{
         server::index_node::VersionInfo,
         store::{
             self, BlockPtrForNumber, BlockStore, DeploymentLocator, EnsLookup as EnsLookupTrait,
-            PruneReporter, PruneRequest, SubgraphFork,
+            EntityDiff, EntityDiffCursor, PruneReporter, PruneRequest, SubgraphFork,
         },
     },
     data::query::QueryTarget,
@@ -41,6 +41,7 @@ use crate::{
     primary::{self, DeploymentId, Mirror as PrimaryMirror, Primary, Site},
     relational::{
         self,
+        advisor::{Advice, AdviceOptions},
         index::{IndexList, Method},
         Layout,
     },
@@ -336,6 +337,9 @@ pub struct SubgraphStoreInner {
     sender: Arc<NotificationSender>,
     writables: Mutex<HashMap<DeploymentId, Arc<WritableStore>>>,
     registry: Arc<MetricsRegistry>,
+    /// The block store is created after the subgraph store and set by
+    /// `Store::new`. Pruning needs it to look up block timestamps
+    block_store: OnceLock<Arc<crate::BlockStore>>,
 }
 
 impl SubgraphStoreInner {
@@ -399,9 +403,22 @@ impl SubgraphStoreInner {
             sender,
             writables: Mutex::new(HashMap::new()),
             registry,
+            block_store: OnceLock::new(),
         }
     }
 
+    pub(crate) fn set_block_store(&self, block_store: Arc<crate::BlockStore>) {
+        // A store is only ever combined with one block store
+        let _ = self.block_store.set(block_store);
+    }
+
+    /// The chain store for `network`, once the block store is known
+    pub(crate) fn chain_store(&self, network: &str) -> Option<Arc<crate::ChainStore>> {
+        self.block_store
+            .get()
+            .and_then(|block_store| block_store.chain_store(network))
+    }
+
     // Only needed for tests
     #[cfg(debug_assertions)]
     pub(crate) fn clear_caches(&self) {
@@ -822,6 +839,25 @@ impl SubgraphStoreInner {
         Ok((store.clone(), site, replica))
     }
 
+    /// The deployment that `target` refers to, and the names of all
+    /// subgraphs whose current or pending version is that deployment
+    pub(crate) fn resolve_target(
+        &self,
+        target: QueryTarget,
+    ) -> Result<(DeploymentHash, Vec<String>), StoreError> {
+        let id = match target {
+            QueryTarget::Name(name, _) => self.mirror.current_deployment_for_subgraph(&name)?,
+            QueryTarget::Deployment(id, _) => id,
+        };
+        let names = self
+            .mirror
+            .subgraphs_by_deployment_hash(id.as_str())?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        Ok((id, names))
+    }
+
     /// Delete all entities. This function exists solely for integration tests
     /// and should never be called from any other code. Unfortunately, Rust makes
     /// it very hard to export items just for testing
@@ -1201,6 +1237,50 @@ impl SubgraphStoreInner {
             .await
     }
 
+    pub async fn advise_indexes(
+        &self,
+        deployment: &DeploymentLocator,
+        opts: AdviceOptions,
+        create: bool,
+    ) -> Result<Advice, StoreError> {
+        let (store, site) = self.store(&deployment.hash)?;
+        store.advise_indexes(site, opts, create).await
+    }
+
+    /// Stop recommending the indexes `names` for `deployment`, for example
+    /// because an operator dropped them after they were created
+    /// automatically. With `exclude` set to `false`, recommend them again
+    pub fn exclude_index_advice(
+        &self,
+        deployment: &DeploymentLocator,
+        names: &[String],
+        exclude: bool,
+    ) -> Result<(), StoreError> {
+        let (store, site) = self.store(&deployment.hash)?;
+        store.exclude_index_advice(&site, names, exclude)
+    }
+
+    /// Write the query shapes that the index advisor sampled in each
+    /// shard to the database
+    pub fn write_workload(&self) -> Result<(), StoreError> {
+        for store in self.stores.values() {
+            store.write_workload()?;
+        }
+        Ok(())
+    }
+
+    /// The deployments for which the index advisor has sampled queries
+    pub fn advised_deployments(&self) -> Result<Vec<DeploymentLocator>, StoreError> {
+        let mut deployments = Vec::new();
+        for store in self.stores.values() {
+            for id in store.advised_deployments()? {
+                let site = self.find_site(id)?;
+                deployments.push(site.as_ref().into());
+            }
+        }
+        Ok(deployments)
+    }
+
     pub async fn indexes_for_entity(
         &self,
         deployment: &DeploymentLocator,
@@ -1250,6 +1330,20 @@ impl SubgraphStoreInner {
         store.prune(reporter, site, req).await
     }
 
+    /// Recompute the aggregation `name` in `deployment` from the data in
+    /// its source timeseries, replacing any data the aggregation already
+    /// has. Returns the number of rows that were inserted
+    pub async fn backfill_aggregation(
+        &self,
+        deployment: &DeploymentLocator,
+        name: &str,
+    ) -> Result<usize, StoreError> {
+        let site = self.find_site(deployment.id.into())?;
+        let store = self.for_site(&site)?;
+
+        store.backfill_aggregation(site, name).await
+    }
+
     pub async fn prune_viewer(
         &self,
         deployment: &DeploymentLocator,
@@ -1272,6 +1366,46 @@ impl SubgraphStoreInner {
         store.set_history_blocks(&site, history_blocks, reorg_threshold)
     }
 
+    /// Change the schema of `deployment` to `schema` by adding entity
+    /// types, nullable attributes, fulltext fields and enum values to it.
+    /// Returns the DDL for the change; with `dry_run`, nothing is changed.
+    ///
+    /// The deployment must not be indexing while its schema is patched so
+    /// that indexing picks up the patched schema when it is resumed
+    pub fn patch_schema(
+        &self,
+        deployment: &DeploymentLocator,
+        schema: &str,
+        dry_run: bool,
+    ) -> Result<String, StoreError> {
+        let site = self.find_site(deployment.id.into())?;
+        if !dry_run {
+            if let Some((node, false)) = self.mirror.assignment_status(site.as_ref())? {
+                return Err(StoreError::Unknown(anyhow!(
+                    "deployment {} is being indexed by {}; pause or unassign it before patching its schema",
+                    deployment,
+                    node
+                )));
+            }
+        }
+        let store = self.for_site(&site)?;
+
+        store.patch_schema(site, schema, dry_run)
+    }
+
+    /// Keep `history_seconds` worth of history for `deployment`, or, when
+    /// it is `None`, stop deriving `history_blocks` from a duration
+    pub fn set_history_seconds(
+        &self,
+        deployment: &DeploymentLocator,
+        history_seconds: Option<i32>,
+    ) -> Result<(), StoreError> {
+        let site = self.find_site(deployment.id.into())?;
+        let store = self.for_site(&site)?;
+
+        store.set_history_seconds(&site, history_seconds)
+    }
+
     pub fn load_deployment(&self, site: Arc<Site>) -> Result<SubgraphDeploymentEntity, StoreError> {
         let src_store = self.for_site(&site)?;
         src_store.load_deployment(site)
@@ -1504,6 +1638,19 @@ impl SubgraphStoreTrait for SubgraphStore {
         Ok(changes)
     }
 
+    fn entity_diff(
+        &self,
+        subgraph_id: &DeploymentHash,
+        entity_type: &str,
+        blocks: RangeInclusive<BlockNumber>,
+        after: Option<EntityDiffCursor>,
+        first: usize,
+        with_data: bool,
+    ) -> Result<Vec<EntityDiff>, StoreError> {
+        let (store, site) = self.store(subgraph_id)?;
+        store.get_entity_diff(site, entity_type, blocks, after, first, with_data)
+    }
+
     fn input_schema(&self, id: &DeploymentHash) -> Result<InputSchema, StoreError> {
         let (store, site) = self.store(id)?;
         let layout = store.find_layout(site)?;
@@ -160,6 +160,9 @@ struct SyncStore {
     input_schema: InputSchema,
     manifest_idx_and_name: Arc<Vec<(u32, String)>>,
     last_rollup: LastRollupTracker,
+    /// The store for the chain of the deployment, used to resolve history
+    /// retention given as a duration when pruning
+    chain_store: Option<Arc<crate::ChainStore>>,
 }
 
 impl SyncStore {
@@ -179,6 +182,7 @@ impl SyncStore {
             input_schema.has_aggregations(),
             block,
         )?;
+        let chain_store = subgraph_store.chain_store(&site.network);
 
         Ok(Self {
             logger,
@@ -188,6 +192,7 @@ impl SyncStore {
             input_schema,
             manifest_idx_and_name,
             last_rollup,
+            chain_store,
         })
     }
 }
@@ -298,6 +303,7 @@ impl SyncStore {
                 self.last_rollup.get(),
                 stopwatch,
                 &self.manifest_idx_and_name,
+                self.chain_store.clone(),
             )?;
             // unwrap: batch.block_times is never empty
             let last_block_time = batch.block_times.last().unwrap().1;
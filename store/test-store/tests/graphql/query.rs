@@ -19,18 +19,19 @@ use test_store::block_store::{
 };
 
 use graph::{
-    components::store::DeploymentLocator,
+    components::store::{DeploymentLocator, EntityCursor},
     data::graphql::{object, object_value},
     data::subgraph::schema::SubgraphError,
     data::{
-        query::{QueryResults, QueryTarget},
+        query::{QueryCost, QueryResults, QueryTarget},
         subgraph::SubgraphFeature,
     },
+    env::MaxCost,
     prelude::{
-        lazy_static, q, r, serde_json, BlockPtr, DeploymentHash, Entity, EntityOperation,
-        GraphQlRunner as _, NodeId, Query, QueryError, QueryExecutionError, QueryResult,
-        QueryVariables, SubgraphManifest, SubgraphName, SubgraphStore,
-        SubgraphVersionSwitchingMode,
+        lazy_static, q, r, serde_json, web3::types::U256, BlockNumber, BlockPtr, DeploymentHash,
+        Entity, EntityOperation, GraphQlRunner as _, NodeId, Query, QueryError,
+        QueryExecutionError, QueryResult, QueryVariables, SubgraphManifest, SubgraphName,
+        SubgraphStore, SubgraphVersionSwitchingMode,
     },
 };
 use graph_graphql::prelude::*;
@@ -52,8 +53,15 @@ const MEDIA_INT: [&str; 7] = ["52", "53", "54", "55", "56", "57", "58"];
 lazy_static! {
     /// The id of the sole publisher in the test data
     static ref PUB1: IdVal = IdType::Bytes.parse("0xb1");
-    /// The chain we actually put into the chain store, blocks 0 to 3
-    static ref CHAIN: Vec<FakeBlock> = vec![GENESIS_BLOCK.clone(), BLOCK_ONE.clone(), BLOCK_TWO.clone(), BLOCK_THREE.clone()];
+    /// The chain we actually put into the chain store, blocks 0 to 3. Block
+    /// `n` has timestamp `(n + 1) * 1000`
+    static ref CHAIN: Vec<FakeBlock> = [&*GENESIS_BLOCK, &*BLOCK_ONE, &*BLOCK_TWO, &*BLOCK_THREE]
+        .into_iter()
+        .map(|block| FakeBlock {
+            timestamp: Some(U256::from((block.number as u64 + 1) * 1000)),
+            ..block.clone()
+        })
+        .collect();
     /// The known block pointers for blocks 0 to 3 from the chain plus a
     /// nonexistent block 4
     static ref BLOCKS: Vec<BlockPtr> = CHAIN.iter().map(|b| b.ptr().clone()).chain(Some(BLOCK_FOUR.ptr().clone())).collect();
@@ -1817,6 +1825,122 @@ fn query_complexity() {
     })
 }
 
+/// Run `query` against the test deployment with a runner that limits
+/// the cost of queries to `max_cost`
+async fn run_with_max_cost(
+    deployment: &DeploymentLocator,
+    query: &str,
+    max_cost: MaxCost,
+    dry_run: bool,
+) -> QueryResults {
+    let runner = Arc::new(
+        GraphQlRunner::new(
+            &LOGGER,
+            STORE.clone(),
+            LOAD_MANAGER.clone(),
+            METRICS_REGISTRY.clone(),
+        )
+        .with_max_cost(max_cost),
+    );
+    let query = q::parse_query(query).unwrap().into_static();
+    let query = Query::new(query, None, false).with_dry_run(dry_run);
+    let target = QueryTarget::Deployment(deployment.hash.clone(), Default::default());
+    runner.run_query(query, target).await
+}
+
+#[test]
+fn query_cost_limits() {
+    // A lookup of one musician and their main band costs 2
+    const QUERY: &str = "query { musician(id: \"m1\") { mainBand { id } } }";
+
+    run_test_sequentially(|store| async move {
+        let id_type = IdType::String;
+        let deployment = setup(
+            store.as_ref(),
+            id_type.deployment_id(),
+            BTreeSet::new(),
+            id_type,
+        )
+        .await;
+        let overrides = |limit: u64| HashMap::from([(deployment.hash.to_string(), limit)]);
+        let too_costly = |result: &QueryResults| {
+            matches!(
+                result.errors().as_slice(),
+                [QueryError::ExecutionError(
+                    QueryExecutionError::CostTooHigh(2, _)
+                )]
+            )
+        };
+
+        // `GRAPH_GRAPHQL_MAX_COST` rejects the query before running it
+        let result = run_with_max_cost(
+            &deployment,
+            QUERY,
+            MaxCost::new(Some(1), HashMap::new()),
+            false,
+        )
+        .await;
+        assert!(too_costly(&result));
+        assert_eq!(
+            Some(QueryCost {
+                estimated: 2,
+                limit: Some(1),
+                dry_run: false
+            }),
+            result.cost
+        );
+
+        // The limit for this subgraph overrides the general one in both
+        // directions
+        let result = run_with_max_cost(
+            &deployment,
+            QUERY,
+            MaxCost::new(Some(1), overrides(2)),
+            false,
+        )
+        .await;
+        let data = extract_data!(result.first().unwrap().duplicate()).unwrap();
+        assert_eq!(
+            object! { musician: object! { mainBand: object! { id: "b1" } } },
+            data
+        );
+        assert_eq!(Some(2), result.cost.and_then(|cost| cost.limit));
+
+        let result = run_with_max_cost(
+            &deployment,
+            QUERY,
+            MaxCost::new(Some(100), overrides(1)),
+            false,
+        )
+        .await;
+        assert!(too_costly(&result));
+
+        // A dry run returns the estimate without running the query, even
+        // if there is no limit
+        let result = run_with_max_cost(&deployment, QUERY, MaxCost::default(), true).await;
+        assert!(!result.has_errors());
+        assert!(result.first().is_none());
+        assert_eq!(
+            Some(QueryCost {
+                estimated: 2,
+                limit: None,
+                dry_run: true
+            }),
+            result.cost
+        );
+
+        // A dry run of a query that is too expensive reports that
+        let result = run_with_max_cost(
+            &deployment,
+            QUERY,
+            MaxCost::new(Some(1), HashMap::new()),
+            true,
+        )
+        .await;
+        assert!(too_costly(&result));
+    })
+}
+
 #[test]
 fn instant_timeout() {
     run_test_sequentially(|store| async move {
@@ -2386,6 +2510,55 @@ fn query_at_block_with_vars() {
     musicians_at_hash(&BLOCKS[4], Err(BLOCK_HASH_NOT_FOUND), "h4");
 }
 
+#[test]
+fn query_at_timestamp() {
+    fn musicians_at_ts(timestamp: i32, expected: Expected, qid: &'static str) {
+        let query = "query by_ts($ts: Int!) { musicians(block: { timestamp_lte: $ts }) { id } }";
+        let var = object! { ts: timestamp };
+
+        check_musicians_at(query, var, expected, qid);
+    }
+
+    /// Put `CHAIN` into the chain store, leaving out block `missing` to
+    /// simulate a chain store that does not have all blocks
+    fn set_chain(missing: Option<BlockNumber>) {
+        run_test_sequentially(move |_| async move {
+            let chain = CHAIN
+                .iter()
+                .filter(|block| Some(block.number) != missing)
+                .collect();
+            test_store::block_store::set_chain(chain, NETWORK_NAME).await;
+        });
+    }
+
+    const NO_BLOCK: &str = "has no block at or before timestamp 999";
+    const NO_TIMESTAMPS: &str =
+        "the timestamps of blocks before block 1 of network fake_network are not available";
+
+    // The subgraph has indexed blocks 0 to 2 with timestamps 1000 to 3000
+    musicians_at_ts(999, Err(NO_BLOCK), "ts999");
+    musicians_at_ts(1000, Ok(vec!["m1", "m2"]), "ts1000");
+    musicians_at_ts(1500, Ok(vec!["m1", "m2"]), "ts1500");
+    musicians_at_ts(2000, Ok(vec!["m1", "m2", "m3", "m4"]), "ts2000");
+    musicians_at_ts(2999, Ok(vec!["m1", "m2", "m3", "m4"]), "ts2999");
+    musicians_at_ts(
+        1_700_000_000,
+        Ok(vec!["m1", "m2", "m3", "m4"]),
+        "ts1700000000",
+    );
+
+    // Without block 1, we settle for the latest block before it
+    set_chain(Some(1));
+    musicians_at_ts(2500, Ok(vec!["m1", "m2"]), "ts2500-no1");
+
+    // Without block 0, we can't tell which block comes before block 1
+    set_chain(Some(0));
+    musicians_at_ts(1500, Err(NO_TIMESTAMPS), "ts1500-no0");
+    musicians_at_ts(2500, Ok(vec!["m1", "m2", "m3", "m4"]), "ts2500-no0");
+
+    set_chain(None);
+}
+
 #[test]
 fn query_detects_reorg() {
     async fn query_at(deployment: &DeploymentLocator, block: i32) -> QueryResult {
@@ -2741,6 +2914,51 @@ fn deterministic_error() {
     })
 }
 
+#[test]
+fn before_cursor_returns_previous_page() {
+    const QUERY: &str = "
+    query($before: String) {
+        byName: musicians(first: 2, orderBy: name, before: $before) {
+            name
+            id
+        }
+    }
+    ";
+
+    // The cursor for Valerie (m4), the last musician by name
+    let cursor = EntityCursor::encode(
+        &[&r::Value::String("Valerie".to_string())],
+        &r::Value::String("m4".to_string()),
+    );
+    run_query((QUERY, object! { before: cursor }), |result, _| {
+        let exp = object! {
+            byName: vec![
+                object! { name: "Lisa", id: "m2" },
+                object! { name: "Tom", id: "m3" },
+            ],
+        };
+        let data = extract_data!(result).unwrap();
+        assert_eq!(data, exp);
+    });
+
+    const BY_ID: &str = "
+    query($before: String) {
+        musicians(first: 1, before: $before) {
+            id
+        }
+    }
+    ";
+
+    let cursor = EntityCursor::encode(&[], &r::Value::String("m3".to_string()));
+    run_query((BY_ID, object! { before: cursor }), |result, _| {
+        let exp = object! {
+            musicians: vec![object! { id: "m2" }],
+        };
+        let data = extract_data!(result).unwrap();
+        assert_eq!(data, exp);
+    })
+}
+
 #[test]
 fn can_query_with_or_filter() {
     const QUERY: &str = "
@@ -1,6 +1,8 @@
 use std::fmt::Write;
 use std::{future::Future, sync::Arc};
 
+use diesel::connection::SimpleConnection;
+
 use graph::{
     blockchain::{block_stream::FirehoseCursor, BlockPtr, BlockTime},
     components::{
@@ -22,7 +24,9 @@ use graph::{
     schema::InputSchema,
 };
 use graph_store_postgres::{Store as DieselStore, SubgraphStore};
-use test_store::{create_test_subgraph, run_test_sequentially, BLOCKS, LOGGER, METRICS_REGISTRY};
+use test_store::{
+    create_test_subgraph, run_test_sequentially, BLOCKS, LOGGER, METRICS_REGISTRY, PRIMARY_POOL,
+};
 
 const SCHEMA: &str = r#"
 type Data @entity(timeseries: true) {
@@ -302,3 +306,31 @@ fn simple() {
         }
     })
 }
+
+#[test]
+fn backfill_matches_indexing() {
+    run_test(|env| async move {
+        // The `vid` of the rows that the backfill writes is different from
+        // that of the rows written while indexing
+        fn rows(entities: &[Entity]) -> Vec<Vec<(&str, &Value)>> {
+            entities.iter().map(|entity| entity.sorted_ref()).collect()
+        }
+
+        let indexed = env.all_entities("Stats_hour", BlockNumber::MAX);
+        assert_eq!(4, indexed.len());
+
+        let mut conn = PRIMARY_POOL.get().unwrap();
+        conn.batch_execute(&format!("delete from sgd{}.stats_hour", env.deployment.id))
+            .unwrap();
+        assert!(env.all_entities("Stats_hour", BlockNumber::MAX).is_empty());
+
+        env.store
+            .subgraph_store()
+            .backfill_aggregation(&env.deployment, "Stats")
+            .await
+            .unwrap();
+
+        let backfilled = env.all_entities("Stats_hour", BlockNumber::MAX);
+        assert_eq!(rows(&indexed), rows(&backfilled));
+    })
+}
@@ -10,7 +10,7 @@ use lazy_static::lazy_static;
 use std::{marker::PhantomData, str::FromStr};
 use test_store::*;
 
-use graph::components::store::{DeploymentLocator, ReadStore, WritableStore};
+use graph::components::store::{DeploymentLocator, EntityDiffOperation, ReadStore, WritableStore};
 use graph::data::subgraph::*;
 use graph::{
     blockchain::DataSource,
@@ -1636,6 +1636,47 @@ fn find_at_block() {
     shaqueeena_at_block(7000, "teeko@email.com");
 }
 
+#[test]
+fn entity_diff_up_to_latest_block() {
+    run_test(|store, _, deployment| async move {
+        let diff = |to: BlockNumber| {
+            store
+                .subgraph_store()
+                .entity_diff(&deployment.hash, USER, 0..=to, None, 100, false)
+                .unwrap()
+                .into_iter()
+                .map(|change| (change.id.to_string(), change.operation, change.cursor.block))
+                .collect::<Vec<_>>()
+        };
+
+        // Ranges past the latest block must not report current versions
+        // as deleted
+        let expected = vec![
+            ("1".to_string(), EntityDiffOperation::Insert, 0),
+            ("2".to_string(), EntityDiffOperation::Insert, 1),
+            ("3".to_string(), EntityDiffOperation::Insert, 1),
+            ("3".to_string(), EntityDiffOperation::Update, 2),
+        ];
+        assert_eq!(expected, diff(2));
+        assert_eq!(expected, diff(7000));
+        assert_eq!(expected, diff(BLOCK_NUMBER_MAX));
+
+        transact_and_wait(
+            &store.subgraph_store(),
+            &deployment,
+            TEST_BLOCK_3_PTR.clone(),
+            vec![EntityOperation::Remove {
+                key: USER_TYPE.parse_key("2").unwrap(),
+            }],
+        )
+        .await
+        .unwrap();
+        let mut expected = expected;
+        expected.push(("2".to_string(), EntityDiffOperation::Delete, 3));
+        assert_eq!(expected, diff(7000));
+    })
+}
+
 #[test]
 fn cleanup_cached_blocks() {
     if store_is_sharded() {
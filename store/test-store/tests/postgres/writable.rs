@@ -2,6 +2,7 @@ use graph::blockchain::block_stream::{EntitySourceOperation, FirehoseCursor};
 use graph::data::subgraph::schema::DeploymentCreate;
 use graph::data::value::Word;
 use graph::data_source::CausalityRegion;
+use graph::env::TEST_WITH_NO_REORG;
 use graph::schema::{EntityKey, EntityType, InputSchema};
 use lazy_static::lazy_static;
 use std::collections::{BTreeMap, BTreeSet};
@@ -17,7 +18,8 @@ use graph::semver::Version;
 use graph::{entity, prelude::*};
 use graph_store_postgres::layout_for_tests::writable;
 use graph_store_postgres::{Store as DieselStore, SubgraphStore as DieselSubgraphStore};
-use web3::types::H256;
+use test_store::block_store::{self, FakeBlock, FakeBlockList, GENESIS_BLOCK};
+use web3::types::{H256, U256};
 
 const SCHEMA_GQL: &str = "
     type Counter @entity {
@@ -520,3 +522,91 @@ fn read_range_pool_created_test() {
         }
     })
 }
+
+#[test]
+fn prune_with_history_duration() {
+    run_test(|store, writable, _, deployment| async move {
+        let subgraph_store = store.subgraph_store();
+        *TEST_WITH_NO_REORG.lock().unwrap() = true;
+
+        // A chain with a block every 10 seconds
+        let timestamp = |number: u64| Some(U256::from(1_000_000 + 10 * number));
+        let genesis: &'static FakeBlock = Box::leak(Box::new(FakeBlock {
+            timestamp: timestamp(0),
+            ..GENESIS_BLOCK.clone()
+        }));
+        let mut chain: FakeBlockList = vec![genesis];
+        for number in 1..=20 {
+            let hash = format!("{:064x}", 0xd0a7_0000u64 + number);
+            let block = chain.last().unwrap().make_child(&hash, timestamp(number));
+            chain.push(Box::leak(Box::new(block)));
+        }
+        block_store::set_chain(chain.clone(), NETWORK_NAME).await;
+
+        let count = |block: &FakeBlock| {
+            let data = entity! { TEST_SUBGRAPH_SCHEMA =>
+                id: "1",
+                count: block.number,
+                vid: block.number as i64,
+            };
+            EntityOperation::Set {
+                key: count_key("1"),
+                data,
+            }
+        };
+        for block in &chain[1..20] {
+            transact_entity_operations(
+                &subgraph_store,
+                &deployment,
+                block.block_ptr(),
+                vec![count(block)],
+            )
+            .await
+            .unwrap();
+        }
+        writable.flush().await.unwrap();
+
+        // Keep 100 seconds of history. The stored `history_blocks` is too
+        // small and gets replaced when the next block triggers pruning
+        subgraph_store
+            .set_history_blocks(&deployment, 2, 0)
+            .unwrap();
+        subgraph_store
+            .set_history_seconds(&deployment, Some(100))
+            .unwrap();
+        let block = chain[20];
+        transact_entity_operations(
+            &subgraph_store,
+            &deployment,
+            block.block_ptr(),
+            vec![count(block)],
+        )
+        .await
+        .unwrap();
+        writable.flush().await.unwrap();
+
+        // Pruning runs in the background
+        let mut info = None;
+        for _ in 0..100 {
+            let status = store
+                .status(status::Filter::Deployments(vec![deployment
+                    .hash
+                    .to_string()]))
+                .unwrap()
+                .pop()
+                .unwrap();
+            if status.chains[0].earliest_block_number > 0 {
+                info = Some(status);
+                break;
+            }
+            graph::tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        *TEST_WITH_NO_REORG.lock().unwrap() = false;
+
+        // Block 10 is the last block that is at least 100 seconds older
+        // than block 20
+        let info = info.expect("pruning finishes");
+        assert_eq!(10, info.history_blocks);
+        assert_eq!(10, info.chains[0].earliest_block_number);
+    })
+}